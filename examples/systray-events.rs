@@ -0,0 +1,37 @@
+#![windows_subsystem = "windows"]
+
+use systray::SystrayEvent;
+
+fn main() -> Result<(), systray::Error> {
+    let mut app;
+    match systray::Application::new() {
+        Ok(w) => app = w,
+        Err(_) => panic!("Can't create window!"),
+    }
+    app.set_icon_from_file("/usr/share/gxkb/flags/ua.png")?;
+
+    let print_item = app.add_menu_entry("Print a thing")?;
+    app.add_menu_separator()?;
+    let quit_item = app.add_menu_entry("Quit")?;
+
+    println!("Waiting on events!");
+    while let Some(event) = app.next_event() {
+        match event {
+            SystrayEvent::MenuItemClicked { item, .. } if item == print_item => {
+                println!("Printing a thing!");
+            }
+            SystrayEvent::MenuItemClicked { item, .. } if item == quit_item => {
+                app.quit();
+            }
+            SystrayEvent::IconClicked { button, .. } => {
+                println!("Icon clicked: {:?}", button);
+            }
+            SystrayEvent::IconDoubleClicked { button, .. } => {
+                println!("Icon double clicked: {:?}", button);
+            }
+            SystrayEvent::Quit => println!("Tray is gone"),
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -16,6 +16,16 @@ fn main() -> Result<(), systray::Error> {
         Ok::<_, systray::Error>(())
     })?;
 
+    app.add_menu_checkable_item("Toggle a thing", false, |_, checked| {
+        println!("Thing is now {}", if checked { "on" } else { "off" });
+        Ok::<_, systray::Error>(())
+    })?;
+
+    app.add_menu_radio_group(&["Slow", "Medium", "Fast"], 1, |_, speed| {
+        println!("Picked speed {}", speed);
+        Ok::<_, systray::Error>(())
+    })?;
+
     app.add_menu_item("Add Menu Item", |window| {
         window.add_menu_item("Interior item", |_| {
             println!("what");
@@ -25,13 +35,18 @@ fn main() -> Result<(), systray::Error> {
         Ok::<_, systray::Error>(())
     })?;
 
-    app.add_menu_separator()?;
-
-    app.add_menu_item("Quit", |window| {
-        window.quit();
+    let mut more = app.add_submenu("More things")?;
+    more.add_menu_item("Print another thing", |_| {
+        println!("Printing another thing!");
         Ok::<_, systray::Error>(())
     })?;
 
+    app.add_standard_items(systray::StandardItems {
+        about: Some("About systray-example"),
+        launch_at_login: true,
+        quit: true,
+    })?;
+
     println!("Waiting on message!");
     app.wait_for_message()?;
     Ok(())
@@ -0,0 +1,132 @@
+// App bundles register with SMAppService, which shows them under Login Items
+// in System Settings. It only exists since macOS 13 and needs a bundle, so
+// anything else gets a LaunchAgent that runs the executable at login.
+use crate::Error;
+use cocoa::{
+    base::{id, nil, BOOL, NO},
+    foundation::{NSAutoreleasePool, NSInteger},
+};
+use objc::{class, msg_send, runtime::Class, sel, sel_impl};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[link(name = "ServiceManagement", kind = "framework")]
+extern "C" {}
+
+// SMAppServiceStatusEnabled
+const SERVICE_ENABLED: NSInteger = 1;
+
+unsafe fn main_app_service() -> Option<id> {
+    let bundle: id = msg_send![class!(NSBundle), mainBundle];
+    let identifier: id = msg_send![bundle, bundleIdentifier];
+    if identifier == nil {
+        return None;
+    }
+    let class = Class::get("SMAppService")?;
+    let service: id = msg_send![class, mainAppService];
+    if service == nil {
+        None
+    } else {
+        Some(service)
+    }
+}
+
+fn agent_path(name: &str) -> Result<PathBuf, Error> {
+    match env::var_os("HOME") {
+        Some(home) => Ok(Path::new(&home)
+            .join("Library/LaunchAgents")
+            .join(format!("{}.plist", agent_label(name)))),
+        None => Err(Error::OsError("HOME is not set".to_owned())),
+    }
+}
+
+fn agent_label(name: &str) -> String {
+    format!("systray.autostart.{}", name)
+}
+
+fn io_error(action: &str, path: &Path, e: io::Error) -> Error {
+    Error::OsError(format!("Couldn't {} {}: {}", action, path.display(), e))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub fn enable(name: &str, exe: &Path) -> Result<(), Error> {
+    unsafe {
+        if let Some(service) = main_app_service() {
+            let pool = NSAutoreleasePool::new(nil);
+            let mut error: id = nil;
+            let ok: BOOL = msg_send![service, registerAndReturnError: &mut error];
+            pool.drain();
+            if ok == NO {
+                return Err(Error::Cocoa {
+                    selector: "registerAndReturnError:",
+                });
+            }
+            return Ok(());
+        }
+    }
+    let path = agent_path(name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| io_error("create", dir, e))?;
+    }
+    let agent = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+"#,
+        xml_escape(&agent_label(name)),
+        xml_escape(&exe.to_string_lossy())
+    );
+    fs::write(&path, agent).map_err(|e| io_error("write", &path, e))
+}
+
+pub fn disable(name: &str) -> Result<(), Error> {
+    unsafe {
+        if let Some(service) = main_app_service() {
+            let pool = NSAutoreleasePool::new(nil);
+            let mut error: id = nil;
+            let ok: BOOL = msg_send![service, unregisterAndReturnError: &mut error];
+            let status: NSInteger = msg_send![service, status];
+            pool.drain();
+            // Unregistering what isn't registered fails too.
+            if ok == NO && status == SERVICE_ENABLED {
+                return Err(Error::Cocoa {
+                    selector: "unregisterAndReturnError:",
+                });
+            }
+            return Ok(());
+        }
+    }
+    let path = agent_path(name)?;
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error("remove", &path, e)),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_enabled(name: &str) -> Result<bool, Error> {
+    unsafe {
+        if let Some(service) = main_app_service() {
+            let status: NSInteger = msg_send![service, status];
+            return Ok(status == SERVICE_ENABLED);
+        }
+    }
+    Ok(agent_path(name)?.exists())
+}
@@ -1,28 +1,1727 @@
-use crate::Error;
-use std;
+pub mod autostart;
+pub mod open;
 
-pub struct Window {}
+use crate::{
+    AboutMetadata, ActivationPolicy, ApplicationMode, Backend, Capabilities, Error, EventSender,
+    HotkeyId, IconBuffer, IconResource, Key, LayoutDirection, MenuOp, MenuStyle, Message,
+    MouseButton, NativeHandle, Rect, ScrollAxis, Shortcut, SystrayEvent, Theme,
+};
+use cocoa::{
+    appkit::{
+        NSApp, NSApplication, NSApplicationActivationPolicyAccessory,
+        NSApplicationActivationPolicyProhibited, NSApplicationActivationPolicyRegular,
+        NSEventMask, NSMenu, NSMenuItem, NSSquareStatusItemLength, NSStatusBar,
+        NSVariableStatusItemLength,
+    },
+    base::{id, nil, BOOL, NO, YES},
+    foundation::{
+        NSArray, NSAutoreleasePool, NSDate, NSDefaultRunLoopMode, NSInteger, NSPoint, NSRect,
+        NSSize, NSString, NSUInteger,
+    },
+};
+use objc::{
+    class,
+    declare::ClassDecl,
+    msg_send,
+    runtime::{Class, Object, Sel},
+    sel, sel_impl,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::CStr,
+    os::raw::c_void,
+    path::PathBuf,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{Receiver, TryRecvError},
+        Once,
+    },
+    time::Duration,
+};
+
+// AppKit, which the cocoa crate links already.
+extern "C" {
+    static NSFontAttributeName: id;
+}
+
+// Our items carry TAG_BASE plus their index as their NSMenuItem tag. Tags from
+// TAG_BASE up are reserved for this: an app that puts its own tagged items in
+// our menus has to keep their tags below it. Clicks on items outside the range
+// are ignored.
+const TAG_BASE: NSInteger = 0x5359_0000_0000;
+
+fn item_tag(item_idx: u32) -> NSInteger {
+    TAG_BASE + item_idx as NSInteger
+}
+
+fn item_index(tag: NSInteger) -> Option<u32> {
+    match tag.checked_sub(TAG_BASE) {
+        Some(idx) if idx >= 0 && idx <= u32::MAX as NSInteger => Some(idx as u32),
+        _ => None,
+    }
+}
+
+// Objective-C object every menu item and the status item button target. Items
+// carry their tag from item_tag, and the handler forwards their index through
+// the event channel, which it keeps a boxed EventSender for in an ivar. It is a
+// responder so it can come after the status item's window in the responder
+// chain, for the events the button leaves alone.
+// None if the name was already taken, e.g. by another copy of this crate,
+// whose class can't be relied on to look like ours.
+fn get_handler_class() -> Option<&'static Class> {
+    static REGISTER_CLASS: Once = Once::new();
+    static REGISTERED: AtomicBool = AtomicBool::new(false);
+    REGISTER_CLASS.call_once(|| {
+        let mut decl = match ClassDecl::new("SystrayMenuHandler", class!(NSResponder)) {
+            Some(d) => d,
+            None => return,
+        };
+        decl.add_ivar::<*mut c_void>("event_tx");
+        // The status item doesn't get its menu set, or AppKit would open it on
+        // every click without telling us. The handler pops it up instead.
+        decl.add_ivar::<id>("status_item");
+        decl.add_ivar::<id>("menu");
+        decl.add_ivar::<BOOL>("menu_on_left_click");
+        // Boxed IconAnimation, null while not animating.
+        decl.add_ivar::<*mut c_void>("animation");
+        decl.add_ivar::<u32>("hotkey_signature");
+        unsafe {
+            decl.add_method(
+                sel!(menuItemClicked:),
+                menu_item_clicked as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(statusItemClicked:),
+                status_item_clicked as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(animationTick:),
+                animation_tick as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(themeChanged:),
+                theme_changed as extern "C" fn(&Object, Sel, id),
+            );
+            // NSMenuDelegate, for the tray menu only.
+            decl.add_method(
+                sel!(menuWillOpen:),
+                menu_will_open as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(menuDidClose:),
+                menu_did_close as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(otherMouseUp:),
+                other_mouse_up as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(scrollWheel:),
+                scroll_wheel as extern "C" fn(&Object, Sel, id),
+            );
+            // NSDraggingDestination, passed on by the status item's window.
+            decl.add_method(
+                sel!(draggingEntered:),
+                dragging_entered as extern "C" fn(&Object, Sel, id) -> NSUInteger,
+            );
+            decl.add_method(
+                sel!(performDragOperation:),
+                perform_drag_operation as extern "C" fn(&Object, Sel, id) -> BOOL,
+            );
+        }
+        decl.register();
+        REGISTERED.store(true, Ordering::SeqCst);
+    });
+    if REGISTERED.load(Ordering::SeqCst) {
+        Class::get("SystrayMenuHandler")
+    } else {
+        None
+    }
+}
+
+unsafe fn get_event_tx(handler: &Object) -> Option<&EventSender> {
+    let tx = *handler.get_ivar::<*mut c_void>("event_tx") as *const EventSender;
+    tx.as_ref()
+}
+
+unsafe fn send_event(handler: &Object, event: SystrayEvent) {
+    if let Some(tx) = get_event_tx(handler) {
+        tx.send(event).ok();
+    }
+}
+
+// AppKit objects may only be touched from the main thread.
+fn is_main_thread() -> bool {
+    unsafe {
+        let is_main_thread: BOOL = msg_send![class!(NSThread), isMainThread];
+        is_main_thread == YES
+    }
+}
+
+// Everything AppKit and Carbon call into goes through this, a panic unwinding
+// into them would abort.
+fn guard<F: FnOnce()>(handler: &Object, f: F) {
+    crate::catch_panic(unsafe { get_event_tx(handler) }, f);
+}
+
+extern "C" fn menu_item_clicked(this: &Object, _: Sel, sender: id) {
+    guard(this, || unsafe {
+        let tag: NSInteger = msg_send![sender, tag];
+        if let (Some(idx), Some(tx)) = (item_index(tag), get_event_tx(this)) {
+            tx.send_click(idx).ok();
+        }
+    });
+}
+
+// The button only sends its action on mouse ups, so anything else is
+// Full Keyboard Access or VoiceOver pressing it.
+extern "C" fn status_item_clicked(this: &Object, _: Sel, _: id) {
+    guard(this, || unsafe {
+        let event: id = msg_send![NSApp(), currentEvent];
+        let event_type: NSUInteger = if event == nil {
+            0
+        } else {
+            msg_send![event, type]
+        };
+        // NSEventTypeLeftMouseUp and NSEventTypeRightMouseUp
+        let button = match event_type {
+            2 => Some(MouseButton::Left),
+            4 => Some(MouseButton::Right),
+            _ => None,
+        };
+        match button {
+            Some(button) => send_icon_click(this, event, button),
+            None => send_event(this, SystrayEvent::IconKeySelected),
+        }
+        let menu_on_left_click = *this.get_ivar::<BOOL>("menu_on_left_click") == YES;
+        if button == Some(MouseButton::Right) || menu_on_left_click {
+            pop_up_menu(this);
+        }
+    });
+}
+
+unsafe fn pop_up_menu(handler: &Object) {
+    let status_item = *handler.get_ivar::<id>("status_item");
+    let menu = *handler.get_ivar::<id>("menu");
+    let _: () = msg_send![status_item, popUpStatusItemMenu: menu];
+}
+
+// The button only tracks the left and right buttons, the others go up the
+// responder chain to the handler.
+extern "C" fn other_mouse_up(this: &Object, _: Sel, event: id) {
+    guard(this, || unsafe {
+        let number: NSInteger = msg_send![event, buttonNumber];
+        if number == 2 {
+            send_icon_click(this, event, MouseButton::Middle);
+        }
+    });
+}
+
+unsafe fn send_icon_click(this: &Object, event: id, button: MouseButton) {
+    let clicks: NSInteger = msg_send![event, clickCount];
+    let location: NSPoint = msg_send![class!(NSEvent), mouseLocation];
+    let (x, y) = (location.x as i32, location.y as i32);
+    // Both in seconds since the system started.
+    let timestamp: f64 = msg_send![event, timestamp];
+    let process: id = msg_send![class!(NSProcessInfo), processInfo];
+    let uptime: f64 = msg_send![process, systemUptime];
+    let time = crate::event_time(Duration::from_secs_f64((uptime - timestamp).max(0.0)));
+    let event = if clicks > 1 {
+        SystrayEvent::IconDoubleClicked {
+            button,
+            x,
+            y,
+            time,
+        }
+    } else {
+        SystrayEvent::IconClicked {
+            button,
+            x,
+            y,
+            time,
+        }
+    };
+    send_event(this, event);
+}
+
+extern "C" fn menu_will_open(this: &Object, _: Sel, _: id) {
+    guard(this, || unsafe {
+        send_event(this, SystrayEvent::MenuWillOpen);
+    });
+}
+
+extern "C" fn menu_did_close(this: &Object, _: Sel, _: id) {
+    guard(this, || unsafe {
+        send_event(this, SystrayEvent::MenuDidClose);
+    });
+}
+
+// deltaY is positive up, deltaX positive left. Both are in lines, even for
+// touchpads.
+extern "C" fn scroll_wheel(this: &Object, _: Sel, event: id) {
+    guard(this, || unsafe {
+        let dx: f64 = msg_send![event, deltaX];
+        let dy: f64 = msg_send![event, deltaY];
+        let event = if dy != 0.0 {
+            SystrayEvent::Scroll {
+                delta: dy,
+                axis: ScrollAxis::Vertical,
+            }
+        } else if dx != 0.0 {
+            SystrayEvent::Scroll {
+                delta: -dx,
+                axis: ScrollAxis::Horizontal,
+            }
+        } else {
+            return;
+        };
+        send_event(this, event);
+    });
+}
+
+const FILENAMES_PBOARD_TYPE: &str = "NSFilenamesPboardType";
+const DRAG_OPERATION_NONE: NSUInteger = 0;
+const DRAG_OPERATION_COPY: NSUInteger = 1;
+
+// Paths of the files being dragged, empty if it isn't files.
+unsafe fn dragged_files(info: id) -> Vec<PathBuf> {
+    let pasteboard: id = msg_send![info, draggingPasteboard];
+    let kind = NSString::alloc(nil)
+        .init_str(FILENAMES_PBOARD_TYPE)
+        .autorelease();
+    let names: id = msg_send![pasteboard, propertyListForType: kind];
+    if names == nil {
+        return Vec::new();
+    }
+    let count: NSUInteger = msg_send![names, count];
+    (0..count)
+        .map(|i| {
+            let name: id = msg_send![names, objectAtIndex: i];
+            let name = CStr::from_ptr(NSString::UTF8String(name));
+            PathBuf::from(name.to_string_lossy().into_owned())
+        })
+        .collect()
+}
+
+extern "C" fn dragging_entered(this: &Object, _: Sel, info: id) -> NSUInteger {
+    let mut operation = DRAG_OPERATION_NONE;
+    guard(this, || unsafe {
+        if !dragged_files(info).is_empty() {
+            operation = DRAG_OPERATION_COPY;
+        }
+    });
+    operation
+}
+
+extern "C" fn perform_drag_operation(this: &Object, _: Sel, info: id) -> BOOL {
+    let mut accepted = NO;
+    guard(this, || unsafe {
+        let files = dragged_files(info);
+        if !files.is_empty() {
+            send_event(this, SystrayEvent::FilesDropped(files));
+            accepted = YES;
+        }
+    });
+    accepted
+}
+
+extern "C" fn theme_changed(this: &Object, _: Sel, _: id) {
+    guard(this, || unsafe {
+        send_event(this, SystrayEvent::ThemeChanged(get_system_theme()));
+    });
+}
+
+// AppleInterfaceStyle is only set at all in dark mode.
+unsafe fn get_system_theme() -> Theme {
+    let pool = NSAutoreleasePool::new(nil);
+    let defaults: id = msg_send![class!(NSUserDefaults), standardUserDefaults];
+    let key = NSString::alloc(nil)
+        .init_str("AppleInterfaceStyle")
+        .autorelease();
+    let style: id = msg_send![defaults, stringForKey: key];
+    let dark = NSString::alloc(nil).init_str("Dark").autorelease();
+    let is_dark: BOOL = if style == nil {
+        NO
+    } else {
+        msg_send![style, isEqualToString: dark]
+    };
+    pool.drain();
+    if is_dark == YES {
+        Theme::Dark
+    } else {
+        Theme::Light
+    }
+}
+
+const THEME_NOTIFICATION: &str = "AppleInterfaceThemeChangedNotification";
+
+// Carbon hotkeys are still the only way to get global hotkeys without asking
+// for accessibility permissions. They are delivered through the main run loop,
+// like everything else here.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EventHotKeyID {
+    signature: u32,
+    id: u32,
+}
+
+#[repr(C)]
+struct EventTypeSpec {
+    event_class: u32,
+    event_kind: u32,
+}
+
+type EventHandlerProcPtr = extern "C" fn(*mut c_void, *mut c_void, *mut c_void) -> i32;
+
+#[link(name = "Carbon", kind = "framework")]
+extern "C" {
+    fn GetApplicationEventTarget() -> *mut c_void;
+    fn InstallEventHandler(
+        target: *mut c_void,
+        handler: EventHandlerProcPtr,
+        num_types: u32,
+        list: *const EventTypeSpec,
+        user_data: *mut c_void,
+        out_ref: *mut *mut c_void,
+    ) -> i32;
+    fn RemoveEventHandler(handler: *mut c_void) -> i32;
+    fn GetEventParameter(
+        event: *mut c_void,
+        name: u32,
+        desired_type: u32,
+        actual_type: *mut u32,
+        buffer_size: usize,
+        actual_size: *mut usize,
+        data: *mut c_void,
+    ) -> i32;
+    fn RegisterEventHotKey(
+        key_code: u32,
+        modifiers: u32,
+        id: EventHotKeyID,
+        target: *mut c_void,
+        options: u32,
+        out_ref: *mut *mut c_void,
+    ) -> i32;
+    fn UnregisterEventHotKey(hotkey: *mut c_void) -> i32;
+}
+
+const fn four_cc(code: &[u8; 4]) -> u32 {
+    (code[0] as u32) << 24 | (code[1] as u32) << 16 | (code[2] as u32) << 8 | code[3] as u32
+}
+
+// Each icon registers its hotkeys with a signature of its own, counting up
+// from this one, and only handles those.
+const HOTKEY_SIGNATURE: u32 = four_cc(b"STry");
+static NEXT_HOTKEY_SIGNATURE: AtomicU32 = AtomicU32::new(HOTKEY_SIGNATURE);
+
+// What a Carbon handler returns to pass an event on to the next one.
+const EVENT_NOT_HANDLED_ERR: i32 = -9874;
+
+// user_data is the handler object.
+extern "C" fn hotkey_pressed(_: *mut c_void, event: *mut c_void, user_data: *mut c_void) -> i32 {
+    let handler = unsafe { &*(user_data as *const Object) };
+    let mut handled = false;
+    guard(handler, || unsafe {
+        let mut hotkey = EventHotKeyID {
+            signature: 0,
+            id: 0,
+        };
+        let status = GetEventParameter(
+            event,
+            four_cc(b"----"),
+            four_cc(b"hkid"),
+            ptr::null_mut(),
+            std::mem::size_of::<EventHotKeyID>(),
+            ptr::null_mut(),
+            &mut hotkey as *mut EventHotKeyID as *mut c_void,
+        );
+        let signature = *handler.get_ivar::<u32>("hotkey_signature");
+        if status == 0 && hotkey.signature == signature {
+            send_event(handler, SystrayEvent::HotkeyPressed(HotkeyId(hotkey.id)));
+            handled = true;
+        }
+    });
+    if handled {
+        0
+    } else {
+        EVENT_NOT_HANDLED_ERR
+    }
+}
+
+// Virtual key codes are positions on an ANSI keyboard, not characters.
+fn get_key_code(key: Key) -> Option<u32> {
+    let code = match key {
+        Key::F(n) => match n {
+            1 => 0x7A,
+            2 => 0x78,
+            3 => 0x63,
+            4 => 0x76,
+            5 => 0x60,
+            6 => 0x61,
+            7 => 0x62,
+            8 => 0x64,
+            9 => 0x65,
+            10 => 0x6D,
+            11 => 0x67,
+            12 => 0x6F,
+            13 => 0x69,
+            14 => 0x6B,
+            15 => 0x71,
+            16 => 0x6A,
+            17 => 0x40,
+            18 => 0x4F,
+            19 => 0x50,
+            20 => 0x5A,
+            _ => return None,
+        },
+        Key::Char(c) => {
+            let keys = "ASDFHGZXCV\0BQWERYT123465=97-80]OU[IP\0LJ'K;\\,/NM.\0\0`";
+            match c {
+                ' ' => 0x31,
+                '\0' => return None,
+                c => return keys.chars().position(|k| k == c).map(|p| p as u32),
+            }
+        }
+    };
+    Some(code)
+}
+
+struct IconAnimation {
+    frames: Vec<id>,
+    current: usize,
+}
+
+extern "C" fn animation_tick(this: &Object, _: Sel, _: id) {
+    guard(this, || unsafe {
+        let animation = *this.get_ivar::<*mut c_void>("animation") as *mut IconAnimation;
+        if animation.is_null() {
+            return;
+        }
+        let animation = &mut *animation;
+        animation.current = (animation.current + 1) % animation.frames.len();
+        let status_item = *this.get_ivar::<id>("status_item");
+        let button: id = msg_send![status_item, button];
+        let _: () = msg_send![button, setImage: animation.frames[animation.current]];
+    });
+}
+
+unsafe fn set_image_description(image: id, desc: &str) {
+    let desc = NSString::alloc(nil).init_str(desc);
+    let _: () = msg_send![image, setAccessibilityDescription: desc];
+    let _: () = msg_send![desc, release];
+}
+
+// Returns a retained NSImage, sized for the status bar.
+unsafe fn image_from_rgba(rgba: &[u8], width: u32, height: u32) -> Result<id, Error> {
+    let pool = NSAutoreleasePool::new(nil);
+    let color_space = NSString::alloc(nil)
+        .init_str("NSDeviceRGBColorSpace")
+        .autorelease();
+    let rep: id = msg_send![class!(NSBitmapImageRep), alloc];
+    // NSBitmapFormatAlphaNonpremultiplied
+    let format: NSUInteger = 1 << 1;
+    let rep: id = msg_send![rep,
+        initWithBitmapDataPlanes: ptr::null_mut::<*mut u8>()
+        pixelsWide: width as NSInteger
+        pixelsHigh: height as NSInteger
+        bitsPerSample: 8 as NSInteger
+        samplesPerPixel: 4 as NSInteger
+        hasAlpha: YES
+        isPlanar: NO
+        colorSpaceName: color_space
+        bitmapFormat: format
+        bytesPerRow: (width * 4) as NSInteger
+        bitsPerPixel: 32 as NSInteger];
+    if rep == nil {
+        pool.drain();
+        return Err(Error::Cocoa {
+            selector: "initWithBitmapDataPlanes:",
+        });
+    }
+    let data: *mut u8 = msg_send![rep, bitmapData];
+    ptr::copy_nonoverlapping(rgba.as_ptr(), data, rgba.len());
+    let image: id = msg_send![class!(NSImage), alloc];
+    let image: id = msg_send![image, initWithSize: NSSize::new(width as f64, height as f64)];
+    let _: () = msg_send![image, addRepresentation: rep];
+    let _: () = msg_send![rep, release];
+    size_for_status_bar(image);
+    pool.drain();
+    Ok(image)
+}
+
+unsafe fn scale_to_height(image: id, height: f64) {
+    let size: NSSize = msg_send![image, size];
+    if size.height > 0.0 {
+        let scaled = NSSize::new(size.width * height / size.height, height);
+        let _: () = msg_send![image, setSize: scaled];
+    }
+}
+
+unsafe fn size_for_status_bar(image: id) {
+    // Status bar icons are drawn 18 points high, whatever their pixel size.
+    scale_to_height(image, 18.0);
+}
+
+// All of this has to live on the main thread, AppKit doesn't support touching
+// the status bar or menus from anywhere else. new checks that it's made there,
+// and the ids keep Window, and with it Application, from being sent to another
+// thread, so every call below runs on the main thread too. ApplicationHandles
+// queue their calls for it and only post the wake event from elsewhere.
+pub struct Window {
+    status_item: id,
+    menu: id,
+    handler: id,
+    submenus: RefCell<HashMap<u32, id>>,
+    // NSUserInterfaceLayoutDirection for the menus, once the app sets one.
+    menu_direction: Cell<Option<NSInteger>>,
+    animation_timer: Cell<id>,
+    // Bitmap of the icon last set from pixels, and its size, while it's the
+    // one showing. Nil otherwise.
+    icon_rep: Cell<(id, (u32, u32))>,
+    // Set by quit, makes recv_event stop pumping and report the end.
+    quit: Cell<bool>,
+    // Whether the status item has been taken out of the status bar.
+    removed: Cell<bool>,
+    // Whether icons are template images, which AppKit recolors to match the
+    // menu bar.
+    template: Cell<bool>,
+    // What VoiceOver reads out for the icon, put on every image it shows.
+    icon_description: RefCell<String>,
+    // Carbon event handler for hotkeys, installed with the first one.
+    hotkey_handler: Cell<*mut c_void>,
+    hotkeys: RefCell<HashMap<u32, *mut c_void>>,
+    mode: ApplicationMode,
+}
 
 impl Window {
-    pub fn new() -> Result<Window, Error> {
-        Err(Error::NotImplementedError)
+    pub fn new(
+        event_tx: EventSender,
+        mode: ApplicationMode,
+        backend: Backend,
+        visible: bool,
+    ) -> Result<Window, Error> {
+        match backend {
+            Backend::Auto | Backend::Cocoa => {}
+            _ => {
+                return Err(Error::NotSupported {
+                    feature: "tray backends other than Cocoa",
+                })
+            }
+        }
+        unsafe {
+            if !is_main_thread() {
+                return Err(Error::OsError(
+                    "Cocoa systray must be created on the main thread".to_owned(),
+                ));
+            }
+            let pool = NSAutoreleasePool::new(nil);
+            let app = NSApp();
+            // Only once, the later icons join the running application. A host
+            // that runs NSApp has launched it already and picked its own policy.
+            static LAUNCHED: Once = Once::new();
+            if mode == ApplicationMode::OwnRunLoop {
+                LAUNCHED.call_once(|| {
+                    app.setActivationPolicy_(NSApplicationActivationPolicyAccessory);
+                    app.finishLaunching();
+                });
+            }
+
+            let class = match get_handler_class() {
+                Some(c) => c,
+                None => {
+                    pool.drain();
+                    let msg = "SystrayMenuHandler is registered by someone else";
+                    return Err(Error::OsError(msg.to_owned()));
+                }
+            };
+            let handler: id = msg_send![class, new];
+            let tx = Box::into_raw(Box::new(event_tx)) as *mut c_void;
+            (*handler).set_ivar("event_tx", tx);
+
+            let menu = NSMenu::new(nil);
+            // Otherwise AppKit decides which items are enabled on its own.
+            let _: () = msg_send![menu, setAutoenablesItems: NO];
+            let _: () = msg_send![menu, setDelegate: handler];
+            let status_item =
+                NSStatusBar::systemStatusBar(nil).statusItemWithLength_(NSSquareStatusItemLength);
+            let _: id = msg_send![status_item, retain];
+            if !visible {
+                let _: () = msg_send![status_item, setVisible: NO];
+            }
+            (*handler).set_ivar("status_item", status_item);
+            (*handler).set_ivar("menu", menu);
+            (*handler).set_ivar::<BOOL>("menu_on_left_click", YES);
+            (*handler).set_ivar("animation", ptr::null_mut::<c_void>());
+            let signature = NEXT_HOTKEY_SIGNATURE.fetch_add(1, Ordering::SeqCst);
+            (*handler).set_ivar("hotkey_signature", signature);
+            let button: id = msg_send![status_item, button];
+            let _: () = msg_send![button, setTarget: handler];
+            let _: () = msg_send![button, setAction: sel!(statusItemClicked:)];
+            let mask = NSEventMask::NSLeftMouseUpMask | NSEventMask::NSRightMouseUpMask;
+            let _: NSInteger = msg_send![button, sendActionOn: mask.bits()];
+            // The button's window hands drags to its delegate and scrolls to
+            // the next responder, which saves subclassing the button.
+            let button_window: id = msg_send![button, window];
+            if button_window != nil {
+                let kind = NSString::alloc(nil)
+                    .init_str(FILENAMES_PBOARD_TYPE)
+                    .autorelease();
+                let types = NSArray::arrayWithObject(nil, kind);
+                let _: () = msg_send![button_window, registerForDraggedTypes: types];
+                let _: () = msg_send![button_window, setDelegate: handler];
+                let _: () = msg_send![button_window, setNextResponder: handler];
+            }
+
+            // Appearance changes are only announced system wide.
+            let center: id = msg_send![class!(NSDistributedNotificationCenter), defaultCenter];
+            let name = NSString::alloc(nil)
+                .init_str(THEME_NOTIFICATION)
+                .autorelease();
+            let _: () = msg_send![center,
+                addObserver: handler
+                selector: sel!(themeChanged:)
+                name: name
+                object: nil];
+            pool.drain();
+
+            Ok(Window {
+                status_item,
+                menu,
+                handler,
+                submenus: RefCell::new(HashMap::new()),
+                menu_direction: Cell::new(None),
+                animation_timer: Cell::new(nil),
+                icon_rep: Cell::new((nil, (0, 0))),
+                quit: Cell::new(false),
+                removed: Cell::new(false),
+                template: Cell::new(false),
+                icon_description: RefCell::new(String::new()),
+                hotkey_handler: Cell::new(ptr::null_mut()),
+                hotkeys: RefCell::new(HashMap::new()),
+                mode,
+            })
+        }
+    }
+
+    // Menu actions are only delivered while the main thread is running its
+    // event loop, so keep pumping it until the handler sends something. With
+    // distantFuture AppKit sleeps in the run loop until an event comes in,
+    // or a wake from another thread, so an idle tray uses no CPU and no
+    // thread polls the channel.
+    pub(crate) fn recv_event(&self, rx: &Receiver<Message>) -> Option<Message> {
+        loop {
+            if self.quit.get() {
+                return None;
+            }
+            match rx.try_recv() {
+                Ok(e) => return Some(e),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => {}
+            }
+            unsafe {
+                let pool = NSAutoreleasePool::new(nil);
+                let app = NSApp();
+                let event = app.nextEventMatchingMask_untilDate_inMode_dequeue_(
+                    NSEventMask::NSAnyEventMask.bits(),
+                    NSDate::distantFuture(nil),
+                    NSDefaultRunLoopMode,
+                    YES,
+                );
+                app.sendEvent_(event);
+                pool.drain();
+            }
+        }
+    }
+
+    // Handles whatever the main thread has queued without waiting for more, then
+    // checks for an event. When attached, the host's loop delivers the events
+    // and only the channel is looked at.
+    pub(crate) fn try_recv_event(
+        &self,
+        rx: &Receiver<Message>,
+    ) -> Result<Message, TryRecvError> {
+        if self.quit.get() {
+            return Err(TryRecvError::Disconnected);
+        }
+        if self.mode == ApplicationMode::AttachToExisting {
+            return rx.try_recv();
+        }
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let app = NSApp();
+            loop {
+                let event = app.nextEventMatchingMask_untilDate_inMode_dequeue_(
+                    NSEventMask::NSAnyEventMask.bits(),
+                    NSDate::distantPast(nil),
+                    NSDefaultRunLoopMode,
+                    YES,
+                );
+                if event == nil {
+                    break;
+                }
+                app.sendEvent_(event);
+            }
+            pool.drain();
+        }
+        rx.try_recv()
+    }
+
+    // recv_event sits in AppKit waiting for an NSEvent, so give it an empty
+    // one to get it to look at the channel again. Posting events is one of the
+    // few things AppKit allows from other threads.
+    pub fn wake() {
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            // NSEventTypeApplicationDefined
+            let event: id = msg_send![class!(NSEvent),
+                otherEventWithType: 15 as NSUInteger
+                location: NSPoint::new(0.0, 0.0)
+                modifierFlags: 0 as NSUInteger
+                timestamp: 0.0 as f64
+                windowNumber: 0 as NSInteger
+                context: nil
+                subtype: 0 as i16
+                data1: 0 as NSInteger
+                data2: 0 as NSInteger];
+            let _: () = msg_send![NSApp(), postEvent: event atStart: NO];
+            pool.drain();
+        }
+    }
+
+    // The tray runs on the app's own main thread, so there is no loop thread
+    // to join and NSApp is left running for the rest of the app. Removing the
+    // icon and ending recv_event is all there is to do.
+    pub fn native_handle(&self) -> Result<NativeHandle, Error> {
+        Ok(NativeHandle::MacOS {
+            status_item: self.status_item as *mut c_void,
+        })
     }
+
+    // The frame of the window the status item's button sits in.
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        Ok(Capabilities {
+            supports_tooltip: true,
+            supports_title_text: true,
+            supports_menu_icons: true,
+            supports_notifications: true,
+            supports_icon_clicks: true,
+            supports_double_click: true,
+            supports_icon_screen_rect: true,
+            supports_icon_from_file: true,
+            supports_icon_accessibility_description: true,
+            supports_global_hotkeys: true,
+            supports_file_drops: true,
+            supports_scroll: true,
+        })
+    }
+
+    pub fn icon_screen_rect(&self) -> Result<Rect, Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let button: id = msg_send![self.status_item, button];
+            let window: id = if button == nil {
+                nil
+            } else {
+                msg_send![button, window]
+            };
+            if window == nil {
+                return Err(Error::Cocoa { selector: "window" });
+            }
+            let frame: NSRect = msg_send![window, frame];
+            Ok(Rect {
+                x: frame.origin.x as i32,
+                y: frame.origin.y as i32,
+                width: frame.size.width as u32,
+                height: frame.size.height as u32,
+            })
+        }
+    }
+
     pub fn quit(&self) {
-        unimplemented!()
+        self.quit.set(true);
+        self.shutdown().ok();
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let tooltip = NSString::alloc(nil).init_str(tooltip).autorelease();
+            let button: id = msg_send![self.status_item, button];
+            let _: () = msg_send![button, setToolTip: tooltip];
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    pub fn register_hotkey(&self, id: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let key_code = match get_key_code(shortcut.key) {
+            Some(k) => k,
+            // Macs have no keys past F20, so Carbon has no key codes for them.
+            None if matches!(shortcut.key, Key::F(n) if n > 20) => {
+                return Err(Error::NotSupported {
+                    feature: "global hotkeys on F21 to F24",
+                })
+            }
+            None => {
+                let msg = format!("No key code for {:?}", shortcut.key);
+                return Err(Error::InvalidShortcut(msg));
+            }
+        };
+        let m = shortcut.modifiers;
+        // cmdKey, shiftKey, optionKey and controlKey.
+        let mut modifiers = 0;
+        for (on, flag) in [(m.meta, 8), (m.shift, 9), (m.alt, 11), (m.ctrl, 12)].iter() {
+            if *on {
+                modifiers |= 1 << flag;
+            }
+        }
+        unsafe {
+            if self.hotkey_handler.get().is_null() {
+                // kEventClassKeyboard, kEventHotKeyPressed
+                let spec = EventTypeSpec {
+                    event_class: four_cc(b"keyb"),
+                    event_kind: 5,
+                };
+                let mut handler = ptr::null_mut();
+                let status = InstallEventHandler(
+                    GetApplicationEventTarget(),
+                    hotkey_pressed,
+                    1,
+                    &spec,
+                    self.handler as *mut c_void,
+                    &mut handler,
+                );
+                if status != 0 {
+                    let msg = format!("Error installing hotkey handler: {}", status);
+                    return Err(Error::OsError(msg));
+                }
+                self.hotkey_handler.set(handler);
+            }
+            let hotkey_id = EventHotKeyID {
+                signature: *(*self.handler).get_ivar::<u32>("hotkey_signature"),
+                id,
+            };
+            let mut hotkey = ptr::null_mut();
+            let status = RegisterEventHotKey(
+                key_code,
+                modifiers,
+                hotkey_id,
+                GetApplicationEventTarget(),
+                0,
+                &mut hotkey,
+            );
+            if status != 0 {
+                return Err(Error::OsError(format!(
+                    "Error registering hotkey: {}",
+                    status
+                )));
+            }
+            self.hotkeys.borrow_mut().insert(id, hotkey);
+        }
+        Ok(())
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        match self.hotkeys.borrow_mut().remove(&id) {
+            Some(hotkey) => {
+                unsafe { UnregisterEventHotKey(hotkey) };
+                Ok(())
+            }
+            None => Err(Error::OsError(format!("No hotkey {}", id))),
+        }
+    }
+
+    // A square status item cuts off any text, so it only grows while there is
+    // a title.
+    pub fn set_title(&self, title: &str) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let button: id = msg_send![self.status_item, button];
+            let string = NSString::alloc(nil).init_str(title).autorelease();
+            let _: () = msg_send![button, setTitle: string];
+            let length = if title.is_empty() {
+                NSSquareStatusItemLength
+            } else {
+                NSVariableStatusItemLength
+            };
+            let _: () = msg_send![self.status_item, setLength: length];
+            // NSImageLeft
+            let _: () = msg_send![button, setImagePosition: 2 as NSUInteger];
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    fn get_menu(&self, parent: Option<u32>) -> Result<id, Error> {
+        match parent {
+            Some(p) => match self.submenus.borrow().get(&p) {
+                Some(m) => Ok(*m),
+                None => Err(Error::OsError(format!("No submenu {}", p))),
+            },
+            None => Ok(self.menu),
+        }
+    }
+
+    // itemWithTag: only looks at a single menu, so go through the submenus as
+    // well.
+    fn find_menu_item(&self, item_idx: u32) -> id {
+        let submenus = self.submenus.borrow();
+        for menu in Some(&self.menu).into_iter().chain(submenus.values()) {
+            let item: id = unsafe { msg_send![*menu, itemWithTag: item_tag(item_idx)] };
+            if item != nil {
+                return item;
+            }
+        }
+        nil
+    }
+
+    unsafe fn new_menu_item(&self, item_idx: u32, item_name: &str) -> id {
+        let title = NSString::alloc(nil).init_str(item_name).autorelease();
+        let key = NSString::alloc(nil).init_str("").autorelease();
+        let item = NSMenuItem::alloc(nil)
+            .initWithTitle_action_keyEquivalent_(title, sel!(menuItemClicked:), key)
+            .autorelease();
+        item.setTarget_(self.handler);
+        let _: () = msg_send![item, setTag: item_tag(item_idx)];
+        item
+    }
+
+    // Submenus made later get it from add_submenu.
+    pub fn set_menu_direction(&self, direction: LayoutDirection) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let direction: NSInteger = match direction {
+            LayoutDirection::LeftToRight => 0,
+            LayoutDirection::RightToLeft => 1,
+        };
+        self.menu_direction.set(Some(direction));
+        let submenus = self.submenus.borrow();
+        for menu in Some(&self.menu).into_iter().chain(submenus.values()) {
+            unsafe {
+                let _: () = msg_send![*menu, setUserInterfaceLayoutDirection: direction];
+            }
+        }
+        Ok(())
+    }
+
+    // NSMenu already follows the system's appearance, dark mode included.
+    pub fn set_menu_style(&self, _: Option<MenuStyle>) -> Result<(), Error> {
+        Err(Error::NotSupported {
+            feature: "menu styles",
+        })
+    }
+
+    // Runs the menu's own event loop until it closes.
+    pub fn show_menu(&self) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe { pop_up_menu(&*self.handler) };
+        Ok(())
+    }
+
+    // With no view, the location is in screen coordinates, from the bottom
+    // left like NSEvent's mouseLocation.
+    pub fn show_menu_at(&self, x: i32, y: i32) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let location = NSPoint::new(x as f64, y as f64);
+        unsafe {
+            let _: BOOL = msg_send![
+                self.menu,
+                popUpMenuPositioningItem: nil
+                atLocation: location
+                inView: nil
+            ];
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let enabled: BOOL = if enabled { YES } else { NO };
+        unsafe {
+            (*self.handler).set_ivar("menu_on_left_click", enabled);
+        }
+        Ok(())
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let menu = self.get_menu(parent)?;
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            menu.addItem_(self.new_menu_item(item_idx, item_name));
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    pub fn add_menu_checkable_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        checked: bool,
+    ) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        self.add_menu_entry(item_idx, parent, item_name)?;
+        self.set_menu_item_checked(item_idx, checked)
+    }
+
+    // Same check mark as checkable items, which is how macOS menus show a
+    // choice too. Unchecking the rest of the group is up to the caller.
+    pub fn add_menu_radio_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        _group: u32,
+        checked: bool,
+    ) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        self.add_menu_checkable_entry(item_idx, parent, item_name, checked)
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let menu = self.get_menu(parent)?;
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let item = self.new_menu_item(item_idx, item_name);
+            // Items with a submenu don't send their action, they open it.
+            let submenu = NSMenu::new(nil);
+            let _: () = msg_send![submenu, setAutoenablesItems: NO];
+            if let Some(direction) = self.menu_direction.get() {
+                let _: () = msg_send![submenu, setUserInterfaceLayoutDirection: direction];
+            }
+            item.setSubmenu_(submenu);
+            menu.addItem_(item);
+            pool.drain();
+            self.submenus.borrow_mut().insert(item_idx, submenu);
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let item = self.find_menu_item(item_idx);
+            if item == nil {
+                return Err(Error::OsError(format!("No menu item {}", item_idx)));
+            }
+            // NSControlStateValueOn / NSControlStateValueOff
+            let state: NSInteger = if checked { 1 } else { 0 };
+            let _: () = msg_send![item, setState: state];
+        }
+        Ok(())
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let item = self.find_menu_item(item_idx);
+            if item == nil {
+                return Err(Error::OsError(format!("No menu item {}", item_idx)));
+            }
+            let menu: id = msg_send![item, menu];
+            let _: () = msg_send![menu, removeItem: item];
+            if let Some(submenu) = self.submenus.borrow_mut().remove(&item_idx) {
+                let _: () = msg_send![submenu, release];
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_icon(&self, item_idx: u32, icon: &IconBuffer) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let item = self.find_menu_item(item_idx);
+            if item == nil {
+                return Err(Error::OsError(format!("No menu item {}", item_idx)));
+            }
+            let image = image_from_rgba(&icon.rgba, icon.width, icon.height)?;
+            // Menu images sit next to the title, so match its height.
+            scale_to_height(image, 16.0);
+            let _: () = msg_send![item, setImage: image];
+            let _: () = msg_send![image, release];
+        }
+        Ok(())
+    }
+
+    // Key equivalents work while the menu is open, like in any other menu.
+    pub fn set_menu_item_shortcut(&self, item_idx: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let key = match shortcut.key {
+            // An upper case key equivalent would imply shift.
+            Key::Char(c) => c.to_ascii_lowercase(),
+            // NSF1FunctionKey and on, in the private use area.
+            Key::F(n) => std::char::from_u32(0xF704 + n as u32 - 1).unwrap_or(' '),
+        };
+        let m = shortcut.modifiers;
+        // NSEventModifierFlagShift, Control, Option and Command.
+        let mut mask: NSUInteger = 0;
+        for (on, flag) in [(m.shift, 17), (m.ctrl, 18), (m.alt, 19), (m.meta, 20)].iter() {
+            if *on {
+                mask |= 1 << flag;
+            }
+        }
+        unsafe {
+            let item = self.find_menu_item(item_idx);
+            if item == nil {
+                return Err(Error::OsError(format!("No menu item {}", item_idx)));
+            }
+            let pool = NSAutoreleasePool::new(nil);
+            let key = NSString::alloc(nil)
+                .init_str(&key.to_string())
+                .autorelease();
+            let _: () = msg_send![item, setKeyEquivalent: key];
+            let _: () = msg_send![item, setKeyEquivalentModifierMask: mask];
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_label(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let item = self.find_menu_item(item_idx);
+            if item == nil {
+                return Err(Error::OsError(format!("No menu item {}", item_idx)));
+            }
+            let pool = NSAutoreleasePool::new(nil);
+            let title = NSString::alloc(nil).init_str(item_name).autorelease();
+            let _: () = msg_send![item, setTitle: title];
+            pool.drain();
+        }
+        Ok(())
     }
-    pub fn set_tooltip(&self, _: &str) -> Result<(), Error> {
-        unimplemented!()
+
+    pub fn set_menu_item_tooltip(&self, item_idx: u32, tooltip: &str) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let item = self.find_menu_item(item_idx);
+            if item == nil {
+                return Err(Error::OsError(format!("No menu item {}", item_idx)));
+            }
+            let pool = NSAutoreleasePool::new(nil);
+            let tooltip = if tooltip.is_empty() {
+                nil
+            } else {
+                NSString::alloc(nil).init_str(tooltip).autorelease()
+            };
+            let _: () = msg_send![item, setToolTip: tooltip];
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let item = self.find_menu_item(item_idx);
+            if item == nil {
+                return Err(Error::OsError(format!("No menu item {}", item_idx)));
+            }
+            let enabled: BOOL = if enabled { YES } else { NO };
+            let _: () = msg_send![item, setEnabled: enabled];
+        }
+        Ok(())
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let menu = self.get_menu(parent)?;
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let item = NSMenuItem::separatorItem(nil);
+            let _: () = msg_send![item, setTag: item_tag(item_idx)];
+            menu.addItem_(item);
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    // sectionHeaderWithTitle: is new in macOS 14. Before that it's a disabled
+    // item with a bold title, like the headers in the system's own menus.
+    pub fn add_menu_header(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        title: &str,
+    ) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let menu = self.get_menu(parent)?;
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let title = NSString::alloc(nil).init_str(title).autorelease();
+            let native: BOOL = msg_send![
+                class!(NSMenuItem),
+                respondsToSelector: sel!(sectionHeaderWithTitle:)
+            ];
+            let item: id = if native == YES {
+                msg_send![class!(NSMenuItem), sectionHeaderWithTitle: title]
+            } else {
+                let item: id = msg_send![class!(NSMenuItem), new];
+                let font: id = msg_send![class!(NSFont), menuFontOfSize: 0.0f64];
+                let size: f64 = msg_send![font, pointSize];
+                let bold: id = msg_send![class!(NSFont), boldSystemFontOfSize: size];
+                let attributes: id = msg_send![
+                    class!(NSDictionary),
+                    dictionaryWithObject: bold
+                    forKey: NSFontAttributeName
+                ];
+                let text: id = msg_send![class!(NSAttributedString), alloc];
+                let text: id = msg_send![text, initWithString: title attributes: attributes];
+                let _: () = msg_send![item, setTitle: title];
+                let _: () = msg_send![item, setAttributedTitle: text.autorelease()];
+                let _: () = msg_send![item, setEnabled: NO];
+                item.autorelease()
+            };
+            let _: () = msg_send![item, setTag: item_tag(item_idx)];
+            menu.addItem_(item);
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    fn clear_menu(&self) {
+        unsafe {
+            let _: () = msg_send![self.menu, removeAllItems];
+            for (_, submenu) in self.submenus.borrow_mut().drain() {
+                let _: () = msg_send![submenu, release];
+            }
+        }
     }
-    pub fn add_menu_item<F>(&self, _: &str, _: F) -> Result<u32, Error>
-    where
-        F: std::ops::Fn(&Window) -> () + 'static,
-    {
-        unimplemented!()
+
+    pub(crate) fn apply_menu_ops(&self, ops: Vec<MenuOp>) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        for op in ops {
+            match op {
+                MenuOp::Entry(idx, parent, name) => self.add_menu_entry(idx, parent, &name)?,
+                MenuOp::CheckableEntry(idx, parent, name, checked) => {
+                    self.add_menu_checkable_entry(idx, parent, &name, checked)?
+                }
+                MenuOp::Separator(idx, parent) => self.add_menu_separator(idx, parent)?,
+                MenuOp::Header(idx, parent, title) => self.add_menu_header(idx, parent, &title)?,
+                MenuOp::Submenu(idx, parent, name) => self.add_submenu(idx, parent, &name)?,
+                MenuOp::SetEnabled(idx, enabled) => self.set_menu_item_enabled(idx, enabled)?,
+                MenuOp::Clear => self.clear_menu(),
+            }
+        }
+        Ok(())
+    }
+
+    // NSImage keeps every frame of an .icns or .ico file, and draws the one
+    // that best fits the status bar at the screen's backing scale.
+    pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let path = NSString::alloc(nil).init_str(file).autorelease();
+            let image: id = msg_send![class!(NSImage), alloc];
+            let image: id = msg_send![image, initWithContentsOfFile: path];
+            if image == nil {
+                return Err(Error::InvalidIcon(format!("Couldn't load {}", file)));
+            }
+            size_for_status_bar(image);
+            self.set_image(image);
+        }
+        Ok(())
     }
-    pub fn wait_for_message(&mut self) {
-        unimplemented!()
+
+    // Images in the app bundle's Resources or asset catalog, by name. They
+    // come out of a cache shared with the rest of the app, so sizing and the
+    // template flag go on a copy.
+    pub fn set_icon_from_resource(&self, resource: &IconResource) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let name = match resource {
+            IconResource::Name(n) => n,
+            IconResource::Ordinal(_) => {
+                return Err(Error::NotSupported {
+                    feature: "icon resources by ordinal",
+                })
+            }
+        };
+        unsafe {
+            let ns_name = NSString::alloc(nil).init_str(name).autorelease();
+            let named: id = msg_send![class!(NSImage), imageNamed: ns_name];
+            if named == nil {
+                return Err(Error::InvalidIcon(format!("No image named {} in the bundle", name)));
+            }
+            let image: id = msg_send![named, copy];
+            size_for_status_bar(image);
+            self.set_image(image);
+        }
+        Ok(())
     }
+
     pub fn set_icon_from_buffer(&self, _: &[u8], _: u32, _: u32) -> Result<(), Error> {
-        unimplemented!()
+        Err(Error::NotSupported {
+            feature: "icons from ICO buffers",
+        })
+    }
+
+    // Takes ownership of image.
+    unsafe fn set_image(&self, image: id) {
+        self.icon_rep.set((nil, (0, 0)));
+        let template: BOOL = if self.template.get() { YES } else { NO };
+        let _: () = msg_send![image, setTemplate: template];
+        set_image_description(image, &self.icon_description.borrow());
+        let button: id = msg_send![self.status_item, button];
+        let _: () = msg_send![button, setImage: image];
+        let _: () = msg_send![image, release];
+    }
+
+    // Icons redrawn every second or so go into the bitmap of the last one
+    // when it has the same size, rather than a new image each time.
+    pub fn set_icon_from_rgba(&self, rgba: &[u8], width: u32, height: u32) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let (rep, size) = self.icon_rep.get();
+            if rep != nil && size == (width, height) {
+                let data: *mut u8 = msg_send![rep, bitmapData];
+                ptr::copy_nonoverlapping(rgba.as_ptr(), data, rgba.len());
+                let button: id = msg_send![self.status_item, button];
+                let image: id = msg_send![button, image];
+                let _: () = msg_send![image, recache];
+                // The button only redraws on a new image.
+                let _: () = msg_send![button, setImage: nil];
+                let _: () = msg_send![button, setImage: image];
+                return Ok(());
+            }
+            let image = image_from_rgba(rgba, width, height)?;
+            let reps: id = msg_send![image, representations];
+            let rep: id = msg_send![reps, objectAtIndex: 0 as NSUInteger];
+            self.set_image(image);
+            self.icon_rep.set((rep, (width, height)));
+        }
+        Ok(())
+    }
+
+    pub fn set_icon_animation(
+        &self,
+        frames: Vec<IconBuffer>,
+        interval: Duration,
+    ) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let template: BOOL = if self.template.get() { YES } else { NO };
+        let mut images = Vec::with_capacity(frames.len());
+        for frame in frames.iter() {
+            match unsafe { image_from_rgba(&frame.rgba, frame.width, frame.height) } {
+                Ok(image) => {
+                    let _: () = unsafe { msg_send![image, setTemplate: template] };
+                    unsafe { set_image_description(image, &self.icon_description.borrow()) };
+                    images.push(image)
+                }
+                Err(e) => {
+                    for image in images {
+                        let _: () = unsafe { msg_send![image, release] };
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.stop_icon_animation()?;
+        self.icon_rep.set((nil, (0, 0)));
+        unsafe {
+            let button: id = msg_send![self.status_item, button];
+            let _: () = msg_send![button, setImage: images[0]];
+            let animation = Box::new(IconAnimation {
+                frames: images,
+                current: 0,
+            });
+            (*self.handler).set_ivar("animation", Box::into_raw(animation) as *mut c_void);
+            let timer: id = msg_send![class!(NSTimer),
+                scheduledTimerWithTimeInterval: interval.as_secs_f64()
+                target: self.handler
+                selector: sel!(animationTick:)
+                userInfo: nil
+                repeats: YES];
+            self.animation_timer.set(timer);
+        }
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let timer = self.animation_timer.replace(nil);
+            if timer != nil {
+                let _: () = msg_send![timer, invalidate];
+            }
+            let animation = *(*self.handler).get_ivar::<*mut c_void>("animation");
+            if !animation.is_null() {
+                (*self.handler).set_ivar("animation", ptr::null_mut::<c_void>());
+                // The button keeps its own reference to the frame it shows.
+                let animation = Box::from_raw(animation as *mut IconAnimation);
+                for image in animation.frames {
+                    let _: () = msg_send![image, release];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Applies to the icon showing now and every one set afterwards.
+    pub fn set_activation_policy(&self, policy: ActivationPolicy) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        let policy = match policy {
+            ActivationPolicy::Regular => NSApplicationActivationPolicyRegular,
+            ActivationPolicy::Accessory => NSApplicationActivationPolicyAccessory,
+            ActivationPolicy::Prohibited => NSApplicationActivationPolicyProhibited,
+        };
+        unsafe {
+            if NSApp().setActivationPolicy_(policy) == NO {
+                return Err(Error::Cocoa {
+                    selector: "setActivationPolicy:",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // The status item keeps its menu and icon while it's hidden.
+    pub fn set_visible(&self, visible: bool) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        if self.removed.get() {
+            return Err(Error::Shutdown);
+        }
+        let visible: BOOL = if visible { YES } else { NO };
+        unsafe {
+            let _: () = msg_send![self.status_item, setVisible: visible];
+        }
+        Ok(())
+    }
+
+    pub fn show_about_panel(&self) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let app = NSApp();
+            // Accessory apps aren't active, the panel would open behind
+            // whatever app is.
+            let _: () = msg_send![app, activateIgnoringOtherApps: YES];
+            let _: () = msg_send![app, orderFrontStandardAboutPanel: nil];
+        }
+        Ok(())
+    }
+
+    // Activating leaves minimized windows in the Dock, so the frontmost window
+    // that can be main is brought back and made key too.
+    pub fn activate_host_app(&self) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let app = NSApp();
+            let _: () = msg_send![app, activateIgnoringOtherApps: YES];
+            let windows: id = msg_send![app, orderedWindows];
+            let count: usize = msg_send![windows, count];
+            for i in 0..count {
+                let window: id = msg_send![windows, objectAtIndex: i];
+                let can_become_main: BOOL = msg_send![window, canBecomeMainWindow];
+                if can_become_main == YES {
+                    let _: () = msg_send![window, deminiaturize: nil];
+                    let _: () = msg_send![window, makeKeyAndOrderFront: nil];
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Fills in the standard About panel instead of letting it read the app
+    // bundle, which command line tools don't have.
+    pub fn show_about(&self, about: AboutMetadata) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let string = |s: &str| NSString::alloc(nil).init_str(s).autorelease();
+            let options: id = msg_send![class!(NSMutableDictionary), dictionary];
+            let set = |key: &str, value: id| {
+                let _: () = msg_send![options, setObject: value forKey: string(key)];
+            };
+            set("ApplicationName", string(&about.name));
+            if let Some(version) = about.version.as_ref() {
+                set("ApplicationVersion", string(version));
+            }
+            if !about.authors.is_empty() {
+                set("Copyright", string(&about.authors.join(", ")));
+            }
+            let credits: Vec<&str> = about
+                .license
+                .iter()
+                .chain(about.website.iter())
+                .map(|s| s.as_str())
+                .collect();
+            if !credits.is_empty() {
+                let text: id = msg_send![class!(NSAttributedString), alloc];
+                let text: id = msg_send![text, initWithString: string(&credits.join("\n"))];
+                set("Credits", text.autorelease());
+            }
+            if let Some(icon) = about.icon.as_ref() {
+                if let Ok(image) = image_from_rgba(&icon.rgba, icon.width, icon.height) {
+                    // Undo the sizing for the status bar.
+                    let size = NSSize::new(icon.width as f64, icon.height as f64);
+                    let _: () = msg_send![image, setSize: size];
+                    set("ApplicationIcon", image.autorelease());
+                }
+            }
+            let app = NSApp();
+            // Accessory apps aren't active, the panel would open behind
+            // whatever app is.
+            let _: () = msg_send![app, activateIgnoringOtherApps: YES];
+            let _: () = msg_send![app, orderFrontStandardAboutPanelWithOptions: options];
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    pub fn set_icon_as_template(&self, template: bool) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        self.template.set(template);
+        let template: BOOL = if template { YES } else { NO };
+        unsafe {
+            let button: id = msg_send![self.status_item, button];
+            let image: id = msg_send![button, image];
+            if image != nil {
+                let _: () = msg_send![image, setTemplate: template];
+                // The button only redraws on a new image.
+                let _: () = msg_send![button, setImage: nil];
+                let _: () = msg_send![button, setImage: image];
+            }
+            let animation = *(*self.handler).get_ivar::<*mut c_void>("animation");
+            if !animation.is_null() {
+                for image in (*(animation as *mut IconAnimation)).frames.iter() {
+                    let _: () = msg_send![*image, setTemplate: template];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // NSImage sizes are in points, so there is no fixed pixel size to scale
+    // to.
+    pub fn icon_size(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    // NSUserNotificationCenter only delivers notifications for apps that run
+    // from a bundle. There is no timeout as such, the notification is taken
+    // away again once it is up.
+    pub fn show_notification(
+        &self,
+        title: &str,
+        body: &str,
+        icon: Option<&IconBuffer>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        unsafe {
+            let image = match icon {
+                Some(i) => {
+                    let image = image_from_rgba(&i.rgba, i.width, i.height)?;
+                    // Undo the status bar sizing, notifications show it larger.
+                    let size = NSSize::new(i.width as f64, i.height as f64);
+                    let _: () = msg_send![image, setSize: size];
+                    image
+                }
+                None => nil,
+            };
+            let pool = NSAutoreleasePool::new(nil);
+            let notification: id = msg_send![class!(NSUserNotification), new];
+            let title = NSString::alloc(nil).init_str(title).autorelease();
+            let body = NSString::alloc(nil).init_str(body).autorelease();
+            let _: () = msg_send![notification, setTitle: title];
+            let _: () = msg_send![notification, setInformativeText: body];
+            if image != nil {
+                let _: () = msg_send![notification, setContentImage: image];
+                let _: () = msg_send![image, release];
+            }
+            let center: id = msg_send![
+                class!(NSUserNotificationCenter),
+                defaultUserNotificationCenter
+            ];
+            let _: () = msg_send![center, deliverNotification: notification];
+            let _: () = msg_send![center,
+                performSelector: sel!(removeDeliveredNotification:)
+                withObject: notification
+                afterDelay: timeout.as_secs_f64()];
+            let _: () = msg_send![notification, release];
+            pool.drain();
+        }
+        Ok(())
+    }
+
+    // Goes on the image showing now, the frames of a running animation, and
+    // the images set after.
+    pub fn set_icon_accessibility_description(&self, desc: &str) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        *self.icon_description.borrow_mut() = desc.to_owned();
+        unsafe {
+            let button: id = msg_send![self.status_item, button];
+            let image: id = msg_send![button, image];
+            if image != nil {
+                set_image_description(image, desc);
+            }
+            let animation = *(*self.handler).get_ivar::<*mut c_void>("animation");
+            if !animation.is_null() {
+                for image in (*(animation as *mut IconAnimation)).frames.iter() {
+                    set_image_description(*image, desc);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pending_command_count(&self) -> usize {
+        // Everything runs on the calling thread, nothing is ever queued.
+        0
+    }
+
+    pub fn shutdown(&self) -> Result<(), Error> {
+        debug_assert!(is_main_thread());
+        if self.removed.replace(true) {
+            return Ok(());
+        }
+        self.stop_icon_animation()?;
+        unsafe {
+            NSStatusBar::systemStatusBar(nil).removeStatusItem_(self.status_item);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+        unsafe {
+            for (_, hotkey) in self.hotkeys.borrow_mut().drain() {
+                UnregisterEventHotKey(hotkey);
+            }
+            if !self.hotkey_handler.get().is_null() {
+                RemoveEventHandler(self.hotkey_handler.get());
+            }
+            let center: id = msg_send![class!(NSDistributedNotificationCenter), defaultCenter];
+            let _: () = msg_send![center, removeObserver: self.handler];
+            let tx = *(*self.handler).get_ivar::<*mut c_void>("event_tx");
+            (*self.handler).set_ivar("event_tx", ptr::null_mut::<c_void>());
+            if !tx.is_null() {
+                drop(Box::from_raw(tx as *mut EventSender));
+            }
+            for (_, submenu) in self.submenus.borrow_mut().drain() {
+                let _: () = msg_send![submenu, release];
+            }
+            let _: () = msg_send![self.menu, release];
+            let _: () = msg_send![self.status_item, release];
+            let _: () = msg_send![self.handler, release];
+        }
     }
 }
@@ -0,0 +1,49 @@
+// NSWorkspace hands URLs to whichever app is registered for the scheme, and
+// has Finder open a window with the file selected.
+use crate::Error;
+use cocoa::{
+    base::{id, nil, BOOL, NO},
+    foundation::{NSAutoreleasePool, NSString},
+};
+use objc::{class, msg_send, sel, sel_impl};
+use std::path::Path;
+
+pub fn open_url(url: &str) -> Result<(), Error> {
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let string = NSString::alloc(nil).init_str(url).autorelease();
+        let nsurl: id = msg_send![class!(NSURL), URLWithString: string];
+        let result = if nsurl == nil {
+            Err(Error::Cocoa {
+                selector: "URLWithString:",
+            })
+        } else {
+            let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+            let opened: BOOL = msg_send![workspace, openURL: nsurl];
+            if opened == NO {
+                Err(Error::OsError(format!("Nothing opened {}", url)))
+            } else {
+                Ok(())
+            }
+        };
+        pool.drain();
+        result
+    }
+}
+
+pub fn reveal(path: &Path) -> Result<(), Error> {
+    unsafe {
+        let pool = NSAutoreleasePool::new(nil);
+        let string = NSString::alloc(nil)
+            .init_str(&path.to_string_lossy())
+            .autorelease();
+        let root = NSString::alloc(nil).init_str("").autorelease();
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        let shown: BOOL = msg_send![workspace, selectFile: string inFileViewerRootedAtPath: root];
+        pool.drain();
+        if shown == NO {
+            return Err(Error::OsError(format!("Finder couldn't show {}", path.display())));
+        }
+        Ok(())
+    }
+}
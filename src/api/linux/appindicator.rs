@@ -0,0 +1,141 @@
+use crate::{Error, EventSender};
+use glib::{
+    gobject_sys,
+    translate::{from_glib, from_glib_none, ToGlibPtr},
+    ObjectExt,
+};
+use gtk_sys;
+use libloading::Library;
+use std::{
+    cell::Cell,
+    ffi::CString,
+    os::raw::{c_char, c_int, c_void},
+};
+
+// Tried in order. Ayatana is what current distros ship, the Ubuntu original is
+// still around on older ones.
+const LIBRARIES: [&str; 2] = ["libayatana-appindicator3.so.1", "libappindicator3.so.1"];
+
+// From the AppIndicatorCategory and AppIndicatorStatus enums.
+const CATEGORY_APPLICATION_STATUS: c_int = 0;
+const STATUS_PASSIVE: c_int = 0;
+const STATUS_ACTIVE: c_int = 1;
+
+struct Api {
+    new: unsafe extern "C" fn(*const c_char, *const c_char, c_int) -> *mut c_void,
+    set_status: unsafe extern "C" fn(*mut c_void, c_int),
+    set_menu: unsafe extern "C" fn(*mut c_void, *mut gtk_sys::GtkMenu),
+    set_icon_full: unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char),
+    set_label: unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char),
+}
+
+unsafe fn load_api() -> Result<Api, Error> {
+    let mut errors = Vec::new();
+    for name in LIBRARIES.iter() {
+        let lib = match Library::new(name) {
+            Ok(l) => l,
+            Err(e) => {
+                errors.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        // Never unloaded, the GObject types it registers can't go away again.
+        let lib: &'static Library = Box::leak(Box::new(lib));
+        let api = (|| {
+            Ok::<_, std::io::Error>(Api {
+                new: *lib.get(b"app_indicator_new\0")?,
+                set_status: *lib.get(b"app_indicator_set_status\0")?,
+                set_menu: *lib.get(b"app_indicator_set_menu\0")?,
+                set_icon_full: *lib.get(b"app_indicator_set_icon_full\0")?,
+                set_label: *lib.get(b"app_indicator_set_label\0")?,
+            })
+        })();
+        match api {
+            Ok(api) => return Ok(api),
+            Err(e) => errors.push(format!("{}: {}", name, e)),
+        }
+    }
+    Err(Error::OsError(format!(
+        "No AppIndicator library: {}",
+        errors.join(", ")
+    )))
+}
+
+pub struct AppIndicator {
+    api: Api,
+    indicator: *mut c_void,
+}
+
+impl AppIndicator {
+    pub fn new(
+        id: &CString,
+        menu: &gtk::Menu,
+        event_tx: EventSender,
+    ) -> Result<AppIndicator, Error> {
+        unsafe {
+            let api = load_api()?;
+            let indicator = (api.new)(
+                id.as_ptr(),
+                "\0".as_ptr() as *const c_char,
+                CATEGORY_APPLICATION_STATUS,
+            );
+            if indicator.is_null() {
+                return Err(Error::OsError("Error creating AppIndicator".to_owned()));
+            }
+            (api.set_status)(indicator, STATUS_ACTIVE);
+            (api.set_menu)(indicator, menu.to_glib_none().0);
+            let object: glib::Object = from_glib_none(indicator as *mut gobject_sys::GObject);
+            // The library registers with a restarted host again by itself.
+            let (tx, seen) = (event_tx.clone(), Cell::new(false));
+            object
+                .connect_local("connection-changed", false, move |values| {
+                    let connected = values[1].get_some::<bool>().unwrap_or(false);
+                    super::report_host(&seen, connected, &tx);
+                    None
+                })
+                .ok();
+            // The delta comes without its sign, which is in the direction.
+            object
+                .connect_local("scroll-event", false, move |values| {
+                    let delta = values[1].get_some::<c_int>().unwrap_or(0);
+                    let direction = values[2].get_some::<u32>().unwrap_or(0);
+                    let direction: gdk::ScrollDirection = from_glib(direction as c_int);
+                    let notches = delta as f64 / super::WHEEL_NOTCH;
+                    if let Some(event) = super::scroll_event(direction, notches) {
+                        event_tx.send(event).ok();
+                    }
+                    None
+                })
+                .ok();
+            Ok(AppIndicator {
+                api,
+                indicator,
+            })
+        }
+    }
+
+    pub fn set_icon_full(&self, file: &CString, desc: &CString) {
+        unsafe { (self.api.set_icon_full)(self.indicator, file.as_ptr(), desc.as_ptr()) };
+    }
+
+    // The guide is for keeping the width stable, which we can't know.
+    pub fn set_label(&self, label: &CString) {
+        let guide = "\0".as_ptr() as *const c_char;
+        unsafe { (self.api.set_label)(self.indicator, label.as_ptr(), guide) };
+    }
+
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.indicator
+    }
+
+    pub fn set_visible(&self, visible: bool) {
+        let status = if visible { STATUS_ACTIVE } else { STATUS_PASSIVE };
+        unsafe { (self.api.set_status)(self.indicator, status) };
+    }
+}
+
+impl Drop for AppIndicator {
+    fn drop(&mut self) {
+        unsafe { gobject_sys::g_object_unref(self.indicator as *mut gobject_sys::GObject) };
+    }
+}
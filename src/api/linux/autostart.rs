@@ -0,0 +1,75 @@
+// An XDG autostart entry, which desktops that follow the spec start at login.
+use crate::Error;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+fn entry_path(name: &str) -> Result<PathBuf, Error> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".config"),
+            None => return Err(Error::OsError("HOME is not set".to_owned())),
+        },
+    };
+    Ok(config.join("autostart").join(format!("{}.desktop", name)))
+}
+
+fn io_error(action: &str, path: &Path, e: io::Error) -> Error {
+    Error::OsError(format!("Couldn't {} {}: {}", action, path.display(), e))
+}
+
+// Quoted the way the desktop entry spec asks for Exec arguments, then escaped
+// again as a string value, which doubles the backslashes.
+fn exec_arg(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '`' | '$' => {
+                quoted.push_str("\\\\");
+                quoted.push(c);
+            }
+            '\\' => quoted.push_str("\\\\\\\\"),
+            '%' => quoted.push_str("%%"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn enable(name: &str, exe: &Path) -> Result<(), Error> {
+    let path = entry_path(name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| io_error("create", dir, e))?;
+    }
+    let entry = format!(
+        "[Desktop Entry]\nType=Application\nName={}\nExec={}\nX-GNOME-Autostart-enabled=true\n",
+        name,
+        exec_arg(&exe.to_string_lossy())
+    );
+    fs::write(&path, entry).map_err(|e| io_error("write", &path, e))
+}
+
+pub fn disable(name: &str) -> Result<(), Error> {
+    let path = entry_path(name)?;
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error("remove", &path, e)),
+        _ => Ok(()),
+    }
+}
+
+// Session settings turn entries off without removing them.
+pub fn is_enabled(name: &str) -> Result<bool, Error> {
+    let path = entry_path(name)?;
+    let entry = match fs::read_to_string(&path) {
+        Ok(entry) => entry,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(io_error("read", &path, e)),
+    };
+    Ok(!entry.lines().any(|line| {
+        let line = line.trim();
+        line == "Hidden=true" || line == "X-GNOME-Autostart-enabled=false"
+    }))
+}
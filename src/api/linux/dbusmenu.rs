@@ -0,0 +1,663 @@
+// com.canonical.dbusmenu for our StatusNotifierItem, so hosts draw the menu
+// themselves, like they do for every other item. Nothing is kept on the side:
+// the layout is read off the gtk menu whenever a host asks for it, and clicks
+// activate the gtk items, which report them like clicks in the gtk menu do.
+// GtkSystrayApp only has to say when the menu changed, for LayoutUpdated.
+use super::{glib_error, to_cstring};
+use crate::{Error, EventSender, SystrayEvent};
+use gdk::{self, ModifierType};
+use gio_sys;
+use glib::{
+    self, glib_sys, gobject_sys,
+    object::Cast,
+    translate::{from_glib, from_glib_full, from_glib_none, ToGlibPtr},
+    GString, ObjectExt,
+};
+use gtk::{
+    self, AccelLabelExt, BinExt, CheckMenuItemExt, ContainerExt, GtkMenuItemExt, ImageExt,
+    LabelExt, TextDirection, WidgetExt,
+};
+use gtk_sys;
+use std::{
+    cell::Cell,
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_uint},
+    ptr,
+    rc::Rc,
+};
+
+pub const MENU_PATH: &str = "/MenuBar\0";
+const MENU_INTERFACE: &str = "com.canonical.dbusmenu\0";
+// Where an item's id is kept on its widget.
+const ID_KEY: &str = "systray-dbusmenu-id\0";
+
+const INTROSPECTION: &str = "<node>
+  <interface name='com.canonical.dbusmenu'>
+    <property name='Version' type='u' access='read'/>
+    <property name='TextDirection' type='s' access='read'/>
+    <property name='Status' type='s' access='read'/>
+    <property name='IconThemePath' type='as' access='read'/>
+    <method name='GetLayout'>
+      <arg name='parentId' type='i' direction='in'/>
+      <arg name='recursionDepth' type='i' direction='in'/>
+      <arg name='propertyNames' type='as' direction='in'/>
+      <arg name='revision' type='u' direction='out'/>
+      <arg name='layout' type='(ia{sv}av)' direction='out'/>
+    </method>
+    <method name='GetGroupProperties'>
+      <arg name='ids' type='ai' direction='in'/>
+      <arg name='propertyNames' type='as' direction='in'/>
+      <arg name='properties' type='a(ia{sv})' direction='out'/>
+    </method>
+    <method name='GetProperty'>
+      <arg name='id' type='i' direction='in'/>
+      <arg name='name' type='s' direction='in'/>
+      <arg name='value' type='v' direction='out'/>
+    </method>
+    <method name='Event'>
+      <arg name='id' type='i' direction='in'/>
+      <arg name='eventId' type='s' direction='in'/>
+      <arg name='data' type='v' direction='in'/>
+      <arg name='timestamp' type='u' direction='in'/>
+    </method>
+    <method name='EventGroup'>
+      <arg name='events' type='a(isvu)' direction='in'/>
+      <arg name='idErrors' type='ai' direction='out'/>
+    </method>
+    <method name='AboutToShow'>
+      <arg name='id' type='i' direction='in'/>
+      <arg name='needUpdate' type='b' direction='out'/>
+    </method>
+    <method name='AboutToShowGroup'>
+      <arg name='ids' type='ai' direction='in'/>
+      <arg name='updatesNeeded' type='ai' direction='out'/>
+      <arg name='idErrors' type='ai' direction='out'/>
+    </method>
+    <signal name='ItemsPropertiesUpdated'>
+      <arg name='updatedProps' type='a(ia{sv})'/>
+      <arg name='removedProps' type='a(ias)'/>
+    </signal>
+    <signal name='LayoutUpdated'>
+      <arg name='revision' type='u'/>
+      <arg name='parent' type='i'/>
+    </signal>
+    <signal name='ItemActivationRequested'>
+      <arg name='id' type='i'/>
+      <arg name='timestamp' type='u'/>
+    </signal>
+  </interface>
+</node>\0";
+
+type Properties = Vec<(&'static str, *mut glib_sys::GVariant)>;
+
+fn variant_type(s: &str) -> *const glib_sys::GVariantType {
+    s.as_ptr() as *const glib_sys::GVariantType
+}
+
+unsafe fn string(s: &str) -> *mut glib_sys::GVariant {
+    let s = to_cstring(s).unwrap_or_default();
+    glib_sys::g_variant_new_string(s.as_ptr())
+}
+
+unsafe fn int_array(ints: &[i32]) -> *mut glib_sys::GVariant {
+    glib_sys::g_variant_new_fixed_array(
+        variant_type("i\0"),
+        ints.as_ptr() as glib_sys::gconstpointer,
+        ints.len(),
+        4,
+    )
+}
+
+// Takes the array, as g_variant_get hands it out.
+unsafe fn take_int_array(array: *mut glib_sys::GVariant) -> Vec<i32> {
+    let ints = (0..glib_sys::g_variant_n_children(array))
+        .map(|i| {
+            let child = glib_sys::g_variant_get_child_value(array, i);
+            let int = glib_sys::g_variant_get_int32(child);
+            glib_sys::g_variant_unref(child);
+            int
+        })
+        .collect();
+    glib_sys::g_variant_unref(array);
+    ints
+}
+
+unsafe fn take_strv(strv: *mut *mut c_char) -> Vec<String> {
+    let mut strings = Vec::new();
+    if strv.is_null() {
+        return strings;
+    }
+    let mut i = 0;
+    while !(*strv.offset(i)).is_null() {
+        strings.push(CStr::from_ptr(*strv.offset(i)).to_string_lossy().into_owned());
+        i += 1;
+    }
+    glib_sys::g_strfreev(strv);
+    strings
+}
+
+// An a{sv} of the properties names asks for, all of them if it's empty.
+unsafe fn dict(props: Properties, names: &[String]) -> *mut glib_sys::GVariant {
+    let builder = glib_sys::g_variant_builder_new(variant_type("a{sv}\0"));
+    for (name, value) in props {
+        if names.is_empty() || names.iter().any(|n| n == name) {
+            let entry = glib_sys::g_variant_new_dict_entry(
+                string(name),
+                glib_sys::g_variant_new_variant(value),
+            );
+            glib_sys::g_variant_builder_add_value(builder, entry);
+        } else {
+            glib_sys::g_variant_unref(value);
+        }
+    }
+    let value = glib_sys::g_variant_builder_end(builder);
+    glib_sys::g_variant_builder_unref(builder);
+    value
+}
+
+// The id and event name of an (isvu) event. Neither the data nor the time
+// are of any use to us.
+unsafe fn event_params(params: *mut glib_sys::GVariant) -> (i32, String) {
+    let (mut id, mut timestamp): (c_int, c_uint) = (0, 0);
+    let mut event: *const c_char = ptr::null();
+    let mut data = ptr::null_mut::<glib_sys::GVariant>();
+    glib_sys::g_variant_get(
+        params,
+        "(i&s@vu)\0".as_ptr() as *const c_char,
+        &mut id,
+        &mut event,
+        &mut data,
+        &mut timestamp,
+    );
+    glib_sys::g_variant_unref(data);
+    (id, CStr::from_ptr(event).to_string_lossy().into_owned())
+}
+
+fn menu_items(menu: &gtk::Menu) -> Vec<gtk::MenuItem> {
+    menu.get_children()
+        .into_iter()
+        .filter_map(|w| w.downcast::<gtk::MenuItem>().ok())
+        .collect()
+}
+
+// Headers have a label with markup of their own, which the host would show
+// as is.
+fn label(item: &gtk::MenuItem) -> Option<GString> {
+    let child = item.get_child().and_then(|w| w.downcast::<gtk::Label>().ok());
+    match child {
+        Some(label) if label.get_use_markup() => label.get_text(),
+        _ => item.get_label(),
+    }
+}
+
+fn submenu(item: &gtk::MenuItem) -> Option<gtk::Menu> {
+    item.get_submenu().and_then(|w| w.downcast::<gtk::Menu>().ok())
+}
+
+fn icon_png(item: &gtk::MenuItem) -> Option<Vec<u8>> {
+    let image_item_type = unsafe { from_glib(gtk_sys::gtk_image_menu_item_get_type()) };
+    if !item.get_type().is_a(&image_item_type) {
+        return None;
+    }
+    let image: gtk::Widget = unsafe {
+        let item: *mut gtk_sys::GtkMenuItem = item.to_glib_none().0;
+        let image = gtk_sys::gtk_image_menu_item_get_image(item as *mut gtk_sys::GtkImageMenuItem);
+        if image.is_null() {
+            return None;
+        }
+        from_glib_none(image)
+    };
+    let pixbuf = image.downcast::<gtk::Image>().ok()?.get_pixbuf()?;
+    pixbuf.save_to_bufferv("png", &[]).ok()
+}
+
+// The accelerator set_menu_item_shortcut put on the item, which its label
+// picks up to show it.
+unsafe fn shortcut(item: &gtk::MenuItem) -> Option<*mut glib_sys::GVariant> {
+    let label = item.get_child()?.downcast::<gtk::AccelLabel>().ok()?;
+    let (key, mods) = label.get_accel();
+    if key == 0 {
+        return None;
+    }
+    let key = gdk::keyval_name(key)?;
+    let keys = glib_sys::g_variant_builder_new(variant_type("as\0"));
+    for (m, name) in [
+        (ModifierType::CONTROL_MASK, "Control"),
+        (ModifierType::MOD1_MASK, "Alt"),
+        (ModifierType::SHIFT_MASK, "Shift"),
+        (ModifierType::SUPER_MASK, "Super"),
+    ]
+    .iter()
+    {
+        if mods.contains(*m) {
+            glib_sys::g_variant_builder_add_value(keys, string(name));
+        }
+    }
+    glib_sys::g_variant_builder_add_value(keys, string(&key));
+    // A list of key combinations to press one after the other, ours are
+    // always just the one.
+    let shortcut = glib_sys::g_variant_builder_new(variant_type("aas\0"));
+    glib_sys::g_variant_builder_add_value(shortcut, glib_sys::g_variant_builder_end(keys));
+    let value = glib_sys::g_variant_builder_end(shortcut);
+    glib_sys::g_variant_builder_unref(keys);
+    glib_sys::g_variant_builder_unref(shortcut);
+    Some(value)
+}
+
+struct State {
+    // A reference of our own, an idle LayoutUpdated can outlive the item.
+    bus: *mut gio_sys::GDBusConnection,
+    menu: gtk::Menu,
+    revision: Cell<u32>,
+    next_id: Cell<i32>,
+    update_pending: Cell<bool>,
+    event_tx: EventSender,
+}
+
+impl State {
+    // Handed out the first time a host sees an item, and kept on its widget,
+    // so they hold across layout changes. 0 is the root.
+    fn item_id(&self, item: &gtk::MenuItem) -> i32 {
+        unsafe {
+            let item: *mut gtk_sys::GtkMenuItem = item.to_glib_none().0;
+            let object = item as *mut gobject_sys::GObject;
+            let key = ID_KEY.as_ptr() as *const c_char;
+            let id = gobject_sys::g_object_get_data(object, key) as isize as i32;
+            if id != 0 {
+                return id;
+            }
+            let id = self.next_id.get();
+            self.next_id.set(id + 1);
+            gobject_sys::g_object_set_data(object, key, id as isize as glib_sys::gpointer);
+            id
+        }
+    }
+
+    fn find(&self, menu: &gtk::Menu, id: i32) -> Option<gtk::MenuItem> {
+        for item in menu_items(menu) {
+            if self.item_id(&item) == id {
+                return Some(item);
+            }
+            if let Some(found) = submenu(&item).and_then(|m| self.find(&m, id)) {
+                return Some(found);
+            }
+        }
+        None
+    }
+
+    // None for the root.
+    fn item(&self, id: i32) -> Result<Option<gtk::MenuItem>, String> {
+        if id == 0 {
+            return Ok(None);
+        }
+        match self.find(&self.menu, id) {
+            Some(item) => Ok(Some(item)),
+            None => Err(format!("No menu item {}", id)),
+        }
+    }
+
+    // Leaves out what the spec has defaults for: standard items that are
+    // enabled and visible.
+    unsafe fn properties(&self, item: Option<&gtk::MenuItem>) -> Properties {
+        let mut props: Properties = Vec::new();
+        let item = match item {
+            Some(item) => item,
+            None => {
+                props.push(("children-display", string("submenu")));
+                return props;
+            }
+        };
+        if item.is::<gtk::SeparatorMenuItem>() {
+            props.push(("type", string("separator")));
+        } else {
+            if let Some(label) = label(item) {
+                // Underscores mark mnemonics in dbusmenu, ours are all
+                // literal.
+                props.push(("label", string(&label.replace('_', "__"))));
+            }
+            if let Some(check) = item.downcast_ref::<gtk::CheckMenuItem>() {
+                let radio = item.is::<gtk::RadioMenuItem>() || check.get_draw_as_radio();
+                let toggle = if radio { "radio" } else { "checkmark" };
+                props.push(("toggle-type", string(toggle)));
+                let state = glib_sys::g_variant_new_int32(check.get_active() as i32);
+                props.push(("toggle-state", state));
+            }
+            if let Some(png) = icon_png(item) {
+                let bytes = glib_sys::g_variant_new_fixed_array(
+                    variant_type("y\0"),
+                    png.as_ptr() as glib_sys::gconstpointer,
+                    png.len(),
+                    1,
+                );
+                props.push(("icon-data", bytes));
+            }
+            if let Some(shortcut) = shortcut(item) {
+                props.push(("shortcut", shortcut));
+            }
+            if submenu(item).is_some() {
+                props.push(("children-display", string("submenu")));
+            }
+        }
+        if !item.get_sensitive() {
+            props.push(("enabled", glib_sys::g_variant_new_boolean(glib_sys::GFALSE)));
+        }
+        if !item.get_visible() {
+            props.push(("visible", glib_sys::g_variant_new_boolean(glib_sys::GFALSE)));
+        }
+        props
+    }
+
+    // (ia{sv}av), with the children depth levels down, or all the way for -1.
+    unsafe fn layout(
+        &self,
+        id: i32,
+        item: Option<&gtk::MenuItem>,
+        depth: i32,
+        names: &[String],
+    ) -> *mut glib_sys::GVariant {
+        let children = glib_sys::g_variant_builder_new(variant_type("av\0"));
+        let menu = match item {
+            Some(item) => submenu(item),
+            None => Some(self.menu.clone()),
+        };
+        if let (Some(menu), true) = (menu, depth != 0) {
+            for child in menu_items(&menu) {
+                let layout = self.layout(self.item_id(&child), Some(&child), depth - 1, names);
+                let layout = glib_sys::g_variant_new_variant(layout);
+                glib_sys::g_variant_builder_add_value(children, layout);
+            }
+        }
+        let value = glib_sys::g_variant_new(
+            "(i@a{sv}@av)\0".as_ptr() as *const c_char,
+            id as c_int,
+            dict(self.properties(item), names),
+            glib_sys::g_variant_builder_end(children),
+        );
+        glib_sys::g_variant_builder_unref(children);
+        value
+    }
+
+    fn event(&self, id: i32, event: &str) -> Result<(), String> {
+        match (self.item(id)?, event) {
+            (None, "opened") => {
+                self.event_tx.send(SystrayEvent::MenuWillOpen).ok();
+            }
+            (None, "closed") => {
+                self.event_tx.send(SystrayEvent::MenuDidClose).ok();
+            }
+            // Checkable items toggle themselves on activate, like they do
+            // when clicked in the gtk menu.
+            (Some(item), "clicked") => item.emit_activate(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    unsafe fn call(
+        &self,
+        method: &str,
+        params: *mut glib_sys::GVariant,
+    ) -> Result<*mut glib_sys::GVariant, String> {
+        let format = |s: &str| s.as_ptr() as *const c_char;
+        match method {
+            "GetLayout" => {
+                let (mut parent, mut depth): (c_int, c_int) = (0, 0);
+                let mut names = ptr::null_mut();
+                let f = format("(ii^as)\0");
+                glib_sys::g_variant_get(params, f, &mut parent, &mut depth, &mut names);
+                let names = take_strv(names);
+                let item = self.item(parent)?;
+                let layout = self.layout(parent, item.as_ref(), depth, &names);
+                let revision = self.revision.get() as c_uint;
+                Ok(glib_sys::g_variant_new(format("(u@(ia{sv}av))\0"), revision, layout))
+            }
+            "GetGroupProperties" => {
+                let mut ids = ptr::null_mut();
+                let mut names = ptr::null_mut();
+                glib_sys::g_variant_get(params, format("(@ai^as)\0"), &mut ids, &mut names);
+                let names = take_strv(names);
+                let builder = glib_sys::g_variant_builder_new(variant_type("a(ia{sv})\0"));
+                // Items that went away in the meantime are left out.
+                for id in take_int_array(ids) {
+                    if let Ok(item) = self.item(id) {
+                        let props = dict(self.properties(item.as_ref()), &names);
+                        let entry = glib_sys::g_variant_new(format("(i@a{sv})\0"), id, props);
+                        glib_sys::g_variant_builder_add_value(builder, entry);
+                    }
+                }
+                let props = glib_sys::g_variant_builder_end(builder);
+                glib_sys::g_variant_builder_unref(builder);
+                Ok(glib_sys::g_variant_new(format("(@a(ia{sv}))\0"), props))
+            }
+            "GetProperty" => {
+                let mut id: c_int = 0;
+                let mut name: *const c_char = ptr::null();
+                glib_sys::g_variant_get(params, format("(i&s)\0"), &mut id, &mut name);
+                let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+                let mut found = None;
+                for (n, value) in self.properties(self.item(id)?.as_ref()) {
+                    if n == name {
+                        found = Some(value);
+                    } else {
+                        glib_sys::g_variant_unref(value);
+                    }
+                }
+                match found {
+                    Some(value) => Ok(glib_sys::g_variant_new(format("(v)\0"), value)),
+                    None => Err(format!("Menu item {} has no {}", id, name)),
+                }
+            }
+            "Event" => {
+                let (id, event) = event_params(params);
+                self.event(id, &event)?;
+                Ok(ptr::null_mut())
+            }
+            "EventGroup" => {
+                let mut events = ptr::null_mut::<glib_sys::GVariant>();
+                glib_sys::g_variant_get(params, format("(@a(isvu))\0"), &mut events);
+                let mut errors = Vec::new();
+                for i in 0..glib_sys::g_variant_n_children(events) {
+                    let child = glib_sys::g_variant_get_child_value(events, i);
+                    let (id, event) = event_params(child);
+                    glib_sys::g_variant_unref(child);
+                    if self.event(id, &event).is_err() {
+                        errors.push(id);
+                    }
+                }
+                glib_sys::g_variant_unref(events);
+                Ok(glib_sys::g_variant_new(format("(@ai)\0"), int_array(&errors)))
+            }
+            // The menu is always up to date, there's nothing to fill in first.
+            "AboutToShow" => Ok(glib_sys::g_variant_new(format("(b)\0"), glib_sys::GFALSE)),
+            "AboutToShowGroup" => Ok(glib_sys::g_variant_new(
+                format("(@ai@ai)\0"),
+                int_array(&[]),
+                int_array(&[]),
+            )),
+            _ => Err(format!("No method {}", method)),
+        }
+    }
+
+    unsafe fn property(&self, name: &str) -> *mut glib_sys::GVariant {
+        match name {
+            "Version" => glib_sys::g_variant_new_uint32(3),
+            // The default direction unless the app set the menu's.
+            "TextDirection" => match self.menu.get_direction() {
+                TextDirection::Rtl => string("rtl"),
+                _ => string("ltr"),
+            },
+            "Status" => string("normal"),
+            "IconThemePath" => glib_sys::g_variant_new_strv(ptr::null(), 0),
+            _ => ptr::null_mut(),
+        }
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        unsafe {
+            gobject_sys::g_object_unref(self.bus as *mut gobject_sys::GObject);
+        }
+    }
+}
+
+unsafe extern "C" fn method_call(
+    _bus: *mut gio_sys::GDBusConnection,
+    _sender: *const c_char,
+    _path: *const c_char,
+    _interface: *const c_char,
+    method: *const c_char,
+    params: *mut glib_sys::GVariant,
+    invocation: *mut gio_sys::GDBusMethodInvocation,
+    data: glib_sys::gpointer,
+) {
+    let state = &*(data as *const State);
+    let method = CStr::from_ptr(method).to_string_lossy();
+    let reply = crate::catch_panic(Some(&state.event_tx), || state.call(&method, params));
+    let message = match reply {
+        Some(Ok(value)) => {
+            gio_sys::g_dbus_method_invocation_return_value(invocation, value);
+            return;
+        }
+        Some(Err(message)) => message,
+        None => format!("{} panicked", method),
+    };
+    let message = to_cstring(&message).unwrap_or_default();
+    gio_sys::g_dbus_method_invocation_return_error_literal(
+        invocation,
+        gio_sys::g_dbus_error_quark(),
+        gio_sys::G_DBUS_ERROR_INVALID_ARGS,
+        message.as_ptr(),
+    );
+}
+
+unsafe extern "C" fn get_property(
+    _bus: *mut gio_sys::GDBusConnection,
+    _sender: *const c_char,
+    _path: *const c_char,
+    _interface: *const c_char,
+    name: *const c_char,
+    error: *mut *mut glib_sys::GError,
+    data: glib_sys::gpointer,
+) -> *mut glib_sys::GVariant {
+    let state = &*(data as *const State);
+    let name = CStr::from_ptr(name).to_string_lossy();
+    let value = crate::catch_panic(Some(&state.event_tx), || state.property(&name));
+    match value {
+        Some(v) if !v.is_null() => v,
+        _ => {
+            glib_sys::g_set_error_literal(
+                error,
+                gio_sys::g_dbus_error_quark(),
+                gio_sys::G_DBUS_ERROR_UNKNOWN_PROPERTY,
+                "No such property\0".as_ptr() as *const c_char,
+            );
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe extern "C" fn drop_state(data: glib_sys::gpointer) {
+    drop(Rc::from_raw(data as *const State));
+}
+
+pub struct DbusMenu {
+    state: Rc<State>,
+    registration: c_uint,
+}
+
+impl DbusMenu {
+    pub fn new(
+        bus: *mut gio_sys::GDBusConnection,
+        menu: &gtk::Menu,
+        event_tx: EventSender,
+    ) -> Result<DbusMenu, Error> {
+        unsafe {
+            gobject_sys::g_object_ref(bus as *mut gobject_sys::GObject);
+            let state = Rc::new(State {
+                bus,
+                menu: menu.clone(),
+                revision: Cell::new(0),
+                next_id: Cell::new(1),
+                update_pending: Cell::new(false),
+                event_tx,
+            });
+            let mut error = ptr::null_mut();
+            let node = gio_sys::g_dbus_node_info_new_for_xml(
+                INTROSPECTION.as_ptr() as *const c_char,
+                &mut error,
+            );
+            if node.is_null() {
+                let error = from_glib_full(error);
+                return Err(glib_error("g_dbus_node_info_new_for_xml", error));
+            }
+            let interface = gio_sys::g_dbus_node_info_lookup_interface(
+                node,
+                MENU_INTERFACE.as_ptr() as *const c_char,
+            );
+            // Copied by gio.
+            let vtable = gio_sys::GDBusInterfaceVTable {
+                method_call: Some(method_call),
+                get_property: Some(get_property),
+                set_property: None,
+                padding: [ptr::null_mut(); 8],
+            };
+            let registration = gio_sys::g_dbus_connection_register_object(
+                bus,
+                MENU_PATH.as_ptr() as *const c_char,
+                interface,
+                &vtable,
+                Rc::into_raw(state.clone()) as glib_sys::gpointer,
+                Some(drop_state),
+                &mut error,
+            );
+            gio_sys::g_dbus_node_info_unref(node);
+            if registration == 0 {
+                let error = from_glib_full(error);
+                return Err(glib_error("g_dbus_connection_register_object", error));
+            }
+            Ok(DbusMenu {
+                state,
+                registration,
+            })
+        }
+    }
+
+    // Hosts fetch the whole layout again on LayoutUpdated. Changes made in
+    // one go, like a whole new menu, only send it once.
+    pub fn changed(&self) {
+        if self.state.update_pending.replace(true) {
+            return;
+        }
+        let state = self.state.clone();
+        glib::idle_add_local(move || {
+            state.update_pending.set(false);
+            state.revision.set(state.revision.get().wrapping_add(1));
+            unsafe {
+                let params = glib_sys::g_variant_new(
+                    "(ui)\0".as_ptr() as *const c_char,
+                    state.revision.get() as c_uint,
+                    0 as c_int,
+                );
+                gio_sys::g_dbus_connection_emit_signal(
+                    state.bus,
+                    ptr::null(),
+                    MENU_PATH.as_ptr() as *const c_char,
+                    MENU_INTERFACE.as_ptr() as *const c_char,
+                    "LayoutUpdated\0".as_ptr() as *const c_char,
+                    params,
+                    ptr::null_mut(),
+                );
+            }
+            glib::Continue(false)
+        });
+    }
+}
+
+impl Drop for DbusMenu {
+    fn drop(&mut self) {
+        unsafe {
+            gio_sys::g_dbus_connection_unregister_object(self.state.bus, self.registration);
+        }
+    }
+}
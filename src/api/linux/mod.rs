@@ -1,27 +1,99 @@
-use crate::{Error, SystrayEvent};
+#[cfg(feature = "appindicator")]
+mod appindicator;
+pub mod autostart;
+mod dbusmenu;
+pub mod open;
+mod sni;
+
+use crate::{
+    ico, AboutMetadata, ApplicationMode, Backend, Capabilities, Error, EventSender, HotkeyId,
+    IconBuffer, IconResource, Key, LayoutDirection, MenuOp, MenuStyle, Message, MouseButton,
+    NativeHandle, Rect, ScrollAxis, Shortcut, SystrayEvent, Theme,
+};
+use gdk::{self, ModifierType, ScrollDirection};
+use gdk_pixbuf::{Colorspace, Pixbuf, PixbufLoader, PixbufLoaderExt};
+use gio_sys;
 use glib;
+use glib::{
+    glib_sys, gobject_sys,
+    object::Cast,
+    translate::{from_glib, from_glib_full, FromGlibPtrNone, ToGlibPtr, ToGlibPtrMut},
+    ObjectExt, SignalHandlerId, ToValue,
+};
 use gtk::{
-    self, MenuShellExt, GtkMenuItemExt, WidgetExt
+    self,
+    prelude::{GtkMenuExtManual, GtkWindowExtManual},
+    AboutDialogExt, AccelFlags, CheckMenuItemExt, ContainerExt, DialogExt, GtkMenuExt,
+    GtkMenuItemExt, LabelExt, MenuShellExt, SettingsExt, TextDirection, WidgetExt,
 };
-use libappindicator::{AppIndicator, AppIndicatorStatus};
+use gtk_sys;
 use std::{
     self,
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    rc::Rc,
     collections::HashMap,
-    sync::mpsc::{channel, Sender},
+    ffi::CString,
+    fs,
+    os::raw::{c_char, c_int, c_long, c_uchar, c_uint, c_ulong, c_void},
+    path::PathBuf,
+    ptr,
+    process,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    sync::{Arc, Mutex, Once},
     thread,
+    time::{Duration, Instant},
 };
 
 // Gtk specific struct that will live only in the Gtk thread, since a lot of the
 // base types involved don't implement Send (for good reason).
 pub struct GtkSystrayApp {
+    id: u32,
     menu: gtk::Menu,
-    ai: RefCell<AppIndicator>,
+    tray: Tray,
+    menu_on_left_click: Rc<Cell<bool>>,
+    // None until the app picks one, which leaves it to GTK's default.
+    menu_direction: Rc<Cell<TextDirection>>,
     menu_items: RefCell<HashMap<u32, gtk::MenuItem>>,
-    event_tx: Sender<SystrayEvent>,
+    // Setting the state of a check item activates it, so we need the handler
+    // to block while changing it from our side.
+    check_items: RefCell<HashMap<u32, (gtk::CheckMenuItem, SignalHandlerId)>>,
+    submenus: RefCell<HashMap<u32, gtk::Menu>>,
+    // Accelerators are what the dbusmenu export turns into shortcuts. Keep
+    // each item's current one, to take it off again when it changes.
+    accel_group: gtk::AccelGroup,
+    shortcuts: RefCell<HashMap<u32, (u32, ModifierType)>>,
+    // The icon description is only ever set together with the icon, so keep
+    // both around to be able to update either one.
+    icon_file: RefCell<String>,
+    icon_desc: RefCell<String>,
+    // File the last icon set from pixel data was written to, and a counter to
+    // give every new one its own name.
+    icon_tmp: RefCell<Option<PathBuf>>,
+    icon_serial: Cell<u32>,
+    // Timer and frame files of the running icon animation.
+    animation: RefCell<Option<(glib::SourceId, Vec<PathBuf>)>>,
+    hotkeys: RefCell<Option<X11Hotkeys>>,
+    // Icon files handed to the notification daemon, which reads them whenever
+    // it gets around to showing the notification.
+    notification_icons: RefCell<Vec<PathBuf>>,
+    theme_handlers: RefCell<Vec<SignalHandlerId>>,
+    event_tx: EventSender,
 }
 
-thread_local!(static GTK_STASH: RefCell<Option<GtkSystrayApp>> = RefCell::new(None));
+// Every tray icon in the process, by Window id. They share one gtk thread,
+// since gtk can only ever be initialized on one.
+thread_local!(
+    static GTK_STASH: RefCell<HashMap<u32, Rc<GtkSystrayApp>>> = RefCell::new(HashMap::new())
+);
+
+static NEXT_WINDOW_ID: AtomicU32 = AtomicU32::new(0);
+static GTK_STARTED: Once = Once::new();
+static GTK_INIT_FAILED: AtomicBool = AtomicBool::new(false);
+
+// Number of run_on_gtk_thread calls ever, which tells an icon update whether
+// anything was queued after the one before it.
+static QUEUED_COMMANDS: AtomicUsize = AtomicUsize::new(0);
 
 pub struct MenuItemInfo {
     mid: u32,
@@ -33,150 +105,2139 @@ pub struct MenuItemInfo {
 
 type Callback = Box<(Fn(&GtkSystrayApp) -> () + 'static)>;
 
+// Handed out as a clone, so f can add or remove icons itself.
+fn get_stash(id: u32) -> Option<Rc<GtkSystrayApp>> {
+    GTK_STASH.with(|stash| stash.borrow().get(&id).cloned())
+}
+
 // Convenience function to clean up thread local unwrapping
-fn run_on_gtk_thread<F>(f: F)
+fn run_on_gtk_thread<F>(id: u32, f: F)
 where
     F: std::ops::Fn(&GtkSystrayApp) -> () + Send + 'static,
 {
     // Note this is glib, not gtk. Calling gtk::idle_add will panic us due to
     // being on different threads. glib::idle_add can run across threads.
+    QUEUED_COMMANDS.fetch_add(1, Ordering::SeqCst);
     glib::idle_add(move || {
-        GTK_STASH.with(|stash| {
-            let stash = stash.borrow();
-            let stash = stash.as_ref();
-            if let Some(stash) = stash {
-                f(stash);
+        if let Some(stash) = get_stash(id) {
+            // Unwinding into the glib main loop would abort.
+            crate::catch_panic(Some(&stash.event_tx), || f(&stash));
+        }
+        gtk::prelude::Continue(false)
+    });
+}
+
+// Started by the first Window and kept for the rest of the process, as gtk
+// can't be initialized again on another thread.
+fn start_gtk_thread() -> Result<(), Error> {
+    GTK_STARTED.call_once(|| {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let ok = gtk::init().is_ok();
+            tx.send(ok).ok();
+            if ok {
+                gtk::main();
             }
         });
-        gtk::prelude::Continue(false)
+        if !rx.recv().unwrap_or(false) {
+            GTK_INIT_FAILED.store(true, Ordering::SeqCst);
+        }
     });
+    if GTK_INIT_FAILED.load(Ordering::SeqCst) {
+        return Err(Error::OsError("Gtk init error!".to_owned()));
+    }
+    Ok(())
+}
+
+// Dark themes either set the prefer-dark flag or, by convention, have a name
+// ending in -dark.
+fn get_gtk_theme(settings: &gtk::Settings) -> Theme {
+    let name = settings.get_property_gtk_theme_name();
+    let dark_name = name.map_or(false, |n| n.to_lowercase().ends_with("-dark"));
+    if dark_name || settings.get_property_gtk_application_prefer_dark_theme() {
+        Theme::Dark
+    } else {
+        Theme::Light
+    }
+}
+
+// The handlers hold on to event_tx, so they have to go with the icon.
+fn watch_theme(event_tx: EventSender) -> Vec<SignalHandlerId> {
+    let settings = match gtk::Settings::get_default() {
+        Some(s) => s,
+        None => return Vec::new(),
+    };
+    let last = Rc::new(Cell::new(get_gtk_theme(&settings)));
+    let notify = move |settings: &gtk::Settings| {
+        let theme = get_gtk_theme(settings);
+        if last.replace(theme) != theme {
+            event_tx.send(SystrayEvent::ThemeChanged(theme)).ok();
+        }
+    };
+    let notify = Rc::new(notify);
+    let n = notify.clone();
+    vec![
+        settings.connect_property_gtk_theme_name_notify(move |s| n(s)),
+        settings.connect_property_gtk_application_prefer_dark_theme_notify(move |s| notify(s)),
+    ]
+}
+
+// Just enough of Xlib for global hotkeys, which gtk 3 has no API for.
+#[repr(C)]
+struct XKeyEvent {
+    type_: c_int,
+    serial: c_ulong,
+    send_event: c_int,
+    display: *mut c_void,
+    window: c_ulong,
+    root: c_ulong,
+    subwindow: c_ulong,
+    time: c_ulong,
+    x: c_int,
+    y: c_int,
+    x_root: c_int,
+    y_root: c_int,
+    state: c_uint,
+    keycode: c_uint,
+    same_screen: c_int,
+}
+
+// XEvent is a union of all event structs, padded to this size.
+#[repr(C)]
+struct XEvent {
+    pad: [c_long; 24],
+}
+
+#[repr(C)]
+struct XClientMessageEvent {
+    type_: c_int,
+    serial: c_ulong,
+    send_event: c_int,
+    display: *mut c_void,
+    window: c_ulong,
+    message_type: c_ulong,
+    format: c_int,
+    data: [c_long; 5],
+    // Up to the size of an XEvent.
+    pad: [c_long; 12],
+}
+
+type XErrorHandler = Option<unsafe extern "C" fn(*mut c_void, *mut c_void) -> c_int>;
+
+#[link(name = "X11")]
+extern "C" {
+    fn XOpenDisplay(name: *const c_char) -> *mut c_void;
+    fn XCloseDisplay(display: *mut c_void) -> c_int;
+    fn XDefaultRootWindow(display: *mut c_void) -> c_ulong;
+    fn XConnectionNumber(display: *mut c_void) -> c_int;
+    fn XKeysymToKeycode(display: *mut c_void, keysym: c_ulong) -> c_uchar;
+    fn XGrabKey(
+        display: *mut c_void,
+        keycode: c_int,
+        modifiers: c_uint,
+        grab_window: c_ulong,
+        owner_events: c_int,
+        pointer_mode: c_int,
+        keyboard_mode: c_int,
+    ) -> c_int;
+    fn XUngrabKey(
+        display: *mut c_void,
+        keycode: c_int,
+        modifiers: c_uint,
+        grab_window: c_ulong,
+    ) -> c_int;
+    fn XPending(display: *mut c_void) -> c_int;
+    fn XNextEvent(display: *mut c_void, event: *mut XEvent) -> c_int;
+    fn XSync(display: *mut c_void, discard: c_int) -> c_int;
+    fn XSetErrorHandler(handler: XErrorHandler) -> XErrorHandler;
+    fn XInternAtom(display: *mut c_void, name: *const c_char, only_if_exists: c_int) -> c_ulong;
+    fn XGetWindowProperty(
+        display: *mut c_void,
+        window: c_ulong,
+        property: c_ulong,
+        long_offset: c_long,
+        long_length: c_long,
+        delete: c_int,
+        req_type: c_ulong,
+        actual_type: *mut c_ulong,
+        actual_format: *mut c_int,
+        nitems: *mut c_ulong,
+        bytes_after: *mut c_ulong,
+        prop: *mut *mut c_uchar,
+    ) -> c_int;
+    fn XFree(data: *mut c_void) -> c_int;
+    fn XDefaultScreen(display: *mut c_void) -> c_int;
+    fn XGetSelectionOwner(display: *mut c_void, selection: c_ulong) -> c_ulong;
+    fn XSendEvent(
+        display: *mut c_void,
+        window: c_ulong,
+        propagate: c_int,
+        event_mask: c_long,
+        event: *mut XClientMessageEvent,
+    ) -> c_int;
+}
+
+const KEY_PRESS: c_int = 2;
+const GRAB_MODE_ASYNC: c_int = 1;
+const SHIFT_MASK: c_uint = 1 << 0;
+const LOCK_MASK: c_uint = 1 << 1;
+const CONTROL_MASK: c_uint = 1 << 2;
+const MOD1_MASK: c_uint = 1 << 3;
+const MOD2_MASK: c_uint = 1 << 4;
+const MOD4_MASK: c_uint = 1 << 6;
+const CLIENT_MESSAGE: c_int = 33;
+const XA_CARDINAL: c_ulong = 6;
+const XA_WINDOW: c_ulong = 33;
+const SUBSTRUCTURE_NOTIFY_MASK: c_long = 1 << 19;
+const SUBSTRUCTURE_REDIRECT_MASK: c_long = 1 << 20;
+// Window managers put requests from pagers through none of the focus
+// stealing checks that an app's own requests get.
+const SOURCE_PAGER: c_long = 2;
+
+// Grabs only match the exact modifier state, so every key is grabbed again
+// with caps lock and num lock on.
+const IGNORED_MODIFIERS: [c_uint; 4] = [0, LOCK_MASK, MOD2_MASK, LOCK_MASK | MOD2_MASK];
+
+// A failed grab is reported as an X error, which would exit the process with
+// the default handler.
+static GRAB_FAILED: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" fn grab_error_handler(_: *mut c_void, _: *mut c_void) -> c_int {
+    GRAB_FAILED.store(true, Ordering::SeqCst);
+    0
+}
+
+// Hotkeys use an X connection of their own, so their events don't get mixed
+// up with gdk's. The gtk main loop watches its socket.
+struct X11Hotkeys {
+    display: *mut c_void,
+    root: c_ulong,
+    source: Option<glib::SourceId>,
+    // Keycode and modifiers of each hotkey.
+    grabs: HashMap<u32, (c_int, c_uint)>,
+}
+
+impl X11Hotkeys {
+    // Events go to the icon with the given id.
+    fn new(id: u32) -> Result<X11Hotkeys, Error> {
+        let display = unsafe { XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return Err(Error::OsError(
+                "Global hotkeys need an X11 display".to_owned(),
+            ));
+        }
+        let fd = unsafe { XConnectionNumber(display) };
+        let source = glib::unix_fd_add_local(fd, glib::IOCondition::IN, move |_, _| {
+            if let Some(stash) = get_stash(id) {
+                crate::catch_panic(Some(&stash.event_tx), || stash.dispatch_hotkeys());
+            }
+            glib::Continue(true)
+        });
+        Ok(X11Hotkeys {
+            display,
+            root: unsafe { XDefaultRootWindow(display) },
+            source: Some(source),
+            grabs: HashMap::new(),
+        })
+    }
+
+    fn grab(&mut self, id: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let keysym = match shortcut.key {
+            // Latin-1 keysyms are their character, the rest of unicode is
+            // offset.
+            Key::Char(c) if (c as u32) < 0x100 => c.to_ascii_lowercase() as c_ulong,
+            Key::Char(c) => 0x0100_0000 | c as c_ulong,
+            Key::F(n) => 0xffbe + n as c_ulong - 1,
+        };
+        let keycode = unsafe { XKeysymToKeycode(self.display, keysym) } as c_int;
+        if keycode == 0 {
+            let msg = format!("No key for {:?} on this keyboard", shortcut.key);
+            return Err(Error::InvalidShortcut(msg));
+        }
+        let m = shortcut.modifiers;
+        let mut modifiers = 0;
+        for (on, mask) in [
+            (m.shift, SHIFT_MASK),
+            (m.ctrl, CONTROL_MASK),
+            (m.alt, MOD1_MASK),
+            (m.meta, MOD4_MASK),
+        ]
+        .iter()
+        {
+            if *on {
+                modifiers |= *mask;
+            }
+        }
+        unsafe {
+            GRAB_FAILED.store(false, Ordering::SeqCst);
+            let old_handler = XSetErrorHandler(Some(grab_error_handler));
+            for ignored in IGNORED_MODIFIERS.iter() {
+                XGrabKey(
+                    self.display,
+                    keycode,
+                    modifiers | ignored,
+                    self.root,
+                    0,
+                    GRAB_MODE_ASYNC,
+                    GRAB_MODE_ASYNC,
+                );
+            }
+            XSync(self.display, 0);
+            XSetErrorHandler(old_handler);
+            if GRAB_FAILED.load(Ordering::SeqCst) {
+                self.ungrab_key(keycode, modifiers);
+                return Err(Error::OsError(
+                    "Hotkey is already taken by another app".to_owned(),
+                ));
+            }
+        }
+        self.grabs.insert(id, (keycode, modifiers));
+        Ok(())
+    }
+
+    unsafe fn ungrab_key(&self, keycode: c_int, modifiers: c_uint) {
+        for ignored in IGNORED_MODIFIERS.iter() {
+            XUngrabKey(self.display, keycode, modifiers | ignored, self.root);
+        }
+        XSync(self.display, 0);
+    }
+
+    fn ungrab(&mut self, id: u32) -> Result<(), Error> {
+        match self.grabs.remove(&id) {
+            Some((keycode, modifiers)) => {
+                unsafe { self.ungrab_key(keycode, modifiers) };
+                Ok(())
+            }
+            None => Err(Error::OsError(format!("No hotkey {}", id))),
+        }
+    }
+
+    // Ids of the hotkeys pressed since the last call.
+    fn pressed(&self) -> Vec<u32> {
+        let mut pressed = Vec::new();
+        unsafe {
+            while XPending(self.display) > 0 {
+                let mut event = XEvent { pad: [0; 24] };
+                XNextEvent(self.display, &mut event);
+                let event = &*(&event as *const XEvent as *const XKeyEvent);
+                if event.type_ != KEY_PRESS {
+                    continue;
+                }
+                let state = event.state & !(LOCK_MASK | MOD2_MASK);
+                for (id, grab) in self.grabs.iter() {
+                    if *grab == (event.keycode as c_int, state) {
+                        pressed.push(*id);
+                    }
+                }
+            }
+        }
+        pressed
+    }
+}
+
+impl Drop for X11Hotkeys {
+    fn drop(&mut self) {
+        if let Some(source) = self.source.take() {
+            glib::source_remove(source);
+        }
+        unsafe { XCloseDisplay(self.display) };
+    }
+}
+
+// Windows can go away while we look at them, which is an X error too.
+unsafe extern "C" fn ignore_error_handler(_: *mut c_void, _: *mut c_void) -> c_int {
+    0
+}
+
+// The values of a 32-bit property, which Xlib hands out as longs.
+unsafe fn x11_property(
+    display: *mut c_void,
+    window: c_ulong,
+    property: c_ulong,
+    kind: c_ulong,
+) -> Vec<c_ulong> {
+    let mut actual_type = 0;
+    let mut actual_format = 0;
+    let mut nitems = 0;
+    let mut bytes_after = 0;
+    let mut data = ptr::null_mut();
+    let status = XGetWindowProperty(
+        display,
+        window,
+        property,
+        0,
+        4096,
+        0,
+        kind,
+        &mut actual_type,
+        &mut actual_format,
+        &mut nitems,
+        &mut bytes_after,
+        &mut data,
+    );
+    if status != 0 || data.is_null() {
+        return Vec::new();
+    }
+    let mut values = Vec::new();
+    if actual_type == kind && actual_format == 32 {
+        values.extend_from_slice(std::slice::from_raw_parts(
+            data as *const c_ulong,
+            nitems as usize,
+        ));
+    }
+    XFree(data as *mut c_void);
+    values
+}
+
+// Asks the window manager to activate the topmost window of this process, the
+// way a taskbar does. That covers windows of any toolkit, not only gtk's.
+fn activate_x11_window() -> Result<(), Error> {
+    unsafe {
+        let display = XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return Err(Error::NotSupported {
+                feature: "activating windows without X11",
+            });
+        }
+        let root = XDefaultRootWindow(display);
+        let atom = |name: &[u8]| XInternAtom(display, name.as_ptr() as *const c_char, 0);
+        let stacking = atom(b"_NET_CLIENT_LIST_STACKING\0");
+        let pid_atom = atom(b"_NET_WM_PID\0");
+        let active = atom(b"_NET_ACTIVE_WINDOW\0");
+        let pid = std::process::id() as c_ulong;
+        let old_handler = XSetErrorHandler(Some(ignore_error_handler));
+        // Bottom to top.
+        let window = x11_property(display, root, stacking, XA_WINDOW)
+            .into_iter()
+            .rev()
+            .find(|&w| x11_property(display, w, pid_atom, XA_CARDINAL).first() == Some(&pid));
+        if let Some(window) = window {
+            let mut event = XClientMessageEvent {
+                type_: CLIENT_MESSAGE,
+                serial: 0,
+                send_event: 1,
+                display,
+                window,
+                message_type: active,
+                format: 32,
+                data: [SOURCE_PAGER, 0, 0, 0, 0],
+                pad: [0; 12],
+            };
+            XSendEvent(
+                display,
+                root,
+                0,
+                SUBSTRUCTURE_NOTIFY_MASK | SUBSTRUCTURE_REDIRECT_MASK,
+                &mut event,
+            );
+        }
+        XSync(display, 0);
+        XSetErrorHandler(old_handler);
+        XCloseDisplay(display);
+    }
+    Ok(())
+}
+
+// An XEmbed tray owns the _NET_SYSTEM_TRAY_S<screen> selection for as long as
+// it runs, that's what GtkStatusIcon docks into.
+fn xembed_tray_available() -> bool {
+    unsafe {
+        let display = XOpenDisplay(ptr::null());
+        if display.is_null() {
+            return false;
+        }
+        let name = format!("_NET_SYSTEM_TRAY_S{}\0", XDefaultScreen(display));
+        let selection = XInternAtom(display, name.as_ptr() as *const c_char, 0);
+        let owner = XGetSelectionOwner(display, selection);
+        XCloseDisplay(display);
+        owner != 0
+    }
+}
+
+fn app_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_default()
+}
+
+// GError codes only mean something together with their domain, the message
+// is what's worth keeping.
+fn glib_error(call: &'static str, e: glib::Error) -> Error {
+    Error::Glib {
+        call,
+        message: e.to_string(),
+    }
+}
+
+fn to_cstring(str: &str) -> Result<CString, Error> {
+    match CString::new(str) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(Error::OsError(format!("{:?} contains a null byte", str))),
+    }
+}
+
+// Calls org.freedesktop.Notifications.Notify without waiting for the reply,
+// the same thing libnotify does under the hood.
+unsafe fn send_notification(
+    title: &str,
+    body: &str,
+    icon: &str,
+    timeout: i32,
+) -> Result<(), Error> {
+    let title = to_cstring(title)?;
+    let body = to_cstring(body)?;
+    let icon = to_cstring(icon)?;
+    let app_name = to_cstring(&app_name())?;
+    let mut error = ptr::null_mut();
+    let bus = gio_sys::g_bus_get_sync(gio_sys::G_BUS_TYPE_SESSION, ptr::null_mut(), &mut error);
+    if bus.is_null() {
+        let error: glib::Error = from_glib_full(error);
+        let msg = format!("Error connecting to the session bus: {}", error);
+        return Err(Error::OsError(msg));
+    }
+    // Nulls stand in for the empty actions array and hints dictionary.
+    let params = glib_sys::g_variant_new(
+        "(susssasa{sv}i)\0".as_ptr() as *const c_char,
+        app_name.as_ptr(),
+        0 as c_uint,
+        icon.as_ptr(),
+        title.as_ptr(),
+        body.as_ptr(),
+        ptr::null_mut::<c_void>(),
+        ptr::null_mut::<c_void>(),
+        timeout as c_int,
+    );
+    gio_sys::g_dbus_connection_call(
+        bus,
+        "org.freedesktop.Notifications\0".as_ptr() as *const c_char,
+        "/org/freedesktop/Notifications\0".as_ptr() as *const c_char,
+        "org.freedesktop.Notifications\0".as_ptr() as *const c_char,
+        "Notify\0".as_ptr() as *const c_char,
+        params,
+        ptr::null(),
+        gio_sys::G_DBUS_CALL_FLAGS_NONE,
+        -1,
+        ptr::null_mut(),
+        None,
+        ptr::null_mut(),
+    );
+    gobject_sys::g_object_unref(bus as *mut gobject_sys::GObject);
+    Ok(())
+}
+
+// Widgets don't take the direction of their parent, so it goes on every item
+// and label, and into the submenus.
+fn set_direction(widget: &gtk::Widget, direction: TextDirection) {
+    widget.set_direction(direction);
+    if let Some(item) = widget.downcast_ref::<gtk::MenuItem>() {
+        if let Some(submenu) = item.get_submenu() {
+            set_direction(&submenu, direction);
+        }
+    }
+    if let Some(container) = widget.downcast_ref::<gtk::Container>() {
+        container.foreach(|child| set_direction(child, direction));
+    }
+}
+
+// gtk-rs doesn't wrap GtkImageMenuItem, as it is deprecated. It is still the
+// only kind of item AppIndicator exports an icon for, so plain items and
+// submenus are made out of it anyway.
+fn new_image_menu_item(label: &str) -> gtk::MenuItem {
+    unsafe {
+        let item = gtk_sys::gtk_image_menu_item_new_with_label(label.to_glib_none().0);
+        gtk_sys::gtk_image_menu_item_set_always_show_image(item as *mut _, glib_sys::GTRUE);
+        gtk::Widget::from_glib_none(item).unsafe_cast()
+    }
+}
+
+fn pixbuf_from_rgba(rgba: &[u8], width: u32, height: u32) -> Pixbuf {
+    Pixbuf::new_from_mut_slice(
+        rgba.to_vec(),
+        Colorspace::Rgb,
+        true,
+        8,
+        width as i32,
+        height as i32,
+        (width * 4) as i32,
+    )
+}
+
+// The frame of an .ico file closest to size, which is the smallest one at
+// least that big or else the biggest, as an .ico file of its own.
+fn ico_frame(data: &[u8], size: u32) -> Option<Vec<u8>> {
+    let entries = ico::get_ico_entries(data).ok()?;
+    // A width of 0 stands for 256. Deeper frames win between equal sizes.
+    let (_, _, n) = (0..entries.len())
+        .map(|n| {
+            let entry = 6 + n * 16;
+            let width = if data[entry] == 0 { 256 } else { data[entry] as u32 };
+            let depth = u16::from_le_bytes([data[entry + 6], data[entry + 7]]);
+            (width, depth, n)
+        })
+        .min_by_key(|&(width, depth, _)| {
+            let distance = if width < size { size - width } else { width - size };
+            (width < size, distance, std::cmp::Reverse(depth))
+        })?;
+    let (offset, len) = entries[n];
+    let entry = 6 + n * 16;
+    let mut frame = Vec::with_capacity(22 + len);
+    frame.extend_from_slice(&[0, 0, 1, 0, 1, 0]);
+    frame.extend_from_slice(&data[entry..entry + 12]);
+    frame.extend_from_slice(&22u32.to_le_bytes());
+    frame.extend_from_slice(&data[offset..offset + len]);
+    Some(frame)
+}
+
+// Puts the menu at the boxed (i32, i32) point show_menu_at passes as data.
+unsafe extern "C" fn position_menu(
+    _: *mut gtk_sys::GtkMenu,
+    x: *mut c_int,
+    y: *mut c_int,
+    push_in: *mut glib_sys::gboolean,
+    data: glib_sys::gpointer,
+) {
+    let point = &*(data as *const (i32, i32));
+    *x = point.0;
+    *y = point.1;
+    *push_in = glib_sys::GTRUE;
+}
+
+unsafe extern "C" fn free_menu_position(data: glib_sys::gpointer) {
+    drop(Box::from_raw(data as *mut (i32, i32)));
+}
+
+// GtkStatusIcon, the XEmbed tray icon. Deprecated, but it's all that panels
+// without a StatusNotifierItem host show.
+struct StatusIcon {
+    icon: glib::Object,
+}
+
+impl StatusIcon {
+    fn new(
+        menu: &gtk::Menu,
+        menu_on_left_click: Rc<Cell<bool>>,
+        event_tx: EventSender,
+    ) -> StatusIcon {
+        let icon: glib::Object =
+            unsafe { from_glib_full(gtk_sys::gtk_status_icon_new() as *mut gobject_sys::GObject) };
+        let (m, tx) = (menu.clone(), event_tx.clone());
+        icon.connect_local("activate", false, move |_| {
+            // Enter or Space while the icon has the focus.
+            let key = gtk::get_current_event().map_or(false, |e| {
+                let kind = e.get_event_type();
+                kind == gdk::EventType::KeyPress || kind == gdk::EventType::KeyRelease
+            });
+            if key {
+                tx.send(SystrayEvent::IconKeySelected).ok();
+            } else {
+                send_click(&tx, MouseButton::Left);
+            }
+            if menu_on_left_click.get() {
+                m.popup_easy(1, gtk::get_current_event_time());
+            }
+            None
+        })
+        .ok();
+        // Only the left and right buttons have signals of their own.
+        let tx = event_tx.clone();
+        icon.connect_local("button-release-event", false, move |values| {
+            let event = values[1].get::<gdk::Event>().ok().and_then(|e| e);
+            if event.and_then(|e| e.get_button()) == Some(2) {
+                send_click(&tx, MouseButton::Middle);
+            }
+            Some(false.to_value())
+        })
+        .ok();
+        let tx = event_tx.clone();
+        icon.connect_local("scroll-event", false, move |values| {
+            let event = values[1].get::<gdk::Event>().ok().and_then(|e| e);
+            if let Some(scroll) = event.and_then(|e| e.downcast::<gdk::EventScroll>().ok()) {
+                let event = match scroll.get_direction() {
+                    // Smooth deltas are positive down and right.
+                    ScrollDirection::Smooth => {
+                        let (dx, dy) = scroll.get_delta();
+                        if dy != 0.0 {
+                            Some(SystrayEvent::Scroll {
+                                delta: -dy,
+                                axis: ScrollAxis::Vertical,
+                            })
+                        } else {
+                            Some(SystrayEvent::Scroll {
+                                delta: dx,
+                                axis: ScrollAxis::Horizontal,
+                            })
+                        }
+                    }
+                    direction => scroll_event(direction, 1.0),
+                };
+                if let Some(event) = event {
+                    tx.send(event).ok();
+                }
+            }
+            Some(true.to_value())
+        })
+        .ok();
+        // GtkStatusIcon docks into a restarted tray again by itself.
+        let (tx, seen) = (event_tx.clone(), Cell::new(false));
+        icon.connect_local("notify::embedded", false, move |values| {
+            let embedded = values[0]
+                .get::<glib::Object>()
+                .ok()
+                .and_then(|o| o)
+                .and_then(|o| o.get_property("embedded").ok())
+                .and_then(|v| v.get_some::<bool>().ok())
+                .unwrap_or(false);
+            report_host(&seen, embedded, &tx);
+            None
+        })
+        .ok();
+        let m = menu.clone();
+        icon.connect_local("popup-menu", false, move |values| {
+            send_click(&event_tx, MouseButton::Right);
+            let button = values[1].get_some::<u32>().unwrap_or(3);
+            let time = values[2].get_some::<u32>().unwrap_or(0);
+            m.popup_easy(button, time);
+            None
+        })
+        .ok();
+        StatusIcon { icon }
+    }
+
+    fn as_ptr(&self) -> *mut gtk_sys::GtkStatusIcon {
+        let icon: *mut gobject_sys::GObject = self.icon.to_glib_none().0;
+        icon as *mut gtk_sys::GtkStatusIcon
+    }
+
+    fn set_icon_full(&self, file: &str, desc: &str) -> Result<(), Error> {
+        let (file, desc) = (to_cstring(file)?, to_cstring(desc)?);
+        unsafe {
+            if file.as_bytes().starts_with(b"/") {
+                gtk_sys::gtk_status_icon_set_from_file(self.as_ptr(), file.as_ptr());
+            } else {
+                gtk_sys::gtk_status_icon_set_from_icon_name(self.as_ptr(), file.as_ptr());
+            }
+            gtk_sys::gtk_status_icon_set_title(self.as_ptr(), desc.as_ptr());
+        }
+        Ok(())
+    }
+
+    // 0 until the icon is embedded.
+    fn size(&self) -> i32 {
+        unsafe { gtk_sys::gtk_status_icon_get_size(self.as_ptr()) }
+    }
+
+    fn screen_rect(&self) -> Result<Rect, Error> {
+        let mut area = gdk::Rectangle {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        };
+        let found = unsafe {
+            gtk_sys::gtk_status_icon_get_geometry(
+                self.as_ptr(),
+                ptr::null_mut(),
+                area.to_glib_none_mut().0,
+                ptr::null_mut(),
+            )
+        };
+        if found == glib_sys::GFALSE {
+            return Err(Error::OsError("Status icon isn't embedded".to_owned()));
+        }
+        Ok(Rect {
+            x: area.x,
+            y: area.y,
+            width: area.width as u32,
+            height: area.height as u32,
+        })
+    }
+
+    fn set_visible(&self, visible: bool) {
+        let visible = if visible { glib_sys::GTRUE } else { glib_sys::GFALSE };
+        unsafe { gtk_sys::gtk_status_icon_set_visible(self.as_ptr(), visible) };
+    }
+}
+
+// AppIndicator and StatusNotifierItem hosts pass on wheel angles the way Qt
+// has them, 120 to a notch.
+const WHEEL_NOTCH: f64 = 120.0;
+
+fn scroll_event(direction: ScrollDirection, notches: f64) -> Option<SystrayEvent> {
+    let (delta, axis) = match direction {
+        ScrollDirection::Up => (notches, ScrollAxis::Vertical),
+        ScrollDirection::Down => (-notches, ScrollAxis::Vertical),
+        ScrollDirection::Left => (-notches, ScrollAxis::Horizontal),
+        ScrollDirection::Right => (notches, ScrollAxis::Horizontal),
+        _ => return None,
+    };
+    Some(SystrayEvent::Scroll {
+        delta,
+        axis,
+    })
+}
+
+// There was a host when the icon was made, so the first one showing up
+// isn't news. Every change after that is.
+fn report_host(seen: &Cell<bool>, present: bool, event_tx: &EventSender) {
+    if present && !seen.replace(true) {
+        return;
+    }
+    if seen.get() {
+        event_tx.send(SystrayEvent::TrayHostChanged(present)).ok();
+    }
+}
+
+// GtkStatusIcon's signals don't say where the click was, but the pointer is
+// still there.
+fn send_click(event_tx: &EventSender, button: MouseButton) {
+    let pointer = gdk::Display::get_default()
+        .and_then(|d| d.get_device_manager())
+        .and_then(|m| m.get_client_pointer());
+    let (x, y) = match pointer {
+        Some(p) => {
+            let (_, x, y) = p.get_position();
+            (x, y)
+        }
+        None => (0, 0),
+    };
+    event_tx
+        .send(SystrayEvent::IconClicked {
+            button,
+            x,
+            y,
+            // GDK event times are on the X server's clock.
+            time: Instant::now(),
+        })
+        .ok();
+}
+
+// What shows the icon, picked once at startup. AppIndicator and our own
+// StatusNotifierItem both need a host on the session bus; without one the
+// icon goes into the XEmbed tray instead, if there is one.
+enum Tray {
+    #[cfg(feature = "appindicator")]
+    AppIndicator(appindicator::AppIndicator),
+    StatusNotifierItem(sni::StatusNotifierItem),
+    StatusIcon(StatusIcon),
+}
+
+impl Tray {
+    fn new(
+        window_id: u32,
+        menu: &gtk::Menu,
+        menu_on_left_click: Rc<Cell<bool>>,
+        event_tx: EventSender,
+        backend: Backend,
+    ) -> Result<Tray, Error> {
+        // Hosts tell items apart by id, the first icon just gets the app's
+        // name.
+        let id = match window_id {
+            0 => app_name(),
+            n => format!("{}-{}", app_name(), n),
+        };
+        let id = to_cstring(&id).unwrap_or_default();
+        // A forced backend is used even without a host, which may still show
+        // up later.
+        match backend {
+            Backend::Auto => {}
+            #[cfg(feature = "appindicator")]
+            Backend::AppIndicator => {
+                return appindicator::AppIndicator::new(&id, menu, event_tx)
+                    .map(Tray::AppIndicator);
+            }
+            #[cfg(not(feature = "appindicator"))]
+            Backend::AppIndicator => {
+                return Err(Error::NotSupported {
+                    feature: "AppIndicator without the appindicator feature",
+                });
+            }
+            Backend::StatusNotifier => {
+                let item = sni::StatusNotifierItem::new(
+                    window_id,
+                    &id,
+                    menu,
+                    menu_on_left_click,
+                    event_tx,
+                )?;
+                return Ok(Tray::StatusNotifierItem(item));
+            }
+            Backend::Gtk => {
+                let icon = StatusIcon::new(menu, menu_on_left_click, event_tx);
+                return Ok(Tray::StatusIcon(icon));
+            }
+            // The mock is picked before getting here.
+            Backend::Win32 | Backend::Cocoa | Backend::Mock => {
+                return Err(Error::NotSupported {
+                    feature: "tray backends of other platforms",
+                });
+            }
+        }
+        if sni::host_available() {
+            #[cfg(feature = "appindicator")]
+            {
+                match appindicator::AppIndicator::new(&id, menu, event_tx.clone()) {
+                    Ok(ai) => return Ok(Tray::AppIndicator(ai)),
+                    Err(e) => log::info!("{}", e),
+                }
+            }
+            let left_click = menu_on_left_click.clone();
+            let tx = event_tx.clone();
+            match sni::StatusNotifierItem::new(window_id, &id, menu, left_click, tx) {
+                Ok(item) => return Ok(Tray::StatusNotifierItem(item)),
+                Err(e) => log::warn!("{}", e),
+            }
+        }
+        if !xembed_tray_available() {
+            return Err(Error::NoTrayAvailable);
+        }
+        let icon = StatusIcon::new(menu, menu_on_left_click, event_tx);
+        Ok(Tray::StatusIcon(icon))
+    }
+
+    fn set_icon_full(&self, file: &str, desc: &str) -> Result<(), Error> {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Tray::AppIndicator(ai) => {
+                ai.set_icon_full(&to_cstring(file)?, &to_cstring(desc)?);
+                Ok(())
+            }
+            Tray::StatusNotifierItem(item) => item.set_icon_full(file, desc),
+            Tray::StatusIcon(icon) => icon.set_icon_full(file, desc),
+        }
+    }
+
+    // Labels are an AppIndicator extension, the others have no room for text.
+    #[cfg_attr(not(feature = "appindicator"), allow(unused_variables))]
+    fn set_label(&self, label: &str) -> Result<(), Error> {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Tray::AppIndicator(ai) => {
+                ai.set_label(&to_cstring(label)?);
+                Ok(())
+            }
+            _ => Err(Error::NotSupported {
+                feature: "labels without AppIndicator",
+            }),
+        }
+    }
+
+    // AppIndicator always opens the menu and never reports clicks on the icon.
+    fn reports_clicks(&self) -> bool {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Tray::AppIndicator(_) => false,
+            _ => true,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        #[cfg(feature = "appindicator")]
+        let labels = match self {
+            Tray::AppIndicator(_) => true,
+            _ => false,
+        };
+        #[cfg(not(feature = "appindicator"))]
+        let labels = false;
+        let status_icon = match self {
+            Tray::StatusIcon(_) => true,
+            _ => false,
+        };
+        Capabilities {
+            supports_tooltip: false,
+            supports_title_text: labels,
+            supports_menu_icons: true,
+            supports_notifications: true,
+            supports_icon_clicks: self.reports_clicks(),
+            supports_double_click: false,
+            supports_icon_screen_rect: status_icon,
+            supports_icon_from_file: true,
+            supports_icon_accessibility_description: true,
+            // The grabs go through X11, which isn't there on plain Wayland.
+            supports_global_hotkeys: std::env::var_os("DISPLAY").is_some(),
+            // Neither a GtkStatusIcon nor a StatusNotifierItem takes drops.
+            supports_file_drops: false,
+            supports_scroll: true,
+        }
+    }
+
+    fn native_handle(&self) -> NativeHandle {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Tray::AppIndicator(ai) => NativeHandle::AppIndicator(ai.as_ptr()),
+            Tray::StatusNotifierItem(item) => NativeHandle::StatusNotifierItem {
+                bus_name: item.bus_name(),
+                object_path: sni::ITEM_PATH.trim_end_matches('\0').to_owned(),
+            },
+            Tray::StatusIcon(icon) => NativeHandle::GtkStatusIcon(icon.as_ptr() as *mut c_void),
+        }
+    }
+
+    // Neither AppIndicator nor StatusNotifierItem hosts say where the icon is.
+    fn screen_rect(&self) -> Result<Rect, Error> {
+        match self {
+            Tray::StatusIcon(icon) => icon.screen_rect(),
+            _ => Err(Error::NotSupported {
+                feature: "icon positions from StatusNotifierItem hosts",
+            }),
+        }
+    }
+
+    fn set_visible(&self, visible: bool) {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Tray::AppIndicator(ai) => ai.set_visible(visible),
+            Tray::StatusNotifierItem(item) => item.set_visible(visible),
+            Tray::StatusIcon(icon) => icon.set_visible(visible),
+        }
+    }
+
+    // AppIndicator's dbusmenu export follows the gtk menu by itself.
+    fn menu_changed(&self) {
+        if let Tray::StatusNotifierItem(item) = self {
+            item.menu_changed();
+        }
+    }
+
+    fn hide(&self) {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Tray::AppIndicator(ai) => ai.set_visible(false),
+            Tray::StatusNotifierItem(item) => item.hide(),
+            Tray::StatusIcon(icon) => icon.set_visible(false),
+        }
+    }
 }
 
 impl GtkSystrayApp {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<GtkSystrayApp, Error> {
-        if let Err(e) = gtk::init() {
-            return Err(Error::OsError(format!("{}", "Gtk init error!")));
-        }
-        let mut m = gtk::Menu::new();
-        let mut ai = AppIndicator::new("", "");
-        ai.set_status(AppIndicatorStatus::Active);
-        ai.set_menu(&mut m);
+    pub fn new(
+        id: u32,
+        event_tx: EventSender,
+        backend: Backend,
+        visible: bool,
+    ) -> Result<GtkSystrayApp, Error> {
+        let m = gtk::Menu::new();
+        let accel_group = gtk::AccelGroup::new();
+        m.set_accel_group(Some(&accel_group));
+        let menu_on_left_click = Rc::new(Cell::new(true));
+        let left_click = menu_on_left_click.clone();
+        let tray = Tray::new(id, &m, left_click, event_tx.clone(), backend)?;
+        // Before the main loop gets to show it.
+        if !visible {
+            tray.set_visible(false);
+        }
+        // Not show and hide, which also come from show_all while the menu is
+        // being built. With AppIndicator the menu is exported over D-Bus and
+        // never mapped here.
+        let tx = event_tx.clone();
+        let menu_direction = Rc::new(Cell::new(TextDirection::None));
+        let direction = menu_direction.clone();
+        m.connect_map(move |m| {
+            // Again for the items added since it was set.
+            if direction.get() != TextDirection::None {
+                set_direction(m.upcast_ref(), direction.get());
+            }
+            tx.send(SystrayEvent::MenuWillOpen).ok();
+        });
+        let tx = event_tx.clone();
+        m.connect_unmap(move |_| {
+            tx.send(SystrayEvent::MenuDidClose).ok();
+        });
         Ok(GtkSystrayApp {
+            id,
             menu: m,
-            ai: RefCell::new(ai),
+            tray,
+            menu_on_left_click,
+            menu_direction,
             menu_items: RefCell::new(HashMap::new()),
-            event_tx: event_tx,
+            check_items: RefCell::new(HashMap::new()),
+            submenus: RefCell::new(HashMap::new()),
+            accel_group,
+            shortcuts: RefCell::new(HashMap::new()),
+            icon_file: RefCell::new(String::new()),
+            icon_desc: RefCell::new("icon".to_owned()),
+            icon_tmp: RefCell::new(None),
+            icon_serial: Cell::new(0),
+            animation: RefCell::new(None),
+            hotkeys: RefCell::new(None),
+            notification_icons: RefCell::new(Vec::new()),
+            theme_handlers: RefCell::new(watch_theme(event_tx.clone())),
+            event_tx,
         })
     }
 
     pub fn systray_menu_selected(&self, menu_id: u32) {
-        self.event_tx
-            .send(SystrayEvent {
-                menu_index: menu_id as u32,
-            })
-            .ok();
+        self.event_tx.send_click(menu_id).ok();
+    }
+
+    fn get_menu(&self, parent: Option<u32>) -> gtk::Menu {
+        match parent {
+            Some(p) => match self.submenus.borrow().get(&p) {
+                Some(m) => m.clone(),
+                None => {
+                    log::warn!("No submenu {}, adding to the top level menu", p);
+                    self.menu.clone()
+                }
+            },
+            None => self.menu.clone(),
+        }
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) {
-        //let mut menu_items = self.menu_items.borrow_mut();
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) {
+        let menu = self.get_menu(parent);
         let m = gtk::SeparatorMenuItem::new();
-        self.menu.append(&m);
-        //menu_items.insert(item_idx, m);
-        self.menu.show_all();
+        menu.append(&m);
+        self.menu_items.borrow_mut().insert(item_idx, m.upcast());
+        menu.show_all();
+        self.tray.menu_changed();
+    }
+
+    // GTK menus have no headers, so it's a greyed out item with a bold label.
+    // The label's markup is left out of the dbusmenu export.
+    pub fn add_menu_header(&self, item_idx: u32, parent: Option<u32>, title: &str) {
+        let menu = self.get_menu(parent);
+        let label = gtk::Label::new(None);
+        label.set_markup(&format!("<b>{}</b>", glib::markup_escape_text(title)));
+        label.set_halign(gtk::Align::Start);
+        let m = gtk::MenuItem::new();
+        m.add(&label);
+        m.set_sensitive(false);
+        menu.append(&m);
+        self.menu_items.borrow_mut().insert(item_idx, m);
+        menu.show_all();
+        self.tray.menu_changed();
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &str) {
+    pub fn add_menu_entry(&self, item_idx: u32, parent: Option<u32>, item_name: &str) {
         let mut menu_items = self.menu_items.borrow_mut();
-        if menu_items.contains_key(&item_idx) {
-            let m: &gtk::MenuItem = menu_items.get(&item_idx).unwrap();
+        if let Some(m) = menu_items.get(&item_idx) {
             m.set_label(item_name);
             self.menu.show_all();
+            self.tray.menu_changed();
             return;
         }
-        let m = gtk::MenuItem::new_with_label(item_name);
-        self.menu.append(&m);
+        let m = new_image_menu_item(item_name);
+        self.append_menu_item(item_idx, parent, &m);
+        menu_items.insert(item_idx, m);
+        self.tray.menu_changed();
+    }
+
+    pub fn add_menu_checkable_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        checked: bool,
+    ) {
+        let m = gtk::CheckMenuItem::new_with_label(item_name);
+        m.set_active(checked);
+        let handler = self.append_menu_item(item_idx, parent, m.upcast_ref());
+        self.menu_items
+            .borrow_mut()
+            .insert(item_idx, m.clone().upcast());
+        self.check_items.borrow_mut().insert(item_idx, (m, handler));
+        self.tray.menu_changed();
+    }
+
+    // group is the id of the first item in the group. GTK unchecks the other
+    // items by itself once one gets picked.
+    pub fn add_menu_radio_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        group: u32,
+        checked: bool,
+    ) {
+        let leader = self
+            .check_items
+            .borrow()
+            .get(&group)
+            .and_then(|(m, _)| m.clone().downcast::<gtk::RadioMenuItem>().ok());
+        let m = match leader {
+            Some(leader) => {
+                gtk::RadioMenuItem::new_with_label_from_widget(&leader, Some(item_name))
+            }
+            None => gtk::RadioMenuItem::new_with_label(item_name),
+        };
+        m.set_active(checked);
+        let menu = self.get_menu(parent);
+        menu.append(&m);
+        menu.show_all();
+        // GTK activates the item being unchecked as well, only report the one
+        // that got picked.
+        let id = self.id;
+        let handler = m.connect_activate(move |m| {
+            if m.get_active() {
+                run_on_gtk_thread(id, move |stash: &GtkSystrayApp| {
+                    stash.systray_menu_selected(item_idx);
+                });
+            }
+        });
+        self.menu_items
+            .borrow_mut()
+            .insert(item_idx, m.clone().upcast());
+        self.check_items
+            .borrow_mut()
+            .insert(item_idx, (m.upcast(), handler));
+        self.tray.menu_changed();
+    }
+
+    pub fn add_submenu(&self, item_idx: u32, parent: Option<u32>, item_name: &str) {
+        let menu = self.get_menu(parent);
+        let submenu = gtk::Menu::new();
+        submenu.set_accel_group(Some(&self.accel_group));
+        let m = new_image_menu_item(item_name);
+        m.set_submenu(Some(&submenu));
+        menu.append(&m);
+        menu.show_all();
+        self.menu_items.borrow_mut().insert(item_idx, m);
+        self.submenus.borrow_mut().insert(item_idx, submenu);
+        self.tray.menu_changed();
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        match self.check_items.borrow().get(&item_idx) {
+            Some((m, handler)) => {
+                m.block_signal(handler);
+                m.set_active(checked);
+                m.unblock_signal(handler);
+                self.tray.menu_changed();
+                Ok(())
+            }
+            None => {
+                let msg = format!("No checkable menu item {}", item_idx);
+                Err(Error::OsError(msg))
+            }
+        }
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        let m = match self.menu_items.borrow_mut().remove(&item_idx) {
+            Some(m) => m,
+            None => return Err(Error::OsError(format!("No menu item {}", item_idx))),
+        };
+        self.check_items.borrow_mut().remove(&item_idx);
+        self.submenus.borrow_mut().remove(&item_idx);
+        self.shortcuts.borrow_mut().remove(&item_idx);
+        if let Some(parent) = m.get_parent() {
+            if let Ok(menu) = parent.downcast::<gtk::Menu>() {
+                menu.remove(&m);
+            }
+        }
+        self.tray.menu_changed();
+        Ok(())
+    }
+
+    pub fn clear_menu(&self) {
+        for child in self.menu.get_children() {
+            self.menu.remove(&child);
+        }
+        self.menu_items.borrow_mut().clear();
+        self.check_items.borrow_mut().clear();
+        self.submenus.borrow_mut().clear();
+        self.shortcuts.borrow_mut().clear();
+        self.tray.menu_changed();
+    }
+
+    pub fn set_menu_item_label(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
+        match self.menu_items.borrow().get(&item_idx) {
+            Some(m) => {
+                m.set_label(item_name);
+                self.tray.menu_changed();
+                Ok(())
+            }
+            None => Err(Error::OsError(format!("No menu item {}", item_idx))),
+        }
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        match self.menu_items.borrow().get(&item_idx) {
+            Some(m) => {
+                m.set_sensitive(enabled);
+                self.tray.menu_changed();
+                Ok(())
+            }
+            None => Err(Error::OsError(format!("No menu item {}", item_idx))),
+        }
+    }
+
+    // dbusmenu has no tooltips, so they only show in the menu we pop up
+    // ourselves, not in those of StatusNotifierItem hosts.
+    pub fn set_menu_item_tooltip(&self, item_idx: u32, tooltip: &str) -> Result<(), Error> {
+        match self.menu_items.borrow().get(&item_idx) {
+            Some(m) => {
+                let tooltip = if tooltip.is_empty() { None } else { Some(tooltip) };
+                m.set_tooltip_text(tooltip);
+                Ok(())
+            }
+            None => Err(Error::OsError(format!("No menu item {}", item_idx))),
+        }
+    }
+
+    pub fn set_menu_item_icon(&self, item_idx: u32, icon: &IconBuffer) -> Result<(), Error> {
+        let menu_items = self.menu_items.borrow();
+        let m = match menu_items.get(&item_idx) {
+            Some(m) => m,
+            None => return Err(Error::OsError(format!("No menu item {}", item_idx))),
+        };
+        let image_item_type = unsafe { from_glib(gtk_sys::gtk_image_menu_item_get_type()) };
+        if !m.get_type().is_a(&image_item_type) {
+            let msg = format!("Menu item {} can't have an icon", item_idx);
+            return Err(Error::OsError(msg));
+        }
+        let pixbuf = pixbuf_from_rgba(&icon.rgba, icon.width, icon.height);
+        let image = gtk::Image::new_from_pixbuf(Some(&pixbuf));
+        unsafe {
+            let item: *mut gtk_sys::GtkMenuItem = m.to_glib_none().0;
+            gtk_sys::gtk_image_menu_item_set_image(
+                item as *mut gtk_sys::GtkImageMenuItem,
+                image.upcast_ref::<gtk::Widget>().to_glib_none().0,
+            );
+        }
+        self.tray.menu_changed();
+        Ok(())
+    }
+
+    pub fn set_menu_item_shortcut(&self, item_idx: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let menu_items = self.menu_items.borrow();
+        let m = match menu_items.get(&item_idx) {
+            Some(m) => m,
+            None => return Err(Error::OsError(format!("No menu item {}", item_idx))),
+        };
+        let key = match shortcut.key {
+            Key::Char(c) => gdk::unicode_to_keyval(c.to_ascii_lowercase() as u32),
+            Key::F(n) => gdk::enums::key::F1 + n as u32 - 1,
+        };
+        let mut mods = ModifierType::empty();
+        let s = shortcut.modifiers;
+        for (on, m) in [
+            (s.ctrl, ModifierType::CONTROL_MASK),
+            (s.alt, ModifierType::MOD1_MASK),
+            (s.shift, ModifierType::SHIFT_MASK),
+            (s.meta, ModifierType::SUPER_MASK),
+        ]
+        .iter()
+        {
+            if *on {
+                mods |= *m;
+            }
+        }
+        let mut shortcuts = self.shortcuts.borrow_mut();
+        if let Some((old_key, old_mods)) = shortcuts.remove(&item_idx) {
+            m.remove_accelerator(&self.accel_group, old_key, old_mods);
+        }
+        m.add_accelerator("activate", &self.accel_group, key, mods, AccelFlags::VISIBLE);
+        shortcuts.insert(item_idx, (key, mods));
+        self.tray.menu_changed();
+        Ok(())
+    }
+
+    fn append_menu_item(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        m: &gtk::MenuItem,
+    ) -> SignalHandlerId {
+        let menu = self.get_menu(parent);
+        menu.append(m);
+        menu.show_all();
+        let id = self.id;
         m.connect_activate(move |_| {
-            run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            run_on_gtk_thread(id, move |stash: &GtkSystrayApp| {
                 stash.systray_menu_selected(item_idx);
             });
-        });
-        menu_items.insert(item_idx, m);
-        self.menu.show_all();
+        })
     }
 
     pub fn set_icon_from_file(&self, file: &str) {
-        let mut ai = self.ai.borrow_mut();
-        ai.set_icon_full(file, "icon");
+        if let Err(e) = self.tray.set_icon_full(file, &self.icon_desc.borrow()) {
+            log::warn!("{}", e);
+        }
+        *self.icon_file.borrow_mut() = file.to_owned();
+    }
+
+    // The tray loads the biggest frame of an .ico and scales it down, which
+    // blurs icons that come with one drawn for the small size. It gets the
+    // frame closest to the size it shows icons at instead.
+    fn set_icon_from_ico_file(&self, file: &str) -> Result<(), Error> {
+        let data = fs::read(file)
+            .map_err(|e| Error::OsError(format!("Couldn't read {}: {}", file, e)))?;
+        let frame = ico_frame(&data, self.tray_icon_size())
+            .ok_or_else(|| Error::InvalidIcon(format!("{} isn't an .ico file", file)))?;
+        let loader = PixbufLoader::new_with_type("ico")
+            .map_err(|e| glib_error("gdk_pixbuf_loader_new_with_type", e))?;
+        loader
+            .write(&frame)
+            .map_err(|e| glib_error("gdk_pixbuf_loader_write", e))?;
+        loader
+            .close()
+            .map_err(|e| glib_error("gdk_pixbuf_loader_close", e))?;
+        let pixbuf = loader
+            .get_pixbuf()
+            .ok_or_else(|| Error::InvalidIcon(format!("{} has no image in it", file)))?;
+        let path = self.write_pixbuf_file(&pixbuf)?;
+        self.set_icon_from_file(&path.to_string_lossy());
+        if let Some(old) = self.icon_tmp.replace(Some(path)) {
+            fs::remove_file(old).ok();
+        }
+        Ok(())
+    }
+
+    // Panels mostly show icons 22 pixels high, an embedded GtkStatusIcon
+    // knows its own size. Either way times the scale factor.
+    fn tray_icon_size(&self) -> u32 {
+        let size = match &self.tray {
+            Tray::StatusIcon(icon) if icon.size() > 0 => icon.size(),
+            _ => 22,
+        };
+        (size * self.menu.get_scale_factor().max(1)) as u32
+    }
+
+    // AppIndicator only takes icon names or paths, so pixel data has to go
+    // through a file. Reusing the name would keep the cached old icon around.
+    fn write_icon_file(&self, rgba: &[u8], width: u32, height: u32) -> Result<PathBuf, Error> {
+        self.write_pixbuf_file(&pixbuf_from_rgba(rgba, width, height))
+    }
+
+    fn write_pixbuf_file(&self, pixbuf: &Pixbuf) -> Result<PathBuf, Error> {
+        let serial = self.icon_serial.get();
+        self.icon_serial.set(serial.wrapping_add(1));
+        let name = format!("systray-{}-{}.png", process::id(), serial);
+        let path = std::env::temp_dir().join(name);
+        match pixbuf.savev(&path, "png", &[]) {
+            Ok(()) => Ok(path),
+            Err(e) => Err(glib_error("gdk_pixbuf_savev", e)),
+        }
+    }
+
+    pub fn set_icon_from_rgba(&self, rgba: &[u8], width: u32, height: u32) -> Result<(), Error> {
+        let path = self.write_icon_file(rgba, width, height)?;
+        self.set_icon_from_file(&path.to_string_lossy());
+        if let Some(old) = self.icon_tmp.replace(Some(path)) {
+            fs::remove_file(old).ok();
+        }
+        Ok(())
+    }
+
+    pub fn set_icon_animation(
+        &self,
+        frames: &[IconBuffer],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        let mut paths = Vec::with_capacity(frames.len());
+        for frame in frames {
+            match self.write_icon_file(&frame.rgba, frame.width, frame.height) {
+                Ok(p) => paths.push(p),
+                Err(e) => {
+                    for p in paths {
+                        fs::remove_file(p).ok();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.stop_icon_animation();
+        self.set_icon_from_file(&paths[0].to_string_lossy());
+        let files: Vec<String> = paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        let mut current = 0;
+        let interval = interval.as_millis().max(1) as u32;
+        let id = self.id;
+        let source = glib::timeout_add_local(interval, move || {
+            current = (current + 1) % files.len();
+            if let Some(stash) = get_stash(id) {
+                let file = &files[current];
+                crate::catch_panic(Some(&stash.event_tx), || stash.set_icon_from_file(file));
+            }
+            glib::Continue(true)
+        });
+        *self.animation.borrow_mut() = Some((source, paths));
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) {
+        if let Some((source, paths)) = self.animation.borrow_mut().take() {
+            glib::source_remove(source);
+            // Keep the file of the icon that is showing now, it gets cleaned
+            // up with the next icon instead.
+            let showing = self.icon_file.borrow().clone();
+            for p in paths {
+                if p.to_string_lossy() == showing {
+                    if let Some(old) = self.icon_tmp.replace(Some(p)) {
+                        fs::remove_file(old).ok();
+                    }
+                } else {
+                    fs::remove_file(p).ok();
+                }
+            }
+        }
+    }
+
+    pub fn register_hotkey(&self, id: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let mut hotkeys = self.hotkeys.borrow_mut();
+        if let Some(h) = hotkeys.as_mut() {
+            return h.grab(id, shortcut);
+        }
+        let mut h = X11Hotkeys::new(self.id)?;
+        let result = h.grab(id, shortcut);
+        *hotkeys = Some(h);
+        result
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        match self.hotkeys.borrow_mut().as_mut() {
+            Some(h) => h.ungrab(id),
+            None => Err(Error::OsError(format!("No hotkey {}", id))),
+        }
+    }
+
+    fn dispatch_hotkeys(&self) {
+        let pressed = match self.hotkeys.borrow().as_ref() {
+            Some(h) => h.pressed(),
+            None => return,
+        };
+        for id in pressed {
+            self.event_tx
+                .send(SystrayEvent::HotkeyPressed(HotkeyId(id)))
+                .ok();
+        }
+    }
+
+    pub fn show_notification(
+        &self,
+        title: &str,
+        body: &str,
+        icon: Option<&IconBuffer>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let icon_file = match icon {
+            Some(i) => {
+                let path = self.write_icon_file(&i.rgba, i.width, i.height)?;
+                let file = path.to_string_lossy().into_owned();
+                self.notification_icons.borrow_mut().push(path);
+                file
+            }
+            None => String::new(),
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        unsafe { send_notification(title, body, &icon_file, timeout) }
+    }
+
+    // Hides the indicator and cleans up the icon files we wrote.
+    pub fn shutdown(&self) {
+        self.stop_icon_animation();
+        self.tray.hide();
+        if let Some(old) = self.icon_tmp.borrow_mut().take() {
+            fs::remove_file(old).ok();
+        }
+        for path in self.notification_icons.borrow_mut().drain(..) {
+            fs::remove_file(path).ok();
+        }
+        // Closing the connection releases its grabs.
+        self.hotkeys.borrow_mut().take();
+        if let Some(settings) = gtk::Settings::get_default() {
+            for handler in self.theme_handlers.borrow_mut().drain(..) {
+                settings.disconnect(handler);
+            }
+        }
+    }
+
+    pub fn set_title(&self, title: &str) -> Result<(), Error> {
+        self.tray.set_label(title)
+    }
+
+    pub fn set_icon_accessibility_description(&self, desc: &str) {
+        *self.icon_desc.borrow_mut() = desc.to_owned();
+        let file = self.icon_file.borrow();
+        if !file.is_empty() {
+            if let Err(e) = self.tray.set_icon_full(&file, desc) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    // A new dialog every time, destroyed once it's closed.
+    pub fn show_about(&self, about: &AboutMetadata) {
+        let dialog = gtk::AboutDialog::new();
+        dialog.set_program_name(&about.name);
+        dialog.set_version(about.version.as_deref());
+        let authors: Vec<&str> = about.authors.iter().map(|a| a.as_str()).collect();
+        dialog.set_authors(&authors);
+        dialog.set_license(about.license.as_deref());
+        dialog.set_wrap_license(true);
+        dialog.set_website(about.website.as_deref());
+        if let Some(icon) = about.icon.as_ref() {
+            dialog.set_logo(Some(&pixbuf_from_rgba(&icon.rgba, icon.width, icon.height)));
+        }
+        dialog.connect_response(|dialog, _| dialog.destroy());
+        dialog.present();
+    }
+
+    pub fn set_visible(&self, visible: bool) {
+        self.tray.set_visible(visible);
+    }
+
+    pub fn set_menu_direction(&self, direction: LayoutDirection) {
+        let direction = match direction {
+            LayoutDirection::LeftToRight => TextDirection::Ltr,
+            LayoutDirection::RightToLeft => TextDirection::Rtl,
+        };
+        self.menu_direction.set(direction);
+        set_direction(self.menu.upcast_ref(), direction);
+        self.tray.menu_changed();
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<(), Error> {
+        if !self.tray.reports_clicks() {
+            return Err(Error::NotSupported {
+                feature: "menu on left click with AppIndicator",
+            });
+        }
+        self.menu_on_left_click.set(enabled);
+        Ok(())
     }
 }
 
+// Pixels, width and height.
+type IconFrame = (Vec<u8>, u32, u32);
+
+// An icon update the gtk thread hasn't got to yet. It takes the frame out when
+// it does.
+struct PendingIcon {
+    // QUEUED_COMMANDS once it was queued.
+    queued: usize,
+    frame: Arc<Mutex<Option<IconFrame>>>,
+}
+
 pub struct Window {
-    gtk_loop: Option<thread::JoinHandle<()>>,
+    id: u32,
+    pending_icon: RefCell<Option<PendingIcon>>,
+    // Calls of this icon that the gtk main loop hasn't picked up yet.
+    pending_commands: Arc<AtomicUsize>,
 }
 
 impl Window {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<Window, Error> {
+    pub fn new(
+        event_tx: EventSender,
+        _: ApplicationMode,
+        backend: Backend,
+        visible: bool,
+    ) -> Result<Window, Error> {
+        start_gtk_thread()?;
+        let id = NEXT_WINDOW_ID.fetch_add(1, Ordering::SeqCst);
         let (tx, rx) = channel();
-        let gtk_loop = thread::spawn(move || {
-            GTK_STASH.with(|stash| match GtkSystrayApp::new(event_tx) {
-                Ok(data) => {
-                    (*stash.borrow_mut()) = Some(data);
-                    tx.send(Ok(()));
-                }
-                Err(e) => {
-                    tx.send(Err(e));
-                    return;
-                }
+        glib::idle_add(move || {
+            let app = crate::catch_panic(None, || {
+                GtkSystrayApp::new(id, event_tx.clone(), backend, visible)
             });
-            gtk::main();
+            let result = match app {
+                Some(Ok(app)) => {
+                    GTK_STASH.with(|stash| stash.borrow_mut().insert(id, Rc::new(app)));
+                    Ok(())
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(Error::OsError("Panicked setting up the tray icon".to_owned())),
+            };
+            tx.send(result).ok();
+            glib::Continue(false)
         });
-        match rx.recv().unwrap() {
-            Ok(()) => Ok(Window {
-                gtk_loop: Some(gtk_loop),
+        match rx.recv() {
+            Ok(Ok(())) => Ok(Window {
+                id,
+                pending_icon: RefCell::new(None),
+                pending_commands: Arc::new(AtomicUsize::new(0)),
             }),
-            Err(e) => Err(e),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::Shutdown),
         }
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
+    // run_on_gtk_thread for this icon, counted until the gtk thread runs it.
+    fn run_on_gtk_thread<F>(&self, f: F)
+    where
+        F: Fn(&GtkSystrayApp) + Send + 'static,
+    {
+        let pending = self.pending_commands.clone();
+        pending.fetch_add(1, Ordering::SeqCst);
+        run_on_gtk_thread(self.id, move |stash: &GtkSystrayApp| {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            f(stash);
+        });
+    }
+
+    // Takes the icon away and drops what the gtk thread kept for it, which
+    // ends its event channel. The thread itself stays for the other icons.
+    fn remove(&self) -> Receiver<()> {
+        let (tx, rx) = channel();
+        let id = self.id;
+        glib::idle_add(move || {
+            if let Some(app) = GTK_STASH.with(|stash| stash.borrow_mut().remove(&id)) {
+                crate::catch_panic(Some(&app.event_tx), || app.shutdown());
+            }
+            tx.send(()).ok();
+            glib::Continue(false)
+        });
+        rx
+    }
+
+    pub(crate) fn recv_event(&self, rx: &Receiver<Message>) -> Option<Message> {
+        rx.recv().ok()
+    }
+
+    pub(crate) fn try_recv_event(
+        &self,
+        rx: &Receiver<Message>,
+    ) -> Result<Message, TryRecvError> {
+        rx.try_recv()
+    }
+
+    // recv_event waits on the channel itself, so it already wakes up.
+    pub fn wake() {}
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
         let n = item_name.to_owned().clone();
-        run_on_gtk_thread(move |stash: &GtkSystrayApp| {
-            stash.add_menu_entry(item_idx, &n);
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.add_menu_entry(item_idx, parent, &n);
+        });
+        Ok(())
+    }
+
+    pub fn add_menu_checkable_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        checked: bool,
+    ) -> Result<(), Error> {
+        let n = item_name.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.add_menu_checkable_entry(item_idx, parent, &n, checked);
+        });
+        Ok(())
+    }
+
+    pub fn add_menu_radio_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        group: u32,
+        checked: bool,
+    ) -> Result<(), Error> {
+        let n = item_name.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.add_menu_radio_entry(item_idx, parent, &n, group, checked);
+        });
+        Ok(())
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
+        let n = item_name.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.add_submenu(item_idx, parent, &n);
+        });
+        Ok(())
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_menu_item_checked(item_idx, checked) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.remove_menu_item(item_idx) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_menu_item_label(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
+        let n = item_name.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_menu_item_label(item_idx, &n) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_menu_item_enabled(item_idx, enabled) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_menu_item_tooltip(&self, item_idx: u32, tooltip: &str) -> Result<(), Error> {
+        let tooltip = tooltip.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_menu_item_tooltip(item_idx, &tooltip) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_menu_item_icon(&self, item_idx: u32, icon: &IconBuffer) -> Result<(), Error> {
+        let i = icon.clone();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_menu_item_icon(item_idx, &i) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_menu_item_shortcut(&self, item_idx: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let s = *shortcut;
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_menu_item_shortcut(item_idx, &s) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.add_menu_separator(item_idx, parent);
+        });
+        Ok(())
+    }
+
+    pub fn add_menu_header(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        title: &str,
+    ) -> Result<(), Error> {
+        let title = title.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.add_menu_header(item_idx, parent, &title);
         });
         Ok(())
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) -> Result<(), Error> {
-        run_on_gtk_thread(move |stash: &GtkSystrayApp| {
-            stash.add_menu_separator(item_idx);
+    pub(crate) fn apply_menu_ops(&self, ops: Vec<MenuOp>) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            for op in ops.iter() {
+                match op {
+                    MenuOp::Entry(idx, parent, name) => stash.add_menu_entry(*idx, *parent, name),
+                    MenuOp::CheckableEntry(idx, parent, name, checked) => {
+                        stash.add_menu_checkable_entry(*idx, *parent, name, *checked)
+                    }
+                    MenuOp::Separator(idx, parent) => stash.add_menu_separator(*idx, *parent),
+                    MenuOp::Header(idx, parent, title) => {
+                        stash.add_menu_header(*idx, *parent, title)
+                    }
+                    MenuOp::Submenu(idx, parent, name) => stash.add_submenu(*idx, *parent, name),
+                    MenuOp::SetEnabled(idx, enabled) => {
+                        if let Err(e) = stash.set_menu_item_enabled(*idx, *enabled) {
+                            log::warn!("{}", e);
+                        }
+                    }
+                    MenuOp::Clear => stash.clear_menu(),
+                }
+            }
         });
         Ok(())
     }
 
     pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error> {
         let n = file.to_owned().clone();
-        run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if n.to_ascii_lowercase().ends_with(".ico") {
+                match stash.set_icon_from_ico_file(&n) {
+                    Ok(()) => return,
+                    Err(e) => log::warn!("{}", e),
+                }
+            }
             stash.set_icon_from_file(&n);
         });
         Ok(())
     }
 
-    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
-        panic!("Not implemented on this platform!");
+    // Updates that come faster than the gtk thread applies them, for a meter
+    // redrawn every second say, replace the frame of the one still waiting,
+    // in the same buffer. Only while nothing else was queued after it, so
+    // everything still happens in order.
+    pub fn set_icon_from_rgba(&self, rgba: &[u8], width: u32, height: u32) -> Result<(), Error> {
+        if let Some(pending) = self.pending_icon.borrow().as_ref() {
+            if pending.queued == QUEUED_COMMANDS.load(Ordering::SeqCst) {
+                let mut frame = pending.frame.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some((data, w, h)) = frame.as_mut() {
+                    data.clear();
+                    data.extend_from_slice(rgba);
+                    *w = width;
+                    *h = height;
+                    return Ok(());
+                }
+            }
+        }
+        let frame = Arc::new(Mutex::new(Some((rgba.to_vec(), width, height))));
+        let queued_frame = frame.clone();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            let frame = queued_frame.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some((data, width, height)) = frame {
+                if let Err(e) = stash.set_icon_from_rgba(&data, width, height) {
+                    log::warn!("{}", e);
+                }
+            }
+        });
+        self.pending_icon.replace(Some(PendingIcon {
+            queued: QUEUED_COMMANDS.load(Ordering::SeqCst),
+            frame,
+        }));
+        Ok(())
+    }
+
+    pub fn set_icon_animation(
+        &self,
+        frames: Vec<IconBuffer>,
+        interval: Duration,
+    ) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_icon_animation(&frames, interval) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.stop_icon_animation();
+        });
+        Ok(())
+    }
+
+    pub fn show_notification(
+        &self,
+        title: &str,
+        body: &str,
+        icon: Option<&IconBuffer>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let t = title.to_owned();
+        let b = body.to_owned();
+        let i = icon.cloned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.show_notification(&t, &b, i.as_ref(), timeout) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    // The tray scales icons itself.
+    pub fn icon_size(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    pub fn set_icon_accessibility_description(&self, desc: &str) -> Result<(), Error> {
+        let n = desc.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.set_icon_accessibility_description(&n);
+        });
+        Ok(())
+    }
+
+    pub fn set_icon_from_resource(&self, _: &IconResource) -> Result<(), Error> {
+        Err(Error::NotSupported {
+            feature: "icons from resources",
+        })
     }
 
     pub fn shutdown(&self) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.shutdown();
+        });
         Ok(())
     }
 
-    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
-        panic!("Not implemented on this platform!");
+    pub fn pending_command_count(&self) -> usize {
+        self.pending_commands.load(Ordering::SeqCst)
     }
 
-    pub fn quit(&self) {
-        glib::idle_add(|| {
-            gtk::main_quit();
-            glib::Continue(false)
+    pub fn set_tooltip(&self, _: &str) -> Result<(), Error> {
+        Err(Error::NotSupported {
+            feature: "tooltips",
+        })
+    }
+
+    // Only shown when the icon goes through AppIndicator, by hosts that
+    // support its labels, like GNOME Shell with the AppIndicator extension and
+    // Unity.
+    pub fn set_title(&self, title: &str) -> Result<(), Error> {
+        let t = title.to_owned();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.set_title(&t) {
+                log::debug!("Not setting the title: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    // Waits for the gtk thread, to be able to tell whether the grab worked.
+    pub fn register_hotkey(&self, id: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let (tx, rx) = channel();
+        let s = *shortcut;
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            tx.send(stash.register_hotkey(id, &s)).ok();
+        });
+        match rx.recv() {
+            Ok(r) => r,
+            Err(_) => Err(Error::Shutdown),
+        }
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            if let Err(e) = stash.unregister_hotkey(id) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.set_visible(visible);
+        });
+        Ok(())
+    }
+
+    pub fn show_about(&self, about: AboutMetadata) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.show_about(&about);
+        });
+        Ok(())
+    }
+
+    // Talks to the window manager directly, the gtk thread isn't needed.
+    pub fn activate_host_app(&self) -> Result<(), Error> {
+        activate_x11_window()
+    }
+
+    pub fn set_menu_direction(&self, direction: LayoutDirection) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.set_menu_direction(direction);
+        });
+        Ok(())
+    }
+
+    // Only gtk's own menu can be opened from here, and with no click to place
+    // it at, it opens at the pointer. Hosts show the dbusmenu one themselves.
+    pub fn show_menu(&self) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            stash.menu.popup_easy(0, gtk::get_current_event_time());
+        });
+        Ok(())
+    }
+
+    // gtk only takes a point through the deprecated position function, which
+    // needs X11 to place the menu.
+    pub fn show_menu_at(&self, x: i32, y: i32) -> Result<(), Error> {
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| unsafe {
+            let point = Box::into_raw(Box::new((x, y)));
+            gtk_sys::gtk_menu_popup_for_device(
+                stash.menu.to_glib_none().0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                Some(position_menu),
+                point as glib_sys::gpointer,
+                Some(free_menu_position),
+                0,
+                gtk::get_current_event_time(),
+            );
+        });
+        Ok(())
+    }
+
+    // The menu follows the gtk theme, or the host's look over dbusmenu.
+    pub fn set_menu_style(&self, _: Option<MenuStyle>) -> Result<(), Error> {
+        Err(Error::NotSupported {
+            feature: "menu styles",
+        })
+    }
+
+    // Waits for the gtk thread, which knows whether the icon reports clicks.
+    pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<(), Error> {
+        let (tx, rx) = channel();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            tx.send(stash.set_menu_on_left_click(enabled)).ok();
+        });
+        match rx.recv() {
+            Ok(r) => r,
+            Err(_) => Err(Error::Shutdown),
+        }
+    }
+
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        let (tx, rx) = channel();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            tx.send(stash.tray.capabilities()).ok();
+        });
+        match rx.recv() {
+            Ok(c) => Ok(c),
+            Err(_) => Err(Error::Shutdown),
+        }
+    }
+
+    pub fn native_handle(&self) -> Result<NativeHandle, Error> {
+        let (tx, rx) = channel();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            tx.send(stash.tray.native_handle()).ok();
+        });
+        match rx.recv() {
+            Ok(h) => Ok(h),
+            Err(_) => Err(Error::Shutdown),
+        }
+    }
+
+    pub fn icon_screen_rect(&self) -> Result<Rect, Error> {
+        let (tx, rx) = channel();
+        self.run_on_gtk_thread(move |stash: &GtkSystrayApp| {
+            tx.send(stash.tray.screen_rect()).ok();
         });
+        match rx.recv() {
+            Ok(r) => r,
+            Err(_) => Err(Error::Shutdown),
+        }
+    }
+
+    // The gtk thread stays, gtk can't be started again in this process. The
+    // icon is gone by the time this returns.
+    pub fn quit(&self) {
+        self.remove().recv().ok();
+    }
+}
+
+impl Drop for Window {
+    // Waits for the icon to be gone, so it doesn't outlive the Application.
+    fn drop(&mut self) {
+        self.remove().recv().ok();
     }
 }
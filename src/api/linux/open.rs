@@ -0,0 +1,85 @@
+// GIO picks the handler the desktop has set as default, the same one xdg-open
+// would, without us having to start (and reap) a process for it.
+use super::{glib_error, sni::session_bus, to_cstring};
+use crate::Error;
+use gio_sys;
+use glib::{glib_sys, gobject_sys, translate::from_glib_full};
+use std::{os::raw::c_char, path::Path, ptr};
+
+fn launch_default(uri: *const c_char) -> Result<(), Error> {
+    unsafe {
+        let mut error = ptr::null_mut();
+        if gio_sys::g_app_info_launch_default_for_uri(uri, ptr::null_mut(), &mut error)
+            == glib_sys::GFALSE
+        {
+            return Err(glib_error("g_app_info_launch_default_for_uri", from_glib_full(error)));
+        }
+        Ok(())
+    }
+}
+
+fn file_uri(path: &Path) -> Result<*mut c_char, Error> {
+    let path = to_cstring(&path.to_string_lossy())?;
+    unsafe {
+        let mut error = ptr::null_mut();
+        let uri = glib_sys::g_filename_to_uri(path.as_ptr(), ptr::null(), &mut error);
+        if uri.is_null() {
+            return Err(glib_error("g_filename_to_uri", from_glib_full(error)));
+        }
+        Ok(uri)
+    }
+}
+
+pub fn open_url(url: &str) -> Result<(), Error> {
+    let url = to_cstring(url)?;
+    launch_default(url.as_ptr())
+}
+
+// Nautilus, Dolphin, Nemo and most others implement FileManager1, which opens
+// the folder with the file selected. Without it the folder is opened instead.
+pub fn reveal(path: &Path) -> Result<(), Error> {
+    let uri = file_uri(path)?;
+    let shown = unsafe { show_items(uri) };
+    unsafe { glib_sys::g_free(uri as *mut _) };
+    match shown {
+        Ok(()) => return Ok(()),
+        Err(e) => log::info!("{}, opening the folder instead", e),
+    }
+    let folder = match path.parent() {
+        Some(parent) if !path.is_dir() => parent,
+        _ => path,
+    };
+    let uri = file_uri(folder)?;
+    let result = launch_default(uri);
+    unsafe { glib_sys::g_free(uri as *mut _) };
+    result
+}
+
+unsafe fn show_items(uri: *const c_char) -> Result<(), Error> {
+    let bus = session_bus()?;
+    let uris = [uri, ptr::null()];
+    let mut error = ptr::null_mut();
+    let reply = gio_sys::g_dbus_connection_call_sync(
+        bus,
+        "org.freedesktop.FileManager1\0".as_ptr() as *const c_char,
+        "/org/freedesktop/FileManager1\0".as_ptr() as *const c_char,
+        "org.freedesktop.FileManager1\0".as_ptr() as *const c_char,
+        "ShowItems\0".as_ptr() as *const c_char,
+        glib_sys::g_variant_new(
+            "(^ass)\0".as_ptr() as *const c_char,
+            uris.as_ptr(),
+            "\0".as_ptr() as *const c_char,
+        ),
+        ptr::null(),
+        gio_sys::G_DBUS_CALL_FLAGS_NONE,
+        -1,
+        ptr::null_mut(),
+        &mut error,
+    );
+    gobject_sys::g_object_unref(bus as *mut gobject_sys::GObject);
+    if reply.is_null() {
+        return Err(glib_error("ShowItems", from_glib_full(error)));
+    }
+    glib_sys::g_variant_unref(reply);
+    Ok(())
+}
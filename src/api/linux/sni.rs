@@ -0,0 +1,597 @@
+// StatusNotifierItem spoken over D-Bus directly, for when the panel has a
+// watcher but no AppIndicator library is installed. The menu is exported
+// through dbusmenu for the host to show; hosts that ask for it with
+// ContextMenu anyway, and left clicks that open it, get the gtk menu.
+use super::dbusmenu::{DbusMenu, MENU_PATH};
+use super::{glib_error, report_host, to_cstring, WHEEL_NOTCH};
+use crate::{Error, EventSender, MouseButton, ScrollAxis, SystrayEvent};
+use gdk_pixbuf::Pixbuf;
+use gio_sys;
+use glib::{glib_sys, gobject_sys, translate::from_glib_full};
+use gtk::{self, prelude::GtkMenuExtManual};
+use std::{
+    cell::{Cell, RefCell},
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_uint},
+    process, ptr,
+    rc::Rc,
+    time::Instant,
+};
+
+const WATCHER: &str = "org.kde.StatusNotifierWatcher\0";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher\0";
+pub const ITEM_PATH: &str = "/StatusNotifierItem\0";
+const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem\0";
+
+const INTROSPECTION: &str = "<node>
+  <interface name='org.kde.StatusNotifierItem'>
+    <property name='Category' type='s' access='read'/>
+    <property name='Id' type='s' access='read'/>
+    <property name='Title' type='s' access='read'/>
+    <property name='Status' type='s' access='read'/>
+    <property name='IconName' type='s' access='read'/>
+    <property name='IconPixmap' type='a(iiay)' access='read'/>
+    <property name='ItemIsMenu' type='b' access='read'/>
+    <property name='Menu' type='o' access='read'/>
+    <method name='Activate'>
+      <arg name='x' type='i' direction='in'/>
+      <arg name='y' type='i' direction='in'/>
+    </method>
+    <method name='SecondaryActivate'>
+      <arg name='x' type='i' direction='in'/>
+      <arg name='y' type='i' direction='in'/>
+    </method>
+    <method name='ContextMenu'>
+      <arg name='x' type='i' direction='in'/>
+      <arg name='y' type='i' direction='in'/>
+    </method>
+    <method name='Scroll'>
+      <arg name='delta' type='i' direction='in'/>
+      <arg name='orientation' type='s' direction='in'/>
+    </method>
+    <signal name='NewTitle'/>
+    <signal name='NewIcon'/>
+    <signal name='NewStatus'>
+      <arg name='status' type='s'/>
+    </signal>
+  </interface>
+</node>\0";
+
+pub(super) fn session_bus() -> Result<*mut gio_sys::GDBusConnection, Error> {
+    unsafe {
+        let mut error = ptr::null_mut();
+        let bus = gio_sys::g_bus_get_sync(gio_sys::G_BUS_TYPE_SESSION, ptr::null_mut(), &mut error);
+        if bus.is_null() {
+            return Err(glib_error("g_bus_get_sync", from_glib_full(error)));
+        }
+        Ok(bus)
+    }
+}
+
+fn private_session_bus() -> Result<*mut gio_sys::GDBusConnection, Error> {
+    unsafe {
+        let mut error = ptr::null_mut();
+        let address = gio_sys::g_dbus_address_get_for_bus_sync(
+            gio_sys::G_BUS_TYPE_SESSION,
+            ptr::null_mut(),
+            &mut error,
+        );
+        if address.is_null() {
+            let error = from_glib_full(error);
+            return Err(glib_error("g_dbus_address_get_for_bus_sync", error));
+        }
+        let bus = gio_sys::g_dbus_connection_new_for_address_sync(
+            address,
+            gio_sys::G_DBUS_CONNECTION_FLAGS_AUTHENTICATION_CLIENT
+                | gio_sys::G_DBUS_CONNECTION_FLAGS_MESSAGE_BUS_CONNECTION,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut error,
+        );
+        glib_sys::g_free(address as glib_sys::gpointer);
+        if bus.is_null() {
+            let error = from_glib_full(error);
+            return Err(glib_error("g_dbus_connection_new_for_address_sync", error));
+        }
+        Ok(bus)
+    }
+}
+
+// Whether something on the session bus shows StatusNotifierItems. Both
+// AppIndicator and our own item need one.
+pub fn host_available() -> bool {
+    let bus = match session_bus() {
+        Ok(b) => b,
+        Err(e) => {
+            log::info!("{}", e);
+            return false;
+        }
+    };
+    unsafe {
+        let reply = gio_sys::g_dbus_connection_call_sync(
+            bus,
+            "org.freedesktop.DBus\0".as_ptr() as *const c_char,
+            "/org/freedesktop/DBus\0".as_ptr() as *const c_char,
+            "org.freedesktop.DBus\0".as_ptr() as *const c_char,
+            "NameHasOwner\0".as_ptr() as *const c_char,
+            glib_sys::g_variant_new("(s)\0".as_ptr() as *const c_char, WATCHER.as_ptr()),
+            "(b)\0".as_ptr() as *const glib_sys::GVariantType,
+            gio_sys::G_DBUS_CALL_FLAGS_NONE,
+            -1,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        );
+        gobject_sys::g_object_unref(bus as *mut gobject_sys::GObject);
+        if reply.is_null() {
+            return false;
+        }
+        let mut owned = glib_sys::GFALSE;
+        glib_sys::g_variant_get(reply, "(b)\0".as_ptr() as *const c_char, &mut owned);
+        glib_sys::g_variant_unref(reply);
+        owned != glib_sys::GFALSE
+    }
+}
+
+// Hosts want the pixels as ARGB in network byte order.
+fn pixmap_from_file(file: &str) -> Result<(i32, i32, Vec<u8>), Error> {
+    let pixbuf = match Pixbuf::new_from_file(file) {
+        Ok(p) => p,
+        Err(e) => return Err(glib_error("gdk_pixbuf_new_from_file", e)),
+    };
+    let (width, height) = (pixbuf.get_width(), pixbuf.get_height());
+    let channels = pixbuf.get_n_channels() as usize;
+    let stride = pixbuf.get_rowstride() as usize;
+    let pixels = unsafe { pixbuf.get_pixels() };
+    let mut argb = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let p = &pixels[y * stride + x * channels..];
+            let alpha = if channels == 4 { p[3] } else { 255 };
+            argb.extend_from_slice(&[alpha, p[0], p[1], p[2]]);
+        }
+    }
+    Ok((width, height, argb))
+}
+
+// Everything the D-Bus callbacks need, shared with them through user_data.
+struct State {
+    bus: *mut gio_sys::GDBusConnection,
+    bus_name: CString,
+    id: CString,
+    title: RefCell<CString>,
+    active: Cell<bool>,
+    // Either an icon theme name or the pixels of an icon file.
+    icon_name: RefCell<CString>,
+    icon_pixmap: RefCell<Option<(i32, i32, Vec<u8>)>>,
+    name_acquired: Cell<bool>,
+    watcher_present: Cell<bool>,
+    // Whether a watcher was ever seen, for report_host.
+    watcher_seen: Cell<bool>,
+    menu: gtk::Menu,
+    menu_on_left_click: Rc<Cell<bool>>,
+    event_tx: EventSender,
+}
+
+impl State {
+    // Called whenever either side shows up, since the watcher forgets about
+    // us when the panel restarts.
+    unsafe fn register(&self) {
+        if !self.name_acquired.get() || !self.watcher_present.get() {
+            return;
+        }
+        gio_sys::g_dbus_connection_call(
+            self.bus,
+            WATCHER.as_ptr() as *const c_char,
+            WATCHER_PATH.as_ptr() as *const c_char,
+            WATCHER.as_ptr() as *const c_char,
+            "RegisterStatusNotifierItem\0".as_ptr() as *const c_char,
+            glib_sys::g_variant_new("(s)\0".as_ptr() as *const c_char, self.bus_name.as_ptr()),
+            ptr::null(),
+            gio_sys::G_DBUS_CALL_FLAGS_NONE,
+            -1,
+            ptr::null_mut(),
+            None,
+            ptr::null_mut(),
+        );
+    }
+
+    unsafe fn emit(&self, signal: &str, params: *mut glib_sys::GVariant) {
+        gio_sys::g_dbus_connection_emit_signal(
+            self.bus,
+            ptr::null(),
+            ITEM_PATH.as_ptr() as *const c_char,
+            ITEM_INTERFACE.as_ptr() as *const c_char,
+            signal.as_ptr() as *const c_char,
+            params,
+            ptr::null_mut(),
+        );
+    }
+
+    fn status(&self) -> &'static str {
+        if self.active.get() {
+            "Active\0"
+        } else {
+            "Passive\0"
+        }
+    }
+
+    unsafe fn icon_pixmap(&self) -> *mut glib_sys::GVariant {
+        let builder =
+            glib_sys::g_variant_builder_new("a(iiay)\0".as_ptr() as *const glib_sys::GVariantType);
+        if let Some((width, height, argb)) = self.icon_pixmap.borrow().as_ref() {
+            let bytes = glib_sys::g_variant_new_fixed_array(
+                "y\0".as_ptr() as *const glib_sys::GVariantType,
+                argb.as_ptr() as glib_sys::gconstpointer,
+                argb.len(),
+                1,
+            );
+            let pixmap = glib_sys::g_variant_new(
+                "(ii@ay)\0".as_ptr() as *const c_char,
+                *width as c_int,
+                *height as c_int,
+                bytes,
+            );
+            glib_sys::g_variant_builder_add_value(builder, pixmap);
+        }
+        let value = glib_sys::g_variant_builder_end(builder);
+        glib_sys::g_variant_builder_unref(builder);
+        value
+    }
+
+    unsafe fn property(&self, name: &str) -> *mut glib_sys::GVariant {
+        let string = |s: *const c_char| glib_sys::g_variant_new_string(s);
+        match name {
+            "Category" => string("ApplicationStatus\0".as_ptr() as *const c_char),
+            "Id" => string(self.id.as_ptr()),
+            "Title" => string(self.title.borrow().as_ptr()),
+            "Status" => string(self.status().as_ptr() as *const c_char),
+            "IconName" => string(self.icon_name.borrow().as_ptr()),
+            "IconPixmap" => self.icon_pixmap(),
+            "ItemIsMenu" => glib_sys::g_variant_new_boolean(glib_sys::GFALSE),
+            "Menu" => glib_sys::g_variant_new_object_path(MENU_PATH.as_ptr() as *const c_char),
+            _ => ptr::null_mut(),
+        }
+    }
+
+    // Positive is up or right, as with AppIndicator.
+    fn scrolled(&self, delta: i32, horizontal: bool) {
+        let axis = if horizontal {
+            ScrollAxis::Horizontal
+        } else {
+            ScrollAxis::Vertical
+        };
+        self.event_tx
+            .send(SystrayEvent::Scroll {
+                delta: delta as f64 / WHEEL_NOTCH,
+                axis,
+            })
+            .ok();
+    }
+
+    fn clicked(&self, button: MouseButton, x: i32, y: i32) {
+        self.event_tx
+            .send(SystrayEvent::IconClicked {
+                button,
+                x,
+                y,
+                // Activate doesn't say when the click was.
+                time: Instant::now(),
+            })
+            .ok();
+        let left = button == MouseButton::Left;
+        if button == MouseButton::Right || (left && self.menu_on_left_click.get()) {
+            self.menu.popup_easy(0, gtk::get_current_event_time());
+        }
+    }
+}
+
+unsafe extern "C" fn method_call(
+    _bus: *mut gio_sys::GDBusConnection,
+    _sender: *const c_char,
+    _path: *const c_char,
+    _interface: *const c_char,
+    method: *const c_char,
+    params: *mut glib_sys::GVariant,
+    invocation: *mut gio_sys::GDBusMethodInvocation,
+    data: glib_sys::gpointer,
+) {
+    let state = &*(data as *const State);
+    let method = CStr::from_ptr(method).to_string_lossy();
+    let button = match method.as_ref() {
+        "Activate" => Some(MouseButton::Left),
+        "SecondaryActivate" => Some(MouseButton::Middle),
+        "ContextMenu" => Some(MouseButton::Right),
+        _ => None,
+    };
+    if let Some(button) = button {
+        // All come with the position of the click.
+        let (mut x, mut y): (c_int, c_int) = (0, 0);
+        glib_sys::g_variant_get(params, "(ii)\0".as_ptr() as *const c_char, &mut x, &mut y);
+        crate::catch_panic(Some(&state.event_tx), || state.clicked(button, x, y));
+    } else if method == "Scroll" {
+        let mut delta: c_int = 0;
+        let mut orientation: *const c_char = ptr::null();
+        glib_sys::g_variant_get(
+            params,
+            "(i&s)\0".as_ptr() as *const c_char,
+            &mut delta,
+            &mut orientation,
+        );
+        // Hosts differ in how they capitalize it.
+        let horizontal = CStr::from_ptr(orientation)
+            .to_string_lossy()
+            .eq_ignore_ascii_case("horizontal");
+        state.scrolled(delta, horizontal);
+    }
+    gio_sys::g_dbus_method_invocation_return_value(invocation, ptr::null_mut());
+}
+
+unsafe extern "C" fn get_property(
+    _bus: *mut gio_sys::GDBusConnection,
+    _sender: *const c_char,
+    _path: *const c_char,
+    _interface: *const c_char,
+    name: *const c_char,
+    error: *mut *mut glib_sys::GError,
+    data: glib_sys::gpointer,
+) -> *mut glib_sys::GVariant {
+    let state = &*(data as *const State);
+    let name = CStr::from_ptr(name).to_string_lossy();
+    let value = crate::catch_panic(Some(&state.event_tx), || state.property(&name));
+    match value {
+        Some(v) if !v.is_null() => v,
+        _ => {
+            glib_sys::g_set_error_literal(
+                error,
+                gio_sys::g_dbus_error_quark(),
+                gio_sys::G_DBUS_ERROR_UNKNOWN_PROPERTY,
+                "No such property\0".as_ptr() as *const c_char,
+            );
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe extern "C" fn name_acquired(
+    _bus: *mut gio_sys::GDBusConnection,
+    _name: *const c_char,
+    data: glib_sys::gpointer,
+) {
+    let state = &*(data as *const State);
+    state.name_acquired.set(true);
+    state.register();
+}
+
+unsafe extern "C" fn name_lost(
+    _bus: *mut gio_sys::GDBusConnection,
+    name: *const c_char,
+    data: glib_sys::gpointer,
+) {
+    let state = &*(data as *const State);
+    state.name_acquired.set(false);
+    log::warn!(
+        "Lost the bus name {}",
+        CStr::from_ptr(name).to_string_lossy()
+    );
+}
+
+unsafe extern "C" fn watcher_appeared(
+    _bus: *mut gio_sys::GDBusConnection,
+    _name: *const c_char,
+    _owner: *const c_char,
+    data: glib_sys::gpointer,
+) {
+    let state = &*(data as *const State);
+    state.watcher_present.set(true);
+    state.register();
+    report_host(&state.watcher_seen, true, &state.event_tx);
+}
+
+unsafe extern "C" fn watcher_vanished(
+    _bus: *mut gio_sys::GDBusConnection,
+    _name: *const c_char,
+    data: glib_sys::gpointer,
+) {
+    let state = &*(data as *const State);
+    state.watcher_present.set(false);
+    report_host(&state.watcher_seen, false, &state.event_tx);
+}
+
+unsafe extern "C" fn drop_state(data: glib_sys::gpointer) {
+    drop(Rc::from_raw(data as *const State));
+}
+
+// Hands a reference to the state to a callback, dropped again by drop_state.
+fn user_data(state: &Rc<State>) -> glib_sys::gpointer {
+    Rc::into_raw(state.clone()) as glib_sys::gpointer
+}
+
+// Registers the item with the watcher once the name is ours, in name_acquired.
+unsafe fn own_name(state: &Rc<State>) -> c_uint {
+    gio_sys::g_bus_own_name_on_connection(
+        state.bus,
+        state.bus_name.as_ptr(),
+        gio_sys::G_BUS_NAME_OWNER_FLAGS_NONE,
+        Some(name_acquired),
+        Some(name_lost),
+        user_data(state),
+        Some(drop_state),
+    )
+}
+
+pub struct StatusNotifierItem {
+    state: Rc<State>,
+    registration: c_uint,
+    menu: DbusMenu,
+    // 0 while hidden.
+    owner: Cell<c_uint>,
+    watcher: c_uint,
+}
+
+impl StatusNotifierItem {
+    pub fn new(
+        window_id: u32,
+        id: &CString,
+        menu: &gtk::Menu,
+        menu_on_left_click: Rc<Cell<bool>>,
+        event_tx: EventSender,
+    ) -> Result<StatusNotifierItem, Error> {
+        let bus_name = format!(
+            "org.kde.StatusNotifierItem-{}-{}",
+            process::id(),
+            window_id + 1
+        );
+        let bus_name = to_cstring(&bus_name)?;
+        // A connection of its own, other icons in the process export their
+        // item at the same path.
+        let bus = private_session_bus()?;
+        let state = Rc::new(State {
+            bus,
+            bus_name,
+            id: id.clone(),
+            title: RefCell::new(id.clone()),
+            active: Cell::new(true),
+            icon_name: RefCell::new(CString::default()),
+            icon_pixmap: RefCell::new(None),
+            name_acquired: Cell::new(false),
+            watcher_present: Cell::new(false),
+            watcher_seen: Cell::new(false),
+            menu: menu.clone(),
+            menu_on_left_click,
+            event_tx: event_tx.clone(),
+        });
+        let dbus_menu = match DbusMenu::new(bus, menu, event_tx) {
+            Ok(m) => m,
+            Err(e) => {
+                unsafe { gobject_sys::g_object_unref(bus as *mut gobject_sys::GObject) };
+                return Err(e);
+            }
+        };
+        unsafe {
+            let mut error = ptr::null_mut();
+            let node = gio_sys::g_dbus_node_info_new_for_xml(
+                INTROSPECTION.as_ptr() as *const c_char,
+                &mut error,
+            );
+            if node.is_null() {
+                gobject_sys::g_object_unref(bus as *mut gobject_sys::GObject);
+                let error = from_glib_full(error);
+                return Err(glib_error("g_dbus_node_info_new_for_xml", error));
+            }
+            let interface = gio_sys::g_dbus_node_info_lookup_interface(
+                node,
+                ITEM_INTERFACE.as_ptr() as *const c_char,
+            );
+            // Copied by gio.
+            let vtable = gio_sys::GDBusInterfaceVTable {
+                method_call: Some(method_call),
+                get_property: Some(get_property),
+                set_property: None,
+                padding: [ptr::null_mut(); 8],
+            };
+            let registration = gio_sys::g_dbus_connection_register_object(
+                bus,
+                ITEM_PATH.as_ptr() as *const c_char,
+                interface,
+                &vtable,
+                user_data(&state),
+                Some(drop_state),
+                &mut error,
+            );
+            gio_sys::g_dbus_node_info_unref(node);
+            if registration == 0 {
+                gobject_sys::g_object_unref(bus as *mut gobject_sys::GObject);
+                let error = from_glib_full(error);
+                return Err(glib_error("g_dbus_connection_register_object", error));
+            }
+            let owner = own_name(&state);
+            let watcher = gio_sys::g_bus_watch_name_on_connection(
+                bus,
+                WATCHER.as_ptr() as *const c_char,
+                gio_sys::G_BUS_NAME_WATCHER_FLAGS_NONE,
+                Some(watcher_appeared),
+                Some(watcher_vanished),
+                user_data(&state),
+                Some(drop_state),
+            );
+            Ok(StatusNotifierItem {
+                state,
+                registration,
+                menu: dbus_menu,
+                owner: Cell::new(owner),
+                watcher,
+            })
+        }
+    }
+
+    // Paths are sent as pixels, hosts don't all take them as icon names.
+    pub fn set_icon_full(&self, file: &str, desc: &str) -> Result<(), Error> {
+        let (name, pixmap) = if file.starts_with('/') {
+            (String::new(), Some(pixmap_from_file(file)?))
+        } else {
+            (file.to_owned(), None)
+        };
+        *self.state.icon_name.borrow_mut() = super::to_cstring(&name)?;
+        *self.state.icon_pixmap.borrow_mut() = pixmap;
+        // Title is the closest the spec has to an accessible name.
+        *self.state.title.borrow_mut() = super::to_cstring(desc)?;
+        unsafe {
+            self.state.emit("NewIcon\0", ptr::null_mut());
+            self.state.emit("NewTitle\0", ptr::null_mut());
+        }
+        Ok(())
+    }
+
+    pub fn menu_changed(&self) {
+        self.menu.changed();
+    }
+
+    pub fn bus_name(&self) -> String {
+        self.state.bus_name.to_string_lossy().into_owned()
+    }
+
+    // Passive items still show in an overflow area on some hosts, so hiding
+    // gives up the bus name instead, which makes hosts drop the item.
+    pub fn set_visible(&self, visible: bool) {
+        if visible == (self.owner.get() != 0) {
+            return;
+        }
+        unsafe {
+            if visible {
+                self.owner.set(own_name(&self.state));
+            } else {
+                gio_sys::g_bus_unown_name(self.owner.get());
+                self.owner.set(0);
+                self.state.name_acquired.set(false);
+            }
+        }
+    }
+
+    pub fn hide(&self) {
+        self.state.active.set(false);
+        unsafe {
+            let status = self.state.status().as_ptr() as *const c_char;
+            let params = glib_sys::g_variant_new("(s)\0".as_ptr() as *const c_char, status);
+            self.state.emit("NewStatus\0", params);
+        }
+    }
+}
+
+impl Drop for StatusNotifierItem {
+    fn drop(&mut self) {
+        unsafe {
+            gio_sys::g_bus_unwatch_name(self.watcher);
+            if self.owner.get() != 0 {
+                gio_sys::g_bus_unown_name(self.owner.get());
+            }
+            gio_sys::g_dbus_connection_unregister_object(self.state.bus, self.registration);
+            gio_sys::g_dbus_connection_close(
+                self.state.bus,
+                ptr::null_mut(),
+                None,
+                ptr::null_mut(),
+            );
+            gobject_sys::g_object_unref(self.state.bus as *mut gobject_sys::GObject);
+        }
+    }
+}
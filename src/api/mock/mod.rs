@@ -0,0 +1,741 @@
+// A backend that shows nothing and only records what it's asked to do, for
+// testing code that drives a tray without a desktop session. Picked with
+// Backend::Mock, or SYSTRAY_BACKEND=mock. The recording is read, and events
+// are made up, through the MockTray of the Application.
+#[cfg(target_os = "macos")]
+use crate::ActivationPolicy;
+use crate::{
+    AboutMetadata, Capabilities, Error, EventSender, HotkeyId,
+    IconBuffer, IconResource, LayoutDirection, MenuItem, MenuOp, MenuStyle, Message, NativeHandle,
+    Rect, Shortcut, SystrayEvent,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockIcon {
+    File(String),
+    Resource(IconResource),
+    Rgba {
+        rgba: Vec<u8>,
+        width: u32,
+        height: u32,
+    },
+    /// An .ico file's bytes, from set_icon_from_buffer on Windows.
+    Ico {
+        buffer: Vec<u8>,
+        width: u32,
+        height: u32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockItemKind {
+    Entry,
+    Checkable,
+    /// group is the id of the group's first item.
+    Radio { group: u32 },
+    Separator,
+    /// Made disabled, as the platforms do.
+    Header,
+    Submenu,
+}
+
+/// One item of the menu as it is now. Items come in menu order, the ones of a
+/// submenu right after it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockMenuItem {
+    pub item: MenuItem,
+    pub parent: Option<MenuItem>,
+    pub kind: MockItemKind,
+    pub label: String,
+    pub enabled: bool,
+    pub checked: bool,
+    pub has_icon: bool,
+    pub shortcut: Option<Shortcut>,
+    pub tooltip: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockNotification {
+    pub title: String,
+    pub body: String,
+    pub has_icon: bool,
+    pub timeout: Duration,
+}
+
+struct State {
+    // Taken on quit, which ends the event channel like the real backends do.
+    event_tx: Option<EventSender>,
+    icon: Option<MockIcon>,
+    // Frame count and interval of the running animation.
+    animation: Option<(usize, Duration)>,
+    accessibility_description: String,
+    tooltip: String,
+    title: String,
+    visible: bool,
+    menu_on_left_click: bool,
+    menu_direction: Option<LayoutDirection>,
+    menu_style: Option<MenuStyle>,
+    menu: Vec<MockMenuItem>,
+    notifications: Vec<MockNotification>,
+    hotkeys: Vec<(HotkeyId, Shortcut)>,
+    // How many times the host app was asked to come to the front.
+    activations: usize,
+    menus_shown: Vec<Option<(i32, i32)>>,
+    // What show_about was last called with.
+    about: Option<AboutMetadata>,
+    // How many changes of the next batch go in before it fails.
+    fail_batch_after: Option<usize>,
+    opened: Vec<String>,
+    revealed: Vec<PathBuf>,
+    autostart: bool,
+    #[cfg(target_os = "macos")]
+    template: bool,
+    #[cfg(target_os = "macos")]
+    activation_policy: Option<ActivationPolicy>,
+}
+
+impl State {
+    fn item_mut(&mut self, item_idx: u32) -> Result<&mut MockMenuItem, Error> {
+        match self.menu.iter_mut().find(|i| i.item.0 == item_idx) {
+            Some(item) => Ok(item),
+            None => Err(Error::OsError(format!("No menu item {}", item_idx))),
+        }
+    }
+
+    // After the last item already in parent, or the end of the menu for the
+    // top level.
+    fn add_item(&mut self, item_idx: u32, parent: Option<u32>, label: &str, kind: MockItemKind) {
+        let item = MockMenuItem {
+            item: MenuItem(item_idx),
+            parent: parent.map(MenuItem),
+            kind,
+            label: label.to_owned(),
+            enabled: kind != MockItemKind::Header,
+            checked: false,
+            has_icon: false,
+            shortcut: None,
+            tooltip: None,
+        };
+        let pos = match parent {
+            Some(parent) => self.end_of(parent),
+            None => self.menu.len(),
+        };
+        self.menu.insert(pos, item);
+    }
+
+    // Where the next item of submenu goes, after its last descendant.
+    fn end_of(&self, submenu: u32) -> usize {
+        let mut end = match self.menu.iter().position(|i| i.item.0 == submenu) {
+            Some(pos) => pos + 1,
+            None => return self.menu.len(),
+        };
+        while end < self.menu.len() && self.is_inside(end, submenu) {
+            end += 1;
+        }
+        end
+    }
+
+    fn is_inside(&self, pos: usize, submenu: u32) -> bool {
+        let mut parent = self.menu[pos].parent;
+        while let Some(p) = parent {
+            if p.0 == submenu {
+                return true;
+            }
+            parent = self.menu.iter().find(|i| i.item == p).and_then(|i| i.parent);
+        }
+        false
+    }
+
+    fn remove_item(&mut self, item_idx: u32) -> Result<(), Error> {
+        let pos = match self.menu.iter().position(|i| i.item.0 == item_idx) {
+            Some(pos) => pos,
+            None => return Err(Error::OsError(format!("No menu item {}", item_idx))),
+        };
+        let end = self.end_of(item_idx);
+        self.menu.drain(pos..end);
+        Ok(())
+    }
+}
+
+static TRAY_AVAILABLE: AtomicBool = AtomicBool::new(true);
+/// The recording of an Application on the mock backend. Clones share it, and
+/// can be sent to other threads.
+#[derive(Clone)]
+pub struct MockTray {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTray {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queues an event as if the platform had sent it.
+    pub fn send_event(&self, event: SystrayEvent) -> Result<(), Error> {
+        match self.lock().event_tx {
+            Some(ref tx) => tx.send(event).map_err(|_| Error::Shutdown),
+            None => Err(Error::Shutdown),
+        }
+    }
+
+    /// Clicks an item, with its tag in the event like a real click.
+    pub fn click(&self, item: MenuItem) -> Result<(), Error> {
+        match self.lock().event_tx {
+            Some(ref tx) => tx.send_click(item.0).map_err(|_| Error::Shutdown),
+            None => Err(Error::Shutdown),
+        }
+    }
+
+    /// False once the Application quit.
+    pub fn is_running(&self) -> bool {
+        self.lock().event_tx.is_some()
+    }
+
+    pub fn icon(&self) -> Option<MockIcon> {
+        self.lock().icon.clone()
+    }
+
+    pub fn animation(&self) -> Option<(usize, Duration)> {
+        self.lock().animation
+    }
+
+    pub fn accessibility_description(&self) -> String {
+        self.lock().accessibility_description.clone()
+    }
+
+    pub fn tooltip(&self) -> String {
+        self.lock().tooltip.clone()
+    }
+
+    pub fn title(&self) -> String {
+        self.lock().title.clone()
+    }
+
+    pub fn visible(&self) -> bool {
+        self.lock().visible
+    }
+
+    pub fn menu_on_left_click(&self) -> bool {
+        self.lock().menu_on_left_click
+    }
+
+    /// None until set_menu_direction is called.
+    pub fn menu_direction(&self) -> Option<LayoutDirection> {
+        self.lock().menu_direction
+    }
+
+    pub fn menu_style(&self) -> Option<MenuStyle> {
+        self.lock().menu_style.clone()
+    }
+
+    pub fn menu(&self) -> Vec<MockMenuItem> {
+        self.lock().menu.clone()
+    }
+
+    pub fn menu_item(&self, item: MenuItem) -> Option<MockMenuItem> {
+        self.lock().menu.iter().find(|i| i.item == item).cloned()
+    }
+
+    /// Every notification shown so far, oldest first.
+    pub fn notifications(&self) -> Vec<MockNotification> {
+        self.lock().notifications.clone()
+    }
+
+    pub fn hotkeys(&self) -> Vec<(HotkeyId, Shortcut)> {
+        self.lock().hotkeys.clone()
+    }
+
+    pub fn activation_count(&self) -> usize {
+        self.lock().activations
+    }
+
+    /// How many times the app opened the menu itself.
+    pub fn menu_shown_count(&self) -> usize {
+        self.lock().menus_shown.len()
+    }
+
+    /// Where the app opened the menu each time, None for show_menu.
+    pub fn menus_shown(&self) -> Vec<Option<(i32, i32)>> {
+        self.lock().menus_shown.clone()
+    }
+
+    pub fn about(&self) -> Option<AboutMetadata> {
+        self.lock().about.clone()
+    }
+
+    /// Everything passed to Application::open_url and reveal so far.
+    pub fn opened_urls(&self) -> Vec<String> {
+        self.lock().opened.clone()
+    }
+
+    pub fn revealed_paths(&self) -> Vec<PathBuf> {
+        self.lock().revealed.clone()
+    }
+
+    /// Set by Application::enable_autostart, and the Launch at Login item.
+    pub fn autostart_enabled(&self) -> bool {
+        self.lock().autostart
+    }
+
+    /// Makes the next with_menu or set_menu fail with an OsError once count of
+    /// its changes went in, like a backend running out of menu handles.
+    pub fn fail_next_menu_batch_after(&self, count: usize) {
+        self.lock().fail_batch_after = Some(count);
+    }
+
+    /// With no tray, making an Application fails with NoTrayAvailable, the
+    /// way it does in a session without one. Applies to the whole process.
+    pub fn set_tray_available(available: bool) {
+        TRAY_AVAILABLE.store(available, Ordering::SeqCst);
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn icon_is_template(&self) -> bool {
+        self.lock().template
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn activation_policy(&self) -> Option<ActivationPolicy> {
+        self.lock().activation_policy
+    }
+}
+
+pub struct Window {
+    tray: MockTray,
+}
+
+impl Window {
+    pub fn new(event_tx: EventSender, visible: bool) -> Result<Window, Error> {
+        if !TRAY_AVAILABLE.load(Ordering::SeqCst) {
+            return Err(Error::NoTrayAvailable);
+        }
+        let state = State {
+            event_tx: Some(event_tx),
+            icon: None,
+            animation: None,
+            accessibility_description: String::new(),
+            tooltip: String::new(),
+            title: String::new(),
+            visible,
+            menu_on_left_click: true,
+            menu_direction: None,
+            menu_style: None,
+            menu: Vec::new(),
+            notifications: Vec::new(),
+            hotkeys: Vec::new(),
+            activations: 0,
+            menus_shown: Vec::new(),
+            about: None,
+            fail_batch_after: None,
+            opened: Vec::new(),
+            revealed: Vec::new(),
+            autostart: false,
+            #[cfg(target_os = "macos")]
+            template: false,
+            #[cfg(target_os = "macos")]
+            activation_policy: None,
+        };
+        Ok(Window {
+            tray: MockTray {
+                state: Arc::new(Mutex::new(state)),
+            },
+        })
+    }
+
+    pub fn mock_tray(&self) -> MockTray {
+        self.tray.clone()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, State>, Error> {
+        let state = self.tray.lock();
+        match state.event_tx {
+            Some(_) => Ok(state),
+            None => Err(Error::Shutdown),
+        }
+    }
+
+    pub(crate) fn recv_event(&self, rx: &Receiver<Message>) -> Option<Message> {
+        rx.recv().ok()
+    }
+
+    pub(crate) fn try_recv_event(
+        &self,
+        rx: &Receiver<Message>,
+    ) -> Result<Message, TryRecvError> {
+        rx.try_recv()
+    }
+
+    /// recv_event waits on the channel itself, so it already wakes up.
+    pub fn wake() {}
+
+    /// These don't need a running tray, so they're recorded even after quit.
+    pub fn open_url(&self, url: &str) -> Result<(), Error> {
+        self.tray.lock().opened.push(url.to_owned());
+        Ok(())
+    }
+
+    pub fn reveal(&self, path: &Path) -> Result<(), Error> {
+        self.tray.lock().revealed.push(path.to_owned());
+        Ok(())
+    }
+
+    pub fn enable_autostart(&self, _: &str, _: &Path) -> Result<(), Error> {
+        self.tray.lock().autostart = true;
+        Ok(())
+    }
+
+    pub fn disable_autostart(&self, _: &str) -> Result<(), Error> {
+        self.tray.lock().autostart = false;
+        Ok(())
+    }
+
+    pub fn autostart_enabled(&self, _: &str) -> Result<bool, Error> {
+        Ok(self.tray.lock().autostart)
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
+        let mut state = self.lock()?;
+        state.add_item(item_idx, parent, item_name, MockItemKind::Entry);
+        Ok(())
+    }
+
+    pub fn add_menu_checkable_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        checked: bool,
+    ) -> Result<(), Error> {
+        let mut state = self.lock()?;
+        state.add_item(item_idx, parent, item_name, MockItemKind::Checkable);
+        state.item_mut(item_idx)?.checked = checked;
+        Ok(())
+    }
+
+    pub fn add_menu_radio_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        group: u32,
+        checked: bool,
+    ) -> Result<(), Error> {
+        let mut state = self.lock()?;
+        let kind = MockItemKind::Radio { group };
+        state.add_item(item_idx, parent, item_name, kind);
+        state.item_mut(item_idx)?.checked = checked;
+        Ok(())
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
+        let mut state = self.lock()?;
+        state.add_item(item_idx, parent, item_name, MockItemKind::Submenu);
+        Ok(())
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        let mut state = self.lock()?;
+        state.add_item(item_idx, parent, "", MockItemKind::Separator);
+        Ok(())
+    }
+
+    pub fn add_menu_header(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        title: &str,
+    ) -> Result<(), Error> {
+        let mut state = self.lock()?;
+        state.add_item(item_idx, parent, title, MockItemKind::Header);
+        Ok(())
+    }
+
+    pub(crate) fn apply_menu_ops(&self, ops: Vec<MenuOp>) -> Result<(), Error> {
+        let mut state = self.lock()?;
+        let fail_after = state.fail_batch_after.take();
+        for (n, op) in ops.into_iter().enumerate() {
+            if fail_after == Some(n) {
+                return Err(Error::OsError(format!("Menu change {} failed", n)));
+            }
+            match op {
+                MenuOp::Entry(idx, parent, name) => {
+                    state.add_item(idx, parent, &name, MockItemKind::Entry)
+                }
+                MenuOp::CheckableEntry(idx, parent, name, checked) => {
+                    state.add_item(idx, parent, &name, MockItemKind::Checkable);
+                    state.item_mut(idx)?.checked = checked;
+                }
+                MenuOp::Separator(idx, parent) => {
+                    state.add_item(idx, parent, "", MockItemKind::Separator)
+                }
+                MenuOp::Header(idx, parent, title) => {
+                    state.add_item(idx, parent, &title, MockItemKind::Header)
+                }
+                MenuOp::Submenu(idx, parent, name) => {
+                    state.add_item(idx, parent, &name, MockItemKind::Submenu)
+                }
+                MenuOp::SetEnabled(idx, enabled) => state.item_mut(idx)?.enabled = enabled,
+                MenuOp::Clear => state.menu.clear(),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        self.lock()?.item_mut(item_idx)?.checked = checked;
+        Ok(())
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        self.lock()?.remove_item(item_idx)
+    }
+
+    pub fn set_menu_item_label(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
+        self.lock()?.item_mut(item_idx)?.label = item_name.to_owned();
+        Ok(())
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        self.lock()?.item_mut(item_idx)?.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_menu_item_tooltip(&self, item_idx: u32, tooltip: &str) -> Result<(), Error> {
+        let tooltip = if tooltip.is_empty() { None } else { Some(tooltip.to_owned()) };
+        self.lock()?.item_mut(item_idx)?.tooltip = tooltip;
+        Ok(())
+    }
+
+    pub fn set_menu_item_icon(&self, item_idx: u32, _: &IconBuffer) -> Result<(), Error> {
+        self.lock()?.item_mut(item_idx)?.has_icon = true;
+        Ok(())
+    }
+
+    pub fn set_menu_item_shortcut(&self, item_idx: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        self.lock()?.item_mut(item_idx)?.shortcut = Some(*shortcut);
+        Ok(())
+    }
+
+    pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error> {
+        self.lock()?.icon = Some(MockIcon::File(file.to_owned()));
+        Ok(())
+    }
+
+    pub fn set_icon_from_rgba(&self, rgba: &[u8], width: u32, height: u32) -> Result<(), Error> {
+        self.lock()?.icon = Some(MockIcon::Rgba {
+            rgba: rgba.to_vec(),
+            width,
+            height,
+        });
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn set_icon_from_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        self.force_icon_from_buffer(buffer, width, height)
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn force_icon_from_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        self.lock()?.icon = Some(MockIcon::Ico {
+            buffer: buffer.to_vec(),
+            width,
+            height,
+        });
+        Ok(())
+    }
+
+    pub fn set_icon_from_resource(&self, resource: &IconResource) -> Result<(), Error> {
+        self.lock()?.icon = Some(MockIcon::Resource(resource.clone()));
+        Ok(())
+    }
+
+    pub fn set_icon_animation(
+        &self,
+        frames: Vec<IconBuffer>,
+        interval: Duration,
+    ) -> Result<(), Error> {
+        self.lock()?.animation = Some((frames.len(), interval));
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        self.lock()?.animation = None;
+        Ok(())
+    }
+
+    pub fn show_notification(
+        &self,
+        title: &str,
+        body: &str,
+        icon: Option<&IconBuffer>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        self.lock()?.notifications.push(MockNotification {
+            title: title.to_owned(),
+            body: body.to_owned(),
+            has_icon: icon.is_some(),
+            timeout,
+        });
+        Ok(())
+    }
+
+    pub fn icon_size(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    pub fn set_icon_accessibility_description(&self, desc: &str) -> Result<(), Error> {
+        self.lock()?.accessibility_description = desc.to_owned();
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_icon_as_template(&self, template: bool) -> Result<(), Error> {
+        self.lock()?.template = template;
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_activation_policy(&self, policy: ActivationPolicy) -> Result<(), Error> {
+        self.lock()?.activation_policy = Some(policy);
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn show_about_panel(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Like the real backends, only takes the icon away. quit ends the events.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        if let Ok(mut state) = self.lock() {
+            state.visible = false;
+        }
+        Ok(())
+    }
+
+    pub fn pending_command_count(&self) -> usize {
+        0
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        self.lock()?.tooltip = tooltip.to_owned();
+        Ok(())
+    }
+
+    pub fn set_title(&self, title: &str) -> Result<(), Error> {
+        self.lock()?.title = title.to_owned();
+        Ok(())
+    }
+
+    pub fn register_hotkey(&self, id: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        self.lock()?.hotkeys.push((HotkeyId(id), *shortcut));
+        Ok(())
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        self.lock()?.hotkeys.retain(|&(h, _)| h != HotkeyId(id));
+        Ok(())
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), Error> {
+        self.lock()?.visible = visible;
+        Ok(())
+    }
+
+    pub fn activate_host_app(&self) -> Result<(), Error> {
+        self.lock()?.activations += 1;
+        Ok(())
+    }
+
+    pub fn show_menu(&self) -> Result<(), Error> {
+        self.lock()?.menus_shown.push(None);
+        Ok(())
+    }
+
+    pub fn show_menu_at(&self, x: i32, y: i32) -> Result<(), Error> {
+        self.lock()?.menus_shown.push(Some((x, y)));
+        Ok(())
+    }
+
+    pub fn show_about(&self, about: AboutMetadata) -> Result<(), Error> {
+        self.lock()?.about = Some(about);
+        Ok(())
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<(), Error> {
+        self.lock()?.menu_on_left_click = enabled;
+        Ok(())
+    }
+
+    pub fn set_menu_direction(&self, direction: LayoutDirection) -> Result<(), Error> {
+        self.lock()?.menu_direction = Some(direction);
+        Ok(())
+    }
+
+    pub fn set_menu_style(&self, style: Option<MenuStyle>) -> Result<(), Error> {
+        self.lock()?.menu_style = style;
+        Ok(())
+    }
+
+    /// Everything is recorded, so everything is supported.
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        Ok(Capabilities {
+            supports_tooltip: true,
+            supports_title_text: true,
+            supports_menu_icons: true,
+            supports_notifications: true,
+            supports_icon_clicks: true,
+            supports_double_click: true,
+            supports_icon_screen_rect: false,
+            supports_icon_from_file: true,
+            supports_icon_accessibility_description: true,
+            supports_global_hotkeys: true,
+            supports_file_drops: true,
+            supports_scroll: true,
+        })
+    }
+
+    pub fn native_handle(&self) -> Result<NativeHandle, Error> {
+        Err(Error::NotSupported {
+            feature: "native handles of the mock backend",
+        })
+    }
+
+    pub fn icon_screen_rect(&self) -> Result<Rect, Error> {
+        Err(Error::NotSupported {
+            feature: "icon positions of the mock backend",
+        })
+    }
+
+    pub fn quit(&self) {
+        let mut state = self.tray.lock();
+        state.visible = false;
+        state.event_tx = None;
+    }
+}
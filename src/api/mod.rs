@@ -1,11 +1,199 @@
+// The platform's own backend, and the mock one that Backend::Mock picks
+// instead. Window hands each call to the one the tray was made with.
 #[cfg(target_os = "windows")]
 #[path = "win32/mod.rs"]
-pub mod api;
+pub mod native;
 
 #[cfg(target_os = "linux")]
 #[path = "linux/mod.rs"]
-pub mod api;
+pub mod native;
 
 #[cfg(target_os = "macos")]
 #[path = "cocoa/mod.rs"]
-pub mod api;
+pub mod native;
+
+pub mod mock;
+
+#[cfg(target_os = "macos")]
+use crate::ActivationPolicy;
+use crate::{
+    AboutMetadata, ApplicationMode, Backend, Capabilities, Error, EventSender, IconBuffer,
+    IconResource, LayoutDirection, MenuOp, MenuStyle, Message, NativeHandle, Rect, Shortcut,
+};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::Duration;
+
+pub enum Window {
+    Native(Box<native::Window>),
+    Mock(mock::Window),
+}
+
+macro_rules! forward {
+    ($($(#[$attr:meta])* $vis:vis fn $name:ident(&self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            $(#[$attr])*
+            $vis fn $name(&self $(, $arg: $ty)*) -> $ret {
+                match self {
+                    Window::Native(window) => window.$name($($arg),*),
+                    Window::Mock(window) => window.$name($($arg),*),
+                }
+            }
+        )*
+    };
+}
+
+impl Window {
+    pub fn new(
+        event_tx: EventSender,
+        mode: ApplicationMode,
+        backend: Backend,
+        visible: bool,
+    ) -> Result<Window, Error> {
+        match backend {
+            Backend::Mock => mock::Window::new(event_tx, visible).map(Window::Mock),
+            _ => native::Window::new(event_tx, mode, backend, visible)
+                .map(|window| Window::Native(Box::new(window))),
+        }
+    }
+
+    // What an ApplicationHandle calls after queueing a command. The mock's
+    // event loop is the channel itself, so it gets nothing to wake.
+    pub fn waker(&self) -> fn() {
+        match self {
+            Window::Native(_) => native::Window::wake,
+            Window::Mock(_) => mock::Window::wake,
+        }
+    }
+
+    pub fn quit(&mut self) {
+        match self {
+            Window::Native(window) => window.quit(),
+            Window::Mock(window) => window.quit(),
+        }
+    }
+
+    pub fn mock_tray(&self) -> Option<mock::MockTray> {
+        match self {
+            Window::Native(_) => None,
+            Window::Mock(window) => Some(window.mock_tray()),
+        }
+    }
+
+    // Not tied to the tray on the platforms, so only the mock has its own.
+    pub fn open_url(&self, url: &str) -> Result<(), Error> {
+        match self {
+            Window::Native(_) => native::open::open_url(url),
+            Window::Mock(window) => window.open_url(url),
+        }
+    }
+
+    pub fn reveal(&self, path: &Path) -> Result<(), Error> {
+        match self {
+            Window::Native(_) => native::open::reveal(path),
+            Window::Mock(window) => window.reveal(path),
+        }
+    }
+
+    pub fn enable_autostart(&self, name: &str, exe: &Path) -> Result<(), Error> {
+        match self {
+            Window::Native(_) => native::autostart::enable(name, exe),
+            Window::Mock(window) => window.enable_autostart(name, exe),
+        }
+    }
+
+    pub fn disable_autostart(&self, name: &str) -> Result<(), Error> {
+        match self {
+            Window::Native(_) => native::autostart::disable(name),
+            Window::Mock(window) => window.disable_autostart(name),
+        }
+    }
+
+    pub fn autostart_enabled(&self, name: &str) -> Result<bool, Error> {
+        match self {
+            Window::Native(_) => native::autostart::is_enabled(name),
+            Window::Mock(window) => window.autostart_enabled(name),
+        }
+    }
+
+    forward! {
+        pub(crate) fn recv_event(&self, rx: &Receiver<Message>) -> Option<Message>;
+        pub(crate) fn try_recv_event(&self, rx: &Receiver<Message>)
+            -> Result<Message, TryRecvError>;
+        pub fn capabilities(&self) -> Result<Capabilities, Error>;
+        pub fn native_handle(&self) -> Result<NativeHandle, Error>;
+        pub fn icon_screen_rect(&self) -> Result<Rect, Error>;
+        pub fn icon_size(&self) -> Option<(u32, u32)>;
+        pub fn pending_command_count(&self) -> usize;
+        pub fn shutdown(&self) -> Result<(), Error>;
+        pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error>;
+        pub fn set_title(&self, title: &str) -> Result<(), Error>;
+        pub fn set_visible(&self, visible: bool) -> Result<(), Error>;
+        pub fn activate_host_app(&self) -> Result<(), Error>;
+        pub fn show_about(&self, about: AboutMetadata) -> Result<(), Error>;
+        pub fn show_menu(&self) -> Result<(), Error>;
+        pub fn show_menu_at(&self, x: i32, y: i32) -> Result<(), Error>;
+        pub fn register_hotkey(&self, id: u32, shortcut: &Shortcut) -> Result<(), Error>;
+        pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error>;
+        pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<(), Error>;
+        pub fn set_menu_direction(&self, direction: LayoutDirection) -> Result<(), Error>;
+        pub fn set_menu_style(&self, style: Option<MenuStyle>) -> Result<(), Error>;
+        pub fn add_menu_entry(&self, item_idx: u32, parent: Option<u32>, name: &str)
+            -> Result<(), Error>;
+        pub fn add_menu_checkable_entry(
+            &self,
+            item_idx: u32,
+            parent: Option<u32>,
+            name: &str,
+            checked: bool
+        ) -> Result<(), Error>;
+        pub fn add_menu_radio_entry(
+            &self,
+            item_idx: u32,
+            parent: Option<u32>,
+            name: &str,
+            group: u32,
+            checked: bool
+        ) -> Result<(), Error>;
+        pub fn add_submenu(&self, item_idx: u32, parent: Option<u32>, name: &str)
+            -> Result<(), Error>;
+        pub fn add_menu_header(&self, item_idx: u32, parent: Option<u32>, title: &str)
+            -> Result<(), Error>;
+        pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error>;
+        pub(crate) fn apply_menu_ops(&self, ops: Vec<MenuOp>) -> Result<(), Error>;
+        pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error>;
+        pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error>;
+        pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error>;
+        pub fn set_menu_item_label(&self, item_idx: u32, name: &str) -> Result<(), Error>;
+        pub fn set_menu_item_tooltip(&self, item_idx: u32, tooltip: &str) -> Result<(), Error>;
+        pub fn set_menu_item_icon(&self, item_idx: u32, icon: &IconBuffer) -> Result<(), Error>;
+        pub fn set_menu_item_shortcut(&self, item_idx: u32, shortcut: &Shortcut)
+            -> Result<(), Error>;
+        pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error>;
+        pub fn set_icon_from_rgba(&self, rgba: &[u8], width: u32, height: u32) -> Result<(), Error>;
+        pub fn set_icon_from_resource(&self, resource: &IconResource) -> Result<(), Error>;
+        pub fn set_icon_animation(&self, frames: Vec<IconBuffer>, interval: Duration)
+            -> Result<(), Error>;
+        pub fn stop_icon_animation(&self) -> Result<(), Error>;
+        pub fn set_icon_accessibility_description(&self, desc: &str) -> Result<(), Error>;
+        pub fn show_notification(
+            &self,
+            title: &str,
+            body: &str,
+            icon: Option<&IconBuffer>,
+            timeout: Duration
+        ) -> Result<(), Error>;
+        #[cfg(target_os = "windows")]
+        pub fn set_icon_from_buffer(&self, buffer: &[u8], width: u32, height: u32)
+            -> Result<(), Error>;
+        #[cfg(target_os = "windows")]
+        pub fn force_icon_from_buffer(&self, buffer: &[u8], width: u32, height: u32)
+            -> Result<(), Error>;
+        #[cfg(target_os = "macos")]
+        pub fn set_icon_as_template(&self, template: bool) -> Result<(), Error>;
+        #[cfg(target_os = "macos")]
+        pub fn set_activation_policy(&self, policy: ActivationPolicy) -> Result<(), Error>;
+        #[cfg(target_os = "macos")]
+        pub fn show_about_panel(&self) -> Result<(), Error>;
+    }
+}
@@ -0,0 +1,79 @@
+// A value named after the app under the Run key, which Explorer starts
+// everything in at login.
+use super::to_wstring;
+use crate::Error;
+use std::path::Path;
+use winapi::{
+    shared::{
+        minwindef::DWORD,
+        winerror::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS},
+    },
+    um::{
+        winnt::REG_SZ,
+        winreg::{self, HKEY_CURRENT_USER, RRF_RT_REG_SZ},
+    },
+};
+
+const RUN_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Run";
+
+fn registry_error(result: i32, call: &'static str) -> Error {
+    Error::WinApi {
+        code: result as u32,
+        call,
+    }
+}
+
+pub fn enable(name: &str, exe: &Path) -> Result<(), Error> {
+    let key = to_wstring(RUN_KEY);
+    let name = to_wstring(name);
+    // Quoted, or a path with spaces in it is taken for a command line.
+    let command = to_wstring(&format!("\"{}\"", exe.display()));
+    let result = unsafe {
+        winreg::RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            name.as_ptr(),
+            REG_SZ,
+            command.as_ptr() as *const _,
+            (command.len() * 2) as DWORD,
+        )
+    };
+    if result != ERROR_SUCCESS as i32 {
+        return Err(registry_error(result, "RegSetKeyValueW"));
+    }
+    Ok(())
+}
+
+pub fn disable(name: &str) -> Result<(), Error> {
+    let key = to_wstring(RUN_KEY);
+    let name = to_wstring(name);
+    let result =
+        unsafe { winreg::RegDeleteKeyValueW(HKEY_CURRENT_USER, key.as_ptr(), name.as_ptr()) };
+    if result != ERROR_SUCCESS as i32 && result != ERROR_FILE_NOT_FOUND as i32 {
+        return Err(registry_error(result, "RegDeleteKeyValueW"));
+    }
+    Ok(())
+}
+
+pub fn is_enabled(name: &str) -> Result<bool, Error> {
+    let key = to_wstring(RUN_KEY);
+    let name = to_wstring(name);
+    let result = unsafe {
+        winreg::RegGetValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            name.as_ptr(),
+            RRF_RT_REG_SZ,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if result == ERROR_SUCCESS as i32 {
+        Ok(true)
+    } else if result == ERROR_FILE_NOT_FOUND as i32 {
+        Ok(false)
+    } else {
+        Err(registry_error(result, "RegGetValueW"))
+    }
+}
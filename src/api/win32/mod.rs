@@ -1,29 +1,60 @@
-use crate::{Error, SystrayEvent};
+pub mod autostart;
+pub mod open;
+
+use crate::{
+    ico, AboutMetadata, ApplicationMode, Backend, Capabilities, Error, EventSender, HotkeyId,
+    IconBuffer, IconResource, Key, LayoutDirection, MenuOp, MenuStyle, Message, MouseButton,
+    NativeHandle, Rect, Shortcut, SystrayEvent, Theme,
+};
+use crate::wstring::{copy_to_wstring, fits_tip, menu_label, shorten_tooltip, to_wstring};
 use std;
-use std::cell::RefCell;
-use std::ffi::OsStr;
-use std::os::windows::ffi::OsStrExt;
-use std::sync::mpsc::{channel, Sender};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use winapi::{
-    ctypes::{c_ulong, c_ushort},
+    ctypes::{c_int, c_ulong, c_ushort},
     shared::{
         basetsd::ULONG_PTR,
         guiddef::GUID,
-        minwindef::{DWORD, HINSTANCE, LPARAM, LRESULT, PBYTE, TRUE, UINT, WPARAM},
-        ntdef::LPCWSTR,
-        windef::{HBITMAP, HBRUSH, HICON, HMENU, HWND, POINT},
+        minwindef::{
+            BOOL, DWORD, FALSE, HINSTANCE, LPARAM, LPVOID, LRESULT, PBYTE, TRUE, UINT, WPARAM,
+        },
+        ntdef::{LPCSTR, LPCWSTR},
+        windef::{
+            COLORREF, HBITMAP, HBRUSH, HDC, HFONT, HGDIOBJ, HICON, HMENU, HWND, POINT, RECT, SIZE,
+        },
+        winerror::{ERROR_CLASS_ALREADY_EXISTS, ERROR_SUCCESS, S_OK},
     },
     um::{
+        commctrl::{
+            self, ICC_BAR_CLASSES, INITCOMMONCONTROLSEX, TOOLTIPS_CLASS, TTF_ABSOLUTE, TTF_TRACK,
+            TTM_ADDTOOLW, TTM_GETBUBBLESIZE, TTM_SETMAXTIPWIDTH, TTM_TRACKACTIVATE,
+            TTM_TRACKPOSITION, TTM_UPDATETIPTEXTW, TTS_ALWAYSTIP, TTS_NOPREFIX, TTTOOLINFOW,
+        },
         errhandlingapi, libloaderapi,
         shellapi::{
-            self, NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NIM_MODIFY, NOTIFYICONDATAW,
+            self, NIF_GUID, NIF_ICON, NIF_INFO, NIF_MESSAGE, NIF_SHOWTIP, NIF_TIP, NIIF_NONE,
+            NIIF_USER, NIM_ADD, NIM_DELETE, NIM_MODIFY, NIM_SETVERSION, NIN_KEYSELECT,
+            NIN_POPUPCLOSE, NIN_POPUPOPEN, NIN_SELECT, NOTIFYICONDATAW, NOTIFYICONIDENTIFIER,
+            NOTIFYICON_VERSION_4,
         },
+        processthreadsapi, sysinfoapi,
+        wingdi::{self, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS},
+        winreg::{self, HKEY_CURRENT_USER, RRF_RT_REG_DWORD},
         winuser::{
             self, CW_USEDEFAULT, IMAGE_ICON, LR_DEFAULTCOLOR, LR_LOADFROMFILE, MENUINFO,
-            MENUITEMINFOW, MFT_SEPARATOR, MFT_STRING, MIIM_FTYPE, MIIM_ID, MIIM_STATE, MIIM_STRING,
-            MIM_APPLYTOSUBMENUS, MIM_STYLE, MNS_NOTIFYBYPOS, WM_DESTROY, WM_USER, WNDCLASSW,
-            WS_OVERLAPPEDWINDOW,
+            MENUITEMINFOW, MFS_CHECKED, MFS_DISABLED, MFS_UNCHECKED, MFT_OWNERDRAW, MFT_RADIOCHECK,
+            MFT_SEPARATOR, MFT_STRING, MF_BYCOMMAND, MF_BYPOSITION, MF_CHECKED, MF_ENABLED,
+            MF_GRAYED, MF_UNCHECKED, MIIM_BITMAP, MIIM_DATA, MIIM_FTYPE, MIIM_ID, MIIM_STATE,
+            MIIM_STRING, MIIM_SUBMENU, MIM_APPLYTOSUBMENUS, MIM_BACKGROUND, MIM_STYLE,
+            MNS_NOTIFYBYPOS, WM_DESTROY, WM_USER, WNDCLASSW, WS_OVERLAPPEDWINDOW,
         },
     },
 };
@@ -32,18 +63,12 @@ use winapi::{
 // doing SetLongPtr tho.
 thread_local!(static WININFO_STASH: RefCell<Option<WindowsLoopData>> = RefCell::new(None));
 
-fn to_wstring(str: &str) -> Vec<u16> {
-    OsStr::new(str)
-        .encode_wide()
-        .chain(Some(0).into_iter())
-        .collect::<Vec<_>>()
-}
-
 #[derive(Clone)]
 struct WindowInfo {
     pub hwnd: HWND,
     pub hinstance: HINSTANCE,
     pub hmenu: HMENU,
+    pub guid: Option<GUID>,
 }
 
 unsafe impl Send for WindowInfo {}
@@ -52,71 +77,893 @@ unsafe impl Sync for WindowInfo {}
 #[derive(Clone)]
 struct WindowsLoopData {
     pub info: WindowInfo,
-    pub tx: Sender<SystrayEvent>,
+    pub tx: EventSender,
+    pub menu_on_left_click: Cell<bool>,
+    pub menu_rtl: Cell<bool>,
+    // Frames of the running icon animation, and the one currently shown.
+    pub animation: RefCell<Option<(Vec<HICON>, usize)>>,
+    pub icon: Arc<Mutex<CurrentIcon>>,
+    // Last theme we saw, WM_SETTINGCHANGE doesn't say whether it changed.
+    pub theme: Cell<Theme>,
+    // Our own tooltip for text the shell's has no room for, made when first
+    // needed. Also shows the tooltips of menu items.
+    pub tip_window: Cell<HWND>,
+    pub item_tooltips: Arc<Mutex<HashMap<u32, String>>>,
+    pub menu_drawing: Arc<Mutex<MenuDrawing>>,
+}
+
+// How owner drawn items are drawn. Shared with the window thread, which does
+// the drawing and switches colors over when the theme changes.
+struct MenuDrawing {
+    style: Option<MenuStyle>,
+    // Behind the items, so the menu's border matches them. Null while there
+    // is no style.
+    background: HBRUSH,
+    headers: HashSet<u32>,
+}
+
+unsafe impl Send for MenuDrawing {}
+
+// Where an icon was loaded from, so it can be loaded again at the new size
+// when the DPI changes.
+#[derive(Clone)]
+enum IconSource {
+    File(String),
+    Resource(IconResource),
+}
+
+// Icon and tooltip handed to the shell last. Shared with the window thread,
+// which reloads the icon on DPI changes and puts both back after Explorer
+// restarts.
+struct CurrentIcon {
+    handle: HICON,
+    source: Option<IconSource>,
+    tooltip: String,
+    // Only read out by screen readers, from szTip while there's no tooltip.
+    description: String,
+    // Taken out of the notification area by set_visible. Changes are only
+    // kept here then, and handed to the shell when the icon comes back.
+    hidden: bool,
 }
 
-unsafe fn get_win_os_error(msg: &str) -> Error {
-    Error::OsError(format!("{}: {}", &msg, errhandlingapi::GetLastError()))
+unsafe impl Send for CurrentIcon {}
+
+// Posted to the window to change menu_on_left_click, with the new value in
+// wParam.
+const WM_SET_MENU_ON_LEFT_CLICK: UINT = WM_USER + 2;
+// Posted to the window to start an icon animation, with the frame interval in
+// milliseconds in wParam and a boxed Vec<HICON> in lParam. A null lParam stops
+// the animation.
+const WM_SET_ICON_ANIMATION: UINT = WM_USER + 3;
+const ANIMATION_TIMER_ID: usize = 1;
+// Sent to the window to register a hotkey, which has to happen on its thread.
+// wParam is the id, lParam the modifiers in the high word and the virtual key
+// in the low one. Returns 0 or the error code.
+const WM_REGISTER_HOTKEY: UINT = WM_USER + 4;
+// Sent to the window with the hotkey id in wParam.
+const WM_UNREGISTER_HOTKEY: UINT = WM_USER + 5;
+// Sent to the window to hide the icon or show it again, with wParam non-zero
+// to show it. Returns 0 or the error code.
+const WM_SET_VISIBLE: UINT = WM_USER + 6;
+// Posted to the window to show an About message box, with a boxed title and
+// text, both wide strings, in lParam.
+const WM_SHOW_ABOUT: UINT = WM_USER + 7;
+// Posted to the window with wParam non-zero to lay the menu out right to
+// left.
+const WM_SET_MENU_RTL: UINT = WM_USER + 8;
+// Posted to the window to open the menu, with the point to open it at packed
+// into wParam the way the shell packs click positions.
+const WM_SHOW_MENU: UINT = WM_USER + 9;
+
+// Broadcast to top-level windows when Explorer starts up again and the
+// notification area comes back empty. Registered by init_window.
+static WM_TASKBAR_CREATED: AtomicUsize = AtomicUsize::new(0);
+// For ChangeWindowMessageFilterEx, winapi doesn't have it.
+const MSGFLT_ALLOW: DWORD = 1;
+
+// Number of icons made so far. Each one has its own window and thread.
+static ICON_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// For calls that report failure through GetLastError.
+unsafe fn get_win_os_error(call: &'static str) -> Error {
+    Error::WinApi {
+        code: errhandlingapi::GetLastError(),
+        call,
+    }
 }
 
+// A panic unwinding out of the window procedure would abort, so it's reported
+// as an event and the message gets the default handling instead.
 unsafe extern "system" fn window_proc(
     h_wnd: HWND,
     msg: UINT,
     w_param: WPARAM,
     l_param: LPARAM,
 ) -> LRESULT {
+    let tx = WININFO_STASH.with(|stash| match stash.try_borrow() {
+        Ok(stash) => stash.as_ref().map(|s| s.tx.clone()),
+        Err(_) => None,
+    });
+    match crate::catch_panic(tx.as_ref(), || handle_message(h_wnd, msg, w_param, l_param)) {
+        Some(result) => result,
+        None => winuser::DefWindowProcW(h_wnd, msg, w_param, l_param),
+    }
+}
+
+unsafe fn handle_message(h_wnd: HWND, msg: UINT, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
+    // Also sent for each submenu, only the tray menu itself counts.
+    if msg == winuser::WM_INITMENUPOPUP {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                if w_param as HMENU == stash.info.hmenu {
+                    stash.tx.send(SystrayEvent::MenuWillOpen).ok();
+                }
+            }
+        });
+    }
     if msg == winuser::WM_MENUCOMMAND {
         WININFO_STASH.with(|stash| {
             let stash = stash.borrow();
             let stash = stash.as_ref();
             if let Some(stash) = stash {
-                let menu_id = winuser::GetMenuItemID(stash.info.hmenu, w_param as i32) as i32;
+                // With MNS_NOTIFYBYPOS, lParam is the (sub)menu the item is in.
+                let menu_id = winuser::GetMenuItemID(l_param as HMENU, w_param as i32) as i32;
                 if menu_id != -1 {
-                    stash
-                        .tx
-                        .send(SystrayEvent {
-                            menu_index: menu_id as u32,
-                        })
-                        .ok();
+                    stash.tx.send_click(menu_id as u32).ok();
+                }
+            }
+        });
+    }
+
+    // Sent as the selection moves through the menu, for submenu items with
+    // their position instead of their id. Closing the menu sends one last with
+    // 0xFFFF for flags and no menu, which finds no tooltip.
+    if msg == winuser::WM_MENUSELECT {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                let flags = ((w_param >> 16) & 0xffff) as UINT;
+                let hmenu = l_param as HMENU;
+                let mut item_idx = (w_param & 0xffff) as UINT;
+                if flags != 0xffff && flags & winuser::MF_POPUP != 0 {
+                    let mut item = get_menu_item_struct();
+                    item.fMask = MIIM_ID;
+                    if winuser::GetMenuItemInfoW(hmenu, item_idx, TRUE, &mut item) != 0 {
+                        item_idx = item.wID;
+                    }
+                }
+                let tooltip = match stash.item_tooltips.lock() {
+                    Ok(tooltips) if !hmenu.is_null() => tooltips.get(&item_idx).cloned(),
+                    _ => None,
+                };
+                match tooltip {
+                    Some(tooltip) => show_menu_item_tooltip(stash, &tooltip),
+                    None => hide_long_tooltip(stash),
+                }
+            }
+        });
+    }
+
+    if msg != 0 && msg as usize == WM_TASKBAR_CREATED.load(Ordering::SeqCst) {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                stash.tx.send(SystrayEvent::TrayHostChanged(true)).ok();
+                if is_hidden(stash) {
+                    return;
+                }
+                if let Err(e) = restore_icon(stash) {
+                    log::warn!("Couldn't add the icon back after Explorer restarted: {}", e);
+                }
+            }
+        });
+        return 0;
+    }
+
+    if msg == WM_SET_MENU_ON_LEFT_CLICK {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                stash.menu_on_left_click.set(w_param != 0);
+            }
+        });
+        return 0;
+    }
+
+    if msg == WM_SET_MENU_RTL {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                stash.menu_rtl.set(w_param != 0);
+            }
+        });
+        return 0;
+    }
+
+    if msg == WM_SET_ICON_ANIMATION {
+        let frames = if l_param == 0 {
+            None
+        } else {
+            Some(*Box::from_raw(l_param as *mut Vec<HICON>))
+        };
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                winuser::KillTimer(h_wnd, ANIMATION_TIMER_ID);
+                let old = stash.animation.replace(frames.map(|f| (f, 0)));
+                if let Some((old_frames, _)) = old {
+                    for icon in old_frames {
+                        winuser::DestroyIcon(icon);
+                    }
+                }
+                if l_param != 0 {
+                    winuser::SetTimer(h_wnd, ANIMATION_TIMER_ID, w_param as UINT, None);
+                }
+            }
+        });
+        return 0;
+    }
+
+    // The message box runs its own message loop, so the icon keeps working
+    // while it's open.
+    if msg == WM_SHOW_ABOUT {
+        let (title, text) = *Box::from_raw(l_param as *mut (Vec<u16>, Vec<u16>));
+        winuser::MessageBoxW(
+            h_wnd,
+            text.as_ptr(),
+            title.as_ptr(),
+            winuser::MB_OK | winuser::MB_ICONINFORMATION | winuser::MB_SETFOREGROUND,
+        );
+        return 0;
+    }
+
+    // Headers are always owner drawn, the other items only while there is a
+    // menu style. Their itemData is the menu they're in.
+    if msg == winuser::WM_MEASUREITEM {
+        let measure = &mut *(l_param as *mut winuser::MEASUREITEMSTRUCT);
+        if measure.CtlType == winuser::ODT_MENU {
+            let (style, header, _) = menu_drawing_for(measure.itemID);
+            let hmenu = measure.itemData as HMENU;
+            let size = if header {
+                let text = menu_item_text(hmenu, measure.itemID, MF_BYCOMMAND);
+                Some(measure_header(h_wnd, &text, style.as_ref()))
+            } else {
+                match (style, owner_drawn_item(hmenu, measure.itemID)) {
+                    (Some(style), Some(item)) => Some(measure_item(h_wnd, &item, &style)),
+                    _ => None,
+                }
+            };
+            if let Some((width, height)) = size {
+                measure.itemWidth = width;
+                measure.itemHeight = height;
+                return TRUE as LRESULT;
+            }
+        }
+    }
+
+    if msg == winuser::WM_DRAWITEM {
+        let draw = &*(l_param as *const winuser::DRAWITEMSTRUCT);
+        if draw.CtlType == winuser::ODT_MENU {
+            let (style, header, theme) = menu_drawing_for(draw.itemID);
+            let hmenu = draw.itemData as HMENU;
+            if header {
+                let text = menu_item_text(hmenu, draw.itemID, MF_BYCOMMAND);
+                draw_header(draw, &text, style.as_ref(), theme);
+                return TRUE as LRESULT;
+            }
+            if let (Some(style), Some(item)) = (style, owner_drawn_item(hmenu, draw.itemID)) {
+                draw_item(draw, &item, &style, theme);
+                return TRUE as LRESULT;
+            }
+        }
+    }
+
+    if msg == WM_REGISTER_HOTKEY {
+        let modifiers = (l_param >> 16) as UINT | winuser::MOD_NOREPEAT as UINT;
+        let vk = (l_param & 0xffff) as UINT;
+        if winuser::RegisterHotKey(h_wnd, w_param as c_int, modifiers, vk) == 0 {
+            return errhandlingapi::GetLastError() as LRESULT;
+        }
+        return 0;
+    }
+
+    if msg == WM_SET_VISIBLE {
+        let result = WININFO_STASH.with(|stash| match stash.borrow().as_ref() {
+            Some(stash) => set_visible(stash, w_param != 0),
+            None => Ok(()),
+        });
+        return match result {
+            Ok(()) => 0,
+            Err(Error::WinApi { code, .. }) => code as LRESULT,
+            Err(_) => 1,
+        };
+    }
+
+    if msg == WM_UNREGISTER_HOTKEY {
+        winuser::UnregisterHotKey(h_wnd, w_param as c_int);
+        return 0;
+    }
+
+    if msg == winuser::WM_HOTKEY {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                let id = HotkeyId(w_param as u32);
+                stash.tx.send(SystrayEvent::HotkeyPressed(id)).ok();
+            }
+        });
+        return 0;
+    }
+
+    if msg == winuser::WM_TIMER && w_param == ANIMATION_TIMER_ID {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                if let Some((frames, current)) = stash.animation.borrow_mut().as_mut() {
+                    *current = (*current + 1) % frames.len();
+                    let mut nid = get_nid_struct(&stash.info);
+                    nid.uFlags |= NIF_ICON;
+                    nid.hIcon = frames[*current];
+                    shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW);
+                }
+            }
+        });
+        return 0;
+    }
+
+    if msg == winuser::WM_SETTINGCHANGE
+        && l_param != 0
+        && from_wstring(l_param as *const u16) == "ImmersiveColorSet"
+    {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                let theme = get_system_theme();
+                if stash.theme.replace(theme) != theme {
+                    stash.tx.send(SystrayEvent::ThemeChanged(theme)).ok();
+                    set_dark_menus(h_wnd, theme == Theme::Dark);
+                    let mut drawing = stash.menu_drawing.lock().unwrap_or_else(|e| e.into_inner());
+                    if drawing.style.as_ref().is_some_and(|s| s.dark.is_none()) {
+                        if let Err(e) = set_menu_background(stash.info.hmenu, &mut drawing, theme) {
+                            log::warn!("Couldn't change the menu background: {}", e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    if msg == winuser::WM_DPICHANGED || msg == winuser::WM_DISPLAYCHANGE {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                if let Ok(mut current) = stash.icon.lock() {
+                    if let Some(source) = current.source.clone() {
+                        let size = get_icon_size(h_wnd);
+                        let result = load_icon(stash.info.hinstance, &source, size)
+                            .and_then(|icon| {
+                                replace_icon(&stash.info, &mut current, icon, Some(source))
+                            });
+                        if let Err(e) = result {
+                            log::warn!("Couldn't reload icon for the new DPI: {}", e);
+                        }
+                    }
                 }
             }
         });
     }
 
     if msg == WM_USER + 1 {
-        if l_param as UINT == winuser::WM_LBUTTONUP || l_param as UINT == winuser::WM_RBUTTONUP {
-            let mut p = POINT { x: 0, y: 0 };
-            if winuser::GetCursorPos(&mut p as *mut POINT) == 0 {
-                return 1;
-            }
-            winuser::SetForegroundWindow(h_wnd);
-            WININFO_STASH.with(|stash| {
-                let stash = stash.borrow();
-                let stash = stash.as_ref();
-                if let Some(stash) = stash {
-                    winuser::TrackPopupMenu(
-                        stash.info.hmenu,
-                        0,
-                        p.x,
-                        p.y,
-                        (winuser::TPM_BOTTOMALIGN | winuser::TPM_LEFTALIGN) as i32,
-                        h_wnd,
-                        std::ptr::null_mut(),
-                    );
+        // With NOTIFYICON_VERSION_4 the event is in the low word of lParam,
+        // and wParam has the point it happened at, which is the icon itself
+        // when it came from the keyboard.
+        let event = (l_param & 0xffff) as UINT;
+        let p = POINT {
+            x: (w_param & 0xffff) as i16 as i32,
+            y: ((w_param >> 16) & 0xffff) as i16 as i32,
+        };
+        let click = match event {
+            winuser::WM_LBUTTONUP => Some((MouseButton::Left, false)),
+            winuser::WM_RBUTTONUP => Some((MouseButton::Right, false)),
+            winuser::WM_MBUTTONUP => Some((MouseButton::Middle, false)),
+            winuser::WM_LBUTTONDBLCLK => Some((MouseButton::Left, true)),
+            winuser::WM_RBUTTONDBLCLK => Some((MouseButton::Right, true)),
+            winuser::WM_MBUTTONDBLCLK => Some((MouseButton::Middle, true)),
+            _ => None,
+        };
+        // Both count milliseconds since the system started.
+        let age = sysinfoapi::GetTickCount().wrapping_sub(winuser::GetMessageTime() as DWORD);
+        let time = crate::event_time(Duration::from_millis(age as u64));
+        let mut show_menu = false;
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                match click {
+                    Some((button, false)) => {
+                        let event = SystrayEvent::IconClicked {
+                            button,
+                            x: p.x,
+                            y: p.y,
+                            time,
+                        };
+                        stash.tx.send(event).ok();
+                    }
+                    Some((button, true)) => {
+                        let event = SystrayEvent::IconDoubleClicked {
+                            button,
+                            x: p.x,
+                            y: p.y,
+                            time,
+                        };
+                        stash.tx.send(event).ok();
+                    }
+                    None => {}
                 }
-            });
+                if event == NIN_KEYSELECT {
+                    stash.tx.send(SystrayEvent::IconKeySelected).ok();
+                }
+                // Sent once the pointer rests on the icon, along with the
+                // standard tooltip that NIF_SHOWTIP asks for. Without it, the
+                // tooltip is ours to show.
+                if event == NIN_POPUPOPEN {
+                    stash.tx.send(SystrayEvent::TooltipShown).ok();
+                    let tooltip = match stash.icon.lock() {
+                        Ok(c) => c.tooltip.clone(),
+                        Err(_) => String::new(),
+                    };
+                    if !fits_tip(&tooltip) {
+                        show_long_tooltip(stash, &tooltip, p);
+                    }
+                }
+                if event == NIN_POPUPCLOSE {
+                    hide_long_tooltip(stash);
+                }
+                // Right clicks and the menu key both come as WM_CONTEXTMENU,
+                // left clicks and Enter or Space as NIN_SELECT and
+                // NIN_KEYSELECT.
+                show_menu = event == winuser::WM_CONTEXTMENU
+                    || ((event == NIN_SELECT || event == NIN_KEYSELECT)
+                        && stash.menu_on_left_click.get());
+            }
+        });
+        if show_menu {
+            show_popup_menu(h_wnd, p);
         }
     }
+    if msg == WM_SHOW_MENU {
+        let p = POINT {
+            x: (w_param & 0xffff) as i16 as i32,
+            y: ((w_param >> 16) & 0xffff) as i16 as i32,
+        };
+        show_popup_menu(h_wnd, p);
+        return 0;
+    }
     if msg == winuser::WM_DESTROY {
         winuser::PostQuitMessage(0);
     }
     return winuser::DefWindowProcW(h_wnd, msg, w_param, l_param);
 }
 
-fn get_nid_struct(hwnd: &HWND) -> NOTIFYICONDATAW {
-    NOTIFYICONDATAW {
+unsafe fn show_popup_menu(h_wnd: HWND, p: POINT) {
+    winuser::SetForegroundWindow(h_wnd);
+    WININFO_STASH.with(|stash| {
+        let stash = stash.borrow();
+        let stash = stash.as_ref();
+        if let Some(stash) = stash {
+            // Mirrored, submenus included, and opening to the left of the
+            // pointer.
+            let layout = if stash.menu_rtl.get() {
+                winuser::TPM_LAYOUTRTL | winuser::TPM_RIGHTALIGN
+            } else {
+                winuser::TPM_LEFTALIGN
+            };
+            winuser::TrackPopupMenu(
+                stash.info.hmenu,
+                winuser::TPM_BOTTOMALIGN | layout,
+                p.x,
+                p.y,
+                0,
+                h_wnd,
+                std::ptr::null_mut(),
+            );
+            // TrackPopupMenu only returns once the menu is gone.
+            stash.tx.send(SystrayEvent::MenuDidClose).ok();
+        }
+    });
+}
+
+// The style owner drawn items are in, whether the item is a header, and the
+// theme to draw in. Only called on the window thread.
+fn menu_drawing_for(item_id: UINT) -> (Option<MenuStyle>, bool, Theme) {
+    WININFO_STASH.with(|stash| match stash.borrow().as_ref() {
+        Some(stash) => {
+            let drawing = stash.menu_drawing.lock().unwrap_or_else(|e| e.into_inner());
+            let header = drawing.headers.contains(&item_id);
+            (drawing.style.clone(), header, stash.theme.get())
+        }
+        None => (None, false, Theme::Light),
+    })
+}
+
+// Null puts the system's background back.
+unsafe fn set_menu_brush(hmenu: HMENU, brush: HBRUSH) -> Result<(), Error> {
+    let m = MENUINFO {
+        cbSize: std::mem::size_of::<MENUINFO>() as DWORD,
+        fMask: MIM_APPLYTOSUBMENUS | MIM_BACKGROUND,
+        dwStyle: 0 as DWORD,
+        cyMax: 0 as UINT,
+        hbrBack: brush,
+        dwContextHelpID: 0 as DWORD,
+        dwMenuData: 0 as ULONG_PTR,
+    };
+    if winuser::SetMenuInfo(hmenu, &m as *const MENUINFO) == 0 {
+        return Err(get_win_os_error("SetMenuInfo"));
+    }
+    Ok(())
+}
+
+// Paints the menu and its submenus in the background of the style, or the
+// system's if there is none, freeing the brush used until now.
+unsafe fn set_menu_background(
+    hmenu: HMENU,
+    drawing: &mut MenuDrawing,
+    theme: Theme,
+) -> Result<(), Error> {
+    let brush = match &drawing.style {
+        Some(style) => wingdi::CreateSolidBrush(menu_colors(style, theme).background),
+        None => std::ptr::null_mut(),
+    };
+    if let Err(e) = set_menu_brush(hmenu, brush) {
+        if !brush.is_null() {
+            wingdi::DeleteObject(brush as HGDIOBJ);
+        }
+        return Err(e);
+    }
+    if !drawing.background.is_null() {
+        wingdi::DeleteObject(drawing.background as HGDIOBJ);
+    }
+    drawing.background = brush;
+    Ok(())
+}
+
+// The menu font, in bold for headers. A style's typeface and size take the
+// place of the system's. Null if the font can't be had, which leaves the
+// DC's own.
+unsafe fn menu_font(style: Option<&MenuStyle>, bold: bool) -> HFONT {
+    let mut metrics: winuser::NONCLIENTMETRICSW = std::mem::zeroed();
+    metrics.cbSize = std::mem::size_of::<winuser::NONCLIENTMETRICSW>() as UINT;
+    let found = winuser::SystemParametersInfoW(
+        winuser::SPI_GETNONCLIENTMETRICS,
+        metrics.cbSize,
+        &mut metrics as *mut _ as LPVOID,
+        0,
+    );
+    if found == 0 {
+        return std::ptr::null_mut();
+    }
+    if let Some(face) = style.and_then(|s| s.font.as_ref()) {
+        copy_to_wstring(&mut metrics.lfMenuFont.lfFaceName, face);
+    }
+    if let Some(points) = style.and_then(|s| s.font_size) {
+        let dc = winuser::GetDC(std::ptr::null_mut());
+        let dpi = wingdi::GetDeviceCaps(dc, wingdi::LOGPIXELSY);
+        winuser::ReleaseDC(std::ptr::null_mut(), dc);
+        metrics.lfMenuFont.lfHeight = -(points as i32 * dpi / 72);
+    }
+    if bold {
+        metrics.lfMenuFont.lfWeight = wingdi::FW_BOLD;
+    }
+    wingdi::CreateFontIndirectW(&metrics.lfMenuFont)
+}
+
+// Without the terminating null.
+unsafe fn menu_item_text(hmenu: HMENU, item: UINT, by: UINT) -> Vec<u16> {
+    let len = winuser::GetMenuStringW(hmenu, item, std::ptr::null_mut(), 0, by);
+    let mut text = vec![0u16; len.max(0) as usize + 1];
+    let len = winuser::GetMenuStringW(hmenu, item, text.as_mut_ptr(), text.len() as c_int, by);
+    text.truncate(len.max(0) as usize);
+    text
+}
+
+// The colors of an owner drawn menu.
+struct MenuColors {
+    background: COLORREF,
+    text: COLORREF,
+    disabled: COLORREF,
+    highlight: COLORREF,
+}
+
+fn rgb(color: [u8; 3]) -> COLORREF {
+    wingdi::RGB(color[0], color[1], color[2])
+}
+
+// Close to the Windows 11 flyouts, where the style leaves them open.
+fn menu_colors(style: &MenuStyle, theme: Theme) -> MenuColors {
+    let dark = style.dark.unwrap_or(theme == Theme::Dark);
+    let (background, text, disabled, highlight) = if dark {
+        ([0x2c; 3], [0xff; 3], [0x78; 3], [0x3d; 3])
+    } else {
+        ([0xf9; 3], [0x1a; 3], [0xa0; 3], [0xe5; 3])
+    };
+    MenuColors {
+        background: rgb(style.background.unwrap_or(background)),
+        text: rgb(style.text_color.unwrap_or(text)),
+        disabled: rgb(disabled),
+        highlight: rgb(style.highlight.unwrap_or(highlight)),
+    }
+}
+
+unsafe fn fill_rect(dc: HDC, rect: &RECT, color: COLORREF) {
+    let brush = wingdi::CreateSolidBrush(color);
+    winuser::FillRect(dc, rect, brush);
+    wingdi::DeleteObject(brush as HGDIOBJ);
+}
+
+unsafe fn draw_text(dc: HDC, text: &[u16], rect: &RECT, format: UINT) {
+    let mut rect = *rect;
+    winuser::DrawTextW(
+        dc,
+        text.as_ptr(),
+        text.len() as c_int,
+        &mut rect,
+        format | winuser::DT_SINGLELINE | winuser::DT_VCENTER | winuser::DT_HIDEPREFIX,
+    );
+}
+
+// Width of text as DrawTextW lays it out, which takes && for a single &.
+unsafe fn text_width(dc: HDC, text: &[u16]) -> i32 {
+    let mut rect = RECT {
+        left: 0,
+        top: 0,
+        right: 0,
+        bottom: 0,
+    };
+    winuser::DrawTextW(
+        dc,
+        text.as_ptr(),
+        text.len() as c_int,
+        &mut rect,
+        winuser::DT_CALCRECT | winuser::DT_SINGLELINE | winuser::DT_HIDEPREFIX,
+    );
+    rect.right
+}
+
+unsafe fn line_height(dc: HDC) -> i32 {
+    let mut metrics: wingdi::TEXTMETRICW = std::mem::zeroed();
+    wingdi::GetTextMetricsW(dc, &mut metrics);
+    metrics.tmHeight
+}
+
+// Room for the text, indented like the labels of the other items, with some
+// space above and below as the themed items have.
+unsafe fn measure_header(hwnd: HWND, text: &[u16], style: Option<&MenuStyle>) -> (UINT, UINT) {
+    let dc = winuser::GetDC(hwnd);
+    let font = menu_font(style, true);
+    let old = wingdi::SelectObject(dc, font as HGDIOBJ);
+    let mut size = SIZE { cx: 0, cy: 0 };
+    wingdi::GetTextExtentPoint32W(dc, text.as_ptr(), text.len() as c_int, &mut size);
+    wingdi::SelectObject(dc, old);
+    if !font.is_null() {
+        wingdi::DeleteObject(font as HGDIOBJ);
+    }
+    winuser::ReleaseDC(hwnd, dc);
+    let indent = match style {
+        Some(_) => size.cy * 2,
+        None => winuser::GetSystemMetrics(winuser::SM_CXMENUCHECK),
+    };
+    ((size.cx + indent) as UINT, (size.cy + size.cy / 2) as UINT)
+}
+
+// Never drawn selected, as headers are disabled and can't be picked.
+unsafe fn draw_header(
+    draw: &winuser::DRAWITEMSTRUCT,
+    text: &[u16],
+    style: Option<&MenuStyle>,
+    theme: Theme,
+) {
+    let dc = draw.hDC;
+    let colors = style.map(|s| menu_colors(s, theme));
+    match &colors {
+        Some(colors) => fill_rect(dc, &draw.rcItem, colors.background),
+        None => {
+            let background = winuser::GetSysColorBrush(winuser::COLOR_MENU);
+            winuser::FillRect(dc, &draw.rcItem, background);
+        }
+    }
+    let font = menu_font(style, true);
+    let old = wingdi::SelectObject(dc, font as HGDIOBJ);
+    wingdi::SetBkMode(dc, wingdi::TRANSPARENT as c_int);
+    let color = match &colors {
+        Some(colors) => colors.text,
+        None => winuser::GetSysColor(winuser::COLOR_MENUTEXT),
+    };
+    wingdi::SetTextColor(dc, color);
+    let mut rect = draw.rcItem;
+    rect.left += match style {
+        Some(_) => line_height(dc) * 2,
+        None => winuser::GetSystemMetrics(winuser::SM_CXMENUCHECK),
+    };
+    draw_text(dc, text, &rect, 0);
+    wingdi::SelectObject(dc, old);
+    if !font.is_null() {
+        wingdi::DeleteObject(font as HGDIOBJ);
+    }
+}
+
+// What drawing an item in a style needs to know about it.
+struct OwnerDrawnItem {
+    separator: bool,
+    radio: bool,
+    checked: bool,
+    disabled: bool,
+    submenu: bool,
+    bitmap: HBITMAP,
+    text: Vec<u16>,
+}
+
+// Owner drawn items only come with their id, and the menu they're in as
+// their itemData. Items with a submenu may come with its handle for an id.
+unsafe fn owner_drawn_item(hmenu: HMENU, item_id: UINT) -> Option<OwnerDrawnItem> {
+    for position in 0..winuser::GetMenuItemCount(hmenu).max(0) {
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_ID | MIIM_SUBMENU | MIIM_FTYPE | MIIM_STATE | MIIM_BITMAP;
+        if winuser::GetMenuItemInfoW(hmenu, position as UINT, TRUE, &mut item) == 0 {
+            continue;
+        }
+        let submenu = !item.hSubMenu.is_null();
+        if item.wID != item_id && !(submenu && item.hSubMenu as usize as UINT == item_id) {
+            continue;
+        }
+        return Some(OwnerDrawnItem {
+            separator: item.fType & MFT_SEPARATOR != 0,
+            radio: item.fType & MFT_RADIOCHECK != 0,
+            checked: item.fState & MFS_CHECKED != 0,
+            disabled: item.fState & MFS_DISABLED != 0,
+            submenu,
+            bitmap: item.hbmpItem,
+            text: menu_item_text(hmenu, position as UINT, MF_BYPOSITION),
+        });
+    }
+    None
+}
+
+// The label, and the shortcut after the tab if there is one.
+fn split_shortcut(text: &[u16]) -> (&[u16], Option<&[u16]>) {
+    match text.iter().position(|&c| c == '\t' as u16) {
+        Some(tab) => (&text[..tab], Some(&text[tab + 1..])),
+        None => (text, None),
+    }
+}
+
+// Laid out like the system's own items, a column for the check mark or icon,
+// the label, the shortcut and one for the submenu arrow, with the height of
+// the Windows 11 flyouts. Separators are a line with some room around it.
+unsafe fn measure_item(hwnd: HWND, item: &OwnerDrawnItem, style: &MenuStyle) -> (UINT, UINT) {
+    let dc = winuser::GetDC(hwnd);
+    let font = menu_font(Some(style), false);
+    let old = wingdi::SelectObject(dc, font as HGDIOBJ);
+    let (label, shortcut) = split_shortcut(&item.text);
+    let line = line_height(dc);
+    let mut width = text_width(dc, label);
+    if let Some(shortcut) = shortcut {
+        width += line * 2 + text_width(dc, shortcut);
+    }
+    wingdi::SelectObject(dc, old);
+    if !font.is_null() {
+        wingdi::DeleteObject(font as HGDIOBJ);
+    }
+    winuser::ReleaseDC(hwnd, dc);
+    if item.separator {
+        return (0, (line / 2).max(3) as UINT);
+    }
+    ((width + line * 4) as UINT, (line * 2) as UINT)
+}
+
+// Scaled down to fit, if need be, and centered.
+unsafe fn draw_item_bitmap(dc: HDC, bitmap: HBITMAP, rect: &RECT) {
+    let mut info: wingdi::BITMAP = std::mem::zeroed();
+    let size = std::mem::size_of::<wingdi::BITMAP>() as c_int;
+    if wingdi::GetObjectW(bitmap as HGDIOBJ, size, &mut info as *mut _ as LPVOID) == 0 {
+        return;
+    }
+    let room = (rect.bottom - rect.top) * 2 / 3;
+    let (width, height) = (info.bmWidth.min(room), info.bmHeight.min(room));
+    let source = wingdi::CreateCompatibleDC(dc);
+    let old = wingdi::SelectObject(source, bitmap as HGDIOBJ);
+    // Item bitmaps are premultiplied, see create_bitmap_from_rgba.
+    let blend = wingdi::BLENDFUNCTION {
+        BlendOp: wingdi::AC_SRC_OVER,
+        BlendFlags: 0,
+        SourceConstantAlpha: 255,
+        AlphaFormat: wingdi::AC_SRC_ALPHA,
+    };
+    wingdi::GdiAlphaBlend(
+        dc,
+        rect.left + (rect.right - rect.left - width) / 2,
+        rect.top + (rect.bottom - rect.top - height) / 2,
+        width,
+        height,
+        source,
+        0,
+        0,
+        info.bmWidth,
+        info.bmHeight,
+        blend,
+    );
+    wingdi::SelectObject(source, old);
+    wingdi::DeleteDC(source);
+}
+
+unsafe fn draw_item(
+    draw: &winuser::DRAWITEMSTRUCT,
+    item: &OwnerDrawnItem,
+    style: &MenuStyle,
+    theme: Theme,
+) {
+    let dc = draw.hDC;
+    let colors = menu_colors(style, theme);
+    let selected = draw.itemState & winuser::ODS_SELECTED != 0 && !item.disabled;
+    let background = if selected { colors.highlight } else { colors.background };
+    fill_rect(dc, &draw.rcItem, background);
+    let rect = draw.rcItem;
+    let font = menu_font(Some(style), false);
+    let old = wingdi::SelectObject(dc, font as HGDIOBJ);
+    let line = line_height(dc);
+    if item.separator {
+        let middle = (rect.top + rect.bottom) / 2;
+        let rule = RECT {
+            left: rect.left + line / 2,
+            top: middle,
+            right: rect.right - line / 2,
+            bottom: middle + 1,
+        };
+        fill_rect(dc, &rule, colors.disabled);
+    } else {
+        wingdi::SetBkMode(dc, wingdi::TRANSPARENT as c_int);
+        let color = if item.disabled { colors.disabled } else { colors.text };
+        wingdi::SetTextColor(dc, color);
+        let column = line * 2;
+        let check = RECT {
+            right: rect.left + column,
+            ..rect
+        };
+        if item.checked {
+            let mark: &[u16] = if item.radio { &[0x2022] } else { &[0x2713] };
+            draw_text(dc, mark, &check, winuser::DT_CENTER);
+        } else if !item.bitmap.is_null() {
+            draw_item_bitmap(dc, item.bitmap, &check);
+        }
+        let text = RECT {
+            left: rect.left + column,
+            right: rect.right - column,
+            ..rect
+        };
+        let (label, shortcut) = split_shortcut(&item.text);
+        draw_text(dc, label, &text, winuser::DT_LEFT);
+        if let Some(shortcut) = shortcut {
+            draw_text(dc, shortcut, &text, winuser::DT_RIGHT);
+        }
+        if item.submenu {
+            let arrow = RECT {
+                left: rect.right - column,
+                ..rect
+            };
+            draw_text(dc, &[0x203a], &arrow, winuser::DT_CENTER);
+        }
+    }
+    wingdi::SelectObject(dc, old);
+    if !font.is_null() {
+        wingdi::DeleteObject(font as HGDIOBJ);
+    }
+    // The system draws the arrow of submenu items after us, in its own
+    // colors, unless the item is kept out of its reach.
+    wingdi::ExcludeClipRect(dc, rect.left, rect.top, rect.right, rect.bottom);
+}
+
+fn hash_icon_buffer(buffer: &[u8], width: u32, height: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buffer.hash(&mut hasher);
+    width.hash(&mut hasher);
+    height.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Comes with NIF_GUID already set if the icon has a GUID, every call has to
+// identify the icon the same way.
+fn get_nid_struct(info: &WindowInfo) -> NOTIFYICONDATAW {
+    let mut nid = NOTIFYICONDATAW {
         cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as DWORD,
-        hWnd: *hwnd,
+        hWnd: info.hwnd,
         uID: 0x1 as UINT,
         uFlags: 0 as UINT,
         uCallbackMessage: 0 as UINT,
@@ -135,7 +982,60 @@ fn get_nid_struct(hwnd: &HWND) -> NOTIFYICONDATAW {
             Data4: [0; 8],
         },
         hBalloonIcon: 0 as HICON,
+    };
+    if let Some(guid) = info.guid {
+        nid.uFlags = NIF_GUID;
+        nid.guidItem = guid;
+    }
+    nid
+}
+
+// Gives the icon the same identity on every run, so the shell remembers where
+// users put it, like pinned to the taskbar rather than in the overflow area.
+// The shell ties the GUID to the path of the executable and refuses it from
+// anywhere else, so it's made from that path. Further icons in the process
+// are told apart by the order they were made in.
+fn get_icon_guid(index: usize) -> Option<GUID> {
+    let exe = std::env::current_exe().ok()?;
+    let mut hasher = DefaultHasher::new();
+    exe.hash(&mut hasher);
+    if index > 0 {
+        index.hash(&mut hasher);
+    }
+    let high = hasher.finish();
+    "systray-rs".hash(&mut hasher);
+    let low = hasher.finish();
+    Some(GUID {
+        Data1: (high >> 32) as c_ulong,
+        Data2: (high >> 16) as c_ushort,
+        Data3: high as c_ushort,
+        Data4: low.to_be_bytes(),
+    })
+}
+
+// Adds the icon to the notification area, taking the place of one with the
+// same GUID that a crashed run left behind.
+unsafe fn add_icon(info: &WindowInfo) -> Result<(), Error> {
+    let mut nid = get_nid_struct(info);
+    if info.guid.is_some() {
+        shellapi::Shell_NotifyIconW(NIM_DELETE, &mut nid as *mut NOTIFYICONDATAW);
+    }
+    nid.uFlags |= NIF_MESSAGE;
+    nid.uCallbackMessage = WM_USER + 1;
+    if shellapi::Shell_NotifyIconW(NIM_ADD, &mut nid as *mut NOTIFYICONDATAW) == 0 {
+        let e = get_win_os_error("Shell_NotifyIconW");
+        // Server Core and shells without a taskbar have no notification area.
+        let taskbar = to_wstring("Shell_TrayWnd");
+        if winuser::FindWindowW(taskbar.as_ptr(), std::ptr::null()).is_null() {
+            return Err(Error::NoTrayAvailable);
+        }
+        return Err(e);
     }
+    *nid.u.uVersion_mut() = NOTIFYICON_VERSION_4;
+    if shellapi::Shell_NotifyIconW(NIM_SETVERSION, &mut nid as *mut NOTIFYICONDATAW) == 0 {
+        return Err(get_win_os_error("Shell_NotifyIconW"));
+    }
+    Ok(())
 }
 
 fn get_menu_item_struct() -> MENUITEMINFOW {
@@ -155,7 +1055,8 @@ fn get_menu_item_struct() -> MENUITEMINFOW {
     }
 }
 
-unsafe fn init_window() -> Result<WindowInfo, Error> {
+// A hidden icon is only added once set_visible shows it.
+unsafe fn init_window(visible: bool) -> Result<WindowInfo, Error> {
     let class_name = to_wstring("my_window");
     let hinstance: HINSTANCE = libloaderapi::GetModuleHandleA(std::ptr::null_mut());
     let wnd = WNDCLASSW {
@@ -170,9 +1071,14 @@ unsafe fn init_window() -> Result<WindowInfo, Error> {
         lpszMenuName: 0 as LPCWSTR,
         lpszClassName: class_name.as_ptr(),
     };
-    if winuser::RegisterClassW(&wnd) == 0 {
-        return Err(get_win_os_error("Error creating window class"));
+    // Already there if another icon was made before this one.
+    if winuser::RegisterClassW(&wnd) == 0
+        && errhandlingapi::GetLastError() != ERROR_CLASS_ALREADY_EXISTS
+    {
+        return Err(get_win_os_error("RegisterClassW"));
     }
+    let taskbar_created = winuser::RegisterWindowMessageW(to_wstring("TaskbarCreated").as_ptr());
+    WM_TASKBAR_CREATED.store(taskbar_created as usize, Ordering::SeqCst);
     let hwnd = winuser::CreateWindowExW(
         0,
         class_name.as_ptr(),
@@ -188,16 +1094,35 @@ unsafe fn init_window() -> Result<WindowInfo, Error> {
         std::ptr::null_mut(),
     );
     if hwnd == std::ptr::null_mut() {
-        return Err(get_win_os_error("Error creating window"));
-    }
-    let mut nid = get_nid_struct(&hwnd);
-    nid.uID = 0x1;
-    nid.uFlags = NIF_MESSAGE;
-    nid.uCallbackMessage = WM_USER + 1;
-    if shellapi::Shell_NotifyIconW(NIM_ADD, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-        return Err(get_win_os_error("Error adding menu icon"));
+        return Err(get_win_os_error("CreateWindowExW"));
     }
+    // Elevated processes don't get the broadcast from a normal Explorer
+    // otherwise.
+    winuser::ChangeWindowMessageFilterEx(
+        hwnd,
+        taskbar_created,
+        MSGFLT_ALLOW,
+        std::ptr::null_mut(),
+    );
     // Setup menu
+    let hmenu = create_menu()?;
+    let info = WindowInfo {
+        hwnd,
+        hmenu,
+        hinstance,
+        guid: get_icon_guid(ICON_COUNT.fetch_add(1, Ordering::SeqCst)),
+    };
+    if visible {
+        if let Err(e) = add_icon(&info) {
+            winuser::DestroyMenu(hmenu);
+            winuser::DestroyWindow(hwnd);
+            return Err(e);
+        }
+    }
+    Ok(info)
+}
+
+unsafe fn create_menu() -> Result<HMENU, Error> {
     let hmenu = winuser::CreatePopupMenu();
     let m = MENUINFO {
         cbSize: std::mem::size_of::<MENUINFO>() as DWORD,
@@ -209,18 +1134,436 @@ unsafe fn init_window() -> Result<WindowInfo, Error> {
         dwMenuData: 0 as ULONG_PTR,
     };
     if winuser::SetMenuInfo(hmenu, &m as *const MENUINFO) == 0 {
-        return Err(get_win_os_error("Error setting up menu"));
+        return Err(get_win_os_error("SetMenuInfo"));
     }
+    Ok(hmenu)
+}
 
-    Ok(WindowInfo {
-        hwnd: hwnd,
-        hmenu: hmenu,
-        hinstance: hinstance,
-    })
+unsafe fn from_wstring(ptr: *const u16) -> String {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16_lossy(std::slice::from_raw_parts(ptr, len))
 }
 
-unsafe fn run_loop() {
-    log::debug!("Running windows loop");
+// The taskbar and tray follow SystemUsesLightTheme, not the app theme.
+unsafe fn get_system_theme() -> Theme {
+    let key = to_wstring("Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize");
+    let name = to_wstring("SystemUsesLightTheme");
+    let mut value: DWORD = 1;
+    let mut size = std::mem::size_of::<DWORD>() as DWORD;
+    let result = winreg::RegGetValueW(
+        HKEY_CURRENT_USER,
+        key.as_ptr(),
+        name.as_ptr(),
+        RRF_RT_REG_DWORD,
+        std::ptr::null_mut(),
+        &mut value as *mut DWORD as *mut _,
+        &mut size,
+    );
+    if result == ERROR_SUCCESS as i32 && value == 0 {
+        Theme::Dark
+    } else {
+        Theme::Light
+    }
+}
+
+// Popup menus are light unless the app opts into dark ones, which only
+// uxtheme's unnamed exports allow. Explorer's own tray menus go through the
+// same ones. SetPreferredAppMode (AllowDarkModeForApp, taking a BOOL, before
+// 1903) is 135, AllowDarkModeForWindow 133, RefreshImmersiveColorPolicyState
+// 104 and FlushMenuThemes 136. Forced rather than allowed, as the taskbar can
+// be dark while apps are light. Does nothing before Windows 10 1809.
+unsafe fn set_dark_menus(hwnd: HWND, dark: bool) {
+    let uxtheme = libloaderapi::LoadLibraryA("uxtheme.dll\0".as_ptr() as LPCSTR);
+    if uxtheme.is_null() {
+        return;
+    }
+    let export = |ordinal: usize| libloaderapi::GetProcAddress(uxtheme, ordinal as LPCSTR);
+    let (app_mode, for_window) = (export(135), export(133));
+    let (refresh, flush) = (export(104), export(136));
+    if app_mode.is_null() || for_window.is_null() || refresh.is_null() || flush.is_null() {
+        return;
+    }
+    let app_mode: unsafe extern "system" fn(c_int) -> c_int = std::mem::transmute(app_mode);
+    let for_window: unsafe extern "system" fn(HWND, BOOL) -> BOOL =
+        std::mem::transmute(for_window);
+    let refresh: unsafe extern "system" fn() = std::mem::transmute(refresh);
+    let flush: unsafe extern "system" fn() = std::mem::transmute(flush);
+    // ForceDark and Default.
+    app_mode(if dark { 2 } else { 0 });
+    for_window(hwnd, dark as BOOL);
+    refresh();
+    flush();
+}
+
+// GetDpiForWindow and GetSystemMetricsForDpi only exist since Windows 10 1607,
+// so look them up at runtime instead of linking against them.
+unsafe fn get_icon_size(hwnd: HWND) -> (i32, i32) {
+    let user32 = libloaderapi::GetModuleHandleA("user32.dll\0".as_ptr() as LPCSTR);
+    if !user32.is_null() {
+        let dpi_for_window =
+            libloaderapi::GetProcAddress(user32, "GetDpiForWindow\0".as_ptr() as LPCSTR);
+        let metrics_for_dpi =
+            libloaderapi::GetProcAddress(user32, "GetSystemMetricsForDpi\0".as_ptr() as LPCSTR);
+        if !dpi_for_window.is_null() && !metrics_for_dpi.is_null() {
+            let dpi_for_window: unsafe extern "system" fn(HWND) -> UINT =
+                std::mem::transmute(dpi_for_window);
+            let metrics_for_dpi: unsafe extern "system" fn(c_int, UINT) -> c_int =
+                std::mem::transmute(metrics_for_dpi);
+            let dpi = dpi_for_window(hwnd);
+            return (
+                metrics_for_dpi(winuser::SM_CXSMICON, dpi),
+                metrics_for_dpi(winuser::SM_CYSMICON, dpi),
+            );
+        }
+    }
+    (
+        winuser::GetSystemMetrics(winuser::SM_CXSMICON),
+        winuser::GetSystemMetrics(winuser::SM_CYSMICON),
+    )
+}
+
+// Loads the frame closest to size out of the file or resource.
+unsafe fn load_icon(
+    hinstance: HINSTANCE,
+    source: &IconSource,
+    size: (i32, i32),
+) -> Result<HICON, Error> {
+    let (instance, flags) = match source {
+        IconSource::File(_) => (std::ptr::null_mut() as HINSTANCE, LR_LOADFROMFILE),
+        IconSource::Resource(_) => (hinstance, 0),
+    };
+    let name = match source {
+        IconSource::File(f) | IconSource::Resource(IconResource::Name(f)) => to_wstring(f),
+        IconSource::Resource(IconResource::Ordinal(_)) => Vec::new(),
+    };
+    // Ordinals go in place of the name pointer, which is all MAKEINTRESOURCE
+    // does.
+    let name_ptr = match source {
+        IconSource::Resource(IconResource::Ordinal(n)) => *n as usize as LPCWSTR,
+        _ => name.as_ptr(),
+    };
+    let icon = winuser::LoadImageW(
+        instance,
+        name_ptr,
+        IMAGE_ICON,
+        size.0,
+        size.1,
+        flags,
+    ) as HICON;
+    if icon == std::ptr::null_mut() as HICON {
+        return Err(get_win_os_error("LoadImageW"));
+    }
+    Ok(icon)
+}
+
+// The menu lives in our process and survives Explorer restarts, but the icon
+// has to be added again, along with everything the shell kept for it.
+unsafe fn restore_icon(stash: &WindowsLoopData) -> Result<(), Error> {
+    add_icon(&stash.info)?;
+    let mut nid = get_nid_struct(&stash.info);
+    nid.uFlags |= NIF_ICON;
+    nid.hIcon = match stash.animation.borrow().as_ref() {
+        Some((frames, current)) => frames[*current],
+        None => match stash.icon.lock() {
+            Ok(c) => c.handle,
+            Err(_) => std::ptr::null_mut(),
+        },
+    };
+    if !nid.hIcon.is_null()
+        && shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW) == 0
+    {
+        return Err(get_win_os_error("Shell_NotifyIconW"));
+    }
+    if let Ok(current) = stash.icon.lock() {
+        if !current.tooltip.is_empty() || !current.description.is_empty() {
+            set_tooltip(&stash.info, &current)?;
+        }
+    }
+    Ok(())
+}
+
+fn get_tool_info(stash: &WindowsLoopData) -> TTTOOLINFOW {
+    let mut tool: TTTOOLINFOW = unsafe { std::mem::zeroed() };
+    tool.cbSize = std::mem::size_of::<TTTOOLINFOW>() as UINT;
+    tool.uFlags = TTF_TRACK | TTF_ABSOLUTE;
+    tool.hwnd = stash.info.hwnd;
+    tool
+}
+
+// A tracking tooltip control, placed above the icon like the shell's own.
+unsafe fn show_long_tooltip(stash: &WindowsLoopData, tooltip: &str, p: POINT) {
+    show_tracking_tooltip(stash, tooltip, |width, height| {
+        let x = (p.x - width / 2).max(0);
+        // Below the icon when the taskbar is at the top of the screen.
+        let y = if p.y - height < 0 { p.y + 16 } else { p.y - height };
+        (x, y)
+    });
+}
+
+// Menu items have no tooltips of their own, so it's the same control, next
+// to the pointer and clear of the cursor.
+unsafe fn show_menu_item_tooltip(stash: &WindowsLoopData, tooltip: &str) {
+    let mut p = POINT { x: 0, y: 0 };
+    if winuser::GetCursorPos(&mut p) == 0 {
+        return;
+    }
+    let cursor = winuser::GetSystemMetrics(winuser::SM_CYCURSOR);
+    show_tracking_tooltip(stash, tooltip, |_, _| (p.x + cursor / 2, p.y + cursor));
+}
+
+// place gets the size of the bubble and returns where its top left corner
+// goes.
+unsafe fn show_tracking_tooltip<F>(stash: &WindowsLoopData, tooltip: &str, place: F)
+where
+    F: FnOnce(i32, i32) -> (i32, i32),
+{
+    let mut tool = get_tool_info(stash);
+    // The control only breaks lines at CRLF.
+    let mut text = to_wstring(&tooltip.replace('\n', "\r\n"));
+    tool.lpszText = text.as_mut_ptr();
+    let tool_ptr = &mut tool as *mut TTTOOLINFOW as LPARAM;
+    let mut tip = stash.tip_window.get();
+    if tip.is_null() {
+        // Registers the tooltip window class.
+        let controls = INITCOMMONCONTROLSEX {
+            dwSize: std::mem::size_of::<INITCOMMONCONTROLSEX>() as DWORD,
+            dwICC: ICC_BAR_CLASSES,
+        };
+        commctrl::InitCommonControlsEx(&controls);
+        tip = winuser::CreateWindowExW(
+            winuser::WS_EX_TOPMOST,
+            to_wstring(TOOLTIPS_CLASS).as_ptr(),
+            std::ptr::null(),
+            winuser::WS_POPUP | TTS_NOPREFIX | TTS_ALWAYSTIP,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            stash.info.hwnd,
+            std::ptr::null_mut(),
+            stash.info.hinstance,
+            std::ptr::null_mut(),
+        );
+        if tip.is_null() {
+            log::warn!("Couldn't show the tooltip: {}", get_win_os_error("CreateWindowExW"));
+            return;
+        }
+        // Also what makes it take more than one line.
+        winuser::SendMessageW(tip, TTM_SETMAXTIPWIDTH, 0, LONG_TIP_WIDTH);
+        winuser::SendMessageW(tip, TTM_ADDTOOLW, 0, tool_ptr);
+        stash.tip_window.set(tip);
+    } else {
+        winuser::SendMessageW(tip, TTM_UPDATETIPTEXTW, 0, tool_ptr);
+    }
+    let size = winuser::SendMessageW(tip, TTM_GETBUBBLESIZE, 0, tool_ptr);
+    let (width, height) = ((size & 0xffff) as i32, ((size >> 16) & 0xffff) as i32);
+    let (x, y) = place(width, height);
+    let position = ((y as LPARAM & 0xffff) << 16) | (x as LPARAM & 0xffff);
+    winuser::SendMessageW(tip, TTM_TRACKPOSITION, 0, position);
+    winuser::SendMessageW(tip, TTM_TRACKACTIVATE, TRUE as WPARAM, tool_ptr);
+}
+
+unsafe fn hide_long_tooltip(stash: &WindowsLoopData) {
+    let tip = stash.tip_window.get();
+    if !tip.is_null() {
+        let mut tool = get_tool_info(stash);
+        let tool_ptr = &mut tool as *mut TTTOOLINFOW as LPARAM;
+        winuser::SendMessageW(tip, TTM_TRACKACTIVATE, 0, tool_ptr);
+    }
+}
+
+fn is_hidden(stash: &WindowsLoopData) -> bool {
+    stash.icon.lock().map(|c| c.hidden).unwrap_or(false)
+}
+
+unsafe fn set_visible(stash: &WindowsLoopData, visible: bool) -> Result<(), Error> {
+    if is_hidden(stash) != visible {
+        return Ok(());
+    }
+    if visible {
+        // restore_icon takes the lock itself.
+        stash.icon.lock().unwrap_or_else(|e| e.into_inner()).hidden = false;
+        if let Err(e) = restore_icon(stash) {
+            stash.icon.lock().unwrap_or_else(|e| e.into_inner()).hidden = true;
+            return Err(e);
+        }
+    } else {
+        let mut nid = get_nid_struct(&stash.info);
+        if shellapi::Shell_NotifyIconW(NIM_DELETE, &mut nid as *mut NOTIFYICONDATAW) == 0 {
+            return Err(get_win_os_error("Shell_NotifyIconW"));
+        }
+        stash.icon.lock().unwrap_or_else(|e| e.into_inner()).hidden = true;
+    }
+    Ok(())
+}
+
+struct HostWindowSearch {
+    pid: DWORD,
+    found: HWND,
+}
+
+// EnumWindows goes from the top of the z-order down, so the first match is
+// the window the user saw last. Our own windows are hidden or tool windows.
+unsafe extern "system" fn find_host_window(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    let search = &mut *(lparam as *mut HostWindowSearch);
+    let mut pid = 0;
+    winuser::GetWindowThreadProcessId(hwnd, &mut pid);
+    let ex_style = winuser::GetWindowLongW(hwnd, winuser::GWL_EXSTYLE) as DWORD;
+    if pid != search.pid
+        || winuser::IsWindowVisible(hwnd) == 0
+        || !winuser::GetWindow(hwnd, winuser::GW_OWNER).is_null()
+        || ex_style & winuser::WS_EX_TOOLWINDOW != 0
+    {
+        return TRUE;
+    }
+    search.found = hwnd;
+    FALSE
+}
+
+// Width our own tooltip wraps its lines at, in pixels.
+const LONG_TIP_WIDTH: LPARAM = 400;
+
+// szTip is also what screen readers read out. Without a tooltip it holds the
+// accessibility description, which isn't shown.
+unsafe fn set_tooltip(info: &WindowInfo, current: &CurrentIcon) -> Result<(), Error> {
+    let tooltip = &current.tooltip;
+    let tip = if tooltip.is_empty() { &current.description } else { tooltip };
+    let mut nid = get_nid_struct(info);
+    copy_to_wstring(&mut nid.szTip, &shorten_tooltip(tip));
+    // Version 4 icons only show the standard tooltip with NIF_SHOWTIP. Long
+    // ones are left out, so the shell sends NIN_POPUPOPEN for us to show the
+    // whole text instead.
+    nid.uFlags |= NIF_TIP;
+    if !tooltip.is_empty() && fits_tip(tooltip) {
+        nid.uFlags |= NIF_SHOWTIP;
+    }
+    if shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW) == 0 {
+        return Err(get_win_os_error("Shell_NotifyIconW"));
+    }
+    Ok(())
+}
+
+// Takes ownership of icon. The shell keeps a copy of its own, so the previous
+// icon can go as soon as the new one is set.
+unsafe fn replace_icon(
+    info: &WindowInfo,
+    current: &mut CurrentIcon,
+    icon: HICON,
+    source: Option<IconSource>,
+) -> Result<(), Error> {
+    let mut nid = get_nid_struct(info);
+    nid.uFlags |= NIF_ICON;
+    nid.hIcon = icon;
+    if !current.hidden
+        && shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW) == 0
+    {
+        let e = get_win_os_error("Shell_NotifyIconW");
+        winuser::DestroyIcon(icon);
+        return Err(e);
+    }
+    if !current.handle.is_null() {
+        winuser::DestroyIcon(current.handle);
+    }
+    current.handle = icon;
+    current.source = source;
+    Ok(())
+}
+
+// scratch is only for the conversion, and is kept by the caller so icons set
+// on every tick don't allocate.
+fn create_icon_from_rgba(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    scratch: &mut Vec<u8>,
+) -> Result<HICON, Error> {
+    // CreateIcon wants BGRA, and an AND mask after it here. The mask only
+    // matters for pixels without alpha, so it can stay empty.
+    scratch.clear();
+    scratch.extend_from_slice(rgba);
+    for pixel in scratch.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+    }
+    scratch.resize(rgba.len() + (width * height) as usize, 0);
+    let (bgra, and_mask) = scratch.split_at(rgba.len());
+    let hicon = unsafe {
+        winuser::CreateIcon(
+            std::ptr::null_mut() as HINSTANCE,
+            width as i32,
+            height as i32,
+            1,
+            32,
+            and_mask.as_ptr(),
+            bgra.as_ptr(),
+        )
+    };
+    if hicon == std::ptr::null_mut() as HICON {
+        return Err(unsafe { get_win_os_error("CreateIcon") });
+    }
+    Ok(hicon)
+}
+
+// Menus draw item bitmaps with alpha only if they are 32 bit, top-down DIBs
+// with premultiplied BGRA pixels.
+unsafe fn create_bitmap_from_rgba(rgba: &[u8], width: u32, height: u32) -> Result<HBITMAP, Error> {
+    let mut info: BITMAPINFO = std::mem::zeroed();
+    info.bmiHeader.biSize = std::mem::size_of::<BITMAPINFOHEADER>() as DWORD;
+    info.bmiHeader.biWidth = width as i32;
+    info.bmiHeader.biHeight = -(height as i32);
+    info.bmiHeader.biPlanes = 1;
+    info.bmiHeader.biBitCount = 32;
+    info.bmiHeader.biCompression = BI_RGB;
+    let mut bits = std::ptr::null_mut();
+    let bitmap = wingdi::CreateDIBSection(
+        std::ptr::null_mut(),
+        &info,
+        DIB_RGB_COLORS,
+        &mut bits,
+        std::ptr::null_mut(),
+        0,
+    );
+    if bitmap.is_null() || bits.is_null() {
+        return Err(get_win_os_error("CreateDIBSection"));
+    }
+    let dest = std::slice::from_raw_parts_mut(bits as *mut u8, rgba.len());
+    for (d, s) in dest.chunks_exact_mut(4).zip(rgba.chunks_exact(4)) {
+        let alpha = s[3] as u32;
+        d[0] = (s[2] as u32 * alpha / 255) as u8;
+        d[1] = (s[1] as u32 * alpha / 255) as u8;
+        d[2] = (s[0] as u32 * alpha / 255) as u8;
+        d[3] = s[3];
+    }
+    Ok(bitmap)
+}
+
+fn shortcut_text(shortcut: &Shortcut) -> String {
+    let m = shortcut.modifiers;
+    let mut text = String::new();
+    for (on, name) in [
+        (m.ctrl, "Ctrl"),
+        (m.alt, "Alt"),
+        (m.shift, "Shift"),
+        (m.meta, "Win"),
+    ]
+    .iter()
+    {
+        if *on {
+            text.push_str(name);
+            text.push('+');
+        }
+    }
+    match shortcut.key {
+        Key::Char(c) => text.push(c),
+        Key::F(n) => text.push_str(&format!("F{}", n)),
+    }
+    text
+}
+
+unsafe fn run_loop() {
+    log::debug!("Running windows loop");
     // Run message loop
     let mut msg = winuser::MSG {
         hwnd: 0 as HWND,
@@ -244,14 +1587,57 @@ unsafe fn run_loop() {
 pub struct Window {
     info: WindowInfo,
     windows_loop: Option<thread::JoinHandle<()>>,
+    // Hash of the buffer the current icon was loaded from, if any. Lets us skip
+    // reloading the same icon when callers set it on every tick.
+    icon_hash: Cell<Option<u64>>,
+    submenus: RefCell<HashMap<u32, HMENU>>,
+    icon: Arc<Mutex<CurrentIcon>>,
+    // Menus don't take ownership of item bitmaps, so they are ours to free.
+    item_bitmaps: RefCell<HashMap<u32, HBITMAP>>,
+    // Shortcut text of the items that have one, kept to put it back on label
+    // changes.
+    shortcuts: RefCell<HashMap<u32, String>>,
+    icon_scratch: RefCell<Vec<u8>>,
+    // Shown by the window thread as items get selected.
+    item_tooltips: Arc<Mutex<HashMap<u32, String>>>,
+    menu_drawing: Arc<Mutex<MenuDrawing>>,
 }
 
 impl Window {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<Window, Error> {
+    pub fn new(
+        event_tx: EventSender,
+        _: ApplicationMode,
+        backend: Backend,
+        visible: bool,
+    ) -> Result<Window, Error> {
+        match backend {
+            Backend::Auto | Backend::Win32 => {}
+            _ => {
+                return Err(Error::NotSupported {
+                    feature: "tray backends other than Win32",
+                })
+            }
+        }
         let (tx, rx) = channel();
+        let icon = Arc::new(Mutex::new(CurrentIcon {
+            handle: std::ptr::null_mut(),
+            source: None,
+            tooltip: String::new(),
+            description: String::new(),
+            hidden: !visible,
+        }));
+        let loop_icon = icon.clone();
+        let item_tooltips = Arc::new(Mutex::new(HashMap::new()));
+        let loop_tooltips = item_tooltips.clone();
+        let menu_drawing = Arc::new(Mutex::new(MenuDrawing {
+            style: None,
+            background: std::ptr::null_mut(),
+            headers: HashSet::new(),
+        }));
+        let loop_drawing = menu_drawing.clone();
         let windows_loop = thread::spawn(move || {
             unsafe {
-                let i = init_window();
+                let i = init_window(visible);
                 let k;
                 match i {
                     Ok(j) => {
@@ -264,30 +1650,138 @@ impl Window {
                         return;
                     }
                 };
+                let theme = get_system_theme();
+                set_dark_menus(k.hwnd, theme == Theme::Dark);
                 WININFO_STASH.with(|stash| {
                     let data = WindowsLoopData {
                         info: k,
                         tx: event_tx,
+                        menu_on_left_click: Cell::new(true),
+                        menu_rtl: Cell::new(false),
+                        animation: RefCell::new(None),
+                        icon: loop_icon,
+                        theme: Cell::new(theme),
+                        tip_window: Cell::new(std::ptr::null_mut()),
+                        item_tooltips: loop_tooltips,
+                        menu_drawing: loop_drawing,
                     };
                     (*stash.borrow_mut()) = Some(data);
                 });
                 run_loop();
+                // The loop is done with the window, clean up what it owned.
+                WININFO_STASH.with(|stash| {
+                    let data = stash.borrow_mut().take();
+                    if let Some(data) = data {
+                        if let Some((frames, _)) = data.animation.into_inner() {
+                            for icon in frames {
+                                winuser::DestroyIcon(icon);
+                            }
+                        }
+                        winuser::DestroyWindow(data.info.hwnd);
+                    }
+                });
             }
         });
-        let info = match rx.recv().unwrap() {
-            Ok(i) => i,
-            Err(e) => {
+        let info = match rx.recv() {
+            Ok(Ok(i)) => i,
+            Ok(Err(e)) => {
                 return Err(e);
             }
+            Err(_) => {
+                return Err(Error::OsError(
+                    "Windows loop thread exited during setup".to_owned(),
+                ));
+            }
         };
         let w = Window {
-            info: info,
+            info,
             windows_loop: Some(windows_loop),
+            icon_hash: Cell::new(None),
+            submenus: RefCell::new(HashMap::new()),
+            icon,
+            item_bitmaps: RefCell::new(HashMap::new()),
+            shortcuts: RefCell::new(HashMap::new()),
+            icon_scratch: RefCell::new(Vec::new()),
+            item_tooltips,
+            menu_drawing,
         };
         Ok(w)
     }
 
+    pub(crate) fn recv_event(&self, rx: &Receiver<Message>) -> Option<Message> {
+        rx.recv().ok()
+    }
+
+    pub(crate) fn try_recv_event(
+        &self,
+        rx: &Receiver<Message>,
+    ) -> Result<Message, TryRecvError> {
+        rx.try_recv()
+    }
+
+    // recv_event waits on the channel itself, so it already wakes up.
+    pub fn wake() {}
+
+    pub fn native_handle(&self) -> Result<NativeHandle, Error> {
+        Ok(NativeHandle::Windows {
+            hwnd: self.info.hwnd as *mut c_void,
+            uid: get_nid_struct(&self.info).uID,
+        })
+    }
+
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        Ok(Capabilities {
+            supports_tooltip: true,
+            supports_title_text: false,
+            supports_menu_icons: true,
+            supports_notifications: true,
+            supports_icon_clicks: true,
+            supports_double_click: true,
+            supports_icon_screen_rect: true,
+            supports_icon_from_file: true,
+            supports_icon_accessibility_description: true,
+            supports_global_hotkeys: true,
+            // Explorer doesn't pass drops on to notification icons.
+            supports_file_drops: false,
+            // Nor does it pass on the wheel.
+            supports_scroll: false,
+        })
+    }
+
+    pub fn icon_screen_rect(&self) -> Result<Rect, Error> {
+        let nid = get_nid_struct(&self.info);
+        let identifier = NOTIFYICONIDENTIFIER {
+            cbSize: std::mem::size_of::<NOTIFYICONIDENTIFIER>() as DWORD,
+            hWnd: nid.hWnd,
+            uID: nid.uID,
+            guidItem: nid.guidItem,
+        };
+        let mut rect = RECT {
+            left: 0,
+            top: 0,
+            right: 0,
+            bottom: 0,
+        };
+        let result = unsafe { shellapi::Shell_NotifyIconGetRect(&identifier, &mut rect) };
+        if result != S_OK {
+            return Err(Error::WinApi {
+                code: result as u32,
+                call: "Shell_NotifyIconGetRect",
+            });
+        }
+        Ok(Rect {
+            x: rect.left,
+            y: rect.top,
+            width: (rect.right - rect.left) as u32,
+            height: (rect.bottom - rect.top) as u32,
+        })
+    }
+
     pub fn quit(&mut self) {
+        if self.windows_loop.is_none() {
+            return;
+        }
+        self.shutdown().ok();
         unsafe {
             winuser::PostMessageW(self.info.hwnd, WM_DESTROY, 0 as WPARAM, 0 as LPARAM);
         }
@@ -296,105 +1790,610 @@ impl Window {
         }
     }
 
+    // Tooltips longer than the shell allows are cut off.
     pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
-        // Add Tooltip
         log::debug!("Setting tooltip to {}", tooltip);
-        // Gross way to convert String to [i8; 128]
-        // TODO: Clean up conversion, test for length so we don't panic at runtime
-        let tt = tooltip.as_bytes().clone();
-        let mut nid = get_nid_struct(&self.info.hwnd);
-        for i in 0..tt.len() {
-            nid.szTip[i] = tt[i] as u16;
-        }
-        nid.uFlags = NIF_TIP;
+        let tooltip = &tooltip.replace("\r\n", "\n");
+        let mut current = self.icon.lock().unwrap_or_else(|e| e.into_inner());
+        let old = std::mem::replace(&mut current.tooltip, tooltip.to_owned());
+        if !current.hidden {
+            if let Err(e) = unsafe { set_tooltip(&self.info, &current) } {
+                current.tooltip = old;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Has to happen on the window thread, which puts the icon back after
+    // Explorer restarts and must know not to.
+    pub fn set_visible(&self, visible: bool) -> Result<(), Error> {
+        let result = unsafe {
+            winuser::SendMessageW(self.info.hwnd, WM_SET_VISIBLE, visible as WPARAM, 0)
+        };
+        if result != 0 {
+            return Err(Error::WinApi {
+                code: result as u32,
+                call: "Shell_NotifyIconW",
+            });
+        }
+        Ok(())
+    }
+
+    // Clicks on the icon give this process the right to take the foreground,
+    // which SetForegroundWindow uses up. Only works while that lasts.
+    pub fn activate_host_app(&self) -> Result<(), Error> {
+        let mut search = HostWindowSearch {
+            pid: unsafe { processthreadsapi::GetCurrentProcessId() },
+            found: std::ptr::null_mut(),
+        };
         unsafe {
-            if shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error setting tooltip"));
+            winuser::EnumWindows(
+                Some(find_host_window),
+                &mut search as *mut HostWindowSearch as LPARAM,
+            );
+            if search.found.is_null() {
+                return Ok(());
+            }
+            // Apps that show their windows from a helper process can still
+            // bring them up themselves.
+            winuser::AllowSetForegroundWindow(winuser::ASFW_ANY);
+            if winuser::IsIconic(search.found) != 0 {
+                winuser::ShowWindow(search.found, winuser::SW_RESTORE);
+            }
+            if winuser::SetForegroundWindow(search.found) == 0 {
+                return Err(Error::OsError(
+                    "Windows didn't let the app take the foreground".to_owned(),
+                ));
             }
         }
         Ok(())
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
-        let mut st = to_wstring(item_name);
-        let mut item = get_menu_item_struct();
-        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
-        item.fType = MFT_STRING;
-        item.wID = item_idx;
-        item.dwTypeData = st.as_mut_ptr();
-        item.cch = (item_name.len() * 2) as u32;
+    pub fn show_about(&self, about: AboutMetadata) -> Result<(), Error> {
+        let mut text = about.name.clone();
+        if let Some(version) = about.version.as_ref() {
+            text.push(' ');
+            text.push_str(version);
+        }
+        let mut details = Vec::new();
+        if !about.authors.is_empty() {
+            details.push(about.authors.join(", "));
+        }
+        details.extend(about.license.iter().cloned());
+        details.extend(about.website.iter().cloned());
+        if !details.is_empty() {
+            text.push_str("\n\n");
+            text.push_str(&details.join("\n"));
+        }
+        let title = to_wstring(&format!("About {}", about.name));
+        let strings = Box::into_raw(Box::new((title, to_wstring(&text))));
         unsafe {
-            if winuser::InsertMenuItemW(self.info.hmenu, item_idx, 1, &item as *const MENUITEMINFOW)
-                == 0
+            if winuser::PostMessageW(self.info.hwnd, WM_SHOW_ABOUT, 0, strings as LPARAM) == 0 {
+                drop(Box::from_raw(strings));
+                return Err(get_win_os_error("PostMessageW"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn register_hotkey(&self, id: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let vk = match shortcut.key {
+            Key::Char(c) => {
+                // The low byte is the key, the high byte the shift state it
+                // needs, which is up to the modifiers here.
+                let scan = unsafe { winuser::VkKeyScanW(c as u16) };
+                if scan == -1 {
+                    let msg = format!("No key for {:?} on this keyboard", c);
+                    return Err(Error::InvalidShortcut(msg));
+                }
+                (scan & 0xff) as LPARAM
+            }
+            Key::F(n) => (winuser::VK_F1 + n as i32 - 1) as LPARAM,
+        };
+        let m = shortcut.modifiers;
+        let mut modifiers = 0;
+        for (on, flag) in [
+            (m.ctrl, winuser::MOD_CONTROL),
+            (m.alt, winuser::MOD_ALT),
+            (m.shift, winuser::MOD_SHIFT),
+            (m.meta, winuser::MOD_WIN),
+        ]
+        .iter()
+        {
+            if *on {
+                modifiers |= *flag as LPARAM;
+            }
+        }
+        let result = unsafe {
+            winuser::SendMessageW(
+                self.info.hwnd,
+                WM_REGISTER_HOTKEY,
+                id as WPARAM,
+                modifiers << 16 | vk,
+            )
+        };
+        if result != 0 {
+            return Err(Error::WinApi {
+                code: result as u32,
+                call: "RegisterHotKey",
+            });
+        }
+        Ok(())
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        unsafe {
+            winuser::SendMessageW(self.info.hwnd, WM_UNREGISTER_HOTKEY, id as WPARAM, 0);
+        }
+        Ok(())
+    }
+
+    pub fn set_title(&self, _: &str) -> Result<(), Error> {
+        Err(Error::NotSupported {
+            feature: "icon titles",
+        })
+    }
+
+    // Opens above the middle of the icon, like the menu Enter opens, or at
+    // the pointer while the icon can't be found, e.g. when it's hidden.
+    pub fn show_menu(&self) -> Result<(), Error> {
+        match self.icon_screen_rect() {
+            Ok(rect) => self.show_menu_at(rect.x + rect.width as i32 / 2, rect.y),
+            Err(_) => {
+                let mut p = POINT { x: 0, y: 0 };
+                unsafe { winuser::GetCursorPos(&mut p) };
+                self.show_menu_at(p.x, p.y)
+            }
+        }
+    }
+
+    // Opens upwards from the point, as the menu does from a click.
+    pub fn show_menu_at(&self, x: i32, y: i32) -> Result<(), Error> {
+        let packed = (x as u16 as WPARAM) | ((y as u16 as WPARAM) << 16);
+        unsafe {
+            if winuser::PostMessageW(self.info.hwnd, WM_SHOW_MENU, packed, 0) == 0 {
+                return Err(get_win_os_error("PostMessageW"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<(), Error> {
+        unsafe {
+            if winuser::PostMessageW(
+                self.info.hwnd,
+                WM_SET_MENU_ON_LEFT_CLICK,
+                enabled as WPARAM,
+                0 as LPARAM,
+            ) == 0
             {
-                return Err(get_win_os_error("Error inserting menu item"));
+                return Err(get_win_os_error("PostMessageW"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_direction(&self, direction: LayoutDirection) -> Result<(), Error> {
+        let rtl = direction == LayoutDirection::RightToLeft;
+        unsafe {
+            if winuser::PostMessageW(self.info.hwnd, WM_SET_MENU_RTL, rtl as WPARAM, 0) == 0 {
+                return Err(get_win_os_error("PostMessageW"));
+            }
+        }
+        Ok(())
+    }
+
+    // Every item but the headers, which are owner drawn already, is owner
+    // drawn while there is a style, with the menu it's in as its itemData.
+    pub fn set_menu_style(&self, style: Option<MenuStyle>) -> Result<(), Error> {
+        let styled = style.is_some();
+        let headers = {
+            let mut drawing = self.menu_drawing.lock().unwrap_or_else(|e| e.into_inner());
+            drawing.style = style;
+            unsafe { set_menu_background(self.info.hmenu, &mut drawing, get_system_theme())? };
+            drawing.headers.clone()
+        };
+        let submenus = self.submenus.borrow();
+        for hmenu in Some(&self.info.hmenu).into_iter().chain(submenus.values()) {
+            unsafe {
+                for position in 0..winuser::GetMenuItemCount(*hmenu).max(0) {
+                    let mut item = get_menu_item_struct();
+                    item.fMask = MIIM_FTYPE | MIIM_ID;
+                    if winuser::GetMenuItemInfoW(*hmenu, position as UINT, TRUE, &mut item) == 0
+                        || headers.contains(&item.wID)
+                    {
+                        continue;
+                    }
+                    item.fMask = MIIM_FTYPE | MIIM_DATA;
+                    if styled {
+                        item.fType |= MFT_OWNERDRAW;
+                    } else {
+                        item.fType &= !MFT_OWNERDRAW;
+                    }
+                    item.dwItemData = *hmenu as ULONG_PTR;
+                    if winuser::SetMenuItemInfoW(*hmenu, position as UINT, TRUE, &item) == 0 {
+                        return Err(get_win_os_error("SetMenuItemInfoW"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Owner drawn while there is a menu style.
+    fn menu_item_style(&self, hmenu: HMENU, item: &mut MENUITEMINFOW) {
+        let drawing = self.menu_drawing.lock().unwrap_or_else(|e| e.into_inner());
+        if drawing.style.is_some() {
+            item.fMask |= MIIM_FTYPE | MIIM_DATA;
+            item.fType |= MFT_OWNERDRAW;
+            item.dwItemData = hmenu as ULONG_PTR;
+        }
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_STATE;
+        self.insert_menu_entry(item_idx, parent, item_name, item)
+    }
+
+    pub fn add_menu_checkable_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        checked: bool,
+    ) -> Result<(), Error> {
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_STATE;
+        item.fState = if checked { MFS_CHECKED } else { MFS_UNCHECKED };
+        self.insert_menu_entry(item_idx, parent, item_name, item)
+    }
+
+    // Drawn with a bullet instead of a check mark. Windows leaves unchecking
+    // the rest of the group to us, so the group id isn't needed here.
+    pub fn add_menu_radio_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        _group: u32,
+        checked: bool,
+    ) -> Result<(), Error> {
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_STATE;
+        item.fType = MFT_RADIOCHECK;
+        item.fState = if checked { MFS_CHECKED } else { MFS_UNCHECKED };
+        self.insert_menu_entry(item_idx, parent, item_name, item)
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+    ) -> Result<(), Error> {
+        let submenu = unsafe { create_menu()? };
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_SUBMENU;
+        item.hSubMenu = submenu;
+        self.insert_menu_entry(item_idx, parent, item_name, item)?;
+        self.submenus.borrow_mut().insert(item_idx, submenu);
+        let background = self.menu_drawing.lock().map(|d| d.background);
+        if let Ok(brush) = background {
+            if !brush.is_null() {
+                unsafe { set_menu_brush(submenu, brush)? };
             }
         }
         Ok(())
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) -> Result<(), Error> {
+    fn get_menu(&self, parent: Option<u32>) -> Result<HMENU, Error> {
+        match parent {
+            None => Ok(self.info.hmenu),
+            Some(p) => match self.submenus.borrow().get(&p) {
+                Some(hmenu) => Ok(*hmenu),
+                None => Err(Error::OsError(format!("No submenu {}", p))),
+            },
+        }
+    }
+
+    // Appends item to the end of the parent menu. The mask needs to be set up
+    // for whatever else the caller filled in.
+    unsafe fn append_menu_item(
+        &self,
+        parent: Option<u32>,
+        item: MENUITEMINFOW,
+    ) -> Result<(), Error> {
+        let hmenu = self.get_menu(parent)?;
+        let position = winuser::GetMenuItemCount(hmenu);
+        if position == -1
+            || winuser::InsertMenuItemW(hmenu, position as UINT, 1, &item as *const MENUITEMINFOW)
+                == 0
+        {
+            return Err(get_win_os_error("InsertMenuItemW"));
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        let check = if checked { MF_CHECKED } else { MF_UNCHECKED };
+        unsafe {
+            if winuser::CheckMenuItem(self.info.hmenu, item_idx, MF_BYCOMMAND | check) == u32::MAX {
+                return Err(get_win_os_error("CheckMenuItem"));
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_menu_entry(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        item_name: &str,
+        mut item: MENUITEMINFOW,
+    ) -> Result<(), Error> {
+        let mut st = to_wstring(&menu_label(item_name));
+        item.fMask |= MIIM_FTYPE | MIIM_STRING | MIIM_ID;
+        item.fType |= MFT_STRING;
+        item.wID = item_idx;
+        // In UTF-16 units, without the null.
+        item.cch = (st.len() - 1) as u32;
+        item.dwTypeData = st.as_mut_ptr();
+        if item.fType & MFT_OWNERDRAW == 0 {
+            self.menu_item_style(self.get_menu(parent)?, &mut item);
+        }
+        unsafe { self.append_menu_item(parent, item) }
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
         let mut item = get_menu_item_struct();
-        item.fMask = MIIM_FTYPE;
+        // Separators need their id as well, so they can be found for removal.
+        item.fMask = MIIM_FTYPE | MIIM_ID;
         item.fType = MFT_SEPARATOR;
         item.wID = item_idx;
+        self.menu_item_style(self.get_menu(parent)?, &mut item);
+        unsafe { self.append_menu_item(parent, item) }
+    }
+
+    // Owner drawn, as Windows menus have no headers, and disabled so it can't
+    // be picked. Its text is kept all the same, for drawing and for
+    // set_menu_item_label.
+    pub fn add_menu_header(
+        &self,
+        item_idx: u32,
+        parent: Option<u32>,
+        title: &str,
+    ) -> Result<(), Error> {
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_STATE | MIIM_DATA;
+        item.fType = MFT_OWNERDRAW;
+        item.fState = MFS_DISABLED;
+        item.dwItemData = self.get_menu(parent)? as ULONG_PTR;
+        self.insert_menu_entry(item_idx, parent, title, item)?;
+        if let Ok(mut drawing) = self.menu_drawing.lock() {
+            drawing.headers.insert(item_idx);
+        }
+        Ok(())
+    }
+
+    // Finds the menu an item lives in and its position there. Submenu items
+    // don't have a usable command id, so look them up by position instead of
+    // relying on MF_BYCOMMAND.
+    unsafe fn find_menu_item(&self, item_idx: u32) -> Result<(HMENU, UINT), Error> {
+        let submenus = self.submenus.borrow();
+        for hmenu in Some(&self.info.hmenu).into_iter().chain(submenus.values()) {
+            for position in 0..winuser::GetMenuItemCount(*hmenu).max(0) {
+                let mut item = get_menu_item_struct();
+                item.fMask = MIIM_ID;
+                if winuser::GetMenuItemInfoW(*hmenu, position as UINT, TRUE, &mut item) != 0
+                    && item.wID == item_idx
+                {
+                    return Ok((*hmenu, position as UINT));
+                }
+            }
+        }
+        Err(Error::OsError(format!("No menu item {}", item_idx)))
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
         unsafe {
-            if winuser::InsertMenuItemW(self.info.hmenu, item_idx, 1, &item as *const MENUITEMINFOW)
-                == 0
-            {
-                return Err(get_win_os_error("Error inserting separator"));
+            let (hmenu, position) = self.find_menu_item(item_idx)?;
+            // DeleteMenu also destroys the submenu of the item, if it has one.
+            if winuser::DeleteMenu(hmenu, position, MF_BYPOSITION) == 0 {
+                return Err(get_win_os_error("DeleteMenu"));
+            }
+            self.submenus
+                .borrow_mut()
+                .retain(|_, m| winuser::IsMenu(*m) != 0);
+            if let Some(bitmap) = self.item_bitmaps.borrow_mut().remove(&item_idx) {
+                wingdi::DeleteObject(bitmap as HGDIOBJ);
+            }
+            self.shortcuts.borrow_mut().remove(&item_idx);
+            if let Ok(mut tooltips) = self.item_tooltips.lock() {
+                tooltips.remove(&item_idx);
+            }
+            if let Ok(mut drawing) = self.menu_drawing.lock() {
+                drawing.headers.remove(&item_idx);
             }
         }
         Ok(())
     }
 
-    fn set_icon(&self, icon: HICON) -> Result<(), Error> {
+    pub fn set_menu_item_icon(&self, item_idx: u32, icon: &IconBuffer) -> Result<(), Error> {
         unsafe {
-            let mut nid = get_nid_struct(&self.info.hwnd);
-            nid.uFlags = NIF_ICON;
-            nid.hIcon = icon;
-            if shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error setting icon"));
+            let (hmenu, position) = self.find_menu_item(item_idx)?;
+            let bitmap = create_bitmap_from_rgba(&icon.rgba, icon.width, icon.height)?;
+            let mut item = get_menu_item_struct();
+            item.fMask = MIIM_BITMAP;
+            item.hbmpItem = bitmap;
+            if winuser::SetMenuItemInfoW(hmenu, position, TRUE, &item as *const MENUITEMINFOW) == 0
+            {
+                let e = get_win_os_error("SetMenuItemInfoW");
+                wingdi::DeleteObject(bitmap as HGDIOBJ);
+                return Err(e);
+            }
+            if let Some(old) = self.item_bitmaps.borrow_mut().insert(item_idx, bitmap) {
+                wingdi::DeleteObject(old as HGDIOBJ);
             }
         }
         Ok(())
     }
 
-    pub fn set_icon_from_resource(&self, resource_name: &str) -> Result<(), Error> {
-        let icon;
+    unsafe fn get_menu_item_text(&self, hmenu: HMENU, position: UINT) -> Result<String, Error> {
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_STRING;
+        if winuser::GetMenuItemInfoW(hmenu, position, TRUE, &mut item) == 0 {
+            return Err(get_win_os_error("GetMenuItemInfoW"));
+        }
+        let mut buffer = vec![0u16; item.cch as usize + 1];
+        item.dwTypeData = buffer.as_mut_ptr();
+        item.cch += 1;
+        if winuser::GetMenuItemInfoW(hmenu, position, TRUE, &mut item) == 0 {
+            return Err(get_win_os_error("GetMenuItemInfoW"));
+        }
+        Ok(String::from_utf16_lossy(&buffer[..item.cch as usize]))
+    }
+
+    unsafe fn set_menu_item_text(
+        &self,
+        hmenu: HMENU,
+        position: UINT,
+        text: &str,
+    ) -> Result<(), Error> {
+        let mut st = to_wstring(text);
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_STRING;
+        item.cch = (st.len() - 1) as u32;
+        item.dwTypeData = st.as_mut_ptr();
+        if winuser::SetMenuItemInfoW(hmenu, position, TRUE, &item as *const MENUITEMINFOW) == 0 {
+            return Err(get_win_os_error("SetMenuItemInfoW"));
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_label(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
+        let item_name = menu_label(item_name);
+        let text = match self.shortcuts.borrow().get(&item_idx) {
+            Some(s) => format!("{}\t{}", item_name, s),
+            None => item_name,
+        };
         unsafe {
-            icon = winuser::LoadImageW(
-                self.info.hinstance,
-                to_wstring(&resource_name).as_ptr(),
-                IMAGE_ICON,
-                64,
-                64,
-                0,
-            ) as HICON;
-            if icon == std::ptr::null_mut() as HICON {
-                return Err(get_win_os_error("Error setting icon from resource"));
+            let (hmenu, position) = self.find_menu_item(item_idx)?;
+            self.set_menu_item_text(hmenu, position, &text)
+        }
+    }
+
+    // Popup menus show whatever comes after a tab right aligned, which is
+    // where shortcuts go. Tray menus never have the focus needed to act on
+    // them as accelerators, so this is only the text.
+    pub fn set_menu_item_shortcut(&self, item_idx: u32, shortcut: &Shortcut) -> Result<(), Error> {
+        let shortcut = shortcut_text(shortcut);
+        unsafe {
+            let (hmenu, position) = self.find_menu_item(item_idx)?;
+            let label = self.get_menu_item_text(hmenu, position)?;
+            let label = label.split('\t').next().unwrap_or("");
+            let text = format!("{}\t{}", label, shortcut);
+            self.set_menu_item_text(hmenu, position, &text)?;
+        }
+        self.shortcuts.borrow_mut().insert(item_idx, shortcut);
+        Ok(())
+    }
+
+    // Empty removes the tooltip.
+    pub fn set_menu_item_tooltip(&self, item_idx: u32, tooltip: &str) -> Result<(), Error> {
+        unsafe { self.find_menu_item(item_idx)? };
+        let mut tooltips = self.item_tooltips.lock().unwrap_or_else(|e| e.into_inner());
+        if tooltip.is_empty() {
+            tooltips.remove(&item_idx);
+        } else {
+            tooltips.insert(item_idx, tooltip.to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        let enable = if enabled { MF_ENABLED } else { MF_GRAYED };
+        unsafe {
+            let (hmenu, position) = self.find_menu_item(item_idx)?;
+            if winuser::EnableMenuItem(hmenu, position, MF_BYPOSITION | enable) == -1 {
+                return Err(get_win_os_error("EnableMenuItem"));
             }
         }
-        self.set_icon(icon)
+        Ok(())
     }
 
-    pub fn set_icon_from_file(&self, icon_file: &str) -> Result<(), Error> {
-        let wstr_icon_file = to_wstring(&icon_file);
-        let hicon;
+    // Deletes every item in the top level menu, submenus included.
+    fn clear_menu(&self) -> Result<(), Error> {
         unsafe {
-            hicon = winuser::LoadImageW(
-                std::ptr::null_mut() as HINSTANCE,
-                wstr_icon_file.as_ptr(),
-                IMAGE_ICON,
-                64,
-                64,
-                LR_LOADFROMFILE,
-            ) as HICON;
-            if hicon == std::ptr::null_mut() as HICON {
-                return Err(get_win_os_error("Error setting icon from file"));
+            for position in (0..winuser::GetMenuItemCount(self.info.hmenu).max(0)).rev() {
+                if winuser::DeleteMenu(self.info.hmenu, position as UINT, MF_BYPOSITION) == 0 {
+                    return Err(get_win_os_error("DeleteMenu"));
+                }
+            }
+            self.submenus.borrow_mut().clear();
+            for (_, bitmap) in self.item_bitmaps.borrow_mut().drain() {
+                wingdi::DeleteObject(bitmap as HGDIOBJ);
+            }
+            self.shortcuts.borrow_mut().clear();
+            if let Ok(mut tooltips) = self.item_tooltips.lock() {
+                tooltips.clear();
+            }
+            if let Ok(mut drawing) = self.menu_drawing.lock() {
+                drawing.headers.clear();
             }
         }
-        self.set_icon(hicon)
+        Ok(())
+    }
+
+    pub(crate) fn apply_menu_ops(&self, ops: Vec<MenuOp>) -> Result<(), Error> {
+        for op in ops {
+            match op {
+                MenuOp::Entry(idx, parent, name) => self.add_menu_entry(idx, parent, &name)?,
+                MenuOp::CheckableEntry(idx, parent, name, checked) => {
+                    self.add_menu_checkable_entry(idx, parent, &name, checked)?
+                }
+                MenuOp::Separator(idx, parent) => self.add_menu_separator(idx, parent)?,
+                MenuOp::Header(idx, parent, title) => self.add_menu_header(idx, parent, &title)?,
+                MenuOp::Submenu(idx, parent, name) => self.add_submenu(idx, parent, &name)?,
+                MenuOp::SetEnabled(idx, enabled) => self.set_menu_item_enabled(idx, enabled)?,
+                MenuOp::Clear => self.clear_menu()?,
+            }
+        }
+        Ok(())
+    }
+
+    fn set_icon(&self, icon: HICON) -> Result<(), Error> {
+        self.set_icon_with_source(icon, None)
+    }
+
+    fn set_icon_with_source(&self, icon: HICON, source: Option<IconSource>) -> Result<(), Error> {
+        self.icon_hash.set(None);
+        // A panic while holding the lock leaves the state as it was, so
+        // carry on with it.
+        let mut current = self.icon.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe { replace_icon(&self.info, &mut current, icon, source) }
+    }
+
+    fn set_icon_from_source(&self, source: IconSource) -> Result<(), Error> {
+        let icon =
+            unsafe { load_icon(self.info.hinstance, &source, get_icon_size(self.info.hwnd))? };
+        self.set_icon_with_source(icon, Some(source))
+    }
+
+    // Icons from files and resources are loaded at the size the tray wants for
+    // the current DPI, and reloaded when it changes.
+    pub fn set_icon_from_resource(&self, resource: &IconResource) -> Result<(), Error> {
+        self.set_icon_from_source(IconSource::Resource(resource.clone()))
+    }
+
+    pub fn set_icon_from_file(&self, icon_file: &str) -> Result<(), Error> {
+        self.set_icon_from_source(IconSource::File(icon_file.to_owned()))
     }
 
     pub fn set_icon_from_buffer(
@@ -403,6 +2402,19 @@ impl Window {
         width: u32,
         height: u32,
     ) -> Result<(), Error> {
+        if self.icon_hash.get() == Some(hash_icon_buffer(buffer, width, height)) {
+            return Ok(());
+        }
+        self.force_icon_from_buffer(buffer, width, height)
+    }
+
+    pub fn force_icon_from_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        let entries = ico::get_ico_entries(buffer)?;
         let offset = unsafe {
             winuser::LookupIconIdFromDirectoryEx(
                 buffer.as_ptr() as PBYTE,
@@ -414,11 +2426,15 @@ impl Window {
         };
 
         if offset != 0 {
-            let icon_data = &buffer[offset as usize..];
+            let (offset, size) = match entries.iter().find(|(o, _)| *o == offset as usize) {
+                Some(e) => *e,
+                None => return Err(Error::InvalidIcon("No matching icon image".to_owned())),
+            };
+            let icon_data = &buffer[offset..offset + size];
             let hicon = unsafe {
                 winuser::CreateIconFromResourceEx(
                     icon_data.as_ptr() as PBYTE,
-                    0,
+                    size as DWORD,
                     TRUE,
                     0x30000,
                     width as i32,
@@ -428,21 +2444,145 @@ impl Window {
             };
 
             if hicon == std::ptr::null_mut() as HICON {
-                return Err(unsafe { get_win_os_error("Cannot load icon from the buffer") });
+                return Err(unsafe { get_win_os_error("CreateIconFromResourceEx") });
             }
 
-            self.set_icon(hicon)
+            self.set_icon(hicon)?;
+            let hash = hash_icon_buffer(buffer, width, height);
+            self.icon_hash.set(Some(hash));
+            Ok(())
         } else {
-            Err(unsafe { get_win_os_error("Error setting icon from buffer") })
+            Err(unsafe { get_win_os_error("LookupIconIdFromDirectoryEx") })
         }
     }
 
+    pub fn set_icon_from_rgba(&self, rgba: &[u8], width: u32, height: u32) -> Result<(), Error> {
+        let scratch = &mut *self.icon_scratch.borrow_mut();
+        let hicon = create_icon_from_rgba(rgba, width, height, scratch)?;
+        self.set_icon(hicon)
+    }
+
+    pub fn set_icon_animation(
+        &self,
+        frames: Vec<IconBuffer>,
+        interval: Duration,
+    ) -> Result<(), Error> {
+        let mut icons = Vec::with_capacity(frames.len());
+        let scratch = &mut *self.icon_scratch.borrow_mut();
+        for frame in frames.iter() {
+            match create_icon_from_rgba(&frame.rgba, frame.width, frame.height, scratch) {
+                Ok(icon) => icons.push(icon),
+                Err(e) => {
+                    for icon in icons {
+                        unsafe { winuser::DestroyIcon(icon) };
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        // The frames belong to the window thread from here on, so show a copy
+        // of the first one.
+        if let Some(first) = icons.first() {
+            self.set_icon(unsafe { winuser::CopyIcon(*first) })?;
+        }
+        let interval = interval.as_millis().max(1) as WPARAM;
+        let icons = Box::into_raw(Box::new(icons));
+        unsafe {
+            if winuser::PostMessageW(
+                self.info.hwnd,
+                WM_SET_ICON_ANIMATION,
+                interval,
+                icons as LPARAM,
+            ) == 0
+            {
+                for icon in *Box::from_raw(icons) {
+                    winuser::DestroyIcon(icon);
+                }
+                return Err(get_win_os_error("PostMessageW"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        unsafe {
+            if winuser::PostMessageW(self.info.hwnd, WM_SET_ICON_ANIMATION, 0, 0) == 0 {
+                return Err(get_win_os_error("PostMessageW"));
+            }
+        }
+        Ok(())
+    }
+
+    // Size the notification area draws icons at, for the current DPI.
+    pub fn icon_size(&self) -> Option<(u32, u32)> {
+        let (width, height) = unsafe { get_icon_size(self.info.hwnd) };
+        Some((width as u32, height as u32))
+    }
+
+    // Shows a balloon, or a toast on Windows 10, anchored to the icon. Titles
+    // and bodies longer than the shell allows are cut off.
+    pub fn show_notification(
+        &self,
+        title: &str,
+        body: &str,
+        icon: Option<&IconBuffer>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let mut nid = get_nid_struct(&self.info);
+        nid.uFlags |= NIF_INFO;
+        copy_to_wstring(&mut nid.szInfoTitle, title);
+        copy_to_wstring(&mut nid.szInfo, body);
+        unsafe {
+            // Ignored since Vista, but doesn't hurt on older versions.
+            *nid.u.uTimeout_mut() = timeout.as_millis().min(UINT::MAX as u128) as UINT;
+        }
+        nid.dwInfoFlags = NIIF_NONE;
+        if let Some(icon) = icon {
+            let scratch = &mut *self.icon_scratch.borrow_mut();
+            nid.hBalloonIcon = create_icon_from_rgba(&icon.rgba, icon.width, icon.height, scratch)?;
+            nid.dwInfoFlags = NIIF_USER;
+        }
+        unsafe {
+            let result = shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW);
+            // The shell makes its own copy of the balloon icon.
+            if !nid.hBalloonIcon.is_null() {
+                winuser::DestroyIcon(nid.hBalloonIcon);
+            }
+            if result == 0 {
+                return Err(get_win_os_error("Shell_NotifyIconW"));
+            }
+        }
+        Ok(())
+    }
+
+    // The notification area reads out the tooltip, there is no separate
+    // description for the icon itself. It goes in the tip while there's no
+    // tooltip, without showing.
+    pub fn set_icon_accessibility_description(&self, desc: &str) -> Result<(), Error> {
+        let mut current = self.icon.lock().unwrap_or_else(|e| e.into_inner());
+        let old = std::mem::replace(&mut current.description, desc.to_owned());
+        if !current.hidden && current.tooltip.is_empty() {
+            if let Err(e) = unsafe { set_tooltip(&self.info, &current) } {
+                current.description = old;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pending_command_count(&self) -> usize {
+        // Every call finishes its work before returning, nothing ever queues.
+        0
+    }
+
     pub fn shutdown(&self) -> Result<(), Error> {
+        if self.icon.lock().map(|c| c.hidden).unwrap_or(false) {
+            return Ok(());
+        }
         unsafe {
-            let mut nid = get_nid_struct(&self.info.hwnd);
-            nid.uFlags = NIF_ICON;
+            let mut nid = get_nid_struct(&self.info);
             if shellapi::Shell_NotifyIconW(NIM_DELETE, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error deleting icon from menu"));
+                return Err(get_win_os_error("Shell_NotifyIconW"));
             }
         }
         Ok(())
@@ -452,5 +2592,24 @@ impl Window {
 impl Drop for Window {
     fn drop(&mut self) {
         self.shutdown().ok();
+        // Stops the loop thread, if quit didn't already.
+        self.quit();
+        unsafe {
+            winuser::DestroyMenu(self.info.hmenu);
+            for (_, bitmap) in self.item_bitmaps.borrow_mut().drain() {
+                wingdi::DeleteObject(bitmap as HGDIOBJ);
+            }
+            if let Ok(drawing) = self.menu_drawing.lock() {
+                if !drawing.background.is_null() {
+                    wingdi::DeleteObject(drawing.background as HGDIOBJ);
+                }
+            }
+            if let Ok(mut current) = self.icon.lock() {
+                if !current.handle.is_null() {
+                    winuser::DestroyIcon(current.handle);
+                    current.handle = std::ptr::null_mut();
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,43 @@
+// ShellExecute does what double-clicking would: URLs go to the default browser
+// and explorer.exe with /select opens the folder with the file selected.
+use super::to_wstring;
+use crate::Error;
+use std::{path::Path, ptr};
+use winapi::{
+    shared::minwindef::HINSTANCE,
+    um::{shellapi, winuser::SW_SHOWNORMAL},
+};
+
+fn shell_execute(file: &str, parameters: Option<&str>) -> Result<(), Error> {
+    let verb = to_wstring("open");
+    let file = to_wstring(file);
+    let parameters = parameters.map(to_wstring);
+    let result: HINSTANCE = unsafe {
+        shellapi::ShellExecuteW(
+            ptr::null_mut(),
+            verb.as_ptr(),
+            file.as_ptr(),
+            parameters.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+            ptr::null(),
+            SW_SHOWNORMAL,
+        )
+    };
+    // Anything up to 32 is an error code rather than an instance handle.
+    if result as usize <= 32 {
+        return Err(Error::WinApi {
+            code: result as usize as u32,
+            call: "ShellExecuteW",
+        });
+    }
+    Ok(())
+}
+
+pub fn open_url(url: &str) -> Result<(), Error> {
+    shell_execute(url, None)
+}
+
+// Explorer wants the path quoted after the comma, not the whole argument.
+pub fn reveal(path: &Path) -> Result<(), Error> {
+    let select = format!("/select,\"{}\"", path.display());
+    shell_execute("explorer.exe", Some(&select))
+}
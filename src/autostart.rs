@@ -0,0 +1,36 @@
+// Starting the app when the user logs in, the way each platform expects it:
+// a value under the Run key on Windows, an XDG autostart entry on Linux, and
+// SMAppService or a LaunchAgent on macOS. What gets started is the executable
+// that is running now, so call these from the installed app. The Application
+// methods of the same name go through the tray's backend instead, so the mock
+// one only records them.
+use crate::{api, Error};
+use std::{env, path::PathBuf};
+
+pub fn enable() -> Result<(), Error> {
+    let (name, exe) = current_app()?;
+    api::native::autostart::enable(&name, &exe)
+}
+
+pub fn disable() -> Result<(), Error> {
+    let (name, _) = current_app()?;
+    api::native::autostart::disable(&name)
+}
+
+pub fn is_enabled() -> Result<bool, Error> {
+    let (name, _) = current_app()?;
+    api::native::autostart::is_enabled(&name)
+}
+
+// Entries are named after the executable, so they stay the same across
+// versions that are installed in a different place.
+pub(crate) fn current_app() -> Result<(String, PathBuf), Error> {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return Err(Error::OsError(format!("Couldn't find the executable: {}", e))),
+    };
+    match exe.file_stem() {
+        Some(name) => Ok((name.to_string_lossy().into_owned(), exe)),
+        None => Err(Error::OsError(format!("{} has no file name", exe.display()))),
+    }
+}
@@ -0,0 +1,118 @@
+// Draws badges onto icons in plain RGBA, so every backend gets the same
+// pixels and none of them needs its own compositing.
+use crate::{BadgeSpec, IconBuffer};
+
+const COUNT_COLOR: [u8; 4] = [0xe5, 0x39, 0x35, 0xff];
+const TEXT_COLOR: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+// 3x5 pixel glyphs, one row per byte with the leftmost pixel in bit 2.
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+const PLUS: [u8; 5] = [0b000, 0b010, 0b111, 0b010, 0b000];
+
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_digit(10) {
+        Some(d) => DIGITS[d as usize],
+        None => PLUS,
+    }
+}
+
+// Source over destination, both with straight alpha.
+fn blend(dst: &mut [u8], src: [u8; 4], coverage: f32) {
+    let sa = src[3] as f32 / 255.0 * coverage;
+    let da = dst[3] as f32 / 255.0;
+    let a = sa + da * (1.0 - sa);
+    if a <= 0.0 {
+        return;
+    }
+    for i in 0..3 {
+        let c = (src[i] as f32 * sa + dst[i] as f32 * da * (1.0 - sa)) / a;
+        dst[i] = c.round() as u8;
+    }
+    dst[3] = (a * 255.0).round() as u8;
+}
+
+// Pill in the top right corner, height pixels high and at least as wide.
+// Short pills are circles.
+fn draw_pill(icon: &mut IconBuffer, width: u32, height: u32, color: [u8; 4]) {
+    let r = height as f32 / 2.0;
+    let right = icon.width as f32 - r;
+    // Past right when the pill got cut down to a narrow icon.
+    let left = (icon.width as f32 - width as f32 + r).min(right);
+    for y in 0..height.min(icon.height) {
+        for x in icon.width.saturating_sub(width)..icon.width {
+            let px = x as f32 + 0.5;
+            let py = y as f32 + 0.5;
+            let cx = px.clamp(left, right);
+            let dist = ((px - cx).powi(2) + (py - r).powi(2)).sqrt();
+            let coverage = (r - dist + 0.5).clamp(0.0, 1.0);
+            if coverage > 0.0 {
+                let i = ((y * icon.width + x) * 4) as usize;
+                blend(&mut icon.rgba[i..i + 4], color, coverage);
+            }
+        }
+    }
+}
+
+fn draw_text(icon: &mut IconBuffer, text: &str, x0: u32, y0: u32, scale: u32) {
+    for (n, c) in text.chars().enumerate() {
+        let gx = x0 + n as u32 * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let x = gx + col * scale + dx;
+                        let y = y0 + row as u32 * scale + dy;
+                        if x < icon.width && y < icon.height {
+                            let i = ((y * icon.width + x) * 4) as usize;
+                            blend(&mut icon.rgba[i..i + 4], TEXT_COLOR, 1.0);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn draw(base: &IconBuffer, badge: &BadgeSpec) -> IconBuffer {
+    let mut icon = base.clone();
+    let size = base.width.min(base.height) as f32;
+    match *badge {
+        BadgeSpec::Dot(color) => {
+            let d = ((size * 0.45).round() as u32).max(3);
+            draw_pill(&mut icon, d, d, color);
+        }
+        BadgeSpec::Count(count) => {
+            let text = if count > 99 {
+                "99+".to_owned()
+            } else {
+                count.to_string()
+            };
+            let d = ((size * 0.6).round() as u32).max(GLYPH_HEIGHT + 2);
+            let scale = ((d as f32 * 0.6 / GLYPH_HEIGHT as f32) as u32).max(1);
+            let chars = text.chars().count() as u32;
+            let text_width = (chars * (GLYPH_WIDTH + 1) - 1) * scale;
+            let width = (text_width + d / 2).max(d).min(icon.width);
+            draw_pill(&mut icon, width, d, COUNT_COLOR);
+            let x0 = (icon.width - width) + width.saturating_sub(text_width) / 2;
+            let y0 = d.saturating_sub(GLYPH_HEIGHT * scale) / 2;
+            draw_text(&mut icon, &text, x0, y0, scale);
+        }
+    }
+    icon
+}
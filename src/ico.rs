@@ -0,0 +1,137 @@
+// Reading .ico files, kept out of the backends so it can be tested
+// anywhere.
+use crate::Error;
+
+fn read_u16(buffer: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buffer[at], buffer[at + 1]])
+}
+
+fn read_u32(buffer: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buffer[at], buffer[at + 1], buffer[at + 2], buffer[at + 3]])
+}
+
+// The Windows resource functions trust the icon directory completely and
+// will read past the end of the buffer if it lies, so check the header and
+// every entry before handing it over. Returns the (offset, size) of each
+// image, in directory order.
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+pub(crate) fn get_ico_entries(buffer: &[u8]) -> Result<Vec<(usize, usize)>, Error> {
+    if buffer.len() < 6 || read_u16(buffer, 0) != 0 || read_u16(buffer, 2) != 1 {
+        return Err(Error::InvalidIcon("Not an ico file".to_owned()));
+    }
+    let count = read_u16(buffer, 4) as usize;
+    let header_len = 6 + count * 16;
+    if count == 0 || buffer.len() < header_len {
+        return Err(Error::InvalidIcon("Truncated icon directory".to_owned()));
+    }
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = 6 + i * 16;
+        let size = read_u32(buffer, entry + 8) as usize;
+        let offset = read_u32(buffer, entry + 12) as usize;
+        let end = offset.saturating_add(size);
+        if size == 0 || offset < header_len || end > buffer.len() {
+            let msg = format!("Icon image {} is out of bounds", i);
+            return Err(Error::InvalidIcon(msg));
+        }
+        entries.push((offset, size));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_ico_entries;
+
+    // An icon directory with one entry per (offset, size), then room for the
+    // images up to len.
+    fn ico(entries: &[(u32, u32)], len: usize) -> Vec<u8> {
+        let mut buffer = vec![0, 0, 1, 0];
+        buffer.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for &(offset, size) in entries {
+            buffer.extend_from_slice(&[16, 16, 0, 0, 1, 0, 32, 0]);
+            buffer.extend_from_slice(&size.to_le_bytes());
+            buffer.extend_from_slice(&offset.to_le_bytes());
+        }
+        buffer.resize(len.max(buffer.len()), 0);
+        buffer
+    }
+
+    #[test]
+    fn reads_every_entry() {
+        let buffer = ico(&[(38, 10), (48, 20)], 68);
+        assert_eq!(get_ico_entries(&buffer).unwrap(), [(38, 10), (48, 20)]);
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert!(get_ico_entries(&[]).is_err());
+        assert!(get_ico_entries(&[0, 0, 1, 0, 1]).is_err());
+        // Two entries announced, one there.
+        let mut buffer = ico(&[(38, 10)], 48);
+        buffer[4] = 2;
+        buffer.truncate(6 + 16 + 8);
+        assert!(get_ico_entries(&buffer).is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut buffer = ico(&[(22, 10)], 32);
+        buffer[2] = 2;
+        assert!(get_ico_entries(&buffer).is_err());
+    }
+
+    #[test]
+    fn rejects_zero_counts() {
+        assert!(get_ico_entries(&ico(&[], 6)).is_err());
+    }
+
+    #[test]
+    fn rejects_entries_past_the_end() {
+        assert!(get_ico_entries(&ico(&[(22, 11)], 32)).is_err());
+        assert!(get_ico_entries(&ico(&[(33, 0)], 32)).is_err());
+        assert!(get_ico_entries(&ico(&[(u32::MAX, 2)], 32)).is_err());
+        assert!(get_ico_entries(&ico(&[(22, u32::MAX)], 32)).is_err());
+        // Inside the buffer, but over the directory.
+        assert!(get_ico_entries(&ico(&[(4, 10)], 32)).is_err());
+    }
+
+    // Whatever comes back has to lie inside the buffer.
+    fn check(buffer: &[u8]) {
+        if let Ok(entries) = get_ico_entries(buffer) {
+            for (offset, size) in entries {
+                assert!(size > 0 && offset + size <= buffer.len());
+            }
+        }
+    }
+
+    #[test]
+    fn survives_truncation_anywhere() {
+        let buffer = ico(&[(38, 10), (48, 20)], 68);
+        for len in 0..buffer.len() {
+            assert!(get_ico_entries(&buffer[..len]).is_err(), "{} bytes", len);
+        }
+        check(&buffer);
+    }
+
+    #[test]
+    fn survives_random_bytes() {
+        // xorshift, seeded so a failure can be reproduced.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..20_000 {
+            let len = (next() % 160) as usize;
+            let mut buffer: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Get most of them past the header check, with a small count.
+            if len >= 6 && next() % 4 != 0 {
+                buffer[..6].copy_from_slice(&[0, 0, 1, 0, (next() % 4) as u8, 0]);
+            }
+            check(&buffer);
+        }
+    }
+}
@@ -1,114 +1,2380 @@
 // Systray Lib
 pub mod api;
+pub mod autostart;
+mod badge;
+mod ico;
+pub mod open;
+#[cfg(feature = "winit")]
+pub mod winit;
+#[cfg(any(target_os = "windows", test))]
+mod wstring;
+
+pub use api::mock::{MockIcon, MockItemKind, MockMenuItem, MockNotification, MockTray};
 
 use std::{
-    collections::HashMap,
-    error, fmt,
-    sync::mpsc::{channel, Receiver},
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    env, error, fmt, iter,
+    os::raw::c_void,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, SendError, Sender, TryRecvError},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "async")]
+use futures::{
+    stream::{self, Stream},
+    task::{AtomicWaker, Poll},
 };
 
 type BoxedError = Box<dyn error::Error + Send + Sync + 'static>;
 
-#[derive(Debug)]
-pub enum Error {
-    OsError(String),
-    NotImplementedError,
-    UnknownError,
-    Error(BoxedError),
-}
+#[derive(Debug)]
+pub enum Error {
+    OsError(String),
+    /// A Windows API call failed, code is what GetLastError or the call itself
+    /// returned.
+    WinApi { code: u32, call: &'static str },
+    /// An Objective-C message returned nil.
+    Cocoa { selector: &'static str },
+    /// A GLib, GIO or GDK call failed with a GError.
+    Glib { call: &'static str, message: String },
+    InvalidIcon(String),
+    InvalidShortcut(String),
+    /// The platform, or the tray host in use, can't do this.
+    NotSupported { feature: &'static str },
+    /// The Application or its backend went away.
+    Shutdown,
+    /// Nothing in this session shows tray icons: no StatusNotifierItem host or
+    /// XEmbed tray on Linux, no taskbar on Windows.
+    NoTrayAvailable,
+    /// A menu callback panicked, for the error handler. The event loop also
+    /// gets a CallbackPanicked event for it.
+    CallbackPanicked(String),
+    Error(BoxedError),
+}
+
+impl From<BoxedError> for Error {
+    fn from(value: BoxedError) -> Self {
+        Error::Error(value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    /// Never opens the menu, so it's free for a primary action like play and
+    /// pause. StatusNotifierItem hosts send it as SecondaryActivate.
+    Middle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrollAxis {
+    Vertical,
+    Horizontal,
+}
+
+/// Which side of the menu items start on, and which way submenus open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayoutDirection {
+    LeftToRight,
+    RightToLeft,
+}
+
+/// Who runs the main thread's event loop on macOS. The other platforms always
+/// run their own on a thread of the backend, and ignore this.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ApplicationMode {
+    /// The tray sets up NSApplication as an accessory app and next_event and
+    /// friends pump its events. The default.
+    #[default]
+    OwnRunLoop,
+    /// Something else, such as winit or tao, already runs NSApp on the main
+    /// thread. Only the status item and menu are added and the activation
+    /// policy is left alone. Call poll_event or dispatch_pending from the
+    /// host's loop, next_event would take the loop over.
+    AttachToExisting,
+}
+
+/// Whether a macOS app shows up in the Dock and the app switcher. Tray apps
+/// are Accessory by default, so they only have their status item.
+#[cfg(target_os = "macos")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActivationPolicy {
+    /// A Dock tile and a menu bar of its own, like any other app.
+    Regular,
+    /// No Dock tile, but windows can still be brought to the front.
+    Accessory,
+    /// No Dock tile and no windows either.
+    Prohibited,
+}
+
+/// What shows the icon. Auto picks the best one the system has, the others
+/// force one for setups where that guess is wrong. Forcing one of another
+/// platform, or one that isn't there, fails with NotSupported or the error
+/// of setting it up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Auto,
+    /// GtkStatusIcon in the XEmbed tray, on Linux.
+    Gtk,
+    /// Our own StatusNotifierItem on the session bus, on Linux.
+    StatusNotifier,
+    /// libappindicator, on Linux with the appindicator feature.
+    AppIndicator,
+    Win32,
+    Cocoa,
+    /// Shows nothing and records what the app does, for tests. See
+    /// Application::mock_tray.
+    Mock,
+}
+
+impl Backend {
+    fn from_name(name: &str) -> Option<Backend> {
+        match name.to_ascii_lowercase().as_str() {
+            "auto" => Some(Backend::Auto),
+            "gtk" | "xembed" => Some(Backend::Gtk),
+            "sni" | "statusnotifier" => Some(Backend::StatusNotifier),
+            "appindicator" => Some(Backend::AppIndicator),
+            "win32" => Some(Backend::Win32),
+            "cocoa" => Some(Backend::Cocoa),
+            "mock" => Some(Backend::Mock),
+            _ => None,
+        }
+    }
+
+    // Users can pick one with SYSTRAY_BACKEND, by the lowercase name of the
+    // variant or sni for StatusNotifier. It only takes over from Auto, a
+    // backend the app asked for itself stays.
+    fn resolve(self) -> Backend {
+        if self != Backend::Auto {
+            return self;
+        }
+        let name = match env::var("SYSTRAY_BACKEND") {
+            Ok(name) => name,
+            Err(_) => return self,
+        };
+        match Backend::from_name(name.trim()) {
+            Some(backend) => backend,
+            None => {
+                log::warn!("Unknown SYSTRAY_BACKEND {:?}, picking one instead", name);
+                self
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+/// An area of the screen, in the platform's own screen coordinates: pixels
+/// from the top left of the screen on Windows and Linux, points from
+/// the bottom left of the main screen on macOS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What the platform and the tray host in use can do, so apps can leave out
+/// what wouldn't work. Calls for the things that are false fail with
+/// NotSupported.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub supports_tooltip: bool,
+    /// set_title, text shown next to the icon.
+    pub supports_title_text: bool,
+    pub supports_menu_icons: bool,
+    pub supports_notifications: bool,
+    /// IconClicked events, and with them set_menu_on_left_click.
+    pub supports_icon_clicks: bool,
+    pub supports_double_click: bool,
+    pub supports_icon_screen_rect: bool,
+    pub supports_icon_from_file: bool,
+    pub supports_icon_accessibility_description: bool,
+    pub supports_global_hotkeys: bool,
+    /// FilesDropped events.
+    pub supports_file_drops: bool,
+    /// Scroll events.
+    pub supports_scroll: bool,
+}
+
+/// The platform objects behind the icon, for doing what this crate doesn't
+/// wrap. Pointers stay valid for as long as the Application does and belong
+/// to its backend thread: the window thread on Windows, the gtk thread on
+/// Linux and the main thread on macOS.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NativeHandle {
+    /// The hidden window that gets the icon's messages, and the uID the icon
+    /// was added with.
+    Windows {
+        hwnd: *mut c_void,
+        uid: u32,
+    },
+    /// The NSStatusItem.
+    MacOS {
+        status_item: *mut c_void,
+    },
+    /// The AppIndicator object, on Linux.
+    AppIndicator(*mut c_void),
+    /// Where the StatusNotifierItem is exported on the session bus, on Linux.
+    StatusNotifierItem {
+        bus_name: String,
+        object_path: String,
+    },
+    /// The GtkStatusIcon, on Linux.
+    GtkStatusIcon(*mut c_void),
+}
+
+// Only the addresses move between threads, the objects are still only to be
+// touched from their own.
+unsafe impl Send for NativeHandle {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HotkeyId(pub(crate) u32);
+
+/// Everything that happens to the tray, for matching on in one place. More
+/// kinds may be added, so matches need a catch-all arm.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq)]
+pub enum SystrayEvent {
+    /// tag is whatever the app tagged the item with, see set_menu_item_tag.
+    MenuItemClicked {
+        item: MenuItem,
+        tag: Option<u64>,
+    },
+    HotkeyPressed(HotkeyId),
+    /// The OS switched between dark and light appearance, e.g. to pick a
+    /// matching icon.
+    ThemeChanged(Theme),
+    /// Clicks on the tray icon itself. Not available with AppIndicator on
+    /// Linux, which only ever opens the menu. x and y are where the click was,
+    /// in the same coordinates as Rect, and time is when it happened, taken
+    /// from the OS event where there is one.
+    IconClicked {
+        button: MouseButton,
+        x: i32,
+        y: i32,
+        time: Instant,
+    },
+    /// Comes after the IconClicked for the first click. Not on Linux.
+    IconDoubleClicked {
+        button: MouseButton,
+        x: i32,
+        y: i32,
+        time: Instant,
+    },
+    /// The wheel or touchpad scrolled over the icon. delta is in wheel notches,
+    /// fractions of one for smooth scrolling, and positive is up or right.
+    /// Not on Windows.
+    Scroll {
+        delta: f64,
+        axis: ScrollAxis,
+    },
+    /// The icon was picked with the keyboard, with Enter or Space while it has
+    /// the focus, or by a screen reader, in place of IconClicked. On Windows,
+    /// macOS and the XEmbed tray. StatusNotifierItem hosts call Activate the
+    /// same way for keys and clicks, so those still come as IconClicked.
+    IconKeySelected,
+    /// The pointer came to rest on the icon and its tooltip shows. Only on
+    /// Windows.
+    TooltipShown,
+    /// Files were dragged onto the icon, e.g. from Finder, and dropped there.
+    /// Only on macOS.
+    FilesDropped(Vec<PathBuf>),
+    /// The tray menu is about to show, for filling in entries that are costly
+    /// to keep up to date. The menu doesn't wait for the app, so changes may
+    /// only show the next time it opens; on macOS they always do, as the menu
+    /// runs the main loop while it's open. Not with AppIndicator on Linux.
+    MenuWillOpen,
+    /// The tray menu closed, after the MenuItemClicked for the item picked.
+    MenuDidClose,
+    /// A menu callback or the backend's own event handling panicked. The tray
+    /// keeps running; item is the one whose callback it was, if any.
+    CallbackPanicked {
+        item: Option<MenuItem>,
+        message: String,
+    },
+    /// The tray went away, with the panel or Explorer quitting or crashing, or
+    /// came back. The icon and menu go back in by themselves. Windows only
+    /// tells when the taskbar comes back, macOS neither.
+    TrayHostChanged(bool),
+    /// The tray shut down, the last event before the event iterators end.
+    Quit,
+}
+
+impl SystrayEvent {
+    pub fn menu_index(&self) -> Option<u32> {
+        match *self {
+            SystrayEvent::MenuItemClicked { item, .. } => Some(item.0),
+            _ => None,
+        }
+    }
+
+    pub fn menu_item_tag(&self) -> Option<u64> {
+        match *self {
+            SystrayEvent::MenuItemClicked { tag, .. } => tag,
+            _ => None,
+        }
+    }
+
+    /// The item that was clicked, to compare with the handles add_menu_item
+    /// and friends gave out.
+    pub fn menu_item(&self) -> Option<MenuItem> {
+        self.menu_index().map(MenuItem)
+    }
+}
+
+/// Handle to an item in the menu, handed out when it is added. Handles stay
+/// valid for as long as the item exists and are never reused, so one that
+/// outlives its item just gets errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MenuItem(pub(crate) u32);
+
+impl MenuItem {
+    /// The index events carry for this item.
+    pub fn index(self) -> u32 {
+        self.0
+    }
+
+    pub fn set_label<S>(self, app: &Application<S>, label: &str) -> Result<(), Error> {
+        app.set_menu_item_label(self, label)
+    }
+
+    pub fn set_enabled<S>(self, app: &Application<S>, enabled: bool) -> Result<(), Error> {
+        app.set_menu_item_enabled(self, enabled)
+    }
+
+    pub fn enabled<S>(self, app: &Application<S>) -> bool {
+        app.menu_item_enabled(self)
+    }
+
+    pub fn set_checked<S>(self, app: &mut Application<S>, checked: bool) -> Result<(), Error> {
+        app.set_menu_item_checked(self, checked)
+    }
+
+    pub fn checked<S>(self, app: &Application<S>) -> Option<bool> {
+        app.menu_item_checked(self)
+    }
+
+    pub fn set_tag<S>(self, app: &Application<S>, tag: Option<u64>) {
+        app.set_menu_item_tag(self, tag)
+    }
+
+    pub fn tag<S>(self, app: &Application<S>) -> Option<u64> {
+        app.menu_item_tag(self)
+    }
+
+    pub fn set_icon<S>(self, app: &Application<S>, icon: &IconBuffer) -> Result<(), Error> {
+        app.set_menu_item_icon(self, icon)
+    }
+
+    pub fn set_shortcut<S>(self, app: &Application<S>, shortcut: &str) -> Result<(), Error> {
+        app.set_menu_item_shortcut(self, shortcut)
+    }
+
+    pub fn set_tooltip<S>(self, app: &Application<S>, tooltip: &str) -> Result<(), Error> {
+        app.set_menu_item_tooltip(self, tooltip)
+    }
+
+    pub fn remove<S>(self, app: &mut Application<S>) -> Result<(), Error> {
+        app.remove_menu_item(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    /// Command on macOS, the Windows or Super key elsewhere.
+    pub meta: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    /// Letters are always upper case, holding shift is up to the modifiers.
+    Char(char),
+    /// F1 to F24. Global hotkeys on F21 to F24 are not supported on macOS.
+    F(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shortcut {
+    pub modifiers: Modifiers,
+    pub key: Key,
+}
+
+impl Shortcut {
+    /// Parses shortcuts like "Ctrl+Shift+S" or "F5", with the key last. "Cmd"
+    /// is Command on macOS and Ctrl everywhere else, so one string gives the
+    /// shortcut users expect on each platform.
+    pub fn parse(shortcut: &str) -> Result<Shortcut, Error> {
+        let invalid = |why: &str| Error::InvalidShortcut(format!("{:?}: {}", shortcut, why));
+        let mut parts: Vec<&str> = shortcut.split('+').map(|p| p.trim()).collect();
+        let key = parts.pop().unwrap_or("");
+        let mut modifiers = Modifiers::default();
+        for part in parts {
+            match part.to_lowercase().as_str() {
+                "ctrl" | "control" => modifiers.ctrl = true,
+                "alt" | "option" => modifiers.alt = true,
+                "shift" => modifiers.shift = true,
+                "meta" | "super" | "win" => modifiers.meta = true,
+                "cmd" | "command" | "cmdorctrl" => {
+                    if cfg!(target_os = "macos") {
+                        modifiers.meta = true
+                    } else {
+                        modifiers.ctrl = true
+                    }
+                }
+                _ => return Err(invalid(&format!("unknown modifier {}", part))),
+            }
+        }
+        let mut chars = key.chars();
+        let key = match (chars.next(), chars.next()) {
+            (Some(c), None) => Key::Char(c.to_ascii_uppercase()),
+            (Some('F'), Some(_)) | (Some('f'), Some(_)) => match key[1..].parse::<u8>() {
+                Ok(n) if (1..=24).contains(&n) => Key::F(n),
+                _ => return Err(invalid("no such function key")),
+            },
+            (None, _) => return Err(invalid("no key")),
+            _ => return Err(invalid(&format!("unknown key {}", key))),
+        };
+        Ok(Shortcut {
+            modifiers,
+            key,
+        })
+    }
+}
+
+/// Drawn over the top right corner of the icon by Application::set_icon_badge.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BadgeSpec {
+    /// White digits on red, 99+ past 99.
+    Count(u32),
+    /// A plain dot of the given RGBA color.
+    Dot([u8; 4]),
+}
+
+/// How Application::set_menu_style draws the menu on Windows. Whatever is
+/// None keeps the system's menu font, or colors close to the Windows 11
+/// flyouts. Colors are RGB.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MenuStyle {
+    /// Name of the typeface, e.g. "Segoe UI Variable Text".
+    pub font: Option<String>,
+    /// In points.
+    pub font_size: Option<u32>,
+    pub text_color: Option<[u8; 3]>,
+    pub background: Option<[u8; 3]>,
+    /// Behind the item under the pointer.
+    pub highlight: Option<[u8; 3]>,
+    /// Dark or light colors for those not set. None follows the theme of the
+    /// taskbar, switching over along with ThemeChanged.
+    pub dark: Option<bool>,
+}
+
+/// Which of the items most tray menus end with Application::add_standard_items
+/// should add.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StandardItems<'a> {
+    /// Label for an item that opens the About window: the standard About
+    /// panel on macOS, with the details from the app bundle, and show_about
+    /// with the executable's name elsewhere. For more than the name, call
+    /// show_about from an item of the app's own instead.
+    pub about: Option<&'a str>,
+    /// Adds a checkable item that turns autostart on and off.
+    pub launch_at_login: bool,
+    /// Adds an item that ends the tray, "Exit" on Windows and "Quit" elsewhere.
+    pub quit: bool,
+}
+
+/// The items add_standard_items added.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StandardMenuItems {
+    pub about: Option<MenuItem>,
+    pub launch_at_login: Option<MenuItem>,
+    pub quit: Option<MenuItem>,
+}
+
+/// What Application::show_about shows. Whatever is None or empty is left out.
+#[derive(Clone, Default)]
+pub struct AboutMetadata {
+    pub name: String,
+    pub version: Option<String>,
+    pub authors: Vec<String>,
+    pub license: Option<String>,
+    pub website: Option<String>,
+    /// Not shown on Windows, whose message box only takes icons from
+    /// resources.
+    pub icon: Option<IconBuffer>,
+}
+
+/// An icon as RGBA pixels, for animation frames and notifications.
+#[derive(Clone)]
+pub struct IconBuffer {
+    pub(crate) rgba: Vec<u8>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl IconBuffer {
+    pub fn from_rgba(rgba: Vec<u8>, width: u32, height: u32) -> Result<IconBuffer, Error> {
+        if rgba.len() as u64 != width as u64 * height as u64 * 4 {
+            return Err(Error::InvalidIcon(format!(
+                "{} bytes of pixel data for a {}x{} icon",
+                rgba.len(),
+                width,
+                height
+            )));
+        }
+        Ok(IconBuffer {
+            rgba,
+            width,
+            height,
+        })
+    }
+}
+
+/// An icon resource linked into the executable, by name or by the number
+/// MAKEINTRESOURCE would make of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IconResource {
+    Name(String),
+    Ordinal(u16),
+}
+
+impl<'a> From<&'a str> for IconResource {
+    fn from(name: &'a str) -> IconResource {
+        IconResource::Name(name.to_owned())
+    }
+}
+
+impl From<String> for IconResource {
+    fn from(name: String) -> IconResource {
+        IconResource::Name(name)
+    }
+}
+
+impl From<u16> for IconResource {
+    fn from(ordinal: u16) -> IconResource {
+        IconResource::Ordinal(ordinal)
+    }
+}
+
+// What goes through the event channel. Wake only gets the thread waiting on it
+// to run the commands handles have queued.
+pub(crate) enum Message {
+    Event(SystrayEvent),
+    Wake,
+}
+
+// Gets a copy of every event once it is queued, on whichever thread sent it.
+// Lets event loops that aren't ours find out there is something to handle.
+pub(crate) type Forwarder = Arc<Mutex<Box<dyn FnMut(SystrayEvent) + Send>>>;
+
+// The tags of the menu items by id, shared between the Application that sets
+// them and the sender that puts them in click events.
+type Tags = Arc<Mutex<HashMap<u32, u64>>>;
+
+fn lock_tags(tags: &Tags) -> MutexGuard<'_, HashMap<u32, u64>> {
+    tags.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// What the backends send their events through. With the async feature it also
+/// wakes up whoever is waiting on an event_stream.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: Arc<Mutex<Sender<Message>>>,
+    forward: Option<Forwarder>,
+    tags: Tags,
+    #[cfg(feature = "async")]
+    waker: Arc<AtomicWaker>,
+}
+
+impl EventSender {
+    // Backends report clicks through this so the item's tag goes along.
+    pub(crate) fn send_click(&self, idx: u32) -> Result<(), SendError<Message>> {
+        let tag = lock_tags(&self.tags).get(&idx).cloned();
+        self.send(SystrayEvent::MenuItemClicked {
+            item: MenuItem(idx),
+            tag,
+        })
+    }
+
+    pub(crate) fn send(&self, event: SystrayEvent) -> Result<(), SendError<Message>> {
+        let copy = self.forward.as_ref().map(|_| event.clone());
+        let result = send_message(&self.tx, Message::Event(event));
+        #[cfg(feature = "async")]
+        self.waker.wake();
+        forward(&self.forward, copy);
+        result
+    }
+
+    fn downgrade(&self) -> WeakEventSender {
+        WeakEventSender {
+            tx: Arc::downgrade(&self.tx),
+            forward: self.forward.clone(),
+            #[cfg(feature = "async")]
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+// The event channel closes once the backend drops its senders, which is how
+// next_event finds out the tray is gone. Senders on our side mustn't keep it
+// open, so they only hold on to it weakly.
+#[derive(Clone)]
+struct WeakEventSender {
+    tx: Weak<Mutex<Sender<Message>>>,
+    forward: Option<Forwarder>,
+    #[cfg(feature = "async")]
+    waker: Arc<AtomicWaker>,
+}
+
+impl WeakEventSender {
+    // False once the channel is gone.
+    fn send(&self, msg: Message) -> bool {
+        match self.tx.upgrade() {
+            Some(tx) => {
+                let copy = match msg {
+                    Message::Event(ref e) if self.forward.is_some() => Some(e.clone()),
+                    _ => None,
+                };
+                let sent = send_message(&tx, msg).is_ok();
+                #[cfg(feature = "async")]
+                self.waker.wake();
+                forward(&self.forward, copy);
+                sent
+            }
+            None => false,
+        }
+    }
+}
+
+fn send_message(tx: &Mutex<Sender<Message>>, msg: Message) -> Result<(), SendError<Message>> {
+    match tx.lock() {
+        Ok(tx) => tx.send(msg),
+        Err(e) => e.into_inner().send(msg),
+    }
+}
+
+fn forward(forward: &Option<Forwarder>, event: Option<SystrayEvent>) {
+    if let (Some(f), Some(event)) = (forward, event) {
+        let mut f = f.lock().unwrap_or_else(|e| e.into_inner());
+        f(event);
+    }
+}
+
+// How often a missing tray is looked for again while waiting for one.
+const TRAY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn new_window(
+    event_tx: EventSender,
+    mode: ApplicationMode,
+    backend: Backend,
+    visible: bool,
+    wait_for_tray: Option<Duration>,
+) -> Result<api::Window, Error> {
+    let deadline = wait_for_tray.map(|timeout| Instant::now() + timeout);
+    loop {
+        match api::Window::new(event_tx.clone(), mode, backend, visible) {
+            Err(Error::NoTrayAvailable) => {
+                let now = Instant::now();
+                match deadline {
+                    Some(deadline) if now < deadline => {
+                        thread::sleep(TRAY_POLL_INTERVAL.min(deadline - now))
+                    }
+                    _ => return Err(Error::NoTrayAvailable),
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+// When an event happened, from how long ago the OS says that was. Linux and
+// the mock backend don't get told.
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+pub(crate) fn event_time(age: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_sub(age).unwrap_or(now)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Unknown panic".to_owned()
+    }
+}
+
+// Runs f, turning a panic into a CallbackPanicked event. Backends wrap what
+// the OS calls into with this, since unwinding into a C message loop aborts.
+pub(crate) fn catch_panic<F, R>(tx: Option<&EventSender>, f: F) -> Option<R>
+where
+    F: FnOnce() -> R,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(r) => Some(r),
+        Err(e) => {
+            let message = panic_message(&*e);
+            log::error!("Panic in tray event handling: {}", message);
+            if let Some(tx) = tx {
+                let event = SystrayEvent::CallbackPanicked {
+                    item: None,
+                    message,
+                };
+                tx.send(event).ok();
+            }
+            None
+        }
+    }
+}
+
+// Menu changes recorded by a MenuEditor, handed to the backend in one go.
+// Entries carry their own index and the index of the submenu they go in, if
+// any.
+pub(crate) enum MenuOp {
+    Entry(u32, Option<u32>, String),
+    CheckableEntry(u32, Option<u32>, String, bool),
+    Separator(u32, Option<u32>),
+    Header(u32, Option<u32>, String),
+    Submenu(u32, Option<u32>, String),
+    SetEnabled(u32, bool),
+    // Takes everything out of the menu.
+    Clear,
+}
+
+pub struct MenuEditor<S> {
+    menu_idx: u32,
+    ops: Vec<MenuOp>,
+    callback: Vec<(u32, Callback<S>)>,
+    checked: Vec<(u32, bool)>,
+    submenus: Vec<u32>,
+    tags: Vec<(u32, u64)>,
+    // Only from set_menu, which greys them out in the same batch.
+    disabled: Vec<u32>,
+}
+
+impl<S> MenuEditor<S> {
+    fn new(menu_idx: u32) -> MenuEditor<S> {
+        MenuEditor {
+            menu_idx,
+            ops: Vec::new(),
+            callback: Vec::new(),
+            checked: Vec::new(),
+            submenus: Vec::new(),
+            tags: Vec::new(),
+            disabled: Vec::new(),
+        }
+    }
+
+    fn push_menu(&mut self, menu: Menu<S>, parent: Option<u32>) {
+        for (pos, entry) in menu.entries.into_iter().enumerate() {
+            let idx = self.menu_idx;
+            self.menu_idx += 1;
+            if let Some(&tag) = menu.tags.get(&pos) {
+                self.tags.push((idx, tag));
+            }
+            match entry {
+                MenuEntry::Item(name, cb) => {
+                    self.ops.push(MenuOp::Entry(idx, parent, name));
+                    if let Some(cb) = cb {
+                        self.callback.push((idx, cb));
+                    }
+                }
+                MenuEntry::CheckableItem(name, checked, make_cb) => {
+                    self.ops
+                        .push(MenuOp::CheckableEntry(idx, parent, name, checked));
+                    self.callback.push((idx, make_cb(idx)));
+                    self.checked.push((idx, checked));
+                }
+                MenuEntry::Separator => self.ops.push(MenuOp::Separator(idx, parent)),
+                MenuEntry::Header(name) => self.ops.push(MenuOp::Header(idx, parent, name)),
+                MenuEntry::Submenu(name, submenu) => {
+                    self.ops.push(MenuOp::Submenu(idx, parent, name));
+                    self.submenus.push(idx);
+                    self.push_menu(submenu, Some(idx));
+                }
+            }
+            if menu.disabled.contains(&pos) {
+                self.ops.push(MenuOp::SetEnabled(idx, false));
+                self.disabled.push(idx);
+            }
+        }
+    }
+
+    pub fn add_menu_item<F, E>(&mut self, item_name: &str, f: F) -> MenuItem
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let item = self.add_menu_entry(item_name);
+        self.callback.push((item.0, make_callback(f)));
+        item
+    }
+
+    pub fn add_menu_entry(&mut self, item_name: &str) -> MenuItem {
+        let idx = self.menu_idx;
+        self.ops
+            .push(MenuOp::Entry(idx, None, item_name.to_owned()));
+        self.menu_idx += 1;
+        MenuItem(idx)
+    }
+
+    pub fn add_menu_checkable_item<F, E>(
+        &mut self,
+        item_name: &str,
+        checked: bool,
+        f: F,
+    ) -> MenuItem
+    where
+        F: FnMut(&mut Application<S>, bool) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let idx = self.menu_idx;
+        self.ops.push(MenuOp::CheckableEntry(
+            idx,
+            None,
+            item_name.to_owned(),
+            checked,
+        ));
+        self.callback.push((idx, make_checkable_callback(idx, f)));
+        self.checked.push((idx, checked));
+        self.menu_idx += 1;
+        MenuItem(idx)
+    }
+
+    pub fn add_menu_separator(&mut self) -> MenuItem {
+        let idx = self.menu_idx;
+        self.ops.push(MenuOp::Separator(idx, None));
+        self.menu_idx += 1;
+        MenuItem(idx)
+    }
+
+    pub fn add_menu_header(&mut self, title: &str) -> MenuItem {
+        let idx = self.menu_idx;
+        self.ops.push(MenuOp::Header(idx, None, title.to_owned()));
+        self.menu_idx += 1;
+        MenuItem(idx)
+    }
+
+    pub fn set_tag(&mut self, item: MenuItem, tag: u64) {
+        self.tags.push((item.0, tag));
+    }
+}
+
+// Checkable callbacks need the id of their item, which is only known once the
+// menu is put in place.
+type MakeCallback<S> = Box<dyn FnOnce(u32) -> Callback<S>>;
+
+enum MenuEntry<S> {
+    Item(String, Option<Callback<S>>),
+    CheckableItem(String, bool, MakeCallback<S>),
+    Separator,
+    Header(String),
+    Submenu(String, Menu<S>),
+}
+
+/// A whole menu, declared up front and put in place by Application::set_menu.
+pub struct Menu<S = ()> {
+    entries: Vec<MenuEntry<S>>,
+    // Tags and greyed out entries by position in entries.
+    tags: HashMap<usize, u64>,
+    disabled: HashSet<usize>,
+}
+
+impl<S> Menu<S> {
+    pub fn builder() -> MenuBuilder<S> {
+        MenuBuilder {
+            menu: Menu {
+                entries: Vec::new(),
+                tags: HashMap::new(),
+                disabled: HashSet::new(),
+            },
+        }
+    }
+
+    /// The menu without its callbacks, for saving or comparing.
+    pub fn to_spec(&self) -> MenuSpec {
+        let entries = self.entries.iter().enumerate().map(|(pos, entry)| {
+            let tag = self.tags.get(&pos).cloned();
+            let enabled = !self.disabled.contains(&pos);
+            match *entry {
+                MenuEntry::Item(ref label, _) => MenuEntrySpec::Item {
+                    label: label.clone(),
+                    enabled,
+                    tag,
+                },
+                MenuEntry::CheckableItem(ref label, checked, _) => MenuEntrySpec::Checkable {
+                    label: label.clone(),
+                    checked,
+                    enabled,
+                    tag,
+                },
+                MenuEntry::Separator => MenuEntrySpec::Separator,
+                MenuEntry::Header(ref title) => MenuEntrySpec::Header {
+                    title: title.clone(),
+                },
+                MenuEntry::Submenu(ref label, ref submenu) => MenuEntrySpec::Submenu {
+                    label: label.clone(),
+                    enabled,
+                    tag,
+                    entries: submenu.to_spec().entries,
+                },
+            }
+        });
+        MenuSpec {
+            entries: entries.collect(),
+        }
+    }
+
+    /// Builds the menu a spec describes. Its items have no callbacks, so apps
+    /// tell them apart by tag in MenuItemClicked. Checkable items still toggle.
+    pub fn from_spec(spec: MenuSpec) -> Menu<S> {
+        let mut builder = Menu::builder();
+        for entry in spec.entries {
+            let (enabled, tag) = match entry {
+                MenuEntrySpec::Item {
+                    label,
+                    enabled,
+                    tag,
+                } => {
+                    builder = builder.entry(&label);
+                    (enabled, tag)
+                }
+                MenuEntrySpec::Checkable {
+                    label,
+                    checked,
+                    enabled,
+                    tag,
+                } => {
+                    builder = builder.checkable_item(&label, checked, |_, _| {
+                        Ok::<_, std::convert::Infallible>(())
+                    });
+                    (enabled, tag)
+                }
+                MenuEntrySpec::Separator => {
+                    builder = builder.separator();
+                    (true, None)
+                }
+                MenuEntrySpec::Header { title } => {
+                    builder = builder.header(&title);
+                    (true, None)
+                }
+                MenuEntrySpec::Submenu {
+                    label,
+                    enabled,
+                    tag,
+                    entries,
+                } => {
+                    let submenu = Menu::from_spec(MenuSpec { entries });
+                    builder.menu.entries.push(MenuEntry::Submenu(label, submenu));
+                    (enabled, tag)
+                }
+            };
+            if !enabled {
+                builder = builder.disabled();
+            }
+            if let Some(tag) = tag {
+                builder = builder.tag(tag);
+            }
+        }
+        builder.build()
+    }
+}
+
+/// What a Menu looks like, without the callbacks. With the serde feature it
+/// can be saved, or written by hand in a config file.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MenuSpec {
+    pub entries: Vec<MenuEntrySpec>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
+pub enum MenuEntrySpec {
+    Item {
+        label: String,
+        #[cfg_attr(feature = "serde", serde(default = "enabled_by_default"))]
+        enabled: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        tag: Option<u64>,
+    },
+    Checkable {
+        label: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        checked: bool,
+        #[cfg_attr(feature = "serde", serde(default = "enabled_by_default"))]
+        enabled: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        tag: Option<u64>,
+    },
+    Separator,
+    Header {
+        title: String,
+    },
+    Submenu {
+        label: String,
+        #[cfg_attr(feature = "serde", serde(default = "enabled_by_default"))]
+        enabled: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        tag: Option<u64>,
+        entries: Vec<MenuEntrySpec>,
+    },
+}
+
+#[cfg(feature = "serde")]
+fn enabled_by_default() -> bool {
+    true
+}
+
+pub struct MenuBuilder<S = ()> {
+    menu: Menu<S>,
+}
+
+impl<S> MenuBuilder<S> {
+    pub fn item<F, E>(mut self, item_name: &str, f: F) -> Self
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let entry = MenuEntry::Item(item_name.to_owned(), Some(make_callback(f)));
+        self.menu.entries.push(entry);
+        self
+    }
+
+    /// An item without a callback, for apps that handle the events themselves.
+    pub fn entry(mut self, item_name: &str) -> Self {
+        self.menu
+            .entries
+            .push(MenuEntry::Item(item_name.to_owned(), None));
+        self
+    }
+
+    pub fn checkable_item<F, E>(mut self, item_name: &str, checked: bool, f: F) -> Self
+    where
+        F: FnMut(&mut Application<S>, bool) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let make_cb = Box::new(move |idx| make_checkable_callback(idx, f));
+        let entry = MenuEntry::CheckableItem(item_name.to_owned(), checked, make_cb);
+        self.menu.entries.push(entry);
+        self
+    }
+
+    pub fn separator(mut self) -> Self {
+        self.menu.entries.push(MenuEntry::Separator);
+        self
+    }
+
+    /// A title for the entries that follow, see Application::add_menu_header.
+    pub fn header(mut self, title: &str) -> Self {
+        self.menu.entries.push(MenuEntry::Header(title.to_owned()));
+        self
+    }
+
+    pub fn submenu<F>(mut self, item_name: &str, f: F) -> Self
+    where
+        F: FnOnce(MenuBuilder<S>) -> MenuBuilder<S>,
+    {
+        let submenu = f(Menu::builder()).build();
+        let entry = MenuEntry::Submenu(item_name.to_owned(), submenu);
+        self.menu.entries.push(entry);
+        self
+    }
+
+    /// Tags the entry added last, see Application::set_menu_item_tag.
+    pub fn tag(mut self, tag: u64) -> Self {
+        if let Some(pos) = self.menu.entries.len().checked_sub(1) {
+            self.menu.tags.insert(pos, tag);
+        }
+        self
+    }
+
+    /// Greys out the entry added last.
+    pub fn disabled(mut self) -> Self {
+        if let Some(pos) = self.menu.entries.len().checked_sub(1) {
+            self.menu.disabled.insert(pos);
+        }
+        self
+    }
+
+    pub fn build(self) -> Menu<S> {
+        self.menu
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use self::Error::*;
+
+        match *self {
+            OsError(ref err_str) => write!(f, "OsError: {}", err_str),
+            WinApi { code, call } => write!(f, "{} failed with error {:#x}", call, code),
+            Cocoa { selector } => write!(f, "[{}] returned nil", selector),
+            Glib { call, ref message } => write!(f, "{} failed: {}", call, message),
+            InvalidIcon(ref err_str) => write!(f, "InvalidIcon: {}", err_str),
+            InvalidShortcut(ref err_str) => write!(f, "InvalidShortcut: {}", err_str),
+            NotSupported { feature } => write!(f, "Not supported here: {}", feature),
+            Shutdown => write!(f, "The tray has shut down"),
+            NoTrayAvailable => write!(f, "There is no system tray to show the icon in"),
+            CallbackPanicked(ref message) => write!(f, "Callback panicked: {}", message),
+            Error(ref e) => write!(f, "Error: {}", e),
+        }
+    }
+}
+
+/// S is app-specific state owned by the Application, so callbacks can get at it
+/// through state()/state_mut() instead of reaching for globals.
+/// Each Application is one tray icon, a process can create several and drive
+/// them side by side.
+pub struct Application<S = ()> {
+    window: api::Window,
+    menu_idx: u32,
+    callback: HashMap<u32, Callback<S>>,
+    // Current state of every checkable item. Not every platform toggles them
+    // by itself on click, so this is the source of truth.
+    checked: HashMap<u32, bool>,
+    // Radio items, with the first id and the size of their group. The ids of
+    // a group are consecutive.
+    radio: HashMap<u32, (u32, u32)>,
+    submenus: HashSet<u32>,
+    // Greyed out items. Their clicks are dropped, in case one was already on
+    // its way or the platform lets it through anyway.
+    disabled: RefCell<HashSet<u32>>,
+    tags: Tags,
+    // The icon last set from pixels, kept for drawing badges onto.
+    icon: RefCell<Option<IconBuffer>>,
+    // Whether that icon is still showing, and not an animation since.
+    icon_showing: Cell<bool>,
+    badge: RefCell<Option<BadgeSpec>>,
+    // The tooltip and title last set, which the icon's accessibility
+    // description follows until the app sets one.
+    tooltip: RefCell<String>,
+    title: RefCell<String>,
+    icon_description_set: Cell<bool>,
+    hotkey_idx: u32,
+    // Ids of items that were removed. A callback can remove its own item, and
+    // wait_for_message must not put that callback back afterwards.
+    removed: HashSet<u32>,
+    // Items with lower ids went away with the menu set_menu replaced.
+    menu_start: u32,
+    // Set by quit. The event functions report Quit from then on, without
+    // waiting for the backend to close the event channel.
+    quit_requested: bool,
+    // Whether next_event and friends handed out Quit already.
+    quit_reported: bool,
+    // Set while a menu callback runs. Clicks a callback dispatches itself,
+    // with dispatch_pending, wait here until it returns, so callbacks never
+    // run inside one another. A radio group's would lock up otherwise.
+    // Dropped if the callback fails.
+    in_callback: bool,
+    deferred_clicks: VecDeque<u32>,
+    // Menu changes made while a callback runs, applied once it returns so the
+    // backend never gets them in the middle of handling the click.
+    menu_calls: RefCell<Vec<MenuCall>>,
+    // Gets the Err results of callbacks. Without one, they end
+    // wait_for_message and dispatch_pending instead.
+    error_handler: Option<ErrorHandler>,
+    // Our own end of the event channel, for reporting panicking callbacks.
+    event_tx: WeakEventSender,
+    // Each platform-specific window module will set up its own thread for
+    // dealing with the OS main loop. Use this channel for receiving events from
+    // that thread.
+    rx: Receiver<Message>,
+    // Calls queued by ApplicationHandles, run whenever a Wake comes in.
+    command_tx: Sender<Command<S>>,
+    commands: Receiver<Command<S>>,
+    #[cfg(feature = "async")]
+    waker: Arc<AtomicWaker>,
+    state: S,
+}
+
+type Callback<S> =
+    Box<dyn FnMut(&mut Application<S>) -> Result<(), BoxedError> + Send + Sync + 'static>;
+
+type ErrorHandler = Box<dyn FnMut(MenuItem, Error) + Send + Sync + 'static>;
+
+type Command<S> = Box<dyn FnOnce(&mut Application<S>) + Send + 'static>;
+
+type MenuCall = Box<dyn FnOnce(&api::Window) -> Result<(), Error> + Send + 'static>;
+
+fn make_callback<S, F, E>(mut f: F) -> Callback<S>
+where
+    F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+    E: error::Error + Send + Sync + 'static,
+{
+    Box::new(move |a: &mut Application<S>| match f(a) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Box::new(e) as BoxedError),
+    }) as Callback<S>
+}
+
+// Checkable item callbacks also get the state the item was toggled to.
+fn make_checkable_callback<S, F, E>(idx: u32, mut f: F) -> Callback<S>
+where
+    F: FnMut(&mut Application<S>, bool) -> Result<(), E> + Send + Sync + 'static,
+    E: error::Error + Send + Sync + 'static,
+{
+    make_callback(move |a: &mut Application<S>| {
+        let checked = a.checked.get(&idx).cloned().unwrap_or(false);
+        f(a, checked)
+    })
+}
+
+// All items of a radio group share the one callback, each passing its own
+// position in the group.
+fn make_radio_callback<S, F, E>(index: usize, f: Arc<Mutex<F>>) -> Callback<S>
+where
+    F: FnMut(&mut Application<S>, usize) -> Result<(), E> + Send + Sync + 'static,
+    E: error::Error + Send + Sync + 'static,
+{
+    make_callback(move |a: &mut Application<S>| {
+        let mut f = f.lock().unwrap_or_else(|e| e.into_inner());
+        (*f)(a, index)
+    })
+}
+
+/// Handle for adding items to a submenu. Items in submenus share their ids and
+/// callbacks with the rest of the Application.
+pub struct Submenu<'a, S> {
+    app: &'a mut Application<S>,
+    idx: u32,
+}
+
+impl<'a, S> Submenu<'a, S> {
+    pub fn id(&self) -> MenuItem {
+        MenuItem(self.idx)
+    }
+
+    pub fn add_menu_item<F, E>(&mut self, item_name: &str, f: F) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.app
+            .insert_menu_item(Some(self.idx), item_name, f)
+            .map(MenuItem)
+    }
+
+    pub fn add_menu_entry(&mut self, item_name: &str) -> Result<MenuItem, Error> {
+        self.app
+            .insert_menu_entry(Some(self.idx), item_name)
+            .map(MenuItem)
+    }
+
+    pub fn add_menu_checkable_item<F, E>(
+        &mut self,
+        item_name: &str,
+        checked: bool,
+        f: F,
+    ) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>, bool) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.app
+            .insert_menu_checkable_item(Some(self.idx), item_name, checked, f)
+            .map(MenuItem)
+    }
+
+    pub fn add_menu_radio_group<F, E>(
+        &mut self,
+        labels: &[&str],
+        selected: usize,
+        f: F,
+    ) -> Result<Vec<MenuItem>, Error>
+    where
+        F: FnMut(&mut Application<S>, usize) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.app
+            .insert_menu_radio_group(Some(self.idx), labels, selected, f)
+    }
+
+    pub fn add_menu_item_with_icon<F, E>(
+        &mut self,
+        item_name: &str,
+        icon: &IconBuffer,
+        f: F,
+    ) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.app
+            .insert_menu_item_with_icon(Some(self.idx), item_name, icon, f)
+            .map(MenuItem)
+    }
+
+    pub fn add_menu_item_with_shortcut<F, E>(
+        &mut self,
+        item_name: &str,
+        shortcut: &str,
+        f: F,
+    ) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.app
+            .insert_menu_item_with_shortcut(Some(self.idx), item_name, shortcut, f)
+            .map(MenuItem)
+    }
+
+    pub fn add_menu_separator(&mut self) -> Result<MenuItem, Error> {
+        self.app.insert_menu_separator(Some(self.idx)).map(MenuItem)
+    }
+
+    pub fn add_menu_header(&mut self, title: &str) -> Result<MenuItem, Error> {
+        self.app
+            .insert_menu_header(Some(self.idx), title)
+            .map(MenuItem)
+    }
+
+    pub fn add_submenu(&mut self, item_name: &str) -> Result<Submenu<'_, S>, Error> {
+        let idx = self.app.insert_submenu(Some(self.idx), item_name)?;
+        Ok(Submenu {
+            app: &mut *self.app,
+            idx,
+        })
+    }
+}
+
+/// Send + Sync handle for changing the tray from worker threads. Calls are
+/// queued and run on the thread driving the Application, the next time it
+/// waits for or polls events, so nothing happens while it's busy elsewhere.
+/// They only fail once the Application is gone; errors from the calls
+/// themselves are logged.
+pub struct ApplicationHandle<S = ()> {
+    commands: Mutex<Sender<Command<S>>>,
+    event_tx: WeakEventSender,
+    // The backend's wake, for the tray this handle belongs to.
+    wake: fn(),
+}
+
+impl<S> Clone for ApplicationHandle<S> {
+    fn clone(&self) -> Self {
+        let commands = match self.commands.lock() {
+            Ok(c) => c.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        ApplicationHandle {
+            commands: Mutex::new(commands),
+            event_tx: self.event_tx.clone(),
+            wake: self.wake,
+        }
+    }
+}
+
+impl<S> ApplicationHandle<S> {
+    /// Runs f on the Application's thread.
+    pub fn run<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Application<S>) + Send + 'static,
+    {
+        let sent = match self.commands.lock() {
+            Ok(c) => c.send(Box::new(f)).is_ok(),
+            Err(e) => e.into_inner().send(Box::new(f)).is_ok(),
+        };
+        if !sent || !self.event_tx.send(Message::Wake) {
+            return Err(Error::Shutdown);
+        }
+        (self.wake)();
+        Ok(())
+    }
+
+    // Like run, for calls that can fail.
+    fn run_logged<F>(&self, what: &'static str, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Application<S>) -> Result<(), Error> + Send + 'static,
+    {
+        self.run(move |app| {
+            if let Err(e) = f(app) {
+                log::warn!("Error {} from a handle: {}", what, e);
+            }
+        })
+    }
+
+    pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error> {
+        let file = file.to_owned();
+        self.run_logged("setting icon", move |app| app.set_icon_from_file(&file))
+    }
+
+    /// Checks the size right away, so a bad buffer fails here instead of in the
+    /// log.
+    pub fn set_icon_from_rgba(
+        &self,
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        let icon = IconBuffer::from_rgba(pixels, width, height)?;
+        self.run_logged("setting icon", move |app| {
+            app.set_icon_from_rgba(icon.rgba, icon.width, icon.height)
+        })
+    }
+
+    pub fn set_icon_badge(&self, badge: Option<BadgeSpec>) -> Result<(), Error> {
+        self.run_logged("setting icon badge", move |app| app.set_icon_badge(badge))
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        let tooltip = tooltip.to_owned();
+        self.run_logged("setting tooltip", move |app| app.set_tooltip(&tooltip))
+    }
+
+    pub fn set_title(&self, title: &str) -> Result<(), Error> {
+        let title = title.to_owned();
+        self.run_logged("setting title", move |app| app.set_title(&title))
+    }
+
+    pub fn set_menu_item_label(&self, item: MenuItem, label: &str) -> Result<(), Error> {
+        let label = label.to_owned();
+        self.run_logged("setting menu item label", move |app| {
+            app.set_menu_item_label(item, &label)
+        })
+    }
+
+    pub fn set_menu_item_tooltip(&self, item: MenuItem, tooltip: &str) -> Result<(), Error> {
+        let tooltip = tooltip.to_owned();
+        self.run_logged("setting menu item tooltip", move |app| {
+            app.set_menu_item_tooltip(item, &tooltip)
+        })
+    }
+
+    pub fn set_menu_item_enabled(&self, item: MenuItem, enabled: bool) -> Result<(), Error> {
+        self.run_logged("enabling menu item", move |app| {
+            app.set_menu_item_enabled(item, enabled)
+        })
+    }
+
+    pub fn set_menu_item_checked(&self, item: MenuItem, checked: bool) -> Result<(), Error> {
+        self.run_logged("checking menu item", move |app| {
+            app.set_menu_item_checked(item, checked)
+        })
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), Error> {
+        self.run_logged("changing icon visibility", move |app| {
+            app.set_visible(visible)
+        })
+    }
+
+    pub fn quit(&self) -> Result<(), Error> {
+        self.run(|app| app.quit())
+    }
+}
+
+/// For setting up an Application with more than its state:
+///
+/// ```no_run
+/// # use systray::{Application, Backend, IconBuffer, Menu};
+/// # fn main() -> Result<(), systray::Error> {
+/// # let state = 0;
+/// # let icon = IconBuffer::from_rgba(vec![0; 4], 1, 1)?;
+/// # let menu = Menu::builder().build();
+/// let app = Application::builder()
+///     .with_state(state)
+///     .backend(Backend::StatusNotifier)
+///     .icon(icon)
+///     .tooltip("Syncing")
+///     .menu(menu)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// With an icon, tooltip or menu, the tray icon is kept hidden until all of
+/// them are in place, instead of showing up empty first.
+pub struct ApplicationBuilder<S = ()> {
+    state: S,
+    mode: ApplicationMode,
+    backend: Backend,
+    icon: Option<IconBuffer>,
+    tooltip: Option<String>,
+    menu: Option<Menu<S>>,
+    wait_for_tray: Option<Duration>,
+}
+
+impl ApplicationBuilder {
+    pub fn new() -> ApplicationBuilder {
+        ApplicationBuilder {
+            state: (),
+            mode: ApplicationMode::default(),
+            backend: Backend::default(),
+            icon: None,
+            tooltip: None,
+            menu: None,
+            wait_for_tray: None,
+        }
+    }
+}
+
+impl Default for ApplicationBuilder {
+    fn default() -> Self {
+        ApplicationBuilder::new()
+    }
+}
+
+impl<S> ApplicationBuilder<S> {
+    /// Menus are made for one type of state, so a menu set before this is
+    /// dropped. Set it after.
+    pub fn with_state<T>(self, state: T) -> ApplicationBuilder<T> {
+        if self.menu.is_some() {
+            log::warn!("The menu was set before the state, leaving it out");
+        }
+        ApplicationBuilder {
+            state,
+            mode: self.mode,
+            backend: self.backend,
+            icon: self.icon,
+            tooltip: self.tooltip,
+            menu: None,
+            wait_for_tray: self.wait_for_tray,
+        }
+    }
+
+    pub fn mode(mut self, mode: ApplicationMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn icon(mut self, icon: IconBuffer) -> Self {
+        self.icon = Some(icon);
+        self
+    }
+
+    pub fn tooltip(mut self, tooltip: &str) -> Self {
+        self.tooltip = Some(tooltip.to_owned());
+        self
+    }
+
+    pub fn menu(mut self, menu: Menu<S>) -> Self {
+        self.menu = Some(menu);
+        self
+    }
+
+    /// Without a tray, keeps trying to put the icon in one for up to timeout
+    /// instead of failing with NoTrayAvailable right away. For apps started
+    /// along with the session, which can come up before the panel does.
+    pub fn wait_for_tray(mut self, timeout: Duration) -> Self {
+        self.wait_for_tray = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> Result<Application<S>, Error> {
+        let configured = self.icon.is_some() || self.tooltip.is_some() || self.menu.is_some();
+        let mut app = Application::create(
+            self.state,
+            self.mode,
+            self.backend,
+            None,
+            !configured,
+            self.wait_for_tray,
+        )?;
+        if !configured {
+            return Ok(app);
+        }
+        if let Some(icon) = self.icon {
+            app.set_icon(icon)?;
+        }
+        if let Some(tooltip) = self.tooltip {
+            app.set_tooltip(&tooltip)?;
+        }
+        if let Some(menu) = self.menu {
+            app.set_menu(menu)?;
+        }
+        app.set_visible(true)?;
+        Ok(app)
+    }
+}
+
+impl Application {
+    pub fn new() -> Result<Application, Error> {
+        Application::with_state(())
+    }
+
+    pub fn builder() -> ApplicationBuilder {
+        ApplicationBuilder::new()
+    }
+}
+
+impl<S> Application<S> {
+    pub fn with_state(state: S) -> Result<Application<S>, Error> {
+        Application::with_mode(state, ApplicationMode::OwnRunLoop)
+    }
+
+    pub fn with_mode(state: S, mode: ApplicationMode) -> Result<Application<S>, Error> {
+        Application::create(state, mode, Backend::Auto, None, true, None)
+    }
+
+    pub(crate) fn create(
+        state: S,
+        mode: ApplicationMode,
+        backend: Backend,
+        forward: Option<Forwarder>,
+        visible: bool,
+        wait_for_tray: Option<Duration>,
+    ) -> Result<Application<S>, Error> {
+        let (tx, event_rx) = channel();
+        #[cfg(feature = "async")]
+        let waker = Arc::new(AtomicWaker::new());
+        let tags = Arc::new(Mutex::new(HashMap::new()));
+        let event_tx = EventSender {
+            tx: Arc::new(Mutex::new(tx)),
+            forward,
+            tags: tags.clone(),
+            #[cfg(feature = "async")]
+            waker: waker.clone(),
+        };
+        let weak_tx = event_tx.downgrade();
+        let (command_tx, commands) = channel();
+        match new_window(event_tx, mode, backend.resolve(), visible, wait_for_tray) {
+            Ok(w) => Ok(Application {
+                window: w,
+                menu_idx: 0,
+                callback: HashMap::new(),
+                checked: HashMap::new(),
+                radio: HashMap::new(),
+                submenus: HashSet::new(),
+                disabled: RefCell::new(HashSet::new()),
+                tags,
+                icon: RefCell::new(None),
+                icon_showing: Cell::new(false),
+                badge: RefCell::new(None),
+                tooltip: RefCell::new(String::new()),
+                title: RefCell::new(String::new()),
+                icon_description_set: Cell::new(false),
+                hotkey_idx: 0,
+                removed: HashSet::new(),
+                menu_start: 0,
+                quit_requested: false,
+                quit_reported: false,
+                in_callback: false,
+                deferred_clicks: VecDeque::new(),
+                menu_calls: RefCell::new(Vec::new()),
+                error_handler: None,
+                event_tx: weak_tx,
+                rx: event_rx,
+                command_tx,
+                commands,
+                #[cfg(feature = "async")]
+                waker,
+                state,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hands the Err results of callbacks to f along with the item, instead of
+    /// returning them from wait_for_message and dispatch_pending, which then
+    /// keep going. Panics come as Error::CallbackPanicked. Menu changes made
+    /// from a callback are applied once it returns, and their errors come here
+    /// the same way.
+    pub fn set_error_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(MenuItem, Error) + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Box::new(f));
+    }
+
+    /// For changing the tray from other threads. See ApplicationHandle.
+    pub fn handle(&self) -> ApplicationHandle<S> {
+        ApplicationHandle {
+            commands: Mutex::new(self.command_tx.clone()),
+            event_tx: self.event_tx.clone(),
+            wake: self.window.waker(),
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    pub fn add_menu_item<F, E>(&mut self, item_name: &str, f: F) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.insert_menu_item(None, item_name, f).map(MenuItem)
+    }
+
+    /// Adds an item without a callback. Clicking it still produces an event,
+    /// but there is nothing to run for it in wait_for_message.
+    pub fn add_menu_entry(&mut self, item_name: &str) -> Result<MenuItem, Error> {
+        self.insert_menu_entry(None, item_name).map(MenuItem)
+    }
+
+    /// Like open::open_url, but recorded by the mock backend instead of opened.
+    pub fn open_url(&self, url: &str) -> Result<(), Error> {
+        self.window.open_url(url)
+    }
+
+    /// Like open::reveal, but recorded by the mock backend instead of shown.
+    pub fn reveal<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.window.reveal(&open::absolute(path.as_ref())?)
+    }
+
+    /// Like autostart::enable, but recorded by the mock backend instead.
+    pub fn enable_autostart(&self) -> Result<(), Error> {
+        let (name, exe) = autostart::current_app()?;
+        self.window.enable_autostart(&name, &exe)
+    }
+
+    /// Like autostart::disable, but recorded by the mock backend instead.
+    pub fn disable_autostart(&self) -> Result<(), Error> {
+        let (name, _) = autostart::current_app()?;
+        self.window.disable_autostart(&name)
+    }
+
+    /// Like autostart::is_enabled, but from the mock backend's recording on it.
+    pub fn autostart_enabled(&self) -> Result<bool, Error> {
+        let (name, _) = autostart::current_app()?;
+        self.window.autostart_enabled(&name)
+    }
+
+    /// An item that opens url in the default browser when clicked.
+    pub fn add_menu_link_item(&mut self, item_name: &str, url: &str) -> Result<MenuItem, Error> {
+        let url = url.to_owned();
+        self.add_menu_item(item_name, move |app: &mut Application<S>| app.open_url(&url))
+    }
+
+    /// An item that shows path in Explorer, Finder or the desktop's file
+    /// manager when clicked, selected in its folder where that's supported.
+    pub fn add_menu_reveal_item<P: AsRef<Path>>(
+        &mut self,
+        item_name: &str,
+        path: P,
+    ) -> Result<MenuItem, Error> {
+        let path = path.as_ref().to_owned();
+        self.add_menu_item(item_name, move |app: &mut Application<S>| app.reveal(&path))
+    }
+
+    pub fn add_menu_checkable_item<F, E>(
+        &mut self,
+        item_name: &str,
+        checked: bool,
+        f: F,
+    ) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>, bool) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.insert_menu_checkable_item(None, item_name, checked, f)
+            .map(MenuItem)
+    }
+
+    /// One item per label, of which only one is checked at a time, starting
+    /// with the one at selected. The callback gets the index of the label that
+    /// was picked. The items come back in the same order as the labels.
+    pub fn add_menu_radio_group<F, E>(
+        &mut self,
+        labels: &[&str],
+        selected: usize,
+        f: F,
+    ) -> Result<Vec<MenuItem>, Error>
+    where
+        F: FnMut(&mut Application<S>, usize) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.insert_menu_radio_group(None, labels, selected, f)
+    }
+
+    /// Menu icons are shown at their pixel size, so keep them small, 16x16 at
+    /// the usual DPI.
+    pub fn add_menu_item_with_icon<F, E>(
+        &mut self,
+        item_name: &str,
+        icon: &IconBuffer,
+        f: F,
+    ) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.insert_menu_item_with_icon(None, item_name, icon, f)
+            .map(MenuItem)
+    }
+
+    /// On Linux only plain items and submenus can have an icon.
+    pub fn set_menu_item_icon(&self, item: MenuItem, icon: &IconBuffer) -> Result<(), Error> {
+        let icon = icon.clone();
+        self.menu_call(move |window| window.set_menu_item_icon(item.0, &icon))
+    }
+
+    /// Shows the shortcut next to the item, see Shortcut::parse for the format.
+    /// It only triggers the item while the menu is open, use a global hotkey
+    /// for anything else.
+    pub fn add_menu_item_with_shortcut<F, E>(
+        &mut self,
+        item_name: &str,
+        shortcut: &str,
+        f: F,
+    ) -> Result<MenuItem, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.insert_menu_item_with_shortcut(None, item_name, shortcut, f)
+            .map(MenuItem)
+    }
+
+    pub fn set_menu_item_shortcut(&self, item: MenuItem, shortcut: &str) -> Result<(), Error> {
+        let shortcut = Shortcut::parse(shortcut)?;
+        self.menu_call(move |window| window.set_menu_item_shortcut(item.0, &shortcut))
+    }
+
+    pub fn menu_item_checked(&self, item: MenuItem) -> Option<bool> {
+        self.checked.get(&item.0).cloned()
+    }
+
+    pub fn set_menu_item_checked(&mut self, item: MenuItem, checked: bool) -> Result<(), Error> {
+        let idx = item.0;
+        if !self.checked.contains_key(&idx) {
+            return Err(Error::OsError(format!(
+                "Menu item {} is not checkable",
+                idx
+            )));
+        }
+        if let Some(&(first, len)) = self.radio.get(&idx) {
+            if !checked {
+                return Err(Error::OsError(format!(
+                    "Radio item {} can only be unchecked by checking another one",
+                    idx
+                )));
+            }
+            return self.select_radio(idx, first, len);
+        }
+        self.menu_call(move |window| window.set_menu_item_checked(idx, checked))?;
+        self.checked.insert(idx, checked);
+        Ok(())
+    }
+
+    pub fn add_menu_separator(&mut self) -> Result<MenuItem, Error> {
+        self.insert_menu_separator(None).map(MenuItem)
+    }
+
+    /// A title for the group of items that follows. It can't be clicked, and
+    /// shows as a section header on macOS 14 and later, and as a bold label
+    /// elsewhere.
+    pub fn add_menu_header(&mut self, title: &str) -> Result<MenuItem, Error> {
+        self.insert_menu_header(None, title).map(MenuItem)
+    }
+
+    /// Appends the standard items, set apart from the ones added before. Quit
+    /// ends the tray the same way quit does.
+    pub fn add_standard_items(
+        &mut self,
+        items: StandardItems<'_>,
+    ) -> Result<StandardMenuItems, Error> {
+        let mut added = StandardMenuItems {
+            about: None,
+            launch_at_login: None,
+            quit: None,
+        };
+        if items.about.is_none() && !items.launch_at_login && !items.quit {
+            return Ok(added);
+        }
+        if self.menu_idx > self.menu_start {
+            self.add_menu_separator()?;
+        }
+        if let Some(label) = items.about {
+            let about = self.add_menu_item(label, |app: &mut Application<S>| {
+                #[cfg(target_os = "macos")]
+                app.window.show_about_panel()?;
+                #[cfg(not(target_os = "macos"))]
+                app.show_about(AboutMetadata {
+                    name: autostart::current_app()?.0,
+                    ..AboutMetadata::default()
+                })?;
+                Ok::<(), Error>(())
+            })?;
+            added.about = Some(about);
+            if items.launch_at_login || items.quit {
+                self.add_menu_separator()?;
+            }
+        }
+        if items.launch_at_login {
+            let label = if cfg!(target_os = "macos") {
+                "Launch at Login"
+            } else {
+                "Start at login"
+            };
+            let enabled = self.autostart_enabled().unwrap_or(false);
+            let item = self.add_menu_checkable_item(label, enabled, |app, checked| {
+                if checked {
+                    app.enable_autostart()
+                } else {
+                    app.disable_autostart()
+                }
+            })?;
+            added.launch_at_login = Some(item);
+        }
+        if items.quit {
+            let label = if cfg!(target_os = "windows") {
+                "Exit"
+            } else {
+                "Quit"
+            };
+            let quit = self.add_menu_item(label, |app: &mut Application<S>| {
+                app.quit();
+                Ok::<(), Error>(())
+            })?;
+            added.quit = Some(quit);
+        }
+        Ok(added)
+    }
+
+    pub fn add_submenu(&mut self, item_name: &str) -> Result<Submenu<'_, S>, Error> {
+        let idx = self.insert_submenu(None, item_name)?;
+        Ok(Submenu {
+            app: self,
+            idx,
+        })
+    }
+
+    /// Removes any kind of item, including separators and whole submenus.
+    pub fn remove_menu_item(&mut self, item: MenuItem) -> Result<(), Error> {
+        let idx = item.0;
+        self.menu_call(move |window| window.remove_menu_item(idx))?;
+        self.callback.remove(&idx);
+        self.checked.remove(&idx);
+        self.radio.remove(&idx);
+        self.submenus.remove(&idx);
+        self.disabled.borrow_mut().remove(&idx);
+        lock_tags(&self.tags).remove(&idx);
+        self.removed.insert(idx);
+        Ok(())
+    }
+
+    pub fn set_menu_item_label(&self, item: MenuItem, label: &str) -> Result<(), Error> {
+        let label = label.to_owned();
+        self.menu_call(move |window| window.set_menu_item_label(item.0, &label))
+    }
+
+    /// Shown while the pointer is over the item, for what doesn't fit in a
+    /// short label. An empty tooltip removes it. Menus that Linux tray hosts
+    /// draw themselves, from AppIndicator or a StatusNotifierItem, don't show
+    /// them.
+    pub fn set_menu_item_tooltip(&self, item: MenuItem, tooltip: &str) -> Result<(), Error> {
+        let tooltip = tooltip.to_owned();
+        self.menu_call(move |window| window.set_menu_item_tooltip(item.0, &tooltip))
+    }
+
+    /// Disabled items are shown greyed out and can't be clicked.
+    pub fn set_menu_item_enabled(&self, item: MenuItem, enabled: bool) -> Result<(), Error> {
+        self.menu_call(move |window| window.set_menu_item_enabled(item.0, enabled))?;
+        let mut disabled = self.disabled.borrow_mut();
+        if enabled {
+            disabled.remove(&item.0);
+        } else {
+            disabled.insert(item.0);
+        }
+        Ok(())
+    }
+
+    pub fn menu_item_enabled(&self, item: MenuItem) -> bool {
+        !self.disabled.borrow().contains(&item.0)
+    }
 
-impl From<BoxedError> for Error {
-    fn from(value: BoxedError) -> Self {
-        Error::Error(value)
+    /// Tags are the app's own ids for items, handed back in MenuItemClicked.
+    /// Unlike the MenuItem handles they can stay the same when the menu is
+    /// rebuilt, so events can be routed on them alone. They are kept here, not
+    /// in the NSMenuItem tag on macOS, where tags from 0x5359_0000_0000 up are
+    /// what the tray routes clicks on. Items an app adds to our menus itself
+    /// need tags below that.
+    pub fn set_menu_item_tag(&self, item: MenuItem, tag: Option<u64>) {
+        let mut tags = lock_tags(&self.tags);
+        match tag {
+            Some(tag) => tags.insert(item.0, tag),
+            None => tags.remove(&item.0),
+        };
     }
-}
 
-pub struct SystrayEvent {
-    menu_index: u32,
-}
+    pub fn menu_item_tag(&self, item: MenuItem) -> Option<u64> {
+        lock_tags(&self.tags).get(&item.0).cloned()
+    }
 
-impl error::Error for Error {}
+    /// The item carrying tag, the first one added if several do.
+    pub fn menu_item_by_tag(&self, tag: u64) -> Option<MenuItem> {
+        lock_tags(&self.tags)
+            .iter()
+            .filter(|&(_, &t)| t == tag)
+            .map(|(&idx, _)| idx)
+            .min()
+            .map(MenuItem)
+    }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        use self::Error::*;
+    /// Gets a handle to a submenu that was added earlier, e.g. from inside a
+    /// callback.
+    pub fn submenu(&mut self, item: MenuItem) -> Option<Submenu<'_, S>> {
+        if self.submenus.contains(&item.0) {
+            Some(Submenu {
+                app: self,
+                idx: item.0,
+            })
+        } else {
+            None
+        }
+    }
 
-        match *self {
-            OsError(ref err_str) => write!(f, "OsError: {}", err_str),
-            NotImplementedError => write!(f, "Functionality is not implemented yet"),
-            UnknownError => write!(f, "Unknown error occurrred"),
-            Error(ref e) => write!(f, "Error: {}", e),
+    fn check_parent(&self, parent: Option<u32>) -> Result<(), Error> {
+        match parent {
+            Some(p) if !self.submenus.contains(&p) => {
+                Err(Error::OsError(format!("Menu item {} is not a submenu", p)))
+            }
+            _ => Ok(()),
         }
     }
-}
 
-pub struct Application {
-    window: api::api::Window,
-    menu_idx: u32,
-    callback: HashMap<u32, Callback>,
-    // Each platform-specific window module will set up its own thread for
-    // dealing with the OS main loop. Use this channel for receiving events from
-    // that thread.
-    rx: Receiver<SystrayEvent>,
-}
+    // Hands a menu change to the backend, or queues it while a callback runs.
+    // Queued changes can't fail here; their errors come after the callback's.
+    fn menu_call<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&api::Window) -> Result<(), Error> + Send + 'static,
+    {
+        if self.in_callback {
+            self.menu_calls.borrow_mut().push(Box::new(f));
+            Ok(())
+        } else {
+            f(&self.window)
+        }
+    }
 
-type Callback =
-    Box<(dyn FnMut(&mut Application) -> Result<(), BoxedError> + Send + Sync + 'static)>;
+    // Applies the changes queued by menu_call, stopping at the first error.
+    fn apply_menu_calls(&self) -> Result<(), Error> {
+        let calls = self.menu_calls.replace(Vec::new());
+        for call in calls {
+            call(&self.window)?;
+        }
+        Ok(())
+    }
 
-fn make_callback<F, E>(mut f: F) -> Callback
-where
-    F: FnMut(&mut Application) -> Result<(), E> + Send + Sync + 'static,
-    E: error::Error + Send + Sync + 'static,
-{
-    Box::new(move |a: &mut Application| match f(a) {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Box::new(e) as BoxedError),
-    }) as Callback
-}
+    fn insert_menu_item<F, E>(
+        &mut self,
+        parent: Option<u32>,
+        item_name: &str,
+        f: F,
+    ) -> Result<u32, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let idx = self.insert_menu_entry(parent, item_name)?;
+        self.callback.insert(idx, make_callback(f));
+        Ok(idx)
+    }
 
-impl Application {
-    pub fn new() -> Result<Application, Error> {
-        let (event_tx, event_rx) = channel();
-        match api::api::Window::new(event_tx) {
-            Ok(w) => Ok(Application {
-                window: w,
-                menu_idx: 0,
-                callback: HashMap::new(),
-                rx: event_rx,
-            }),
-            Err(e) => Err(e),
+    fn insert_menu_item_with_icon<F, E>(
+        &mut self,
+        parent: Option<u32>,
+        item_name: &str,
+        icon: &IconBuffer,
+        f: F,
+    ) -> Result<u32, Error>
+    where
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let idx = self.insert_menu_item(parent, item_name, f)?;
+        if let Err(e) = self.set_menu_item_icon(MenuItem(idx), icon) {
+            self.remove_menu_item(MenuItem(idx)).ok();
+            return Err(e);
         }
+        Ok(idx)
     }
 
-    pub fn add_menu_item<F, E>(&mut self, item_name: &str, f: F) -> Result<u32, Error>
+    fn insert_menu_item_with_shortcut<F, E>(
+        &mut self,
+        parent: Option<u32>,
+        item_name: &str,
+        shortcut: &str,
+        f: F,
+    ) -> Result<u32, Error>
     where
-        F: FnMut(&mut Application) -> Result<(), E> + Send + Sync + 'static,
+        F: FnMut(&mut Application<S>) -> Result<(), E> + Send + Sync + 'static,
         E: error::Error + Send + Sync + 'static,
     {
-        let idx = self.menu_idx;
-        if let Err(e) = self.window.add_menu_entry(idx, item_name) {
+        // Parse first, so a typo doesn't leave an item behind.
+        let shortcut = Shortcut::parse(shortcut)?;
+        let idx = self.insert_menu_item(parent, item_name, f)?;
+        let set = self.menu_call(move |window| window.set_menu_item_shortcut(idx, &shortcut));
+        if let Err(e) = set {
+            self.remove_menu_item(MenuItem(idx)).ok();
             return Err(e);
         }
-        self.callback.insert(idx, make_callback(f));
+        Ok(idx)
+    }
+
+    fn insert_menu_entry(&mut self, parent: Option<u32>, item_name: &str) -> Result<u32, Error> {
+        self.check_parent(parent)?;
+        let idx = self.menu_idx;
+        let name = item_name.to_owned();
+        self.menu_call(move |window| window.add_menu_entry(idx, parent, &name))?;
         self.menu_idx += 1;
         Ok(idx)
     }
 
-    pub fn add_menu_separator(&mut self) -> Result<u32, Error> {
+    fn insert_menu_checkable_item<F, E>(
+        &mut self,
+        parent: Option<u32>,
+        item_name: &str,
+        checked: bool,
+        f: F,
+    ) -> Result<u32, Error>
+    where
+        F: FnMut(&mut Application<S>, bool) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.check_parent(parent)?;
         let idx = self.menu_idx;
-        if let Err(e) = self.window.add_menu_separator(idx) {
-            return Err(e);
+        let name = item_name.to_owned();
+        self.menu_call(move |window| window.add_menu_checkable_entry(idx, parent, &name, checked))?;
+        self.callback.insert(idx, make_checkable_callback(idx, f));
+        self.checked.insert(idx, checked);
+        self.menu_idx += 1;
+        Ok(idx)
+    }
+
+    fn insert_menu_radio_group<F, E>(
+        &mut self,
+        parent: Option<u32>,
+        labels: &[&str],
+        selected: usize,
+        f: F,
+    ) -> Result<Vec<MenuItem>, Error>
+    where
+        F: FnMut(&mut Application<S>, usize) -> Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.check_parent(parent)?;
+        if selected >= labels.len() {
+            return Err(Error::OsError(format!(
+                "Radio group of {} items has no item {}",
+                labels.len(),
+                selected
+            )));
+        }
+        let group = self.menu_idx;
+        let f = Arc::new(Mutex::new(f));
+        let mut items = Vec::new();
+        for (i, label) in labels.iter().enumerate() {
+            let idx = self.menu_idx;
+            let checked = i == selected;
+            let label = label.to_string();
+            let added = self.menu_call(move |window| {
+                window.add_menu_radio_entry(idx, parent, &label, group, checked)
+            });
+            if let Err(e) = added {
+                for item in items {
+                    self.remove_menu_item(item).ok();
+                }
+                return Err(e);
+            }
+            self.callback.insert(idx, make_radio_callback(i, f.clone()));
+            self.checked.insert(idx, checked);
+            self.radio.insert(idx, (group, labels.len() as u32));
+            self.menu_idx += 1;
+            items.push(MenuItem(idx));
+        }
+        Ok(items)
+    }
+
+    // Checks idx and unchecks whatever else in its group is still around.
+    fn select_radio(&mut self, idx: u32, first: u32, len: u32) -> Result<(), Error> {
+        self.menu_call(move |window| window.set_menu_item_checked(idx, true))?;
+        self.checked.insert(idx, true);
+        for other in first..first + len {
+            if other != idx && self.radio.contains_key(&other) {
+                self.menu_call(move |window| window.set_menu_item_checked(other, false))?;
+                self.checked.insert(other, false);
+            }
         }
+        Ok(())
+    }
+
+    fn insert_menu_separator(&mut self, parent: Option<u32>) -> Result<u32, Error> {
+        self.check_parent(parent)?;
+        let idx = self.menu_idx;
+        self.menu_call(move |window| window.add_menu_separator(idx, parent))?;
+        self.menu_idx += 1;
+        Ok(idx)
+    }
+
+    fn insert_menu_header(&mut self, parent: Option<u32>, title: &str) -> Result<u32, Error> {
+        self.check_parent(parent)?;
+        let idx = self.menu_idx;
+        let title = title.to_owned();
+        self.menu_call(move |window| window.add_menu_header(idx, parent, &title))?;
+        self.menu_idx += 1;
+        Ok(idx)
+    }
+
+    fn insert_submenu(&mut self, parent: Option<u32>, item_name: &str) -> Result<u32, Error> {
+        self.check_parent(parent)?;
+        let idx = self.menu_idx;
+        let name = item_name.to_owned();
+        self.menu_call(move |window| window.add_submenu(idx, parent, &name))?;
+        self.submenus.insert(idx);
         self.menu_idx += 1;
         Ok(idx)
     }
 
+    /// Batches every change made through the editor into a single trip to the
+    /// backend. The backends still add the items one at a time, so when one
+    /// fails the ones before it stay in the menu, without their callbacks.
+    pub fn with_menu<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut MenuEditor<S>),
+    {
+        let mut editor = MenuEditor::new(self.menu_idx);
+        f(&mut editor);
+        self.apply_menu_editor(editor)
+    }
+
+    /// Swaps the whole menu for a new one, in a single trip to the backend like
+    /// with_menu. Handles to the old items stop working. When the backend
+    /// fails partway the old menu is gone all the same: the new items that
+    /// went in work as usual, and the rest are missing.
+    pub fn set_menu(&mut self, menu: Menu<S>) -> Result<(), Error> {
+        let start = self.menu_idx;
+        let mut editor = MenuEditor::new(start);
+        editor.ops.push(MenuOp::Clear);
+        editor.push_menu(menu, None);
+        // Never hand out the indices again, even if only some items went in.
+        self.menu_idx = editor.menu_idx;
+        // The old menu is cleared first, so on an error too it's only the new
+        // items that can be left in it.
+        let ops = editor.ops;
+        let result = self.menu_call(move |window| window.apply_menu_ops(ops));
+        self.callback.clear();
+        self.checked.clear();
+        self.radio.clear();
+        self.submenus.clear();
+        self.disabled.borrow_mut().clear();
+        self.removed.clear();
+        self.menu_start = start;
+        self.callback.extend(editor.callback);
+        self.checked.extend(editor.checked);
+        self.submenus.extend(editor.submenus);
+        self.disabled.borrow_mut().extend(editor.disabled);
+        let mut tags = lock_tags(&self.tags);
+        tags.clear();
+        tags.extend(editor.tags);
+        result
+    }
+
+    fn apply_menu_editor(&mut self, editor: MenuEditor<S>) -> Result<(), Error> {
+        self.menu_idx = editor.menu_idx;
+        let ops = editor.ops;
+        self.menu_call(move |window| window.apply_menu_ops(ops))?;
+        self.callback.extend(editor.callback);
+        self.checked.extend(editor.checked);
+        self.submenus.extend(editor.submenus);
+        lock_tags(&self.tags).extend(editor.tags);
+        Ok(())
+    }
+
     pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error> {
-        self.window.set_icon_from_file(file)
+        self.window.set_icon_from_file(file)?;
+        self.icon.replace(None);
+        Ok(())
+    }
+
+    pub fn set_icon_from_resource<R>(&self, resource: R) -> Result<(), Error>
+    where
+        R: Into<IconResource>,
+    {
+        self.window.set_icon_from_resource(&resource.into())?;
+        self.icon.replace(None);
+        Ok(())
+    }
+
+    /// Row by row from the top left, 4 bytes per pixel. The native image is
+    /// made straight from the pixels, for icons drawn at runtime that would
+    /// otherwise have to be encoded first.
+    pub fn set_icon_from_rgba(
+        &self,
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        let icon = IconBuffer::from_rgba(pixels, width, height)?;
+        // Meters redrawn on a timer often come out the same.
+        if let (true, Some(current)) = (self.icon_showing.get(), self.icon.borrow().as_ref()) {
+            if current.width == width && current.height == height && current.rgba == icon.rgba {
+                return Ok(());
+            }
+        }
+        self.set_icon(icon)
+    }
+
+    fn set_icon(&self, icon: IconBuffer) -> Result<(), Error> {
+        match *self.badge.borrow() {
+            Some(ref badge) => {
+                let badged = badge::draw(&icon, badge);
+                self.window
+                    .set_icon_from_rgba(&badged.rgba, badged.width, badged.height)?
+            }
+            None => self
+                .window
+                .set_icon_from_rgba(&icon.rgba, icon.width, icon.height)?,
+        }
+        self.icon.replace(Some(icon));
+        self.icon_showing.set(true);
+        Ok(())
     }
 
-    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
-        self.window.set_icon_from_resource(resource)
+    /// The badge is drawn onto the icon last set from pixels, and onto the
+    /// ones set that way after it, until it's set back to None. Icons from
+    /// files have no pixels to draw on.
+    pub fn set_icon_badge(&self, badge: Option<BadgeSpec>) -> Result<(), Error> {
+        let icon = self.icon.borrow().clone();
+        if badge.is_some() && icon.is_none() {
+            return Err(Error::InvalidIcon(
+                "Badges need an icon set from pixels".to_owned(),
+            ));
+        }
+        self.badge.replace(badge);
+        match icon {
+            Some(icon) => self.set_icon(icon),
+            None => Ok(()),
+        }
     }
 
     #[cfg(target_os = "windows")]
@@ -118,7 +2384,155 @@ impl Application {
         width: u32,
         height: u32,
     ) -> Result<(), Error> {
-        self.window.set_icon_from_buffer(buffer, width, height)
+        self.window.set_icon_from_buffer(buffer, width, height)?;
+        self.icon.replace(None);
+        Ok(())
+    }
+
+    /// set_icon_from_buffer skips reloading a buffer identical to the current
+    /// icon. Use this to reload it anyway.
+    #[cfg(target_os = "windows")]
+    pub fn force_icon_from_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        self.window.force_icon_from_buffer(buffer, width, height)?;
+        self.icon.replace(None);
+        Ok(())
+    }
+
+    /// Converts the image to what the platform wants, scaling it to the tray
+    /// icon size where the platform has a fixed one.
+    #[cfg(feature = "image")]
+    pub fn set_icon_from_image(&self, image: &image::DynamicImage) -> Result<(), Error> {
+        let mut image = image.to_rgba8();
+        if let Some((width, height)) = self.window.icon_size() {
+            if image.dimensions() != (width, height) {
+                image = image::imageops::resize(
+                    &image,
+                    width,
+                    height,
+                    image::imageops::FilterType::Lanczos3,
+                );
+            }
+        }
+        let (width, height) = image.dimensions();
+        self.set_icon(IconBuffer {
+            rgba: image.into_raw(),
+            width,
+            height,
+        })
+    }
+
+    /// Takes an encoded image in any format the image crate can decode, such as
+    /// PNG, JPEG, BMP or ICO.
+    #[cfg(feature = "image")]
+    pub fn set_icon_from_image_data(&self, buffer: &[u8]) -> Result<(), Error> {
+        let image = match image::load_from_memory(buffer) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::InvalidIcon(e.to_string())),
+        };
+        self.set_icon_from_image(&image)
+    }
+
+    /// Cycles through frames every interval until stop_icon_animation is called
+    /// or another animation is started. The timer runs in the backend, so the
+    /// app doesn't have to keep setting icons itself.
+    pub fn set_icon_animation(
+        &self,
+        frames: Vec<IconBuffer>,
+        interval: Duration,
+    ) -> Result<(), Error> {
+        if frames.is_empty() {
+            return Err(Error::InvalidIcon("Animation has no frames".to_owned()));
+        }
+        self.window.set_icon_animation(frames, interval)?;
+        self.icon_showing.set(false);
+        Ok(())
+    }
+
+    /// Leaves whatever frame is showing as the icon.
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        self.window.stop_icon_animation()
+    }
+
+    /// Template icons only use the alpha channel, and macOS colors them to fit
+    /// dark and light menu bars and the highlighted state.
+    #[cfg(target_os = "macos")]
+    pub fn set_icon_as_template(&self, template: bool) -> Result<(), Error> {
+        self.window.set_icon_as_template(template)
+    }
+
+    /// Takes the icon out of the tray and puts it back, with its menu and
+    /// everything else as it was. Changes made while it's hidden show once
+    /// it's back.
+    pub fn set_visible(&self, visible: bool) -> Result<(), Error> {
+        self.window.set_visible(visible)
+    }
+
+    /// Opens the platform's About window with the app's details: the standard
+    /// About panel on macOS, a GtkAboutDialog on Linux and a message box on
+    /// Windows. Tray apps have no window of their own to put them in.
+    pub fn show_about(&self, about: AboutMetadata) -> Result<(), Error> {
+        self.window.show_about(about)
+    }
+
+    /// Brings the app's own main window to the front, for a click on the tray
+    /// icon or an "Open" menu item. Windows and window managers only let an
+    /// app take the focus while it's handling input, so call it straight from
+    /// the event. Does nothing if the app has no window open. On Linux this
+    /// needs X11, Wayland only lets a window activate itself.
+    pub fn activate_host_app(&self) -> Result<(), Error> {
+        self.window.activate_host_app()
+    }
+
+    /// Takes effect straight away, so an app can switch to Regular while it
+    /// has a window open and back to Accessory once it closes.
+    #[cfg(target_os = "macos")]
+    pub fn set_activation_policy(&self, policy: ActivationPolicy) -> Result<(), Error> {
+        self.window.set_activation_policy(policy)
+    }
+
+    /// Pops up a notification from the tray icon, with an optional icon of its
+    /// own. The timeout is only a hint: Windows picks the duration from its
+    /// accessibility settings, and notification daemons may ignore it.
+    pub fn show_notification(
+        &self,
+        title: &str,
+        body: &str,
+        icon: Option<&IconBuffer>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        self.window.show_notification(title, body, icon, timeout)
+    }
+
+    /// What screen readers read out for the icon. Until this is called it is
+    /// the tooltip, or the title without one. On Windows it's read from the
+    /// tooltip, so it only goes in, without showing, while there's none.
+    pub fn set_icon_accessibility_description(&self, desc: &str) -> Result<(), Error> {
+        self.window.set_icon_accessibility_description(desc)?;
+        self.icon_description_set.set(true);
+        Ok(())
+    }
+
+    fn update_icon_description(&self) {
+        if self.icon_description_set.get() {
+            return;
+        }
+        let tooltip = self.tooltip.borrow();
+        let desc = if tooltip.is_empty() { self.title.borrow() } else { tooltip };
+        match self.window.set_icon_accessibility_description(&desc) {
+            Ok(()) | Err(Error::NotSupported { .. }) => {}
+            Err(e) => log::warn!("Couldn't set the icon description: {}", e),
+        }
+    }
+
+    /// How many calls have been handed to the backend thread but not applied
+    /// yet. A number that keeps growing means that thread is stuck or flooded.
+    pub fn pending_command_count(&self) -> usize {
+        self.window.pending_command_count()
     }
 
     pub fn shutdown(&self) -> Result<(), Error> {
@@ -126,36 +2540,315 @@ impl Application {
     }
 
     pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        *self.tooltip.borrow_mut() = tooltip.to_owned();
+        self.update_icon_description();
         self.window.set_tooltip(tooltip)
     }
 
+    /// Sends HotkeyPressed whenever the key combination is pressed, whichever
+    /// app has the focus. Fails if another app already has it. On Linux this
+    /// needs X11, Wayland doesn't let apps grab keys. macOS has no F21 to F24,
+    /// so hotkeys on those fail there with NotSupported.
+    pub fn register_global_hotkey(
+        &mut self,
+        modifiers: Modifiers,
+        key: Key,
+    ) -> Result<HotkeyId, Error> {
+        let idx = self.hotkey_idx;
+        let shortcut = Shortcut {
+            modifiers,
+            key,
+        };
+        self.window.register_hotkey(idx, &shortcut)?;
+        self.hotkey_idx += 1;
+        Ok(HotkeyId(idx))
+    }
+
+    pub fn unregister_global_hotkey(&mut self, id: HotkeyId) -> Result<(), Error> {
+        self.window.unregister_hotkey(id.0)
+    }
+
+    /// Text shown next to the icon, for values that keep changing like a timer.
+    /// The notification area on Windows has no room for text.
+    pub fn set_title(&self, title: &str) -> Result<(), Error> {
+        *self.title.borrow_mut() = title.to_owned();
+        self.update_icon_description();
+        self.window.set_title(title)
+    }
+
+    /// Whether a left click on the icon opens the menu, on by default. Turn it
+    /// off to handle left clicks through IconClicked events instead; a right
+    /// click always opens the menu.
+    pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<(), Error> {
+        self.window.set_menu_on_left_click(enabled)
+    }
+
+    /// Menus follow the direction of the system's language by default. Apps
+    /// with a language setting of their own set it to match.
+    pub fn set_menu_direction(&self, direction: LayoutDirection) -> Result<(), Error> {
+        self.window.set_menu_direction(direction)
+    }
+
+    /// Draws the menu ourselves, in the given style, instead of leaving it to
+    /// the system, which only has light and dark menus in the system font.
+    /// None hands it back to the system. Only on Windows.
+    pub fn set_menu_style(&self, style: Option<MenuStyle>) -> Result<(), Error> {
+        self.window.set_menu_style(style)
+    }
+
+    /// Opens the menu as clicking the icon would, e.g. from a hotkey, so it can
+    /// be had without the pointer. At the icon on Windows and macOS, at the
+    /// pointer on Linux. On macOS this returns once the menu is closed.
+    pub fn show_menu(&self) -> Result<(), Error> {
+        self.window.show_menu()
+    }
+
+    /// Like show_menu, at a point on the screen in the coordinates IconClicked
+    /// comes with, e.g. to open it where the app's own window was clicked.
+    pub fn show_menu_at(&self, x: i32, y: i32) -> Result<(), Error> {
+        self.window.show_menu_at(x, y)
+    }
+
+    pub fn native_handle(&self) -> Result<NativeHandle, Error> {
+        self.window.native_handle()
+    }
+
+    /// What the app did to the tray so far, and a way to send it events. None
+    /// unless the tray was made with Backend::Mock.
+    pub fn mock_tray(&self) -> Option<MockTray> {
+        self.window.mock_tray()
+    }
+
+    /// Worked out at runtime, on Linux it depends on what shows the icon.
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        self.window.capabilities()
+    }
+
+    /// Where the icon is on screen, e.g. to open a window right next to it.
+    /// Linux only knows this for GtkStatusIcon; elsewhere there, use the
+    /// position of the IconClicked event instead.
+    pub fn icon_screen_rect(&self) -> Result<Rect, Error> {
+        self.window.icon_screen_rect()
+    }
+
+    /// Takes the icon away and stops the backend, then wait_for_message
+    /// returns and the event functions hand out Quit. From other threads, go
+    /// through ApplicationHandle::quit.
     pub fn quit(&mut self) {
+        self.quit_requested = true;
         self.window.quit()
     }
 
+    /// For apps that would rather match on menu ids than register callbacks.
+    /// Blocks until the next event, returns Quit once the tray is gone and
+    /// None after that.
+    pub fn next_event(&mut self) -> Option<SystrayEvent> {
+        loop {
+            if self.quit_requested {
+                return self.quit_event();
+            }
+            match self.window.recv_event(&self.rx) {
+                Some(Message::Event(e)) => {
+                    if let Some(e) = self.handle_event(e) {
+                        return Some(e);
+                    }
+                }
+                Some(Message::Wake) => self.run_commands(),
+                None => return self.quit_event(),
+            }
+        }
+    }
+
+    /// Like next_event, but returns None right away when nothing is queued.
+    /// For apps that already run their own loop and check in on the tray now
+    /// and then.
+    pub fn poll_event(&mut self) -> Option<SystrayEvent> {
+        loop {
+            if self.quit_requested {
+                return self.quit_event();
+            }
+            match self.window.try_recv_event(&self.rx) {
+                Ok(Message::Event(e)) => {
+                    if let Some(e) = self.handle_event(e) {
+                        return Some(e);
+                    }
+                }
+                Ok(Message::Wake) => self.run_commands(),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => return self.quit_event(),
+            }
+        }
+    }
+
+    fn quit_event(&mut self) -> Option<SystrayEvent> {
+        if self.quit_reported {
+            return None;
+        }
+        self.quit_reported = true;
+        Some(SystrayEvent::Quit)
+    }
+
+    /// Stream of events for async code, ending once the tray shuts down. It
+    /// only hands out events, callbacks are not run. Doesn't work on macOS,
+    /// where events only show up while the main thread is pumping the AppKit
+    /// loop through next_event or poll_event.
+    #[cfg(feature = "async")]
+    pub fn event_stream(&mut self) -> impl Stream<Item = SystrayEvent> + '_ {
+        stream::poll_fn(move |cx| {
+            // Register first, so an event sent right after try_recv still
+            // wakes us up.
+            self.waker.register(cx.waker());
+            loop {
+                if self.quit_requested {
+                    return Poll::Ready(self.quit_event());
+                }
+                match self.window.try_recv_event(&self.rx) {
+                    Ok(Message::Event(e)) => {
+                        if let Some(e) = self.handle_event(e) {
+                            return Poll::Ready(Some(e));
+                        }
+                    }
+                    Ok(Message::Wake) => self.run_commands(),
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    Err(TryRecvError::Disconnected) => return Poll::Ready(self.quit_event()),
+                }
+            }
+        })
+    }
+
+    /// Iterator over the events queued right now. It ends once they are all
+    /// handled, so it can be drained once per frame or tick.
+    pub fn poll_events(&mut self) -> impl Iterator<Item = SystrayEvent> + '_ {
+        iter::from_fn(move || self.poll_event())
+    }
+
+    fn run_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            command(self);
+        }
+    }
+
+    // None for events that are dropped.
+    fn handle_event(&mut self, msg: SystrayEvent) -> Option<SystrayEvent> {
+        if let Some(idx) = msg.menu_index() {
+            if self.disabled.borrow().contains(&idx) {
+                return None;
+            }
+            if let Some(&(first, len)) = self.radio.get(&idx) {
+                if let Err(e) = self.select_radio(idx, first, len) {
+                    log::warn!("Couldn't update menu item {}: {}", idx, e);
+                }
+            } else if let Some(checked) = self.checked.get_mut(&idx) {
+                *checked = !*checked;
+                let checked = *checked;
+                let set = self.menu_call(move |window| window.set_menu_item_checked(idx, checked));
+                if let Err(e) = set {
+                    log::warn!("Couldn't update menu item {}: {}", idx, e);
+                }
+            }
+        }
+        Some(msg)
+    }
+
+    /// Blocking iterator over events, ending once the tray shuts down. Drive it
+    /// from the thread that created the Application; on macOS that is also the
+    /// thread that has to run the AppKit event loop.
+    pub fn events_blocking_iter(&mut self) -> impl Iterator<Item = SystrayEvent> + '_ {
+        iter::from_fn(move || self.next_event())
+    }
+
     pub fn wait_for_message(&mut self) -> Result<(), Error> {
         loop {
-            let msg;
-            match self.rx.recv() {
-                Ok(m) => msg = m,
-                Err(_) => {
+            match self.next_event() {
+                Some(m) => self.run_callback(&m)?,
+                None => {
                     self.quit();
                     break;
                 }
             }
-            if self.callback.contains_key(&msg.menu_index) {
-                if let Some(mut f) = self.callback.remove(&msg.menu_index) {
-                    f(self)?;
-                    self.callback.insert(msg.menu_index, f);
-                }
+        }
+
+        Ok(())
+    }
+
+    /// Non-blocking version of wait_for_message. Runs the callbacks for whatever
+    /// events are queued right now, then returns.
+    pub fn dispatch_pending(&mut self) -> Result<(), Error> {
+        while let Some(m) = self.poll_event() {
+            self.run_callback(&m)?;
+        }
+        Ok(())
+    }
+
+    fn run_callback(&mut self, msg: &SystrayEvent) -> Result<(), Error> {
+        let idx = match msg.menu_index() {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        if self.in_callback {
+            self.deferred_clicks.push_back(idx);
+            return Ok(());
+        }
+        self.in_callback = true;
+        let mut result = self.run_item_callback(idx);
+        while result.is_ok() {
+            match self.deferred_clicks.pop_front() {
+                Some(idx) => result = self.run_item_callback(idx),
+                None => break,
             }
         }
+        self.in_callback = false;
+        if result.is_err() {
+            // Clicks the failed callback dispatched don't outlive it.
+            self.deferred_clicks.clear();
+        }
+        result
+    }
 
+    fn run_item_callback(&mut self, idx: u32) -> Result<(), Error> {
+        if let Some(mut f) = self.callback.remove(&idx) {
+            // Don't let a panicking callback take the tray down with it.
+            // Report it, put the callback back, and keep handling events.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+            if idx >= self.menu_start && !self.removed.contains(&idx) {
+                self.callback.insert(idx, f);
+            }
+            // Whatever the callback changed in the menu goes in now, even if
+            // it failed, since the ids it was handed are in use.
+            let applied = self.apply_menu_calls();
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => match self.error_handler.as_mut() {
+                    Some(handler) => handler(MenuItem(idx), Error::from(e)),
+                    None => return Err(Error::from(e)),
+                },
+                Err(e) => {
+                    let message = panic_message(&*e);
+                    log::error!("Callback for menu item {} panicked: {}", idx, message);
+                    // wait_for_message and dispatch_pending drop the event,
+                    // so tell the error handler too.
+                    if let Some(handler) = self.error_handler.as_mut() {
+                        handler(MenuItem(idx), Error::CallbackPanicked(message.clone()));
+                    }
+                    let event = SystrayEvent::CallbackPanicked {
+                        item: Some(MenuItem(idx)),
+                        message,
+                    };
+                    self.event_tx.send(Message::Event(event));
+                }
+            }
+            if let Err(e) = applied {
+                match self.error_handler.as_mut() {
+                    Some(handler) => handler(MenuItem(idx), e),
+                    None => return Err(e),
+                }
+            }
+        }
         Ok(())
     }
 }
 
-impl Drop for Application {
+impl<S> Drop for Application<S> {
     fn drop(&mut self) {
         self.shutdown().ok();
     }
@@ -0,0 +1,27 @@
+// Handing URLs and files to the desktop: the default browser (or whatever
+// handles the scheme) for URLs, and the file manager with the file selected
+// for paths. Both return once the request is passed on, not when it's shown.
+// Application::open_url and reveal do the same through the tray's backend, so
+// on the mock one they are only recorded.
+use crate::{api, Error};
+use std::path::{Path, PathBuf};
+
+pub fn open_url(url: &str) -> Result<(), Error> {
+    api::native::open::open_url(url)
+}
+
+pub fn reveal<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    api::native::open::reveal(&absolute(path.as_ref())?)
+}
+
+// Paths are made absolute first, since the file manager doesn't share our
+// working directory.
+pub(crate) fn absolute(path: &Path) -> Result<PathBuf, Error> {
+    if path.is_absolute() {
+        return Ok(path.to_owned());
+    }
+    match std::env::current_dir() {
+        Ok(dir) => Ok(dir.join(path)),
+        Err(e) => Err(Error::OsError(format!("Couldn't resolve {}: {}", path.display(), e))),
+    }
+}
@@ -0,0 +1,71 @@
+// Trays for apps built on winit. The tray attaches to the event loop the app
+// already runs instead of starting its own, and each tray event is sent to the
+// loop as a user event:
+//
+//     let event_loop = EventLoop::<AppEvent>::with_user_event();
+//     let proxy = event_loop.create_proxy();
+//     let mut tray = SystrayBuilder::new().build_with_event_loop(&event_loop, proxy)?;
+//     event_loop.run(move |event, _, flow| match event {
+//         Event::UserEvent(AppEvent::Tray(e)) => {
+//             // Runs the menu callbacks and keeps checkmarks in sync.
+//             tray.dispatch_pending().ok();
+//             ...
+//         }
+//         ...
+//     });
+//
+// Commands queued through an ApplicationHandle run on the next
+// dispatch_pending, which only comes with the next tray event unless the app
+// wakes its loop itself.
+use crate::{Application, ApplicationMode, Backend, Error, Forwarder, SystrayEvent};
+use ::winit::event_loop::{EventLoopProxy, EventLoopWindowTarget};
+use std::sync::{Arc, Mutex};
+
+pub struct SystrayBuilder<S = ()> {
+    state: S,
+    backend: Backend,
+}
+
+impl SystrayBuilder {
+    pub fn new() -> SystrayBuilder {
+        SystrayBuilder::with_state(())
+    }
+}
+
+impl Default for SystrayBuilder {
+    fn default() -> Self {
+        SystrayBuilder::new()
+    }
+}
+
+impl<S> SystrayBuilder<S> {
+    pub fn with_state(state: S) -> SystrayBuilder<S> {
+        SystrayBuilder {
+            state,
+            backend: Backend::Auto,
+        }
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    // The event loop is only borrowed to make sure the tray is built on its
+    // thread, which on macOS has to be the main one.
+    pub fn build_with_event_loop<T>(
+        self,
+        _event_loop: &EventLoopWindowTarget<T>,
+        proxy: EventLoopProxy<T>,
+    ) -> Result<Application<S>, Error>
+    where
+        T: From<SystrayEvent> + Send + 'static,
+    {
+        let forward: Forwarder = Arc::new(Mutex::new(Box::new(move |event| {
+            // Only fails once the loop has exited, and then nobody's listening.
+            proxy.send_event(T::from(event)).ok();
+        })));
+        let mode = ApplicationMode::AttachToExisting;
+        Application::create(self.state, mode, self.backend, Some(forward), true, None)
+    }
+}
@@ -0,0 +1,107 @@
+// The UTF-16 strings of the Windows API. Kept out of the backend so the
+// conversions can be tested anywhere.
+
+// Null terminated UTF-16, as the W functions take it.
+pub(crate) fn to_wstring(str: &str) -> Vec<u16> {
+    str.encode_utf16().chain(Some(0)).collect()
+}
+
+// Copies as much of str as fits into one of the fixed size string fields of
+// NOTIFYICONDATAW, leaving room for the terminating null. Never stops between
+// the two halves of a surrogate pair, which would leave half an emoji.
+pub(crate) fn copy_to_wstring(dest: &mut [u16], str: &str) {
+    let src = to_wstring(str);
+    let mut len = (src.len() - 1).min(dest.len() - 1);
+    if len > 0 && len < src.len() - 1 && (0xd800..0xdc00).contains(&src[len - 1]) {
+        len -= 1;
+    }
+    dest[..len].copy_from_slice(&src[..len]);
+    dest[len] = 0;
+}
+
+// Menus take & to underline the next letter and a tab to start the shortcut
+// column. Labels are shown as given.
+pub(crate) fn menu_label(label: &str) -> String {
+    label.replace('&', "&&").replace('\t', " ")
+}
+
+// Longest tooltip the shell shows, szTip less its terminating null.
+const MAX_TIP_LEN: usize = 127;
+
+pub(crate) fn fits_tip(tooltip: &str) -> bool {
+    tooltip.encode_utf16().count() <= MAX_TIP_LEN
+}
+
+// As much of tooltip as fits in szTip, with an ellipsis where it was cut.
+// Screen readers still get this much when we show the full text ourselves.
+pub(crate) fn shorten_tooltip(tooltip: &str) -> String {
+    if fits_tip(tooltip) {
+        return tooltip.to_owned();
+    }
+    let mut short = String::new();
+    let mut len = 1;
+    for c in tooltip.chars() {
+        len += c.len_utf16();
+        if len > MAX_TIP_LEN {
+            break;
+        }
+        short.push(c);
+    }
+    short.push('\u{2026}');
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_wstring(wstr: &[u16]) -> String {
+        let len = wstr.iter().position(|&c| c == 0).unwrap();
+        String::from_utf16(&wstr[..len]).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        for label in ["Open", "Öffnen…", "打开", "😀 Smile", "פתח קובץ", "فتح الملف", ""] {
+            let wstr = to_wstring(label);
+            assert_eq!(wstr.last(), Some(&0));
+            assert_eq!(from_wstring(&wstr), label);
+
+            let mut field = [0xffff; 64];
+            copy_to_wstring(&mut field, label);
+            assert_eq!(from_wstring(&field), label);
+        }
+    }
+
+    #[test]
+    fn copy_cuts_at_whole_characters() {
+        let mut field = [0xffff; 4];
+        copy_to_wstring(&mut field, "abcdef");
+        assert_eq!(from_wstring(&field), "abc");
+        // The emoji would need two of the three units left.
+        copy_to_wstring(&mut field, "ab😀");
+        assert_eq!(from_wstring(&field), "ab");
+        copy_to_wstring(&mut field, "a😀b");
+        assert_eq!(from_wstring(&field), "a😀");
+    }
+
+    #[test]
+    fn menu_labels_are_shown_as_given() {
+        assert_eq!(menu_label("Save & Quit"), "Save && Quit");
+        assert_eq!(menu_label("&&"), "&&&&");
+        assert_eq!(menu_label("Name\tCtrl+N"), "Name Ctrl+N");
+        assert_eq!(menu_label("حفظ & خروج"), "حفظ && خروج");
+    }
+
+    #[test]
+    fn long_tooltips_are_shortened() {
+        let short = "תיאור קצר";
+        assert_eq!(shorten_tooltip(short), short);
+
+        let long = "😀".repeat(100);
+        let shortened = shorten_tooltip(&long);
+        assert!(fits_tip(&shortened));
+        assert!(shortened.ends_with('\u{2026}'));
+        assert_eq!(shortened.chars().filter(|&c| c == '😀').count(), 63);
+    }
+}
@@ -0,0 +1,241 @@
+// Menu callbacks run by dispatch_pending, on the mock backend.
+use std::sync::{Arc, Mutex};
+use systray::{Application, Backend, Error, MenuItem, MockTray, SystrayEvent};
+
+fn mock_app() -> (Application, MockTray) {
+    let app = Application::builder().backend(Backend::Mock).build().unwrap();
+    let tray = app.mock_tray().unwrap();
+    (app, tray)
+}
+
+#[test]
+fn panicking_callback_keeps_the_loop_running() {
+    let (mut app, tray) = mock_app();
+    let panicked = Arc::new(Mutex::new(Vec::new()));
+    let errors = panicked.clone();
+    app.set_error_handler(move |item, e| match e {
+        Error::CallbackPanicked(message) => errors.lock().unwrap().push((item, message)),
+        e => panic!("unexpected error {}", e),
+    });
+    let runs = Arc::new(Mutex::new(0));
+    let counter = runs.clone();
+    let bad = app
+        .add_menu_item("Bad", |_| -> Result<(), std::io::Error> { panic!("boom") })
+        .unwrap();
+    let good = app
+        .add_menu_item("Good", move |_| -> Result<(), std::io::Error> {
+            *counter.lock().unwrap() += 1;
+            Ok(())
+        })
+        .unwrap();
+
+    tray.click(bad).unwrap();
+    tray.click(good).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*runs.lock().unwrap(), 1);
+    assert_eq!(*panicked.lock().unwrap(), [(bad, "boom".to_owned())]);
+
+    // The callback stays, and panics again on the next click.
+    tray.click(bad).unwrap();
+    tray.click(good).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*runs.lock().unwrap(), 2);
+    assert_eq!(panicked.lock().unwrap().len(), 2);
+    assert!(tray.is_running());
+}
+
+#[test]
+fn panicking_callback_sends_an_event() {
+    let (mut app, tray) = mock_app();
+    let bad = app
+        .add_menu_item("Bad", |_| -> Result<(), std::io::Error> { panic!("boom") })
+        .unwrap();
+    let seen: Arc<Mutex<Vec<MenuItem>>> = Arc::new(Mutex::new(Vec::new()));
+    let events = seen.clone();
+    // dispatch_pending drops events other than clicks, so look at the queue
+    // from the callback of an item clicked right after.
+    let check = app
+        .add_menu_item("Check", move |app| -> Result<(), std::io::Error> {
+            while let Some(e) = app.poll_event() {
+                if let SystrayEvent::CallbackPanicked { item, message } = e {
+                    assert_eq!(message, "boom");
+                    events.lock().unwrap().extend(item);
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    tray.click(bad).unwrap();
+    tray.click(check).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*seen.lock().unwrap(), [bad]);
+}
+
+#[test]
+fn callback_adds_interior_items() {
+    let (mut app, tray) = mock_app();
+    let interior_runs = Arc::new(Mutex::new(0));
+    let counter = interior_runs.clone();
+    let add = app
+        .add_menu_item("Add Menu Item", move |app| {
+            let counter = counter.clone();
+            app.add_menu_item("Interior item", move |_| -> Result<(), Error> {
+                *counter.lock().unwrap() += 1;
+                Ok(())
+            })?;
+            app.add_menu_separator()?;
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+
+    tray.click(add).unwrap();
+    tray.click(add).unwrap();
+    app.dispatch_pending().unwrap();
+
+    let menu = tray.menu();
+    let labels: Vec<_> = menu.iter().map(|item| item.label.as_str()).collect();
+    assert_eq!(labels, ["Add Menu Item", "Interior item", "", "Interior item", ""]);
+    let indices: Vec<_> = menu.iter().map(|item| item.item.index()).collect();
+    assert_eq!(indices, [0, 1, 2, 3, 4]);
+
+    // Both new items got their own callback.
+    tray.click(menu[1].item).unwrap();
+    tray.click(menu[3].item).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*interior_runs.lock().unwrap(), 2);
+}
+
+#[test]
+fn callback_removes_its_own_item() {
+    let (mut app, tray) = mock_app();
+    let runs = Arc::new(Mutex::new(0));
+    let counter = runs.clone();
+    let own: Arc<Mutex<Option<MenuItem>>> = Arc::new(Mutex::new(None));
+    let handle = own.clone();
+    let first = app.add_menu_entry("First").unwrap();
+    let once = app
+        .add_menu_item("Once", move |app| {
+            *counter.lock().unwrap() += 1;
+            let item = handle.lock().unwrap().unwrap();
+            app.remove_menu_item(item)
+        })
+        .unwrap();
+    *own.lock().unwrap() = Some(once);
+
+    tray.click(once).unwrap();
+    tray.click(once).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*runs.lock().unwrap(), 1);
+    assert!(tray.menu_item(once).is_none());
+
+    // The index isn't handed out again.
+    let last = app.add_menu_entry("Last").unwrap();
+    assert_eq!(first.index(), 0);
+    assert_eq!(last.index(), once.index() + 1);
+    let items: Vec<_> = tray.menu().iter().map(|item| item.item).collect();
+    assert_eq!(items, [first, last]);
+}
+
+#[test]
+fn dispatch_from_a_callback_waits_for_it() {
+    let (mut app, tray) = mock_app();
+    let picked = Arc::new(Mutex::new(Vec::new()));
+    let log = picked.clone();
+    let group = Arc::new(Mutex::new(Vec::new()));
+    let items = group.clone();
+    let radios = app
+        .add_menu_radio_group(&["Slow", "Fast"], 0, move |app, idx| {
+            log.lock().unwrap().push(idx);
+            if idx == 0 {
+                // Another item of the same group, whose closure is this one.
+                let fast = items.lock().unwrap()[1];
+                app.mock_tray().unwrap().click(fast)?;
+                app.dispatch_pending()?;
+            }
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+    *group.lock().unwrap() = radios.clone();
+
+    tray.click(radios[0]).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*picked.lock().unwrap(), [0, 1]);
+    assert_eq!(app.menu_item_checked(radios[1]), Some(true));
+    assert_eq!(app.menu_item_checked(radios[0]), Some(false));
+}
+
+#[test]
+fn menu_changes_wait_for_the_callback() {
+    let (mut app, tray) = mock_app();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let during = seen.clone();
+    let first = app.add_menu_entry("First").unwrap();
+    let change = app
+        .add_menu_item("Change", move |app| {
+            app.set_menu_item_label(first, "Renamed")?;
+            app.add_menu_entry("Added")?;
+            let labels = app.mock_tray().unwrap().menu().into_iter().map(|item| item.label);
+            during.lock().unwrap().extend(labels);
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+
+    tray.click(change).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*seen.lock().unwrap(), ["First", "Change"]);
+    let labels: Vec<_> = tray.menu().into_iter().map(|item| item.label).collect();
+    assert_eq!(labels, ["Renamed", "Change", "Added"]);
+}
+
+#[test]
+fn failed_menu_changes_go_to_the_error_handler() {
+    let (mut app, tray) = mock_app();
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let handled = errors.clone();
+    app.set_error_handler(move |item, e| handled.lock().unwrap().push((item, e.to_string())));
+    let gone = app.add_menu_entry("Gone").unwrap();
+    let change = app
+        .add_menu_item("Change", move |app| {
+            app.remove_menu_item(gone)?;
+            // Fine until the removal goes in.
+            app.set_menu_item_label(gone, "Still here?")?;
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+
+    tray.click(change).unwrap();
+    app.dispatch_pending().unwrap();
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, change);
+    assert!(tray.menu_item(gone).is_none());
+}
+
+#[test]
+fn failed_callback_drops_the_clicks_it_dispatched() {
+    let (mut app, tray) = mock_app();
+    let runs = Arc::new(Mutex::new(0));
+    let counter = runs.clone();
+    let other = app
+        .add_menu_item("Other", move |_| -> Result<(), Error> {
+            *counter.lock().unwrap() += 1;
+            Ok(())
+        })
+        .unwrap();
+    let failing = app
+        .add_menu_item("Failing", move |app| {
+            app.mock_tray().unwrap().click(other)?;
+            app.dispatch_pending()?;
+            Err(Error::OsError("failed".to_owned()))
+        })
+        .unwrap();
+
+    tray.click(failing).unwrap();
+    assert!(app.dispatch_pending().is_err());
+    assert_eq!(*runs.lock().unwrap(), 0);
+    // Only the new click runs it.
+    tray.click(other).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*runs.lock().unwrap(), 1);
+}
@@ -0,0 +1,370 @@
+// Drives Application through the mock backend, so these run without a
+// desktop session.
+use std::sync::{Arc, Mutex};
+use systray::{
+    Application, Backend, BadgeSpec, IconBuffer, Key, LayoutDirection, Menu, MenuEntrySpec,
+    MenuSpec, MockIcon, MockItemKind, MockTray, Shortcut, StandardItems, SystrayEvent,
+};
+
+fn mock_app() -> (Application, MockTray) {
+    let app = Application::builder().backend(Backend::Mock).build().unwrap();
+    let tray = app.mock_tray().unwrap();
+    (app, tray)
+}
+
+#[test]
+fn builder_sets_up_the_tray() {
+    let icon = IconBuffer::from_rgba(vec![0xff; 4 * 4 * 4], 4, 4).unwrap();
+    let app = Application::builder()
+        .backend(Backend::Mock)
+        .icon(icon)
+        .tooltip("Hello")
+        .build()
+        .unwrap();
+    let tray = app.mock_tray().unwrap();
+    assert!(tray.visible());
+    assert_eq!(tray.tooltip(), "Hello");
+    match tray.icon() {
+        Some(MockIcon::Rgba { width, height, .. }) => assert_eq!((width, height), (4, 4)),
+        icon => panic!("unexpected icon {:?}", icon),
+    }
+}
+
+#[test]
+fn menu_is_recorded_in_order() {
+    let (mut app, tray) = mock_app();
+    let open = app.add_menu_entry("Open").unwrap();
+    app.add_menu_separator().unwrap();
+    let quit = app.add_menu_entry("Quit").unwrap();
+
+    let menu = tray.menu();
+    let labels: Vec<_> = menu.iter().map(|item| item.label.as_str()).collect();
+    assert_eq!(labels, ["Open", "", "Quit"]);
+    assert_eq!(menu[0].item, open);
+    assert_eq!(menu[1].kind, MockItemKind::Separator);
+    assert_eq!(menu[2].item, quit);
+
+    app.set_menu_item_label(open, "Open...").unwrap();
+    app.set_menu_item_enabled(quit, false).unwrap();
+    assert_eq!(tray.menu_item(open).unwrap().label, "Open...");
+    assert!(!tray.menu_item(quit).unwrap().enabled);
+}
+
+#[test]
+fn clicks_run_callbacks() {
+    let (mut app, tray) = mock_app();
+    let clicks = Arc::new(Mutex::new(0));
+    let counter = clicks.clone();
+    let item = app
+        .add_menu_item("Count", move |_| -> Result<(), std::io::Error> {
+            *counter.lock().unwrap() += 1;
+            Ok(())
+        })
+        .unwrap();
+
+    tray.click(item).unwrap();
+    tray.click(item).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*clicks.lock().unwrap(), 2);
+}
+
+#[test]
+fn events_come_out_of_poll_event() {
+    let (mut app, tray) = mock_app();
+    let item = app.add_menu_entry("Entry").unwrap();
+    app.set_menu_item_tag(item, Some(7));
+
+    tray.click(item).unwrap();
+    tray.send_event(SystrayEvent::MenuDidClose).unwrap();
+    assert_eq!(
+        app.poll_event(),
+        Some(SystrayEvent::MenuItemClicked {
+            item,
+            tag: Some(7),
+        })
+    );
+    assert_eq!(app.poll_event(), Some(SystrayEvent::MenuDidClose));
+    assert_eq!(app.poll_event(), None);
+}
+
+#[test]
+fn checkable_items_toggle_on_click() {
+    let (mut app, tray) = mock_app();
+    let item = app
+        .add_menu_checkable_item("Check", false, |_, _| -> Result<(), std::io::Error> { Ok(()) })
+        .unwrap();
+
+    tray.click(item).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(app.menu_item_checked(item), Some(true));
+    assert!(tray.menu_item(item).unwrap().checked);
+}
+
+#[test]
+fn quit_ends_the_events() {
+    let (mut app, tray) = mock_app();
+    assert!(tray.is_running());
+    app.quit();
+    assert_eq!(app.next_event(), Some(SystrayEvent::Quit));
+    assert_eq!(app.next_event(), None);
+    assert!(!tray.is_running());
+}
+
+#[test]
+fn right_to_left_menus() {
+    let (mut app, tray) = mock_app();
+    assert_eq!(tray.menu_direction(), None);
+    app.set_menu_direction(LayoutDirection::RightToLeft).unwrap();
+    assert_eq!(tray.menu_direction(), Some(LayoutDirection::RightToLeft));
+
+    let item = app.add_menu_entry("فتح").unwrap();
+    assert_eq!(tray.menu_item(item).unwrap().label, "فتح");
+    app.set_menu_item_label(item, "😀 פתח & סגור").unwrap();
+    assert_eq!(tray.menu_item(item).unwrap().label, "😀 פתח & סגור");
+}
+
+#[test]
+fn accessibility_description_follows_the_tooltip() {
+    let (app, tray) = mock_app();
+    app.set_title("Title").unwrap();
+    assert_eq!(tray.accessibility_description(), "Title");
+    app.set_tooltip("Tooltip").unwrap();
+    assert_eq!(tray.accessibility_description(), "Tooltip");
+    app.set_tooltip("").unwrap();
+    assert_eq!(tray.accessibility_description(), "Title");
+
+    app.set_icon_accessibility_description("Description").unwrap();
+    app.set_tooltip("Another tooltip").unwrap();
+    assert_eq!(tray.accessibility_description(), "Description");
+}
+
+// macOS opens the standard About panel instead.
+#[cfg(not(target_os = "macos"))]
+#[test]
+fn about_item_shows_about() {
+    let (mut app, tray) = mock_app();
+    let items = app
+        .add_standard_items(StandardItems {
+            about: Some("About"),
+            ..StandardItems::default()
+        })
+        .unwrap();
+
+    tray.click(items.about.unwrap()).unwrap();
+    app.dispatch_pending().unwrap();
+    let exe = std::env::current_exe().unwrap();
+    let name = exe.file_stem().unwrap().to_string_lossy();
+    assert_eq!(tray.about().unwrap().name, name);
+}
+
+#[test]
+fn open_and_autostart_are_recorded_per_tray() {
+    let (mut app, tray) = mock_app();
+    let (other, other_tray) = mock_app();
+    let link = app.add_menu_link_item("Site", "https://example.com").unwrap();
+    tray.click(link).unwrap();
+    app.dispatch_pending().unwrap();
+    app.reveal("/tmp/file.txt").unwrap();
+    assert_eq!(tray.opened_urls(), ["https://example.com"]);
+    assert_eq!(tray.revealed_paths(), [std::path::PathBuf::from("/tmp/file.txt")]);
+
+    app.enable_autostart().unwrap();
+    assert!(tray.autostart_enabled());
+    assert!(app.autostart_enabled().unwrap());
+    assert!(!other.autostart_enabled().unwrap());
+    assert!(other_tray.opened_urls().is_empty());
+    app.disable_autostart().unwrap();
+    assert!(!tray.autostart_enabled());
+}
+
+#[test]
+fn set_menu_greys_out_disabled_entries() {
+    let (mut app, tray) = mock_app();
+    let menu = Menu::builder()
+        .entry("On")
+        .entry("Off")
+        .disabled()
+        .submenu("More", |more| more.entry("Inside"))
+        .disabled()
+        .build();
+    app.set_menu(menu).unwrap();
+
+    let enabled: Vec<_> = tray.menu().iter().map(|item| item.enabled).collect();
+    assert_eq!(enabled, [true, false, false, true]);
+    let menu = tray.menu();
+    assert!(!app.menu_item_enabled(menu[1].item));
+    assert!(!app.menu_item_enabled(menu[2].item));
+    assert!(app.menu_item_enabled(menu[3].item));
+}
+
+#[test]
+fn set_menu_failing_partway_keeps_the_new_items() {
+    let (mut app, tray) = mock_app();
+    let old = app
+        .add_menu_checkable_item("Old", true, |_, _| -> Result<(), std::io::Error> { Ok(()) })
+        .unwrap();
+    let clicks = Arc::new(Mutex::new(0));
+    let counter = clicks.clone();
+    let menu = Menu::builder()
+        .item("New", move |_| -> Result<(), std::io::Error> {
+            *counter.lock().unwrap() += 1;
+            Ok(())
+        })
+        .entry("Missing")
+        .build();
+
+    // The clear and the first item go in.
+    tray.fail_next_menu_batch_after(2);
+    assert!(app.set_menu(menu).is_err());
+    let menu = tray.menu();
+    let labels: Vec<_> = menu.iter().map(|item| item.label.as_str()).collect();
+    assert_eq!(labels, ["New"]);
+    assert_eq!(app.menu_item_checked(old), None);
+
+    tray.click(menu[0].item).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*clicks.lock().unwrap(), 1);
+}
+
+fn shown_pixels(tray: &MockTray) -> Vec<u8> {
+    match tray.icon() {
+        Some(MockIcon::Rgba { rgba, .. }) => rgba,
+        icon => panic!("unexpected icon {:?}", icon),
+    }
+}
+
+#[test]
+fn badges_are_drawn_onto_the_icon() {
+    let (app, tray) = mock_app();
+    assert!(app.set_icon_badge(Some(BadgeSpec::Count(3))).is_err());
+
+    let base = vec![0x20; 32 * 32 * 4];
+    app.set_icon_from_rgba(base.clone(), 32, 32).unwrap();
+    app.set_icon_badge(Some(BadgeSpec::Dot([255, 0, 0, 255]))).unwrap();
+    assert_ne!(shown_pixels(&tray), base);
+
+    // It stays on the icons set after it, until it's taken off.
+    let next = vec![0x40; 32 * 32 * 4];
+    app.set_icon_from_rgba(next.clone(), 32, 32).unwrap();
+    assert_ne!(shown_pixels(&tray), next);
+    app.set_icon_badge(None).unwrap();
+    assert_eq!(shown_pixels(&tray), next);
+}
+
+#[test]
+fn menu_items_get_their_shortcut() {
+    let (mut app, tray) = mock_app();
+    let quit = app
+        .add_menu_item_with_shortcut("Quit", "Cmd+Q", |_| -> Result<(), std::io::Error> { Ok(()) })
+        .unwrap();
+    let shortcut = tray.menu_item(quit).unwrap().shortcut.unwrap();
+    assert_eq!(shortcut, Shortcut::parse("Cmd+Q").unwrap());
+    assert_eq!(shortcut.key, Key::Char('Q'));
+
+    app.set_menu_item_shortcut(quit, "Ctrl+Shift+F5").unwrap();
+    let shortcut = tray.menu_item(quit).unwrap().shortcut.unwrap();
+    assert!(shortcut.modifiers.ctrl && shortcut.modifiers.shift);
+
+    // A shortcut that doesn't parse leaves no item behind.
+    let bad = app.add_menu_item_with_shortcut("Bad", "Cmd+", |_| -> Result<(), std::io::Error> {
+        Ok(())
+    });
+    assert!(bad.is_err());
+    assert_eq!(tray.menu().len(), 1);
+}
+
+#[test]
+fn menus_are_rebuilt_from_a_spec() {
+    let (mut app, tray) = mock_app();
+    let spec = MenuSpec {
+        entries: vec![
+            MenuEntrySpec::Header {
+                title: "Sync".to_owned(),
+            },
+            MenuEntrySpec::Checkable {
+                label: "Paused".to_owned(),
+                checked: true,
+                enabled: true,
+                tag: Some(1),
+            },
+            MenuEntrySpec::Separator,
+            MenuEntrySpec::Submenu {
+                label: "More".to_owned(),
+                enabled: true,
+                tag: None,
+                entries: vec![MenuEntrySpec::Item {
+                    label: "Later".to_owned(),
+                    enabled: false,
+                    tag: Some(2),
+                }],
+            },
+        ],
+    };
+    let menu: Menu = Menu::from_spec(spec.clone());
+    assert_eq!(menu.to_spec(), spec);
+    app.set_menu(menu).unwrap();
+
+    let menu = tray.menu();
+    let kinds: Vec<_> = menu.iter().map(|item| item.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            MockItemKind::Header,
+            MockItemKind::Checkable,
+            MockItemKind::Separator,
+            MockItemKind::Submenu,
+            MockItemKind::Entry,
+        ]
+    );
+    assert!(menu[1].checked);
+    assert_eq!(app.menu_item_checked(menu[1].item), Some(true));
+    assert_eq!(menu[4].label, "Later");
+    assert_eq!(menu[4].parent, Some(menu[3].item));
+    assert!(!menu[4].enabled);
+    assert_eq!(app.menu_item_by_tag(1), Some(menu[1].item));
+    assert_eq!(app.menu_item_tag(menu[4].item), Some(2));
+}
+
+#[test]
+fn with_menu_adds_the_batch() {
+    let (mut app, tray) = mock_app();
+    let clicks = Arc::new(Mutex::new(0));
+    let counter = clicks.clone();
+    let mut added = Vec::new();
+    app.with_menu(|menu| {
+        added.push(menu.add_menu_header("Batch"));
+        let item = menu.add_menu_item("Count", move |_| -> Result<(), std::io::Error> {
+            *counter.lock().unwrap() += 1;
+            Ok(())
+        });
+        menu.set_tag(item, 5);
+        added.push(item);
+        added.push(menu.add_menu_separator());
+    })
+    .unwrap();
+
+    let items: Vec<_> = tray.menu().iter().map(|item| item.item).collect();
+    assert_eq!(items, added);
+    assert_eq!(app.menu_item_by_tag(5), Some(added[1]));
+    tray.click(added[1]).unwrap();
+    app.dispatch_pending().unwrap();
+    assert_eq!(*clicks.lock().unwrap(), 1);
+}
+
+#[test]
+fn with_menu_failing_partway_keeps_the_earlier_items() {
+    let (mut app, tray) = mock_app();
+    let mut added = Vec::new();
+    tray.fail_next_menu_batch_after(1);
+    let result = app.with_menu(|menu| {
+        added.push(menu.add_menu_entry("In"));
+        added.push(menu.add_menu_entry("Out"));
+    });
+    assert!(result.is_err());
+    let items: Vec<_> = tray.menu().iter().map(|item| item.item).collect();
+    assert_eq!(items, [added[0]]);
+
+    // Neither index is handed out again.
+    let next = app.add_menu_entry("Next").unwrap();
+    assert!(next.index() > added[1].index());
+}